@@ -0,0 +1,135 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use cmri::{
+    CmriMessage, CmriStateMachine, MessageBuilder, RxState, TX_BUFFER_LEN,
+};
+use criterion::{
+    black_box, criterion_group, criterion_main, BenchmarkId, Criterion,
+    Throughput,
+};
+
+/// A payload with a few bytes which need escaping
+fn typical(payload_len: usize) -> Vec<u8> {
+    (0..payload_len).map(|n| (n * 7) as u8).collect()
+}
+
+/// A payload of alternating STOP and ESCAPE bytes, all of which need
+/// escaping, doubling its encoded size
+fn worst_case(payload_len: usize) -> Vec<u8> {
+    [0x03, 0x10]
+        .iter()
+        .copied()
+        .cycle()
+        .take(payload_len)
+        .collect()
+}
+
+fn message(payload: &[u8]) -> CmriMessage {
+    MessageBuilder::set(0x41, payload).build().unwrap()
+}
+
+/// A stream of Set messages carrying the payload
+fn stream(payload: &[u8]) -> Vec<u8> {
+    let mut buf = [0_u8; TX_BUFFER_LEN];
+    let len = message(payload).encode(&mut buf).unwrap();
+    buf[..len].repeat(32)
+}
+
+/// Bench names and payloads for each payload length
+fn payloads() -> Vec<(String, Vec<u8>)> {
+    let mut payloads = Vec::new();
+    for payload_len in [6, 64, 256].iter() {
+        payloads.push((format!("{}", payload_len), typical(*payload_len)));
+        payloads.push((
+            format!("{}/worst_case", payload_len),
+            worst_case(*payload_len),
+        ));
+    }
+    payloads
+}
+
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    for (name, payload) in payloads() {
+        let msg = message(&payload);
+        group.throughput(Throughput::Bytes(msg.encoded_len() as u64));
+
+        group.bench_with_input(
+            BenchmarkId::new("encode", &name),
+            &msg,
+            |b, msg| {
+                let mut buf = [0_u8; TX_BUFFER_LEN];
+                b.iter(|| black_box(msg.encode(&mut buf).unwrap()))
+            },
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("encode_iter", &name),
+            &msg,
+            |b, msg| {
+                b.iter(|| {
+                    let mut sum = 0_u8;
+                    for byte in msg.encode_iter().unwrap() {
+                        sum = sum.wrapping_add(byte);
+                    }
+                    black_box(sum)
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    for (name, payload) in payloads() {
+        let bytes = stream(&payload);
+        group.throughput(Throughput::Bytes(bytes.len() as u64));
+
+        group.bench_with_input(
+            BenchmarkId::new("process", &name),
+            &bytes,
+            |b, bytes| {
+                let mut s = CmriStateMachine::new();
+                b.iter(|| {
+                    let mut count = 0;
+                    for byte in bytes.iter() {
+                        if let Ok(RxState::Complete) = s.process(*byte) {
+                            count += 1;
+                        }
+                    }
+                    black_box(count)
+                })
+            },
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("process_buf", &name),
+            &bytes,
+            |b, bytes| {
+                let mut s = CmriStateMachine::new();
+                b.iter(|| {
+                    let mut count = 0;
+                    let mut buf = &bytes[..];
+                    while !buf.is_empty() {
+                        let (n, res) = s.process_buf(buf);
+                        buf = &buf[n..];
+                        if let Ok(RxState::Complete) = res {
+                            count += 1;
+                        }
+                    }
+                    black_box(count)
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, encode, decode);
+criterion_main!(benches);
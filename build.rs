@@ -0,0 +1,41 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Writes the C header for the `ffi` feature. Nothing to do otherwise.
+
+fn main() {
+    #[cfg(feature = "ffi")]
+    ffi_header();
+}
+
+#[cfg(feature = "ffi")]
+fn ffi_header() {
+    use std::env;
+    use std::path::PathBuf;
+
+    println!("cargo:rerun-if-changed=src/ffi.rs");
+    println!("cargo:rerun-if-env-changed=CMRI_FFI_HEADER");
+
+    let crate_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let mut config = cbindgen::Config::default();
+    config.language = cbindgen::Language::C;
+    config.include_guard = Some(String::from("CMRI_H"));
+    config.autogen_warning = Some(String::from(
+        "/* Generated by the cmri build script; do not edit */",
+    ));
+    // Only the C API, not the rest of the crate's public items
+    let header = cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(PathBuf::from(&crate_dir).join("src/ffi.rs"))
+        .generate()
+        .expect("Unable to generate the C header");
+    header.write_to_file(out_dir.join("cmri.h"));
+    if let Some(path) = env::var_os("CMRI_FFI_HEADER") {
+        header.write_to_file(path);
+    }
+}
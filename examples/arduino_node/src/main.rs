@@ -10,6 +10,7 @@
 #![no_std]
 #![no_main]
 
+use cmri::serial_config::{Parity, SerialConfig, StopBits};
 use ruduino::Pin;
 use ruduino::legacy::serial;
 use ruduino::modules::Timer16Setup;
@@ -20,8 +21,8 @@ use avr_core::{port::B5, Timer16};
 
 
 const CPU_FREQUENCY_HZ: u64 = 16_000_000;
-const BAUD: u64 = 9600;
-const UBRR: u16 = (CPU_FREQUENCY_HZ / 16 / BAUD - 1) as u16;
+const SERIAL: SerialConfig = SerialConfig::new(9600);
+const UBRR: u16 = (CPU_FREQUENCY_HZ / 16 / SERIAL.baud as u64 - 1) as u16;
 
 const DESIRED_HZ_TIM1: f64 = 1.0;
 const TIM1_PRESCALER: u64 = 1024;
@@ -43,8 +44,15 @@ pub extern "C" fn main() -> ! {
     serial::Serial::new(UBRR)
         .character_size(serial::CharacterSize::EightBits)
         .mode(serial::Mode::Asynchronous)
-        .parity(serial::Parity::Disabled)
-        .stop_bits(serial::StopBits::OneBit)
+        .parity(match SERIAL.parity {
+            Parity::None => serial::Parity::Disabled,
+            Parity::Even => serial::Parity::Even,
+            Parity::Odd => serial::Parity::Odd,
+        })
+        .stop_bits(match SERIAL.stop_bits {
+            StopBits::One => serial::StopBits::OneBit,
+            StopBits::Two => serial::StopBits::TwoBits,
+        })
         .configure();
 
     // initialise a timer
@@ -5,16 +5,26 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-use cmri::{CmriMessage, CmriStateMachine, RxState};
-use std::io::Read;
-use std::net::{Shutdown, TcpListener, TcpStream};
-use std::thread;
+use cmri::{Filter, FrameReader};
+use std::io::BufReader;
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::{env, process, thread};
 
 const PORT: u16 = 4000;
 
-/// Listens on [::1]:4000 and prints out incoming packets
-
+/// Listens on [::1]:4000 and prints out incoming packets. Given
+/// `--filter "<rules>"`, only prints the packets the rules pass, e.g.
+/// `--filter "drop type poll; pass addr 3-10; drop"`
 fn main() {
+    let filter = Arc::new(match parse_args() {
+        Ok(filter) => filter,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("usage: cmridump [--filter <rules>]");
+            process::exit(2);
+        }
+    });
     let listener = TcpListener::bind(format!("[::1]:{}", PORT)).unwrap();
     println!("Server listening on port {}", PORT);
 
@@ -22,7 +32,8 @@ fn main() {
         match stream {
             Ok(stream) => {
                 println!("Connection from {}", stream.peer_addr().unwrap());
-                thread::spawn(move || tcp_rx(stream));
+                let filter = Arc::clone(&filter);
+                thread::spawn(move || tcp_rx(stream, &filter));
             }
             Err(e) => {
                 println!("Connection failed with error \"{}\"", e);
@@ -32,54 +43,28 @@ fn main() {
     drop(listener);
 }
 
-fn tcp_rx(mut stream: TcpStream) {
-    use RxState::*;
-    // Single-byte buffer so that we process one byte at a time
-    let mut buf = [0_u8; 1];
-    // State machine for receiving
-    let mut state = CmriStateMachine::new();
-    loop {
-        // try reading a byte off the stream
-        //TODO timeout
-        match stream.read(&mut buf) {
-            Ok(1) => {
-                // got a single byte
-                match state.process(buf[0]) {
-                    Ok(Listening) => {
-                        // Do nothing, is listening still
-                    }
-                    Ok(Complete) => {
-                        // Message is complete, print it
-                        if let Err(e) = print_message(state.message()) {
-                            println!("Error: {}", e);
-                        }
-                    }
-                    Err(e) => {
-                        println!("Receive error: {:?}", e);
-                        break;
-                    }
-                }
-            }
+fn parse_args() -> Result<Filter, String> {
+    let mut args = env::args().skip(1);
+    match args.next().as_deref() {
+        None => Ok(Filter::new()),
+        Some("--filter") => {
+            let rules = args.next().ok_or("--filter needs some rules")?;
+            rules.parse().map_err(|e: cmri::Error| e.to_string())
+        }
+        Some(arg) => Err(format!("unexpected argument {}", arg)),
+    }
+}
+
+fn tcp_rx(stream: TcpStream, filter: &Filter) {
+    for msg in FrameReader::new(BufReader::new(stream)) {
+        match msg {
+            Ok(msg) if !filter.allows(&msg) => {}
+            Ok(msg) => println!("{}", msg),
             Err(e) => {
-                println!("Read failed: {}", e);
-                stream.shutdown(Shutdown::Both).unwrap();
+                println!("Receive error: {}", e);
                 break;
             }
-            _ => {}
         }
     }
     println!("client exited");
 }
-
-fn print_message(m: &CmriMessage) -> Result<(), cmri::Error> {
-    use cmri::Error;
-
-    let payload = hex::encode(&m.payload[..m.len]);
-    println!(
-        "{}\t{}\t{:?}",
-        m.address.ok_or(Error::MissingAddress)?,
-        m.message_type.ok_or(Error::MissingType)?,
-        payload,
-    );
-    Ok(())
-}
@@ -5,10 +5,9 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-use cmri::TX_BUFFER_LEN;
-use cmri::{CmriMessage, CmriStateMachine, RxState};
-use std::io::Read;
-use std::thread;
+use cmri::{Address, TX_BUFFER_LEN};
+use cmri::{CmriStateMachine, MessageBuilder, RxState};
+use std::time::Duration;
 
 use rppal::uart::{Parity, Uart};
 
@@ -17,9 +16,10 @@ const BAUD_RATE: u32 = 19200;
 //const RTS_PIN: u8 = 11;
 const ADDR_START: u8 = 1;
 const ADDR_END: u8 = 26;
+/// How long to wait for a node to respond to a Poll
+const POLL_TIMEOUT: Duration = Duration::from_millis(100);
 
 /// Scans the connection for listening C/MRI nodes
-
 fn main() {
     println!("Scanning for nodes via {}", UART);
 
@@ -27,28 +27,28 @@ fn main() {
 
     let mut uart =
         Uart::with_path(UART, BAUD_RATE, Parity::None, 8, 2).unwrap();
-    let mut message = CmriMessage::new();
+    uart.set_read_mode(0, POLL_TIMEOUT).unwrap();
     let mut tx_buffer = [0_u8; TX_BUFFER_LEN];
+    let mut rx_buffer = [0_u8];
     // Send a Poll request to each node address in turn, allowing some
     // time for it to respond
     for addr in ADDR_START..ADDR_END {
         println!("Trying address {}...", addr);
 
         // send Poll
-        message.address = Some(65 + addr);
-        message.encode(&mut tx_buffer);
+        let poll = MessageBuilder::poll(Address::Ua(addr).wire().unwrap())
+            .build()
+            .unwrap();
+        let len = poll.encode(&mut tx_buffer).unwrap();
+        uart.write(&tx_buffer[..len]).unwrap();
+
+        // Read until the node responds or the read times out
+        state.clear();
+        while uart.read(&mut rx_buffer).unwrap() > 0 {
+            if let Ok(RxState::Complete) = state.process(rx_buffer[0]) {
+                println!("{}", state.message());
+                break;
+            }
+        }
     }
 }
-
-fn print_message(m: &CmriMessage) -> Result<(), cmri::Error> {
-    use cmri::Error;
-
-    let payload = hex::encode(&m.payload[..m.len]);
-    println!(
-        "{}\t{}\t{:?}",
-        m.address.ok_or(Error::MissingAddress)?,
-        m.message_type.ok_or(Error::MissingType)?,
-        payload,
-    );
-    Ok(())
-}
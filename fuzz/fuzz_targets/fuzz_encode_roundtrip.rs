@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use cmri::testing::encode_decode_roundtrip;
+
+fuzz_target!(|data: &[u8]| {
+    encode_decode_roundtrip(data);
+});
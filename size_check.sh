@@ -0,0 +1,47 @@
+#!/bin/bash
+
+# Builds the library for a bare-metal target with and without the `fmt`
+# feature and compares the size of the code. Fails if leaving out `fmt`
+# no longer saves anything, which means something has started formatting
+# regardless, or if the build without it has grown past MAX_SIZE bytes
+# when that is set.
+#
+#   TARGET=thumbv6m-none-eabi MAX_SIZE=30000 ./size_check.sh
+#
+# The default target is a Cortex-M0 rather than avr-atmega328p.json, the
+# chip the feature is for. Current nightlies no longer load that target
+# spec, and building for any AVR target needs nightly and rust-src to build
+# core. A Cortex-M0 is a fair stand-in for this check. Nothing in the crate
+# is specific to a target, so both compile the same code. The saving comes
+# from the Debug and Display impls and the core::fmt code they pull in,
+# which are left out just the same on AVR. AVR code is usually bigger in
+# absolute terms, so take MAX_SIZE from a thumbv6m build rather than from
+# the ATmega328's flash.
+
+set -euo pipefail
+
+TARGET=${TARGET:-thumbv6m-none-eabi}
+LLVM_SIZE=${LLVM_SIZE:-$(command -v llvm-size ||
+	ls "$(rustc --print sysroot)"/lib/rustlib/*/bin/llvm-size)}
+
+# Total of text and data in the crate's own object files
+size_with() {
+	local dir="target/size-check/$1"
+	cargo build --quiet --release --lib --target "$TARGET" \
+		--no-default-features --features "$1" --target-dir "$dir"
+	"$LLVM_SIZE" -t "$dir/$TARGET/release/libcmri.rlib" 2>/dev/null |
+		awk '/TOTALS/ { print $1 + $2 }'
+}
+
+with_fmt=$(size_with fmt)
+without_fmt=$(size_with "")
+echo "$TARGET: $with_fmt bytes with fmt, $without_fmt without"
+
+if [ "$without_fmt" -ge "$with_fmt" ]; then
+	echo "Leaving out fmt no longer makes the build any smaller"
+	exit 1
+fi
+if [ -n "${MAX_SIZE:-}" ] && [ "$without_fmt" -gt "$MAX_SIZE" ]; then
+	echo "Build without fmt is over the $MAX_SIZE byte budget"
+	exit 1
+fi
@@ -0,0 +1,201 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Node addresses. A node's unit address (UA) is what's set on its
+// switches, from 0 to 127, and it goes over the wire as UA + 65 so that
+// UA 0 is 'A'. Mixing the two up is an easy mistake to make, so anything
+// which takes an address from the user says which one it means.
+//
+// When two layouts are merged onto one bus, some nodes usually have to
+// move to new addresses. An `AddressMap` lets a gateway translate between
+// the addresses the controller software still uses and the ones now set
+// on the nodes, so neither has to change.
+//
+// Some layouts reserve an address for broadcast Sets which every node
+// acts on. C/MRI itself has no broadcast, so this is opt-in on both the
+// controller and the nodes.
+
+use crate::{Error, Result, ADDRESS_OFFSET};
+
+/// Highest unit address a node can have
+pub const MAX_UA: u8 = 127;
+
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub enum Address {
+    /// Unit address, as set on the node
+    Ua(u8),
+    /// Address byte as sent on the wire
+    Wire(u8),
+}
+
+impl Address {
+    /// Address most commonly reserved for broadcast Sets
+    pub const BROADCAST: Address = Address::Ua(0);
+
+    /// The unit address, or `Error::OutOfBounds` if this isn't a valid
+    /// node address
+    pub fn ua(self) -> Result<u8> {
+        match self {
+            Address::Ua(ua) if ua <= MAX_UA => Ok(ua),
+            Address::Wire(wire) => match wire.checked_sub(ADDRESS_OFFSET) {
+                Some(ua) if ua <= MAX_UA => Ok(ua),
+                _ => Err(Error::OutOfBounds),
+            },
+            _ => Err(Error::OutOfBounds),
+        }
+    }
+
+    /// The address byte as sent on the wire, or `Error::OutOfBounds` if
+    /// this isn't a valid node address
+    pub fn wire(self) -> Result<u8> {
+        Ok(self.ua()? + ADDRESS_OFFSET)
+    }
+}
+
+/// Number of unit addresses
+const UA_COUNT: usize = MAX_UA as usize + 1;
+
+/// One-to-one translation between the unit addresses a controller uses
+/// and those set on the nodes. Starts out leaving every address alone
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct AddressMap {
+    /// Physical UA for each logical UA
+    to_physical: [u8; UA_COUNT],
+    /// Logical UA for each physical UA
+    to_logical: [u8; UA_COUNT],
+}
+
+impl AddressMap {
+    pub fn new() -> Self {
+        let mut identity = [0; UA_COUNT];
+        for (ua, slot) in (0..=MAX_UA).zip(identity.iter_mut()) {
+            *slot = ua;
+        }
+        Self {
+            to_physical: identity,
+            to_logical: identity,
+        }
+    }
+
+    /// Sends frames for unit address `logical` to the node at `physical`.
+    /// To keep the map one to one, whichever logical address had
+    /// `physical` before takes over `logical`'s old physical address
+    pub fn map(&mut self, logical: u8, physical: u8) -> Result<&mut Self> {
+        let old_physical = self.physical(logical)?;
+        let displaced = self.logical(physical)?;
+        self.set(logical, physical);
+        self.set(displaced, old_physical);
+        Ok(self)
+    }
+
+    /// Maps a run of unit addresses onto another run of the same length,
+    /// starting at `physical`
+    pub fn map_range(
+        &mut self,
+        logical: core::ops::RangeInclusive<u8>,
+        physical: u8,
+    ) -> Result<&mut Self> {
+        let last = physical
+            .checked_add(logical.end().saturating_sub(*logical.start()))
+            .filter(|last| *last <= MAX_UA)
+            .ok_or(Error::OutOfBounds)?;
+        for (logical, physical) in logical.zip(physical..=last) {
+            self.map(logical, physical)?;
+        }
+        Ok(self)
+    }
+
+    /// The address byte to send on the bus for a controller's address
+    /// byte. Bytes which aren't node addresses are left alone
+    pub fn to_bus(&self, wire: u8) -> u8 {
+        translate(&self.to_physical, wire)
+    }
+
+    /// The address byte to show the controller for one from the bus
+    pub fn from_bus(&self, wire: u8) -> u8 {
+        translate(&self.to_logical, wire)
+    }
+
+    fn physical(&self, logical: u8) -> Result<u8> {
+        self.to_physical
+            .get(usize::from(logical))
+            .copied()
+            .ok_or(Error::OutOfBounds)
+    }
+
+    fn logical(&self, physical: u8) -> Result<u8> {
+        self.to_logical
+            .get(usize::from(physical))
+            .copied()
+            .ok_or(Error::OutOfBounds)
+    }
+
+    fn set(&mut self, logical: u8, physical: u8) {
+        if let Some(slot) = self.to_physical.get_mut(usize::from(logical)) {
+            *slot = physical;
+        }
+        if let Some(slot) = self.to_logical.get_mut(usize::from(physical)) {
+            *slot = logical;
+        }
+    }
+}
+
+impl Default for AddressMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn translate(table: &[u8; UA_COUNT], wire: u8) -> u8 {
+    Address::Wire(wire)
+        .ua()
+        .ok()
+        .and_then(|ua| table.get(usize::from(ua)))
+        .map_or(wire, |ua| ua + ADDRESS_OFFSET)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn conversions() {
+        assert_eq!(Address::Ua(0).wire(), Ok(b'A'));
+        assert_eq!(Address::Ua(MAX_UA).wire(), Ok(192));
+        assert_eq!(Address::Wire(0x42).ua(), Ok(1));
+        assert_eq!(Address::Wire(0x42).wire(), Ok(0x42));
+
+        assert_eq!(Address::Ua(MAX_UA + 1).wire(), Err(Error::OutOfBounds));
+        assert_eq!(Address::Wire(0x40).ua(), Err(Error::OutOfBounds));
+        assert_eq!(Address::Wire(193).ua(), Err(Error::OutOfBounds));
+    }
+
+    #[test]
+    fn address_map() {
+        let mut map = AddressMap::new();
+        assert_eq!(map.to_bus(b'B'), b'B');
+        map.map_range(1..=10, 30).unwrap();
+        assert_eq!(map.to_bus(b'A' + 1), b'A' + 30);
+        assert_eq!(map.to_bus(b'A' + 10), b'A' + 39);
+        assert_eq!(map.from_bus(b'A' + 39), b'A' + 10);
+        // The nodes which were at 30 to 39 swap places with them
+        assert_eq!(map.to_bus(b'A' + 30), b'A' + 1);
+        assert_eq!(map.from_bus(b'A' + 1), b'A' + 30);
+        assert_eq!(map.to_bus(b'A'), b'A');
+        for ua in 0..=MAX_UA {
+            let wire = b'A' + ua;
+            assert_eq!(map.from_bus(map.to_bus(wire)), wire);
+        }
+        // Bytes which aren't addresses pass through
+        assert_eq!(map.to_bus(0x02), 0x02);
+
+        assert_eq!(map.map(0, MAX_UA + 1), Err(Error::OutOfBounds));
+        assert_eq!(map.map_range(0..=10, 120), Err(Error::OutOfBounds));
+    }
+}
@@ -1,4 +1,8 @@
-use crate::{CmriStateMachine, MessageType, RxState};
+use crate::serial_config::{self, SerialConfig};
+use crate::{
+    Address, BitOrder, CmriMessage, CmriStateMachine, Error, MessageType,
+    Result, RxRing, RxState, TX_BUFFER_LEN,
+};
 use ruduino::legacy::serial;
 
 /// Hardcode this for now. Only used to calculate baud rates for serial.
@@ -13,17 +17,53 @@ const OUTPUT_BYTES: u8 = OUTPUT_BITS / 8;
 
 /// Stores 64 input and 64 output bits as u64. This may not be as efficient
 /// as using arrays of u8 on a 16-bit CPU, but hard to tell without testing
-#[derive(Default)]
 pub struct CmriProcessor {
     input_bits: u64,
     output_bits: u64,
     state: CmriStateMachine,
+    bit_order: BitOrder,
+    /// Address byte put on our Gets
+    address: u8,
+    /// Number of input bytes sent in reply to a Poll
+    input_len: u8,
+    /// Bytes lost because the receive ring was full
+    rx_overflows: u32,
+}
+
+impl Default for CmriProcessor {
+    fn default() -> Self {
+        Self {
+            input_bits: 0,
+            output_bits: 0,
+            state: CmriStateMachine::default(),
+            // What this processor has always done, though C/MRI itself
+            // counts from the LSB
+            bit_order: BitOrder::MsbFirst,
+            address: crate::ADDRESS_OFFSET,
+            input_len: INPUT_BYTES,
+            rx_overflows: 0,
+        }
+    }
 }
 
 impl CmriProcessor {
     /// Initialise a processor attached to the given UART
-    pub fn new(baud: u64) -> Self {
-        let ubrr = (CPU_FREQUENCY_HZ / 16 / baud - 1) as u16;
+    pub fn new(config: &SerialConfig) -> Self {
+        // A zero baud rate gets the slowest setting rather than a panic
+        let ubrr = (CPU_FREQUENCY_HZ / 16)
+            .checked_div(config.baud as u64)
+            .unwrap_or(u64::MAX)
+            .saturating_sub(1)
+            .min(u16::MAX as u64) as u16;
+        let parity = match config.parity {
+            serial_config::Parity::None => serial::Parity::Disabled,
+            serial_config::Parity::Even => serial::Parity::Even,
+            serial_config::Parity::Odd => serial::Parity::Odd,
+        };
+        let stop_bits = match config.stop_bits {
+            serial_config::StopBits::One => serial::StopBits::OneBit,
+            serial_config::StopBits::Two => serial::StopBits::TwoBits,
+        };
 
         // Initialise the UART
         // Don't run this when running unit tests
@@ -31,38 +71,108 @@ impl CmriProcessor {
         serial::Serial::new(ubrr)
             .character_size(serial::CharacterSize::EightBits)
             .mode(serial::Mode::Asynchronous)
-            .parity(serial::Parity::Disabled)
-            .stop_bits(serial::StopBits::OneBit)
+            .parity(parity)
+            .stop_bits(stop_bits)
             .configure();
 
-        // todo address filter
+        // Answers every address until `set_address()` is called
         Default::default()
     }
 
-    pub fn process(&mut self) {
-        use MessageType::*;
-        // Read input chars while they are available
-        while let Some(b) = serial::try_receive() {
-            if let Ok(RxState::Complete) = self.state.process(b) {
-                // got the end of a message; process its contents
-                if let Some(t) = self.state.message().message_type {
-                    match t {
-                        Set => {
-                            // copy message bits into local buffer
-                        }
-                        Poll => {
-                            // send a response back with our local input
-                            // buffer
-                        }
-                        _ => {}
-                    }
+    /// Reads input chars while they are available, answering a Poll with
+    /// the current inputs. Returns true once a whole message has been
+    /// handled, so that the program can update hardware outputs with new
+    /// information/pull new sensor data in before the next poll
+    pub fn process(&mut self) -> bool {
+        self.process_from(serial::try_receive)
+    }
+
+    /// As `process()`, but takes the bytes from a ring filled by the UART
+    /// receive interrupt, so that none are lost while the program is busy
+    /// elsewhere
+    pub fn process_ring<const N: usize>(&mut self, ring: &RxRing<N>) -> bool {
+        let lost = u32::from(ring.take_overflows());
+        self.rx_overflows = self.rx_overflows.wrapping_add(lost);
+        self.process_from(|| ring.pop())
+    }
+
+    /// Bytes lost so far because the receive ring was full. If this goes
+    /// up, drain the ring more often or make it longer
+    pub fn rx_overflows(&self) -> u32 {
+        self.rx_overflows
+    }
+
+    fn process_from(&mut self, mut next: impl FnMut() -> Option<u8>) -> bool {
+        while let Some(b) = next() {
+            if let Some(t) = self.process_byte(b) {
+                if t == MessageType::Poll {
+                    self.transmit();
                 }
-                // Break to allow program to update hardware outputs
-                // with new information/pull new sensor data in before
-                // next poll
-                break;
+                return true;
             }
         }
+        false
+    }
+
+    /// Feeds one received byte through the state machine. At the end of a
+    /// message its type is returned, having copied the bits of a Set into
+    /// the output buffer
+    pub fn process_byte(&mut self, byte: u8) -> Option<MessageType> {
+        match self.state.process(byte) {
+            Ok(RxState::Complete) => {}
+            _ => return None,
+        }
+        let message = self.state.message();
+        let t = message.message_type?;
+        if t == MessageType::Set {
+            let mut bytes = self.output_bits.to_be_bytes();
+            bytes
+                .iter_mut()
+                .zip(message.data())
+                .for_each(|(b, new)| *b = *new);
+            self.output_bits = u64::from_be_bytes(bytes);
+        }
+        Some(t)
+    }
+
+    /// Only answers messages sent to this unit address, and puts it on
+    /// our Gets. Without this every message on the bus is acted on
+    pub fn set_address(&mut self, ua: u8) -> Result<()> {
+        self.state.filter(Address::Ua(ua))?;
+        self.address = Address::Ua(ua).wire()?;
+        Ok(())
+    }
+
+    /// The Get which `transmit` sends, carrying the input bytes
+    fn get_message(&self) -> Result<CmriMessage> {
+        let bytes = self.input_bits.to_be_bytes();
+        let inputs = bytes
+            .get(..self.input_len as usize)
+            .ok_or(Error::OutOfBounds)?;
+        let mut msg = CmriMessage::new();
+        msg.address(self.address)
+            .message_type(MessageType::Get)
+            .payload(inputs)?;
+        Ok(msg)
+    }
+
+    /// Sends the input bytes to the controller as a Get
+    pub fn transmit(&self) {
+        let mut buf = [0_u8; TX_BUFFER_LEN];
+        // The Get always fits, so there's nothing to go wrong here
+        let len = match self.get_message().and_then(|m| m.encode(&mut buf)) {
+            Ok(len) => len,
+            Err(_) => return,
+        };
+        for b in buf.iter().take(len) {
+            serial::transmit(*b);
+        }
+    }
+
+    /// Sets how `get_bit` and `set_bit` number the bits within each byte.
+    /// Defaults to `BitOrder::MsbFirst`
+    pub fn set_bit_order(&mut self, bit_order: BitOrder) {
+        self.bit_order = bit_order;
     }
 
     pub fn get_bit(&self, bit: u8) -> bool {
@@ -71,9 +181,7 @@ impl CmriProcessor {
             return false;
         }
 
-        let mask: u64 = 1 << (OUTPUT_BITS - 1 - bit);
-
-        self.output_bits & mask != 0
+        self.output_bits & self.mask(bit) != 0
     }
 
     pub fn get_byte(&self, byte: u8) -> u8 {
@@ -82,7 +190,8 @@ impl CmriProcessor {
             return 0;
         }
 
-        self.output_bits.to_be_bytes()[byte as usize]
+        let bytes = self.output_bits.to_be_bytes();
+        bytes.get(byte as usize).copied().unwrap_or(0)
 
         //((self.output_bits >> 8*(OUTPUT_BYTES - 1 - byte)) & 0xff) as u8
     }
@@ -93,12 +202,20 @@ impl CmriProcessor {
             return;
         }
 
+        let mask = self.mask(bit);
         match state {
-            true => self.input_bits |= 1 << (INPUT_BITS - 1 - bit),
-            false => self.input_bits &= !(1 << (INPUT_BITS - 1 - bit)),
+            true => self.input_bits |= mask,
+            false => self.input_bits &= !mask,
         }
     }
 
+    /// Mask for a bit in either u64, with byte 0 as the most significant
+    /// byte
+    fn mask(&self, bit: u8) -> u64 {
+        let (byte, shift) = self.bit_order.locate(bit as usize);
+        1 << (8 * (7 - byte) + shift as usize)
+    }
+
     pub fn set_byte(&mut self, byte: u8, state: u8) {
         // ignore overflows
         if byte > INPUT_BYTES - 1 {
@@ -106,11 +223,112 @@ impl CmriProcessor {
         }
 
         let mut bytes = self.input_bits.to_be_bytes();
-        bytes[byte as usize] = state;
+        if let Some(b) = bytes.get_mut(byte as usize) {
+            *b = state;
+        }
         self.input_bits = u64::from_be_bytes(bytes);
     }
 }
 
+/// Mirrors the `CMRI` class of the ArduinoCMRI library, so that a sketch
+/// can be ported call for call. Bits are numbered as there, from the
+/// least significant bit of byte 0. Where ArduinoCMRI would quietly read
+/// or write past the end of its buffers this returns
+/// `Error::OutOfBounds` instead, and at most 64 input and 64 output bits
+/// are supported
+pub struct ArduinoCmri {
+    processor: CmriProcessor,
+    input_bits: u8,
+    output_bits: u8,
+}
+
+impl ArduinoCmri {
+    /// As `CMRI cmri(address, input_bits, output_bits)`. ArduinoCMRI
+    /// defaults to UA 0 with 24 inputs and 48 outputs
+    pub fn new(
+        address: u8,
+        input_bits: u8,
+        output_bits: u8,
+        config: &SerialConfig,
+    ) -> Result<Self> {
+        if input_bits > INPUT_BITS || output_bits > OUTPUT_BITS {
+            return Err(Error::OutOfBounds);
+        }
+        let mut processor = CmriProcessor::new(config);
+        processor.set_address(address)?;
+        processor.set_bit_order(BitOrder::LsbFirst);
+        processor.input_len = bytes_for(input_bits);
+        Ok(Self {
+            processor,
+            input_bits,
+            output_bits,
+        })
+    }
+
+    /// Handles incoming bytes, answering a Poll. True once a message has
+    /// been handled
+    pub fn process(&mut self) -> bool {
+        self.processor.process()
+    }
+
+    /// As `process()`, taking bytes from a ring filled by the UART
+    /// receive interrupt
+    pub fn process_ring<const N: usize>(&mut self, ring: &RxRing<N>) -> bool {
+        self.processor.process_ring(ring)
+    }
+
+    /// Handles a single byte, returning the type of a complete message.
+    /// ArduinoCMRI returns `INVALID` where this returns `None`
+    pub fn process_char(&mut self, c: u8) -> Option<MessageType> {
+        self.processor.process_byte(c)
+    }
+
+    /// Sends the inputs to the controller
+    pub fn transmit(&self) {
+        self.processor.transmit()
+    }
+
+    /// Reads an output bit, as last set by the controller
+    pub fn get_bit(&self, n: u8) -> Result<bool> {
+        if n >= self.output_bits {
+            return Err(Error::OutOfBounds);
+        }
+        Ok(self.processor.get_bit(n))
+    }
+
+    /// Reads a byte of outputs
+    pub fn get_byte(&self, n: u8) -> Result<u8> {
+        if n >= bytes_for(self.output_bits) {
+            return Err(Error::OutOfBounds);
+        }
+        Ok(self.processor.get_byte(n))
+    }
+
+    /// Sets an input bit, ready for the next Poll. ArduinoCMRI returns
+    /// false where this returns `Error::OutOfBounds`
+    pub fn set_bit(&mut self, n: u8, b: bool) -> Result<()> {
+        if n >= self.input_bits {
+            return Err(Error::OutOfBounds);
+        }
+        self.processor.set_bit(n, b);
+        Ok(())
+    }
+
+    /// Sets a byte of inputs
+    pub fn set_byte(&mut self, n: u8, b: u8) -> Result<()> {
+        if n >= bytes_for(self.input_bits) {
+            return Err(Error::OutOfBounds);
+        }
+        self.processor.set_byte(n, b);
+        Ok(())
+    }
+}
+
+/// Bytes needed to hold a number of bits
+fn bytes_for(bits: u8) -> u8 {
+    bits.div_ceil(8)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -129,7 +347,7 @@ mod test {
 
     #[test]
     fn get_bit() {
-        let mut p = CmriProcessor::new(9600);
+        let mut p = CmriProcessor::new(&SerialConfig::new(9600));
         // 1111 0000 0001 0010 1010 1011 0011 0100
         // 1100 1101 0000 0000 0000 0000 1010 1010
         p.output_bits = 0xf012_ab34_cd00_00aa;
@@ -142,7 +360,7 @@ mod test {
     #[test]
     fn get_bit_random() {
         // Try fetching bits from five random numbers
-        let mut p = CmriProcessor::new(9600);
+        let mut p = CmriProcessor::new(&SerialConfig::new(9600));
 
         for _ in 0..5 {
             let number: u64 = random();
@@ -158,7 +376,7 @@ mod test {
 
     #[test]
     fn get_byte() {
-        let mut p = CmriProcessor::new(9600);
+        let mut p = CmriProcessor::new(&SerialConfig::new(9600));
         p.output_bits = 0x1234_5678_90ab_cdef;
 
         assert_eq!(p.get_byte(0), 0x12);
@@ -176,7 +394,7 @@ mod test {
 
     #[test]
     fn get_byte_random() {
-        let mut p = CmriProcessor::new(9600);
+        let mut p = CmriProcessor::new(&SerialConfig::new(9600));
         for _ in 0..5 {
             let number: u64 = random();
             eprintln!("Random number is: {}", number);
@@ -197,7 +415,7 @@ mod test {
 
     #[test]
     fn set_byte() {
-        let mut p = CmriProcessor::new(9600);
+        let mut p = CmriProcessor::new(&SerialConfig::new(9600));
         let bytes: [u8; 8] = [12, 34, 45, 67, 78, 89, 123, 43];
 
         for (n, b) in bytes.iter().enumerate() {
@@ -209,7 +427,7 @@ mod test {
 
     #[test]
     fn set_byte_random() {
-        let mut p = CmriProcessor::new(9600);
+        let mut p = CmriProcessor::new(&SerialConfig::new(9600));
         let mut bytes = [0_u8; 8];
 
         for _ in (0..5) {
@@ -226,7 +444,7 @@ mod test {
 
     #[test]
     fn set_bit() {
-        let mut p = CmriProcessor::new(9600);
+        let mut p = CmriProcessor::new(&SerialConfig::new(9600));
 
         // 1001 1010 00000000...0
         let number: u64 = 0x9a00000000000000;
@@ -239,9 +457,24 @@ mod test {
         assert_eq!(p.input_bits, number);
     }
 
+    #[test]
+    fn lsb_first_bits() {
+        let mut p = CmriProcessor::new(&SerialConfig::new(9600));
+        p.set_bit_order(BitOrder::LsbFirst);
+
+        p.set_bit(0, true);
+        p.set_bit(9, true);
+        assert_eq!(p.input_bits, 0x0102_0000_0000_0000);
+
+        p.output_bits = 0x8001_0000_0000_0000;
+        assert_eq!(p.get_bit(7), true);
+        assert_eq!(p.get_bit(8), true);
+        assert_eq!(p.get_bit(0), false);
+    }
+
     #[test]
     fn set_bit_random() {
-        let mut p = CmriProcessor::new(9600);
+        let mut p = CmriProcessor::new(&SerialConfig::new(9600));
 
         for _ in 0..5 {
             let number: u64 = random();
@@ -253,4 +486,89 @@ mod test {
             assert_eq!(p.input_bits, number);
         }
     }
+
+    fn encoded(msg: &CmriMessage) -> Vec<u8> {
+        let mut buf = [0_u8; TX_BUFFER_LEN];
+        let len = msg.encode(&mut buf).unwrap();
+        buf[..len].to_vec()
+    }
+
+    #[test]
+    fn arduino_cmri_bounds() {
+        let config = SerialConfig::new(9600);
+        assert!(ArduinoCmri::new(0, 65, 48, &config).is_err());
+        assert!(ArduinoCmri::new(128, 24, 48, &config).is_err());
+
+        let mut cmri = ArduinoCmri::new(0, 24, 48, &config).unwrap();
+        assert_eq!(cmri.set_bit(23, true), Ok(()));
+        assert_eq!(cmri.set_bit(24, true), Err(Error::OutOfBounds));
+        assert_eq!(cmri.set_byte(3, 0xff), Err(Error::OutOfBounds));
+        assert_eq!(cmri.get_bit(47), Ok(false));
+        assert_eq!(cmri.get_bit(48), Err(Error::OutOfBounds));
+        assert_eq!(cmri.get_byte(6), Err(Error::OutOfBounds));
+    }
+
+    #[test]
+    fn arduino_cmri_set_and_poll() {
+        let config = SerialConfig::new(9600);
+        let mut cmri = ArduinoCmri::new(1, 24, 48, &config).unwrap();
+
+        // Only the message to UA 1 should be acted on
+        let mut other = CmriMessage::new();
+        other
+            .address(0x41)
+            .message_type(MessageType::Set)
+            .payload(&[0xff])
+            .unwrap();
+        let mut set = CmriMessage::new();
+        set.address(0x42)
+            .message_type(MessageType::Set)
+            .payload(&[0x01, 0x80])
+            .unwrap();
+        let mut types = Vec::new();
+        for b in encoded(&other).into_iter().chain(encoded(&set)) {
+            types.extend(cmri.process_char(b));
+        }
+        assert_eq!(types, [MessageType::Set]);
+
+        // Same numbering as ArduinoCMRI: LSB of byte 0 first
+        assert_eq!(cmri.get_bit(0), Ok(true));
+        assert_eq!(cmri.get_bit(7), Ok(false));
+        assert_eq!(cmri.get_bit(15), Ok(true));
+        assert_eq!(cmri.get_byte(1), Ok(0x80));
+
+        cmri.set_bit(0, true).unwrap();
+        cmri.set_bit(9, true).unwrap();
+        let get = cmri.processor.get_message().unwrap();
+        assert_eq!(get.address, Some(0x42));
+        assert_eq!(get.message_type, Some(MessageType::Get));
+        assert_eq!(get.data(), [0x01, 0x02, 0x00]);
+    }
+
+    #[test]
+    fn process_from_ring() {
+        let ring = RxRing::<16>::new();
+        let mut p = CmriProcessor::new(&SerialConfig::new(9600));
+        let mut set = CmriMessage::new();
+        set.address(0x41)
+            .message_type(MessageType::Set)
+            .payload(&[0x12, 0x34])
+            .unwrap();
+        for b in encoded(&set) {
+            assert!(ring.push(b));
+        }
+        assert!(p.process_ring(&ring));
+        assert_eq!(p.get_byte(0), 0x12);
+        assert_eq!(p.get_byte(1), 0x34);
+        assert!(ring.is_empty());
+        assert!(!p.process_ring(&ring));
+
+        // The main loop fell behind and the ring filled up
+        for b in 0..20 {
+            ring.push(b);
+        }
+        assert!(!p.process_ring(&ring));
+        assert_eq!(p.rx_overflows(), 4);
+        assert!(ring.is_empty());
+    }
 }
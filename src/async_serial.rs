@@ -0,0 +1,267 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Async equivalent of `CmriSocket` built on tokio, plus a serial port
+// wrapper which reopens the port if it goes away (e.g. a USB adapter
+// being unplugged) instead of spinning on a dead file descriptor.
+
+use crate::serial_config::{self, SerialConfig};
+use crate::{
+    CmriMessage, CmriStateMachine, Error, Result, RxState, TX_BUFFER_LEN,
+};
+use std::format;
+use std::string::{String, ToString};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_serial::{Parity, SerialPortBuilderExt, SerialStream, StopBits};
+
+/// Sends and receives C/MRI messages over any async byte stream
+pub struct AsyncCmriPort<T> {
+    transport: T,
+    state: CmriStateMachine,
+    tx_buffer: [u8; TX_BUFFER_LEN],
+    read_timeout: Option<Duration>,
+}
+
+impl<T> AsyncCmriPort<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            state: CmriStateMachine::new(),
+            tx_buffer: [0; TX_BUFFER_LEN],
+            read_timeout: None,
+        }
+    }
+
+    /// Maximum time `receive()` will wait for a complete message. `None`
+    /// waits forever
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip(self, msg),
+            fields(
+                address = ?msg.address,
+                message_type = ?msg.message_type,
+                len = msg.len,
+            ),
+            err(Debug),
+        )
+    )]
+    pub async fn send(&mut self, msg: &CmriMessage) -> Result<()> {
+        let frame = msg.encode_slice(&mut self.tx_buffer)?;
+        self.transport.write_all(frame).await?;
+        self.transport.flush().await?;
+        Ok(())
+    }
+
+    /// Waits for the next complete message, or `Error::Timeout` if the
+    /// read timeout expires first
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(self), err(Debug))
+    )]
+    pub async fn receive(&mut self) -> Result<CmriMessage> {
+        match self.read_timeout {
+            Some(timeout) => {
+                tokio::time::timeout(timeout, self.receive_inner())
+                    .await
+                    .map_err(|_| Error::Timeout)?
+            }
+            None => self.receive_inner().await,
+        }
+    }
+
+    async fn receive_inner(&mut self) -> Result<CmriMessage> {
+        loop {
+            let byte = self.transport.read_u8().await?;
+            if self.state.process(byte)? == RxState::Complete {
+                return Ok(*self.state.message());
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+}
+
+/// How hard to try reopening a serial port after an IO error
+#[derive(Copy, Clone, Debug)]
+pub struct ReconnectPolicy {
+    /// Give up after this many consecutive failed attempts. `None` keeps
+    /// trying forever
+    pub max_attempts: Option<u32>,
+    /// Time to wait between attempts
+    pub delay: Duration,
+}
+
+impl ReconnectPolicy {
+    /// True if no more attempts should be made after `attempts` failures
+    fn exhausted(&self, attempts: u32) -> bool {
+        match self.max_attempts {
+            Some(max) => attempts >= max,
+            None => false,
+        }
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: Some(5),
+            delay: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Async serial port which is opened lazily and reopened according to
+/// its `ReconnectPolicy` whenever an IO error occurs
+pub struct AsyncSerial {
+    path: String,
+    config: SerialConfig,
+    policy: ReconnectPolicy,
+    read_timeout: Option<Duration>,
+    port: Option<AsyncCmriPort<SerialStream>>,
+}
+
+impl AsyncSerial {
+    /// Creates the wrapper; the port is not opened until first use
+    pub fn new(path: &str, config: SerialConfig) -> Self {
+        Self {
+            path: path.to_string(),
+            config,
+            policy: ReconnectPolicy::default(),
+            read_timeout: None,
+            port: None,
+        }
+    }
+
+    pub fn set_reconnect_policy(&mut self, policy: ReconnectPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+        if let Some(port) = self.port.as_mut() {
+            port.set_read_timeout(timeout);
+        }
+    }
+
+    pub async fn send(&mut self, msg: &CmriMessage) -> Result<()> {
+        let res = self.port().await?.send(msg).await;
+        self.drop_port_on_io_error(&res);
+        res
+    }
+
+    pub async fn receive(&mut self) -> Result<CmriMessage> {
+        let res = self.port().await?.receive().await;
+        self.drop_port_on_io_error(&res);
+        res
+    }
+
+    /// Forget the port after an IO error so that it gets reopened next
+    /// time. Timeouts and protocol errors leave it alone
+    fn drop_port_on_io_error<R>(&mut self, res: &Result<R>) {
+        if let Err(Error::IoError(_)) = res {
+            self.port = None;
+        }
+    }
+
+    /// Returns the open port, opening it if necessary
+    async fn port(&mut self) -> Result<&mut AsyncCmriPort<SerialStream>> {
+        if self.port.is_none() {
+            let mut attempts = 0;
+            let stream = loop {
+                match self.open() {
+                    Ok(stream) => break stream,
+                    Err(e) => {
+                        attempts += 1;
+                        if self.policy.exhausted(attempts) {
+                            return Err(e);
+                        }
+                        tokio::time::sleep(self.policy.delay).await;
+                    }
+                }
+            };
+            let mut port = AsyncCmriPort::new(stream);
+            port.set_read_timeout(self.read_timeout);
+            self.port = Some(port);
+        }
+        // Just populated above
+        self.port.as_mut().ok_or(Error::Disconnected)
+    }
+
+    fn open(&self) -> Result<SerialStream> {
+        let parity = match self.config.parity {
+            serial_config::Parity::None => Parity::None,
+            serial_config::Parity::Even => Parity::Even,
+            serial_config::Parity::Odd => Parity::Odd,
+        };
+        let stop_bits = match self.config.stop_bits {
+            serial_config::StopBits::One => StopBits::One,
+            serial_config::StopBits::Two => StopBits::Two,
+        };
+        tokio_serial::new(&self.path, self.config.baud)
+            .parity(parity)
+            .stop_bits(stop_bits)
+            .open_native_async()
+            .map_err(|e| Error::IoError(format!("{}", e)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MessageBuilder;
+
+    #[tokio::test]
+    async fn send_and_receive() {
+        let (a, b) = tokio::io::duplex(TX_BUFFER_LEN);
+        let mut a = AsyncCmriPort::new(a);
+        let mut b = AsyncCmriPort::new(b);
+
+        let msg = MessageBuilder::set(0x41, &[1, 2, 3]).build().unwrap();
+        a.send(&msg).await.unwrap();
+
+        let received = b.receive().await.unwrap();
+        assert_eq!(received.address, Some(0x41));
+        assert_eq!(received.payload[..received.len], [1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn receive_timeout() {
+        let (_a, b) = tokio::io::duplex(TX_BUFFER_LEN);
+        let mut b = AsyncCmriPort::new(b);
+        b.set_read_timeout(Some(Duration::from_millis(10)));
+        assert_eq!(b.receive().await.unwrap_err(), Error::Timeout);
+    }
+
+    #[tokio::test]
+    async fn open_missing_port() {
+        let mut serial = AsyncSerial::new(
+            "/dev/this-port-does-not-exist",
+            SerialConfig::new(9600),
+        );
+        serial.set_reconnect_policy(ReconnectPolicy {
+            max_attempts: Some(2),
+            delay: Duration::from_millis(1),
+        });
+        let msg = MessageBuilder::poll(0x41).build().unwrap();
+        match serial.send(&msg).await {
+            Err(Error::IoError(_)) => {}
+            other => panic!("Expected IoError, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,292 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Simulates one or more nodes behind a TCP listener, for testing
+// controller software without hardware. Every node shares the one
+// connection, as they would share an RS485 bus, and answers Polls with
+// as many input bytes as it was given:
+//
+//   cmri-node-sim --node 0,smini,random --node 1,cpnode,2,toggle=3/5
+//   cmri-node-sim --listen 0.0.0.0:4000 --node 5,susic,8,seq=01/02/04
+
+use cmri::sim::{Behaviour, VirtualBus, VirtualNode};
+use cmri::{expected_get_len, Address, NodeType};
+use std::collections::HashMap;
+use std::env;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process;
+use std::time::{Duration, SystemTime};
+
+const USAGE: &str = "\
+usage: cmri-node-sim [--listen ADDR] --node SPEC [--node SPEC...]
+SPEC is ua,type[,input bytes][,behaviour]
+  type       usic, susic, smini or cpnode
+  input bytes may be left out for an smini
+  behaviour  manual (default), mirror, random[=SEED], toggle=BIT/EVERY
+             or seq=PATTERN/PATTERN/... with each pattern in hex";
+const DEFAULT_LISTEN: &str = "127.0.0.1:4000";
+/// How long to wait for bytes from the controller before checking for
+/// responses to send
+const READ_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// What the controller last told a node, for reporting changes
+#[derive(Clone, Default)]
+struct NodeState {
+    node_type: Option<NodeType>,
+    outputs: Vec<u8>,
+}
+
+struct NodeSpec {
+    ua: u8,
+    node_type: NodeType,
+    input_bytes: usize,
+    behaviour: Behaviour,
+}
+
+fn main() {
+    let (listen, nodes) = match parse_args() {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    let bus = VirtualBus::new();
+    let mut node_types = HashMap::new();
+    for spec in nodes {
+        let node = Address::Ua(spec.ua).wire().and_then(|addr| {
+            VirtualNode::new(addr, spec.input_bytes, spec.behaviour.clone())
+        });
+        match node {
+            Ok(node) => {
+                node_types.insert(node.address(), spec.node_type);
+                bus.add_node(node);
+            }
+            Err(e) => {
+                eprintln!("Failed to create node {}: {}", spec.ua, e);
+                process::exit(2);
+            }
+        }
+        println!(
+            "UA {}: {} with {} input bytes, {:?}",
+            spec.ua, spec.node_type, spec.input_bytes, spec.behaviour
+        );
+    }
+
+    let listener = match TcpListener::bind(&listen) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to listen on {}: {}", listen, e);
+            process::exit(1);
+        }
+    };
+    println!("Listening on {}", listen);
+    // One controller at a time, as on a real bus
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let peer = stream.peer_addr().map(|a| a.to_string());
+                println!(
+                    "Controller connected from {}",
+                    peer.as_deref().unwrap_or("?")
+                );
+                if let Err(e) = serve(stream, &bus, &node_types) {
+                    println!("Connection failed: {}", e);
+                }
+                println!("Controller disconnected");
+            }
+            Err(e) => println!("Connection failed: {}", e),
+        }
+    }
+}
+
+/// Passes bytes between the controller and the nodes until the
+/// controller disconnects
+fn serve(
+    mut stream: TcpStream,
+    bus: &VirtualBus,
+    node_types: &HashMap<u8, NodeType>,
+) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut to_nodes = bus.clone();
+    let mut from_nodes = bus.clone();
+    let mut seen = HashMap::new();
+    let mut buf = [0_u8; 512];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => {
+                to_nodes.write_all(buf.get(..n).unwrap_or_default())?;
+                report_changes(bus, node_types, &mut seen);
+            }
+            Err(e)
+                if e.kind() == ErrorKind::WouldBlock
+                    || e.kind() == ErrorKind::TimedOut
+                    || e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+        loop {
+            match from_nodes.read(&mut buf) {
+                Ok(n) if n > 0 => {
+                    stream.write_all(buf.get(..n).unwrap_or_default())?
+                }
+                _ => break,
+            }
+        }
+    }
+}
+
+/// Shows any node whose node type or outputs the controller has changed
+/// since `seen` was last updated, warning about an Init for a different
+/// type of node than the one simulated
+fn report_changes(
+    bus: &VirtualBus,
+    node_types: &HashMap<u8, NodeType>,
+    seen: &mut HashMap<u8, NodeState>,
+) {
+    for addr in bus.addresses() {
+        let now = bus.with_node(addr, |n| NodeState {
+            node_type: n.driver().node_type(),
+            outputs: n.outputs().to_vec(),
+        });
+        let now = match now {
+            Some(now) => now,
+            None => continue,
+        };
+        let old = seen.insert(addr, now.clone()).unwrap_or_default();
+        let ua = Address::Wire(addr).ua().unwrap_or(addr);
+        if now.node_type != old.node_type {
+            match (now.node_type, node_types.get(&addr)) {
+                (Some(got), Some(expected)) if got != *expected => println!(
+                    "UA {}: initialised as {}, but simulating {}",
+                    ua, got, expected
+                ),
+                (Some(got), _) => println!("UA {}: initialised as {}", ua, got),
+                (None, _) => {}
+            }
+        }
+        if now.outputs != old.outputs {
+            let bytes: Vec<String> =
+                now.outputs.iter().map(|b| format!("{:02x}", b)).collect();
+            println!("UA {}: outputs {}", ua, bytes.join(" "));
+        }
+    }
+}
+
+fn parse_args() -> Result<(String, Vec<NodeSpec>), String> {
+    let mut args = env::args().skip(1);
+    let mut listen = String::from(DEFAULT_LISTEN);
+    let mut nodes = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--listen" => {
+                listen = args.next().ok_or("--listen needs an address")?;
+            }
+            "--node" => {
+                let spec = args.next().ok_or("--node needs a node spec")?;
+                let spec = parse_node(&spec)?;
+                if nodes.iter().any(|n: &NodeSpec| n.ua == spec.ua) {
+                    return Err(format!(
+                        "UA {} is used more than once",
+                        spec.ua
+                    ));
+                }
+                nodes.push(spec);
+            }
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+    if nodes.is_empty() {
+        return Err(String::from("no nodes given"));
+    }
+    Ok((listen, nodes))
+}
+
+fn parse_node(spec: &str) -> Result<NodeSpec, String> {
+    let mut fields = spec.split(',');
+    let ua = fields
+        .next()
+        .and_then(|f| f.parse().ok())
+        .ok_or_else(|| format!("{}: bad unit address", spec))?;
+    let node_type = match fields.next() {
+        Some("usic") => NodeType::Usic,
+        Some("susic") => NodeType::Susic,
+        Some("smini") => NodeType::Smini,
+        Some("cpnode") => NodeType::Cpnode,
+        _ => return Err(format!("{}: bad node type", spec)),
+    };
+    let mut rest: Vec<&str> = fields.collect();
+    let input_bytes = match rest.first().map(|f| f.parse()) {
+        Some(Ok(bytes)) => {
+            rest.remove(0);
+            bytes
+        }
+        _ if node_type == NodeType::Smini => expected_get_len(node_type, &[]),
+        _ => return Err(format!("{}: needs a number of input bytes", spec)),
+    };
+    let behaviour = match rest.as_slice() {
+        [] => Behaviour::Manual,
+        [behaviour] => parse_behaviour(behaviour)
+            .ok_or_else(|| format!("{}: bad behaviour", spec))?,
+        _ => return Err(format!("{}: too many fields", spec)),
+    };
+    Ok(NodeSpec {
+        ua,
+        node_type,
+        input_bytes,
+        behaviour,
+    })
+}
+
+fn parse_behaviour(behaviour: &str) -> Option<Behaviour> {
+    let (name, value) = match behaviour.split_once('=') {
+        Some((name, value)) => (name, Some(value)),
+        None => (behaviour, None),
+    };
+    match (name, value) {
+        ("manual", None) => Some(Behaviour::Manual),
+        ("mirror", None) => Some(Behaviour::MirrorOutputs),
+        ("random", None) => {
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+            Some(Behaviour::Random {
+                seed: now.map_or(1, |t| t.subsec_nanos()),
+            })
+        }
+        ("random", Some(seed)) => Some(Behaviour::Random {
+            seed: seed.parse().ok()?,
+        }),
+        ("toggle", Some(value)) => {
+            let (bit, every) = value.split_once('/')?;
+            Some(Behaviour::Toggle {
+                bit: bit.parse().ok()?,
+                every: every.parse().ok()?,
+            })
+        }
+        ("seq", Some(value)) => value
+            .split('/')
+            .map(parse_hex)
+            .collect::<Option<_>>()
+            .map(Behaviour::Sequence),
+        _ => None,
+    }
+}
+
+/// Parses a run of hex digit pairs, e.g. 01ff
+fn parse_hex(pattern: &str) -> Option<Vec<u8>> {
+    if pattern.is_empty() || !pattern.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..pattern.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(pattern.get(i..i + 2)?, 16).ok())
+        .collect()
+}
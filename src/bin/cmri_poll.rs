@@ -0,0 +1,261 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Interactive tool for bench-testing a single node. Connects over a
+// serial port or to a TCP bridge, then reads commands from stdin or from
+// a script file:
+//
+//   cmri-poll /dev/ttyUSB0 --baud 19200
+//   cmri-poll /dev/ttyUSB0 --tx-enable rts-low
+//   cmri-poll 192.168.1.10:4000 --script init-and-test.txt
+
+use cmri::commission;
+use cmri::transport::serial::SerialTransport;
+use cmri::transport::ReadWrite;
+use cmri::{
+    Address, CmriMessage, CmriSocket, ControlLine, MessageBuilder, ScanOptions,
+    SerialConfig, TxEnable, MAX_UA,
+};
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::net::TcpStream;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+const USAGE: &str = "\
+usage: cmri-poll <serial port | host:port> [--baud N] [--script FILE]
+                 [--tx-enable rts|dtr|both[-low]]";
+const HELP: &str = "\
+commands:
+  addr <ua>           choose the node to talk to
+  init <bytes...>     send an Init with the given payload
+  set <bytes...>      send a Set with the given outputs
+  poll                poll the node and show its inputs
+  scan [first last]   poll every unit address and report conflicts
+  wait <ms>           pause, for scripts
+  help                show this list
+  quit                exit
+bytes are hex (0x1f, 1f) or binary (0b00011111); # starts a comment";
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(500);
+
+struct Args {
+    target: String,
+    baud: u32,
+    script: Option<String>,
+    tx_enable: Option<TxEnable>,
+}
+
+fn main() {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    let transport = match open(&args) {
+        Ok(transport) => transport,
+        Err(e) => {
+            eprintln!("Failed to open {}: {}", args.target, e);
+            process::exit(1);
+        }
+    };
+    let mut socket = CmriSocket::builder(transport)
+        .read_timeout(RESPONSE_TIMEOUT)
+        .build();
+    let mut ua = 0;
+
+    match &args.script {
+        Some(path) => {
+            let script = match fs::read_to_string(path) {
+                Ok(script) => script,
+                Err(e) => {
+                    eprintln!("Failed to read {}: {}", path, e);
+                    process::exit(1);
+                }
+            };
+            for (n, line) in script.lines().enumerate() {
+                println!("> {}", line);
+                if let Err(e) = run(&mut socket, &mut ua, line) {
+                    eprintln!("{}:{}: {}", path, n + 1, e);
+                    process::exit(1);
+                }
+            }
+        }
+        None => {
+            println!("Connected to {}, type help for commands", args.target);
+            let stdin = io::stdin();
+            loop {
+                print!("UA {}> ", ua);
+                io::stdout().flush().unwrap();
+                let mut line = String::new();
+                if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+                    break;
+                }
+                if let Err(e) = run(&mut socket, &mut ua, &line) {
+                    println!("error: {}", e);
+                }
+            }
+        }
+    }
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = env::args().skip(1);
+    let mut parsed = Args {
+        target: String::new(),
+        baud: SerialConfig::default().baud,
+        script: None,
+        tx_enable: None,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--baud" => {
+                parsed.baud = args
+                    .next()
+                    .and_then(|b| b.parse().ok())
+                    .ok_or("--baud needs a number")?;
+            }
+            "--script" => {
+                parsed.script =
+                    Some(args.next().ok_or("--script needs a file")?);
+            }
+            "--tx-enable" => {
+                let line = args.next().ok_or("--tx-enable needs a line")?;
+                parsed.tx_enable = Some(parse_tx_enable(&line)?);
+            }
+            _ if parsed.target.is_empty() => parsed.target = arg,
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+    if parsed.target.is_empty() {
+        return Err(String::from("no serial port or address given"));
+    }
+    Ok(parsed)
+}
+
+/// A control line name, with `-low` on the end if it is driven low to
+/// transmit
+fn parse_tx_enable(arg: &str) -> Result<TxEnable, String> {
+    let (name, active_low) = match arg.strip_suffix("-low") {
+        Some(name) => (name, true),
+        None => (arg, false),
+    };
+    let line = match name {
+        "rts" => ControlLine::Rts,
+        "dtr" => ControlLine::Dtr,
+        "both" => ControlLine::RtsAndDtr,
+        _ => return Err(format!("unknown control line {}", arg)),
+    };
+    let tx_enable = TxEnable::new(line);
+    Ok(if active_low {
+        tx_enable.active_low()
+    } else {
+        tx_enable
+    })
+}
+
+/// Anything with a colon in it is taken to be a TCP address
+fn open(args: &Args) -> cmri::Result<Box<dyn ReadWrite>> {
+    if args.target.contains(':') {
+        let stream = TcpStream::connect(&args.target)?;
+        stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+        Ok(Box::new(stream))
+    } else {
+        let mut config = SerialConfig::new(args.baud);
+        config.tx_enable = args.tx_enable;
+        Ok(Box::new(SerialTransport::open(&args.target, &config)?))
+    }
+}
+
+/// Runs one command line
+fn run(socket: &mut CmriSocket, ua: &mut u8, line: &str) -> Result<(), String> {
+    let line = line.split('#').next().unwrap_or("");
+    let mut words = line.split_whitespace();
+    let command = match words.next() {
+        Some(command) => command,
+        None => return Ok(()),
+    };
+    let addr = Address::Ua(*ua).wire().map_err(|e| e.to_string())?;
+    match command {
+        "addr" => {
+            let new = words
+                .next()
+                .and_then(|w| w.parse().ok())
+                .ok_or("addr needs a unit address")?;
+            Address::Ua(new)
+                .wire()
+                .map_err(|_| "no such unit address")?;
+            *ua = new;
+        }
+        "init" | "set" => {
+            let bytes = words.map(parse_byte).collect::<Result<Vec<_>, _>>()?;
+            let msg = if command == "init" {
+                MessageBuilder::init(addr, &bytes)
+            } else {
+                MessageBuilder::set(addr, &bytes)
+            }
+            .build()
+            .map_err(|e| e.to_string())?;
+            socket.send(&msg).map_err(|e| e.to_string())?;
+        }
+        "poll" => {
+            let msg = socket.poll(addr).map_err(|e| e.to_string())?;
+            print_inputs(&msg);
+        }
+        "scan" => {
+            let mut bound = |default| match words.next() {
+                Some(w) => w.parse().map_err(|_| "scan needs unit addresses"),
+                None => Ok(default),
+            };
+            let (first, last) = (bound(0)?, bound(MAX_UA)?);
+            let report =
+                commission::scan(socket, first..=last, &ScanOptions::default())
+                    .map_err(|e| e.to_string())?;
+            println!("{}", report);
+        }
+        "wait" => {
+            let ms = words
+                .next()
+                .and_then(|w| w.parse().ok())
+                .ok_or("wait needs a number of milliseconds")?;
+            thread::sleep(Duration::from_millis(ms));
+        }
+        "help" => println!("{}", HELP),
+        "quit" | "exit" => process::exit(0),
+        _ => return Err(format!("unknown command {}", command)),
+    }
+    Ok(())
+}
+
+fn parse_byte(word: &str) -> Result<u8, String> {
+    let res = if let Some(bin) = word.strip_prefix("0b") {
+        u8::from_str_radix(bin, 2)
+    } else {
+        u8::from_str_radix(word.trim_start_matches("0x"), 16)
+    };
+    res.map_err(|_| format!("{} is not a byte", word))
+}
+
+/// Shows each input byte with its bits in C/MRI order, LSB first
+fn print_inputs(msg: &CmriMessage) {
+    println!("{}", msg);
+    for (n, byte) in msg.data().iter().enumerate() {
+        let bits: Vec<&str> = (0..8)
+            .map(|bit| if byte & (1 << bit) != 0 { "1" } else { "." })
+            .collect();
+        println!(
+            "  {:>4}-{:<4} 0x{:02x}  {}",
+            n * 8,
+            n * 8 + 7,
+            byte,
+            bits.join(" ")
+        );
+    }
+}
@@ -0,0 +1,224 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Bit-level access to message payloads. C/MRI numbers bits from the
+// least significant bit of the first payload byte, so bit 0 is the LSB of
+// byte 0 and bit 8 is the LSB of byte 1. Cards follow on from each other
+// with no gaps. Some node firmwares count from the MSB of each byte
+// instead, which `BitOrder` allows for where bits are looked up by number.
+
+use crate::{CmriMessage, Error, NodeType, Result, MAX_PAYLOAD_LEN};
+
+impl CmriMessage {
+    /// Iterates over every bit of the payload in C/MRI order
+    pub fn bits(&self) -> impl Iterator<Item = bool> + '_ {
+        self.data()
+            .iter()
+            .flat_map(|byte| (0..8).map(move |n| byte & (1 << n) != 0))
+    }
+
+    /// Reads a payload bit, counting from the start of the payload
+    pub fn bit(&self, bit: usize) -> Result<bool> {
+        let byte = self.data().get(bit / 8).ok_or(Error::OutOfBounds)?;
+        Ok(byte & (1 << (bit % 8)) != 0)
+    }
+
+    /// Sets a payload bit, counting from the start of the payload. The
+    /// payload is extended with zeroes if the bit is beyond its end
+    pub fn set_bit(&mut self, bit: usize, val: bool) -> Result<()> {
+        let byte = bit / 8;
+        if byte >= MAX_PAYLOAD_LEN {
+            return Err(Error::OutOfBounds);
+        }
+        if byte >= self.len {
+            self.payload
+                .iter_mut()
+                .take(byte + 1)
+                .skip(self.len)
+                .for_each(|b| *b = 0);
+            self.len = byte + 1;
+        }
+        let mask = 1 << (bit % 8);
+        let byte = self.payload.get_mut(byte).ok_or(Error::OutOfBounds)?;
+        if val {
+            *byte |= mask;
+        } else {
+            *byte &= !mask;
+        }
+        Ok(())
+    }
+
+    /// Reads a pin on a card, using the node type to find the card size
+    pub fn pin(&self, node_type: NodeType, card: u8, pin: u8) -> Result<bool> {
+        self.bit(pin_to_bit(node_type, card, pin)?)
+    }
+
+    /// Sets a pin on a card, using the node type to find the card size
+    pub fn set_pin(
+        &mut self,
+        node_type: NodeType,
+        card: u8,
+        pin: u8,
+        val: bool,
+    ) -> Result<()> {
+        self.set_bit(pin_to_bit(node_type, card, pin)?, val)
+    }
+}
+
+/// Which end of each byte bit 0 is at. Bytes are always in payload
+/// order; this only changes the numbering within each byte
+#[derive(Copy, Clone, Default, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum BitOrder {
+    /// Bit 0 is the least significant bit of byte 0, as in C/MRI
+    #[default]
+    LsbFirst,
+    /// Bit 0 is the most significant bit of byte 0
+    MsbFirst,
+}
+
+impl BitOrder {
+    /// Splits a bit number into the byte it is in and its shift from the
+    /// least significant bit of that byte
+    pub fn locate(self, bit: usize) -> (usize, u8) {
+        let shift = (bit % 8) as u8;
+        match self {
+            BitOrder::LsbFirst => (bit / 8, shift),
+            BitOrder::MsbFirst => (bit / 8, 7 - shift),
+        }
+    }
+
+    /// True if the bit is at the default end of the byte
+    pub fn is_lsb_first(&self) -> bool {
+        *self == BitOrder::LsbFirst
+    }
+}
+
+/// An input which changed between two Gets from a node
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct InputChanged {
+    /// Address byte of the node
+    pub node: u8,
+    /// Bit number, counting from the start of the payload
+    pub bit: usize,
+    /// True if the input turned on, false if it turned off
+    pub rising: bool,
+}
+
+/// Every input which differs between two Get payloads from `node`, in bit
+/// order. Bytes missing from the shorter payload count as all off
+pub fn input_changes<'a>(
+    node: u8,
+    old: &'a [u8],
+    new: &'a [u8],
+) -> impl Iterator<Item = InputChanged> + 'a {
+    let len = old.len().max(new.len());
+    (0..len).flat_map(move |n| {
+        let old = old.get(n).copied().unwrap_or(0);
+        let new = new.get(n).copied().unwrap_or(0);
+        let changed = old ^ new;
+        (0..8)
+            .filter(move |bit| changed & (1 << bit) != 0)
+            .map(move |bit| InputChanged {
+                node,
+                bit: n * 8 + bit,
+                rising: new & (1 << bit) != 0,
+            })
+    })
+}
+
+/// Converts a card and pin to a bit index within the payload
+fn pin_to_bit(node_type: NodeType, card: u8, pin: u8) -> Result<usize> {
+    let size = node_type.card_size();
+    if pin as usize >= size.bits() {
+        return Err(Error::OutOfBounds);
+    }
+    Ok(size.range(card as usize).start * 8 + pin as usize)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn iterate_bits() {
+        let mut m = CmriMessage::new();
+        m.payload(&[0b0000_0101, 0b1000_0000]).unwrap();
+        let bits: Vec<bool> = m.bits().collect();
+        assert_eq!(bits.len(), 16);
+        assert_eq!(bits[..4], [true, false, true, false]);
+        assert!(bits[15]);
+        assert_eq!(bits.iter().filter(|b| **b).count(), 3);
+    }
+
+    #[test]
+    fn set_and_get_bits() {
+        let mut m = CmriMessage::new();
+        m.set_bit(9, true).unwrap();
+        assert_eq!(m.len, 2);
+        assert_eq!(m.payload[..2], [0, 0b10]);
+        assert_eq!(m.bit(9), Ok(true));
+        assert_eq!(m.bit(8), Ok(false));
+        assert_eq!(m.bit(16), Err(Error::OutOfBounds));
+
+        m.set_bit(9, false).unwrap();
+        assert_eq!(m.payload[..2], [0, 0]);
+        assert_eq!(
+            m.set_bit(MAX_PAYLOAD_LEN * 8, true),
+            Err(Error::OutOfBounds)
+        );
+    }
+
+    #[test]
+    fn card_and_pin() {
+        // Card 3 pin 5 on 24-bit cards is bit 77: byte 9, bit 5
+        let mut m = CmriMessage::new();
+        m.set_pin(NodeType::Usic, 3, 5, true).unwrap();
+        assert_eq!(m.len, 10);
+        assert_eq!(m.payload[9], 0b0010_0000);
+        assert_eq!(m.pin(NodeType::Usic, 3, 5), Ok(true));
+
+        // The same pin on 32-bit cards is bit 101: byte 12, bit 5
+        let mut m = CmriMessage::new();
+        m.set_pin(NodeType::Susic, 3, 5, true).unwrap();
+        assert_eq!(m.payload[12], 0b0010_0000);
+
+        // Pin numbers must fit on the card
+        let res = m.set_pin(NodeType::Cpnode, 0, 8, true);
+        assert_eq!(res, Err(Error::OutOfBounds));
+    }
+
+    #[test]
+    fn bit_orders() {
+        assert_eq!(BitOrder::default(), BitOrder::LsbFirst);
+        assert_eq!(BitOrder::LsbFirst.locate(0), (0, 0));
+        assert_eq!(BitOrder::LsbFirst.locate(10), (1, 2));
+        assert_eq!(BitOrder::MsbFirst.locate(0), (0, 7));
+        assert_eq!(BitOrder::MsbFirst.locate(10), (1, 5));
+        assert_eq!(BitOrder::MsbFirst.locate(15), (1, 0));
+    }
+
+    #[test]
+    fn input_edges() {
+        let changes: Vec<_> =
+            input_changes(0x41, &[0b0000_0011], &[0b0000_0110, 0x80]).collect();
+        let edge = |bit, rising| InputChanged {
+            node: 0x41,
+            bit,
+            rising,
+        };
+        assert_eq!(changes, [edge(0, false), edge(2, true), edge(15, true)]);
+        assert_eq!(input_changes(0x41, &[1, 2], &[1, 2]).count(), 0);
+    }
+}
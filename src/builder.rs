@@ -0,0 +1,154 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::payload::InitPayload;
+use crate::{CmriMessage, Error, MessageType, Result};
+
+/// Typed builder for `CmriMessage`s which checks that the payload makes
+/// sense for the message type before handing back a message. The raw
+/// `CmriMessage` struct is still available for anything unusual.
+#[derive(Copy, Clone)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct MessageBuilder<'a> {
+    address: u8,
+    message_type: MessageType,
+    payload: &'a [u8],
+}
+
+impl<'a> MessageBuilder<'a> {
+    /// Poll a node for its inputs. Polls never carry a payload
+    pub fn poll(addr: u8) -> Self {
+        Self {
+            address: addr,
+            message_type: MessageType::Poll,
+            payload: &[],
+        }
+    }
+
+    /// Set a node's outputs. There must be at least one output byte
+    pub fn set(addr: u8, outputs: &'a [u8]) -> Self {
+        Self {
+            address: addr,
+            message_type: MessageType::Set,
+            payload: outputs,
+        }
+    }
+
+    /// Initialise a node. The config must start with a valid node type
+    /// and contain at least the delay and card count
+    pub fn init(addr: u8, config: &'a [u8]) -> Self {
+        Self {
+            address: addr,
+            message_type: MessageType::Init,
+            payload: config,
+        }
+    }
+
+    /// Report a node's inputs back to the controller. There must be at
+    /// least one input byte
+    pub fn get(addr: u8, inputs: &'a [u8]) -> Self {
+        Self {
+            address: addr,
+            message_type: MessageType::Get,
+            payload: inputs,
+        }
+    }
+
+    /// Check the payload against the message type and produce the message
+    pub fn build(&self) -> Result<CmriMessage> {
+        validate(self.message_type, self.payload)?;
+
+        let mut message = CmriMessage::new();
+        message
+            .address(self.address)
+            .message_type(self.message_type)
+            .payload(self.payload)?;
+        Ok(message)
+    }
+}
+
+/// Checks that a payload is acceptable for the given message type
+pub fn validate(message_type: MessageType, payload: &[u8]) -> Result<()> {
+    use MessageType::*;
+    match message_type {
+        Poll => {
+            if !payload.is_empty() {
+                return Err(Error::UnexpectedPayload);
+            }
+        }
+        Set | Get => {
+            if payload.len() < message_type.min_payload_len() {
+                return Err(Error::EmptyPayload);
+            }
+        }
+        Init => {
+            InitPayload::parse(payload)?;
+        }
+        // Nothing is known about the payload of an unrecognised type
+        Unknown(_) => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{NodeType, MAX_PAYLOAD_LEN};
+
+    #[test]
+    fn build_poll() {
+        let m = MessageBuilder::poll(0x41).build().unwrap();
+        assert_eq!(m.address, Some(0x41));
+        assert_eq!(m.message_type, Some(MessageType::Poll));
+        assert_eq!(m.len, 0);
+    }
+
+    #[test]
+    fn build_set_and_get() {
+        let m = MessageBuilder::set(0x42, &[1, 2, 3]).build().unwrap();
+        assert_eq!(m.message_type, Some(MessageType::Set));
+        assert_eq!(m.payload[..m.len], [1, 2, 3]);
+
+        let m = MessageBuilder::get(0x42, &[4, 5]).build().unwrap();
+        assert_eq!(m.message_type, Some(MessageType::Get));
+        assert_eq!(m.payload[..m.len], [4, 5]);
+
+        let res = MessageBuilder::set(0x42, &[]).build();
+        assert_eq!(res.unwrap_err(), Error::EmptyPayload);
+        let res = MessageBuilder::get(0x42, &[]).build();
+        assert_eq!(res.unwrap_err(), Error::EmptyPayload);
+    }
+
+    #[test]
+    fn build_init() {
+        let config = [u8::from(NodeType::Smini), 0, 0, 0];
+        let m = MessageBuilder::init(0x43, &config).build().unwrap();
+        assert_eq!(m.message_type, Some(MessageType::Init));
+        assert_eq!(m.len, 4);
+
+        let res = MessageBuilder::init(0x43, &config[..3]).build();
+        assert_eq!(res.unwrap_err(), Error::InitTooShort);
+
+        let res = MessageBuilder::init(0x43, &[b'Z', 0, 0, 0]).build();
+        assert_eq!(res.unwrap_err(), Error::InvalidNodeType);
+    }
+
+    #[test]
+    fn build_rejects_bad_lengths() {
+        let res = MessageBuilder::poll(0x41);
+        let res = MessageBuilder {
+            payload: &[1],
+            ..res
+        }
+        .build();
+        assert_eq!(res.unwrap_err(), Error::UnexpectedPayload);
+
+        let outputs = [0_u8; MAX_PAYLOAD_LEN + 1];
+        let res = MessageBuilder::set(0x41, &outputs).build();
+        assert_eq!(res.unwrap_err(), Error::DataTooLong);
+    }
+}
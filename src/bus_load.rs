@@ -0,0 +1,245 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Bus utilisation, for choosing poll intervals and baud rates. The
+// estimate adds up the time each node's Poll and Get take on the wire,
+// along with the line turnarounds and the node's own transmit delay, and
+// divides by how often it is polled. Sets are counted at however often
+// outputs are expected to change. Anything over 1.0 means the schedule
+// can't be kept, and in practice it's worth leaving plenty of headroom
+// for retries.
+//
+// Estimates assume that no payload bytes need escaping, which holds for
+// most traffic. `UtilisationMeter` measures the real figure from a
+// socket's counters.
+
+use crate::serial_config::SerialConfig;
+use crate::{timing, Error, Result, SocketStats};
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+
+/// Preambles, start, address, type and stop bytes
+const FRAME_OVERHEAD: usize = 6;
+
+/// Schedule for one node
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct NodeLoad {
+    /// Address byte
+    pub address: u8,
+    pub input_bytes: usize,
+    pub output_bytes: usize,
+    pub poll_interval: Duration,
+    /// How often the outputs are expected to change, or `None` if they
+    /// never do
+    pub set_interval: Option<Duration>,
+    /// Transmit delay from the node's Init, before it answers a Poll
+    pub response_delay: Duration,
+}
+
+impl NodeLoad {
+    pub fn new(
+        address: u8,
+        input_bytes: usize,
+        poll_interval: Duration,
+    ) -> Self {
+        Self {
+            address,
+            input_bytes,
+            output_bytes: 0,
+            poll_interval,
+            set_interval: None,
+            response_delay: Duration::ZERO,
+        }
+    }
+
+    /// Takes the sizes and poll interval from a roster entry, using
+    /// `default_interval` for nodes which are polled as often as possible
+    #[cfg(feature = "config")]
+    pub fn from_roster(
+        entry: &crate::RosterEntry,
+        default_interval: Duration,
+    ) -> Self {
+        Self {
+            output_bytes: entry.output_bytes,
+            ..Self::new(
+                entry.address,
+                entry.input_bytes,
+                entry.poll_interval.unwrap_or(default_interval),
+            )
+        }
+    }
+
+    /// Bus time for one Poll and its Get, in microseconds
+    pub fn poll_time(&self, config: &SerialConfig) -> u64 {
+        let bytes = 2 * FRAME_OVERHEAD + self.input_bytes;
+        // The bus turns around once for the Get and once after it
+        let turnarounds = 2 * timing::TURNAROUND_BYTES;
+        (bytes as u64 + turnarounds) * config.byte_time()
+            + self.response_delay.as_micros() as u64
+    }
+
+    /// Bus time for one Set, in microseconds
+    pub fn set_time(&self, config: &SerialConfig) -> u64 {
+        (FRAME_OVERHEAD + self.output_bytes) as u64 * config.byte_time()
+    }
+
+    /// Fraction of the bus this node needs
+    pub fn utilisation(&self, config: &SerialConfig) -> f64 {
+        let sets = self
+            .set_interval
+            .map(|i| fraction(self.set_time(config), i))
+            .unwrap_or(0.0);
+        fraction(self.poll_time(config), self.poll_interval) + sets
+    }
+}
+
+/// Expected bus utilisation for a set of nodes
+#[derive(Clone, Debug, PartialEq)]
+pub struct BusEstimate {
+    pub config: SerialConfig,
+    /// Each node's address byte and share of the bus
+    pub nodes: Vec<(u8, f64)>,
+}
+
+impl BusEstimate {
+    pub fn new(config: SerialConfig, nodes: &[NodeLoad]) -> Self {
+        Self {
+            config,
+            nodes: nodes
+                .iter()
+                .map(|n| (n.address, n.utilisation(&config)))
+                .collect(),
+        }
+    }
+
+    /// Fraction of the bus needed overall, where 1.0 is all of it
+    pub fn utilisation(&self) -> f64 {
+        self.nodes.iter().map(|(_, u)| u).sum()
+    }
+
+    /// `Error::BusOverloaded` if the schedule needs more than `limit` of
+    /// the bus. A limit of 1.0 only catches schedules which can't work
+    /// at all
+    pub fn check(&self, limit: f64) -> Result<()> {
+        if self.utilisation() > limit {
+            return Err(Error::BusOverloaded);
+        }
+        Ok(())
+    }
+}
+
+/// Measures how busy the bus actually is from a socket's byte counters
+#[derive(Copy, Clone, Debug)]
+pub struct UtilisationMeter {
+    config: SerialConfig,
+    hears_echo: bool,
+    last: Option<(Instant, u32)>,
+}
+
+impl UtilisationMeter {
+    pub fn new(config: SerialConfig) -> Self {
+        Self {
+            config,
+            hears_echo: false,
+            last: None,
+        }
+    }
+
+    /// Set for half-duplex adapters which read back everything they
+    /// send, so that those bytes aren't counted twice
+    pub fn hears_echo(mut self, hears_echo: bool) -> Self {
+        self.hears_echo = hears_echo;
+        self
+    }
+
+    /// Fraction of the time since the last sample that the bus was
+    /// carrying data. The first sample only sets the starting point, so
+    /// gives `None`
+    pub fn sample(&mut self, stats: &SocketStats, now: Instant) -> Option<f64> {
+        let bytes = if self.hears_echo {
+            stats.bytes_received
+        } else {
+            stats.bytes_received.wrapping_add(stats.bytes_sent)
+        };
+        let (then, before) = self.last.replace((now, bytes))?;
+        let busy = bytes.wrapping_sub(before) as u64 * self.config.byte_time();
+        Some(fraction(busy, now.saturating_duration_since(then)))
+    }
+}
+
+/// `us` microseconds as a fraction of `interval`
+fn fraction(us: u64, interval: Duration) -> f64 {
+    if interval.is_zero() {
+        return f64::INFINITY;
+    }
+    us as f64 / interval.as_micros() as f64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn poll_and_set_times() {
+        // 573us per byte
+        let config = SerialConfig::new(19200);
+        let mut node = NodeLoad::new(0x41, 3, Duration::from_millis(100));
+        node.output_bytes = 6;
+        assert_eq!(node.poll_time(&config), (12 + 3 + 8) * 573);
+        assert_eq!(node.set_time(&config), 12 * 573);
+
+        node.response_delay = Duration::from_micros(1500);
+        assert_eq!(node.poll_time(&config), 23 * 573 + 1500);
+    }
+
+    #[test]
+    fn estimate() {
+        let config = SerialConfig::new(19200);
+        let mut fast = NodeLoad::new(0x41, 3, Duration::from_millis(20));
+        fast.output_bytes = 6;
+        fast.set_interval = Some(Duration::from_millis(100));
+        let slow = NodeLoad::new(0x42, 3, Duration::from_millis(200));
+
+        let estimate = BusEstimate::new(config, &[fast, slow]);
+        let expected =
+            13179.0 / 20_000.0 + 6876.0 / 100_000.0 + 13179.0 / 200_000.0;
+        assert!((estimate.utilisation() - expected).abs() < 1e-9);
+        assert_eq!(estimate.check(1.0), Ok(()));
+        assert_eq!(estimate.check(0.5), Err(Error::BusOverloaded));
+
+        // Polling every 10ms can't be done at 19200
+        let tight = NodeLoad::new(0x41, 3, Duration::from_millis(10));
+        let estimate = BusEstimate::new(config, &[tight]);
+        assert_eq!(estimate.check(1.0), Err(Error::BusOverloaded));
+        let estimate = BusEstimate::new(SerialConfig::new(115200), &[tight]);
+        assert_eq!(estimate.check(1.0), Ok(()));
+    }
+
+    #[test]
+    fn measured() {
+        // 1146us per byte
+        let config = SerialConfig::new(9600);
+        let start = Instant::now();
+        let mut stats = SocketStats::default();
+        let mut meter = UtilisationMeter::new(config);
+        assert_eq!(meter.sample(&stats, start), None);
+
+        stats.bytes_sent = 100;
+        stats.bytes_received = 100;
+        let u = meter.sample(&stats, start + Duration::from_secs(1));
+        assert_eq!(u, Some(0.2292));
+
+        let mut meter = UtilisationMeter::new(config).hears_echo(true);
+        stats.bytes_sent = 0;
+        stats.bytes_received = 0;
+        meter.sample(&stats, start);
+        stats.bytes_sent = 100;
+        stats.bytes_received = 200;
+        let u = meter.sample(&stats, start + Duration::from_secs(1));
+        assert_eq!(u, Some(0.2292));
+    }
+}
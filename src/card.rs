@@ -0,0 +1,299 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Input/output cards. A USIC or SUSIC has a row of card slots, each
+// holding an input card, an output card or nothing, as listed in its Init
+// message. An SMINI has one input card and two output cards built in, and
+// a cpNode counts each byte as a card. In a Set or Get payload the cards
+// of that direction follow on from each other in slot order with no gaps,
+// so a card's place in the payload depends on every slot before it.
+
+use crate::payload::CardType;
+use crate::NodeType;
+use core::ops::Range;
+
+/// Each card type byte in an Init message describes four cards
+pub(crate) const CARDS_PER_CARD_TYPE_BYTE: usize = 4;
+
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub enum CardSize {
+    /// cpNode ports
+    Bits8,
+    /// USIC and SMINI cards
+    Bits24,
+    /// SUSIC cards
+    Bits32,
+}
+
+impl CardSize {
+    pub fn bytes(self) -> usize {
+        match self {
+            CardSize::Bits8 => 1,
+            CardSize::Bits24 => 3,
+            CardSize::Bits32 => 4,
+        }
+    }
+
+    pub fn bits(self) -> usize {
+        self.bytes() * 8
+    }
+
+    /// Byte range of the nth card in a payload made up of cards of this
+    /// size
+    pub fn range(self, n: usize) -> Range<usize> {
+        n * self.bytes()..(n + 1) * self.bytes()
+    }
+}
+
+/// A card in an occupied slot
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct Card {
+    /// Position of the card on the node, counting empty slots
+    pub slot: usize,
+    /// Either `CardType::Input` or `CardType::Output`
+    pub card_type: CardType,
+    pub size: CardSize,
+    /// First byte of the card in the Get (input) or Set (output) payload
+    pub offset: usize,
+}
+
+impl Card {
+    /// Bytes of the Get or Set payload belonging to this card
+    pub fn range(&self) -> Range<usize> {
+        self.offset..self.offset + self.size.bytes()
+    }
+
+    /// This card's bytes from a Get or Set payload, or `None` if the
+    /// payload is too short
+    pub fn data<'p>(&self, payload: &'p [u8]) -> Option<&'p [u8]> {
+        payload.get(self.range())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+enum Slots<'a> {
+    /// Two bits per slot, as in an Init message
+    CardTypes(&'a [u8]),
+    /// Input cards followed by output cards
+    Fixed { inputs: usize, outputs: usize },
+}
+
+/// The cards on a node, in slot order
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct CardSet<'a> {
+    size: CardSize,
+    slots: Slots<'a>,
+}
+
+impl<'a> CardSet<'a> {
+    /// Cards as listed by the card type bytes of a USIC or SUSIC Init
+    /// message
+    pub fn from_card_types(size: CardSize, card_types: &'a [u8]) -> Self {
+        Self {
+            size,
+            slots: Slots::CardTypes(card_types),
+        }
+    }
+
+    /// The cards on a node of the given type. The card type bytes from
+    /// its Init message are ignored for an SMINI, which has fixed cards.
+    pub fn for_node(node_type: NodeType, card_types: &'a [u8]) -> Self {
+        match node_type {
+            NodeType::Smini => Self::smini(),
+            _ => Self::from_card_types(node_type.card_size(), card_types),
+        }
+    }
+
+    /// `inputs` input cards followed by `outputs` output cards, with no
+    /// empty slots
+    pub fn fixed(size: CardSize, inputs: usize, outputs: usize) -> Self {
+        Self {
+            size,
+            slots: Slots::Fixed { inputs, outputs },
+        }
+    }
+
+    /// The SMINI's 24 inputs and 48 outputs
+    pub fn smini() -> Self {
+        Self::fixed(CardSize::Bits24, 1, 2)
+    }
+
+    pub fn size(&self) -> CardSize {
+        self.size
+    }
+
+    /// What is in every slot, including empty ones
+    pub fn slots(&self) -> impl Iterator<Item = CardType> + 'a {
+        let slots = self.slots;
+        let len = match slots {
+            Slots::CardTypes(bytes) => bytes.len() * CARDS_PER_CARD_TYPE_BYTE,
+            Slots::Fixed { inputs, outputs } => inputs + outputs,
+        };
+        (0..len).map(move |n| match slots {
+            Slots::CardTypes(bytes) => {
+                let byte =
+                    bytes.get(n / CARDS_PER_CARD_TYPE_BYTE).map_or(0, |byte| {
+                        byte >> (2 * (n % CARDS_PER_CARD_TYPE_BYTE))
+                    });
+                card_type_from_bits(byte)
+            }
+            Slots::Fixed { inputs, .. } if n < inputs => CardType::Input,
+            Slots::Fixed { .. } => CardType::Output,
+        })
+    }
+
+    /// Iterates over the occupied slots, with each card's place in its
+    /// payload
+    pub fn iter(&self) -> impl Iterator<Item = Card> + 'a {
+        let size = self.size;
+        self.slots()
+            .enumerate()
+            .scan((0, 0), move |(inputs, outputs), (slot, card_type)| {
+                let offset = match card_type {
+                    CardType::Input => inputs,
+                    CardType::Output => outputs,
+                    CardType::None => return Some(None),
+                };
+                let card = Card {
+                    slot,
+                    card_type,
+                    size,
+                    offset: *offset,
+                };
+                *offset += size.bytes();
+                Some(Some(card))
+            })
+            .flatten()
+    }
+
+    pub fn inputs(&self) -> impl Iterator<Item = Card> + 'a {
+        self.iter().filter(|c| c.card_type == CardType::Input)
+    }
+
+    pub fn outputs(&self) -> impl Iterator<Item = Card> + 'a {
+        self.iter().filter(|c| c.card_type == CardType::Output)
+    }
+
+    /// The nth card of the given type
+    pub fn card(&self, card_type: CardType, n: usize) -> Option<Card> {
+        self.iter().filter(|c| c.card_type == card_type).nth(n)
+    }
+
+    /// Length of the Get payload
+    pub fn input_bytes(&self) -> usize {
+        self.inputs().count() * self.size.bytes()
+    }
+
+    /// Length of the Set payload
+    pub fn output_bytes(&self) -> usize {
+        self.outputs().count() * self.size.bytes()
+    }
+}
+
+/// Length of the Get payload a node of the given type should answer a
+/// Poll with, given the card type bytes from its Init message
+pub fn expected_get_len(node_type: NodeType, card_types: &[u8]) -> usize {
+    CardSet::for_node(node_type, card_types).input_bytes()
+}
+
+/// Length of the Set payload a node of the given type expects, given the
+/// card type bytes from its Init message
+pub fn expected_set_len(node_type: NodeType, card_types: &[u8]) -> usize {
+    CardSet::for_node(node_type, card_types).output_bytes()
+}
+
+/// Decodes the low two bits of a card type byte
+pub fn card_type_from_bits(bits: u8) -> CardType {
+    match bits & 0b11 {
+        0b01 => CardType::Input,
+        0b10 => CardType::Output,
+        _ => CardType::None,
+    }
+}
+
+/// Two bit code for a card type in an Init message
+pub fn card_type_bits(card_type: CardType) -> u8 {
+    match card_type {
+        CardType::None => 0b00,
+        CardType::Input => 0b01,
+        CardType::Output => 0b10,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn card_offsets() {
+        // I-OI then O
+        let set = CardSet::from_card_types(
+            CardSize::Bits32,
+            &[0b0110_0001, 0b0000_0010],
+        );
+        let cards: std::vec::Vec<_> = set.iter().collect();
+        assert_eq!(cards.len(), 4);
+        assert_eq!(
+            cards[2],
+            Card {
+                slot: 3,
+                card_type: CardType::Input,
+                size: CardSize::Bits32,
+                offset: 4,
+            }
+        );
+        assert_eq!(cards[3].slot, 4);
+        assert_eq!(cards[3].range(), 4..8);
+        assert_eq!(set.input_bytes(), 8);
+        assert_eq!(set.output_bytes(), 8);
+        assert_eq!(set.slots().count(), 8);
+
+        let out = set.card(CardType::Output, 1).unwrap();
+        assert_eq!(
+            out.data(&[0, 1, 2, 3, 4, 5, 6, 7]),
+            Some(&[4, 5, 6, 7][..])
+        );
+        assert_eq!(out.data(&[0, 1, 2, 3]), None);
+        assert_eq!(set.card(CardType::Output, 2), None);
+    }
+
+    #[test]
+    fn smini_cards() {
+        let set = CardSet::smini();
+        assert_eq!(set.input_bytes(), 3);
+        assert_eq!(set.output_bytes(), 6);
+        let outputs: std::vec::Vec<_> =
+            set.outputs().map(|c| (c.slot, c.range())).collect();
+        assert_eq!(outputs, [(1, 0..3), (2, 3..6)]);
+    }
+
+    #[test]
+    fn payload_lengths() {
+        assert_eq!(expected_get_len(NodeType::Smini, &[]), 3);
+        assert_eq!(expected_set_len(NodeType::Smini, &[0xff]), 6);
+        // IOOI on a SUSIC
+        assert_eq!(expected_get_len(NodeType::Susic, &[0b0110_1001]), 8);
+        assert_eq!(expected_set_len(NodeType::Susic, &[0b0110_1001]), 8);
+        // IIIO, O on a USIC
+        let card_types = [0b1001_0101, 0b0000_0010];
+        assert_eq!(expected_get_len(NodeType::Usic, &card_types), 9);
+        assert_eq!(expected_set_len(NodeType::Usic, &card_types), 6);
+        assert_eq!(expected_get_len(NodeType::Usic, &[]), 0);
+    }
+
+    #[test]
+    fn card_type_codes() {
+        for t in [CardType::None, CardType::Input, CardType::Output].iter() {
+            assert_eq!(card_type_from_bits(card_type_bits(*t)), *t);
+        }
+        assert_eq!(card_type_from_bits(0b11), CardType::None);
+    }
+}
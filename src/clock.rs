@@ -0,0 +1,175 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Time sources for the parts of the crate which need to know the time but
+// can't use std::time, such as the node watchdog and output pulses. A
+// clock counts ticks at a fixed rate from some arbitrary starting point;
+// the node driver only ever compares ticks from the same clock, so any
+// timer which never runs backwards will do.
+
+/// A monotonic tick counter with a known rate
+pub trait Clock {
+    /// Ticks per second
+    fn tick_hz(&self) -> u32;
+
+    /// Ticks since the clock's starting point. Must never go backwards
+    fn now(&self) -> u64;
+
+    /// Number of ticks in `ms` milliseconds, rounded up so that waiting
+    /// this long is always long enough. Saturates rather than wrapping
+    fn ticks_from_millis(&self, ms: u64) -> u64 {
+        ms.saturating_mul(u64::from(self.tick_hz())).div_ceil(1000)
+    }
+
+    /// Milliseconds from `earlier` until now, or zero if `earlier` is
+    /// in the future
+    fn millis_since(&self, earlier: u64) -> u64 {
+        let ticks = self.now().saturating_sub(earlier);
+        ticks.saturating_mul(1000) / u64::from(self.tick_hz().max(1))
+    }
+}
+
+/// Microseconds since the clock was created, from `std::time::Instant`
+#[cfg(feature = "std")]
+#[derive(Copy, Clone)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct StdClock {
+    start: std::time::Instant,
+}
+
+#[cfg(feature = "std")]
+impl StdClock {
+    pub fn new() -> Self {
+        Self {
+            start: std::time::Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for StdClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clock for StdClock {
+    fn tick_hz(&self) -> u32 {
+        1_000_000
+    }
+
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_micros() as u64
+    }
+}
+
+/// Wraps a function reading a hardware timer as a `fugit` instant, as
+/// provided by most embedded HALs' monotonic timers, e.g.
+/// `FugitClock::new(|| timer.get_counter())` on an RP2040. Needs the
+/// `fugit` feature, which `cortex_m` turns on
+#[cfg(feature = "fugit")]
+pub struct FugitClock<F, const HZ: u32> {
+    read: F,
+}
+
+#[cfg(feature = "fugit")]
+impl<F, const HZ: u32> FugitClock<F, HZ>
+where
+    F: Fn() -> fugit::TimerInstantU64<HZ>,
+{
+    pub fn new(read: F) -> Self {
+        Self { read }
+    }
+}
+
+#[cfg(feature = "fugit")]
+impl<F, const HZ: u32> Clock for FugitClock<F, HZ>
+where
+    F: Fn() -> fugit::TimerInstantU64<HZ>,
+{
+    fn tick_hz(&self) -> u32 {
+        HZ
+    }
+
+    fn now(&self) -> u64 {
+        (self.read)().ticks()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::cell::Cell;
+
+    /// Clock which only moves when told to
+    struct ManualClock {
+        hz: u32,
+        ticks: Cell<u64>,
+    }
+
+    impl Clock for ManualClock {
+        fn tick_hz(&self) -> u32 {
+            self.hz
+        }
+
+        fn now(&self) -> u64 {
+            self.ticks.get()
+        }
+    }
+
+    #[test]
+    fn conversions() {
+        let clock = ManualClock {
+            hz: 32_768,
+            ticks: Cell::new(0),
+        };
+        assert_eq!(clock.ticks_from_millis(1000), 32_768);
+        // 32.768 ticks, rounded up
+        assert_eq!(clock.ticks_from_millis(1), 33);
+        clock.ticks.set(65_536);
+        assert_eq!(clock.millis_since(32_768), 1000);
+        assert_eq!(clock.millis_since(70_000), 0);
+    }
+
+    #[test]
+    fn extreme_values() {
+        let clock = ManualClock {
+            hz: 1_000_000,
+            ticks: Cell::new(u64::MAX),
+        };
+        assert_eq!(clock.ticks_from_millis(u64::MAX), u64::MAX.div_ceil(1000));
+        assert_eq!(clock.millis_since(0), u64::MAX / 1_000_000);
+        let clock = ManualClock {
+            hz: 0,
+            ticks: Cell::new(1000),
+        };
+        assert_eq!(clock.ticks_from_millis(1000), 0);
+        assert_eq!(clock.millis_since(0), 1_000_000);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn std_clock_counts_up() {
+        let clock = StdClock::new();
+        let start = clock.now();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        assert!(clock.millis_since(start) >= 2);
+        assert_eq!(clock.ticks_from_millis(3), 3000);
+    }
+
+    #[cfg(feature = "fugit")]
+    #[test]
+    fn fugit_clock() {
+        let clock = FugitClock::new(|| {
+            fugit::TimerInstantU64::<1_000>::from_ticks(1234)
+        });
+        assert_eq!(clock.tick_hz(), 1_000);
+        assert_eq!(clock.now(), 1234);
+        assert_eq!(clock.ticks_from_millis(10), 10);
+    }
+}
@@ -5,10 +5,38 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-use crate::Result;
-use crate::{CmriMessage, CmriStateMachine, RxState, TX_BUFFER_LEN};
+use crate::collision::{check_echo, Backoff, CollisionPolicy};
+use crate::health::{HealthPolicy, NodeHealth, NodeStatus};
+use crate::latency::{Correlation, LatencyReport, LatencyTracker};
+use crate::line::LineDiscipline;
+use crate::retry::RetryPolicy;
+use crate::stats;
+use crate::transport::reconnect::{ConnectionState, Opener, Reconnector};
+use crate::tx_queue::{
+    TxQueue, TxQueueDepth, DEFAULT_STARVATION_LIMIT, DEFAULT_TX_QUEUE_LEN,
+};
+use crate::{
+    Address, CmriMessage, CmriStateMachine, MessageBuilder, MessageType,
+    RxState, Stats, SyncGuard, TX_BUFFER_LEN,
+};
+use crate::{Clock, Error, Quarantine, Result, SerialConfig, StdClock};
 use std::boxed::Box;
-use std::io::{Read, Write};
+use std::collections::{HashMap, VecDeque};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+
+/// How long the ready-made sockets wait for a node to answer a Poll
+const RESPONSE_TIMEOUT: Duration =
+    Duration::from_micros(crate::timing::RESPONSE_TIMEOUT_US);
+
+/// Decoder events are traced when the `tracing` feature is enabled
+#[cfg(feature = "tracing")]
+type SocketEvents = crate::TracingEvents;
+#[cfg(not(feature = "tracing"))]
+type SocketEvents = crate::NoEvents;
 
 // Presents a socket abstraction that provides a convenient abstraction
 // for sending and receiving C/MRI messages
@@ -17,39 +45,480 @@ pub trait ReadWrite: Read + Write {}
 
 impl<T> ReadWrite for T where T: Read + Write {}
 
+/// Handles a received message, optionally returning a reply to send
+/// straight away, such as a Get in answer to a Poll
+pub type RxHandler = fn(&CmriMessage) -> Option<CmriMessage>;
+
+/// One end of a C/MRI bus.
+///
+/// A socket stays on the thread which drives the bus, as transports are
+/// only required to be `Read + Write` and so it is not `Send`. Other
+/// threads reach the bus through a `ControllerHandle` or `GatewayHandle`.
+///
+/// ```compile_fail
+/// fn send<T: Send>() {}
+/// send::<cmri::CmriSocket>();
+/// ```
 pub struct CmriSocket {
     duplex: Duplex,
     transport: Box<dyn ReadWrite>,
     rx_buffer: CmriMessage,
     tx_buffer: [u8; TX_BUFFER_LEN],
     tx_switch: fn(bool) -> (),
-    rx_callback: fn(&CmriMessage) -> (),
+    /// Called for messages with no address-specific handler
+    rx_callback: RxHandler,
+    /// Per-address handlers, so one socket can serve several nodes
+    handlers: HashMap<u8, RxHandler>,
     state: CmriStateMachine,
+    stats: SocketStats,
+    /// If set, received frames identical to the last one sent within this
+    /// window are treated as our own echo and dropped
+    echo_window: Option<Duration>,
+    last_sent: Option<(CmriMessage, Instant)>,
+    /// Give up on `receive()` if no complete message arrives in this time
+    read_timeout: Option<Duration>,
+    /// Number of times `poll()` resends a Poll before giving up
+    poll_retries: u8,
+    /// Time to keep the line driver enabled after flushing, half duplex only
+    turnaround: Duration,
+    /// Drives the transceiver in place of `tx_switch` and `turnaround`
+    line: Option<Box<dyn LineDiscipline>>,
+    /// Liveness of every node that has been polled
+    health: HashMap<u8, NodeHealth>,
+    health_policy: HealthPolicy,
+    /// Called with the address and new status when a node changes status
+    on_status_change: fn(u8, NodeStatus),
+    /// Encoded frames waiting for `pump_tx()`
+    tx_queue: TxQueue,
+    /// Called with the ID from `enqueue()` once a frame has been written
+    on_tx_complete: fn(u32),
+    /// Address used by `broadcast_set()`
+    broadcast_address: Address,
+    /// Poll/response correlation, if enabled
+    latency: Option<LatencyTracker>,
+    /// Called with any Get which doesn't follow a Poll to its node
+    on_unmatched_response: fn(&CmriMessage),
+    /// If set, `send()` reads back the echo of every frame and resends
+    /// after a collision
+    collision_policy: Option<CollisionPolicy>,
+    backoff: Backoff,
+    /// If set, `send()` retries writes which fail with an IO error
+    tx_retry: Option<RetryPolicy>,
+    /// Reopens the transport after an IO error, if set
+    reconnector: Option<Reconnector>,
+    /// Number of times the transport has been reopened
+    session: u32,
+    /// Least quiet time on the bus between frames
+    frame_gap: Duration,
+    /// Line settings, for working out when a written frame has finished
+    /// going out
+    line_config: Option<SerialConfig>,
+    /// When the bus last went quiet, or will once a written frame has
+    /// left the UART
+    quiet_from: Option<Instant>,
+    /// Time stamped on quarantined frames
+    clock: StdClock,
 }
 
-#[derive(Copy, Clone, Debug)]
+/// Transport-level counters, plus the decoder's own counters
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct SocketStats {
+    pub rx: Stats,
+    pub frames_sent: u32,
+    pub bytes_sent: u32,
+    pub bytes_received: u32,
+    /// Frames dropped as echoes of our own transmissions
+    pub echoes_suppressed: u32,
+    /// Writes retried after an IO error
+    pub tx_retries: u32,
+    /// Time at which a byte was last sent or received
+    pub last_activity: Option<Instant>,
+    /// Most frames waiting in the TX queue at once
+    pub tx_queue_peak: usize,
+    /// Frames whose echo showed another talker on the bus
+    pub collisions: u32,
+}
+
+/// In half duplex mode the TX switch is toggled around each transmission
+/// and any partially received frame is dropped before sending, since the
+/// bus can't be receiving while we transmit. In full duplex mode the TX
+/// switch and turnaround delay are not used.
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Duplex {
     Half,
     Full,
 }
 
+/// Configures a `CmriSocket`. Defaults are half duplex, no read timeout,
+/// no poll retries and no turnaround delay
+pub struct CmriSocketBuilder {
+    transport: Box<dyn ReadWrite>,
+    duplex: Duplex,
+    read_timeout: Option<Duration>,
+    poll_retries: u8,
+    turnaround: Duration,
+    tx_switch: fn(bool) -> (),
+    line: Option<Box<dyn LineDiscipline>>,
+    rx_callback: RxHandler,
+    health_policy: HealthPolicy,
+    on_status_change: fn(u8, NodeStatus),
+    tx_queue_len: usize,
+    starvation_limit: u32,
+    on_tx_complete: fn(u32),
+    broadcast_address: Address,
+    trace_latency: bool,
+    on_unmatched_response: fn(&CmriMessage),
+    collision_policy: Option<CollisionPolicy>,
+    tx_retry: Option<RetryPolicy>,
+    reconnect: Option<(Opener, RetryPolicy)>,
+    on_connection_change: fn(ConnectionState),
+    frame_gap: Duration,
+    line_config: Option<SerialConfig>,
+    sync_guard: SyncGuard,
+}
+
+impl CmriSocketBuilder {
+    pub fn new(transport: Box<dyn ReadWrite>) -> Self {
+        Self {
+            transport,
+            duplex: Duplex::Half,
+            read_timeout: None,
+            poll_retries: 0,
+            turnaround: Duration::from_secs(0),
+            tx_switch: |_| {},
+            line: None,
+            rx_callback: |_| None,
+            health_policy: HealthPolicy::default(),
+            on_status_change: |_, _| {},
+            tx_queue_len: DEFAULT_TX_QUEUE_LEN,
+            starvation_limit: DEFAULT_STARVATION_LIMIT,
+            on_tx_complete: |_| {},
+            broadcast_address: Address::BROADCAST,
+            trace_latency: false,
+            on_unmatched_response: |_| {},
+            collision_policy: None,
+            tx_retry: None,
+            reconnect: None,
+            on_connection_change: |_| {},
+            frame_gap: Duration::from_secs(0),
+            line_config: None,
+            sync_guard: SyncGuard::Off,
+        }
+    }
+
+    pub fn duplex(mut self, duplex: Duplex) -> Self {
+        self.duplex = duplex;
+        self
+    }
+
+    /// Maximum time `receive()` waits for a complete message. The
+    /// transport must also have a read timeout of its own (e.g.
+    /// `TcpStream::set_read_timeout`) for a blocked read to return.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Number of times `poll()` resends a Poll before reporting
+    /// `Error::NoResponse`
+    pub fn poll_retries(mut self, retries: u8) -> Self {
+        self.poll_retries = retries;
+        self
+    }
+
+    /// Time to hold the TX switch on after flushing, to allow the UART to
+    /// finish sending. Only used in half duplex mode
+    pub fn turnaround(mut self, turnaround: Duration) -> Self {
+        self.turnaround = turnaround;
+        self
+    }
+
+    pub fn tx_switch(mut self, tx_switch: fn(bool) -> ()) -> Self {
+        self.tx_switch = tx_switch;
+        self
+    }
+
+    /// Drives the transceiver through `line`, which takes care of its own
+    /// guard and hold times, instead of `tx_switch()` and `turnaround()`.
+    /// Only used in half duplex mode
+    pub fn line_discipline(mut self, line: Box<dyn LineDiscipline>) -> Self {
+        self.line = Some(line);
+        self
+    }
+
+    pub fn rx_callback(mut self, rx_callback: RxHandler) -> Self {
+        self.rx_callback = rx_callback;
+        self
+    }
+
+    /// Thresholds for marking polled nodes Degraded or Offline
+    pub fn health_policy(mut self, policy: HealthPolicy) -> Self {
+        self.health_policy = policy;
+        self
+    }
+
+    /// Called with the address and new status whenever a polled node
+    /// changes status
+    pub fn on_status_change(mut self, callback: fn(u8, NodeStatus)) -> Self {
+        self.on_status_change = callback;
+        self
+    }
+
+    /// Maximum number of frames `enqueue()` will hold before reporting
+    /// `Error::QueueFull`
+    pub fn tx_queue_len(mut self, len: usize) -> Self {
+        self.tx_queue_len = len;
+        self
+    }
+
+    /// Number of queued Set or Init frames sent in a row, ahead of a
+    /// waiting Poll, before the Poll is let through
+    pub fn starvation_limit(mut self, limit: u32) -> Self {
+        self.starvation_limit = limit;
+        self
+    }
+
+    /// Called with a frame's ID once `pump_tx()` has written all of it
+    pub fn on_tx_complete(mut self, callback: fn(u32)) -> Self {
+        self.on_tx_complete = callback;
+        self
+    }
+
+    /// Address which every node on this layout treats as a broadcast,
+    /// for `broadcast_set()`. Defaults to `Address::BROADCAST`
+    pub fn broadcast_address(mut self, addr: Address) -> Self {
+        self.broadcast_address = addr;
+        self
+    }
+
+    /// Matches each Get against the Poll that asked for it, for
+    /// `latency_report()`. Off by default
+    pub fn trace_latency(mut self, enable: bool) -> Self {
+        self.trace_latency = enable;
+        self
+    }
+
+    /// Called with any Get which doesn't follow a Poll to its node, when
+    /// latency tracing is on
+    pub fn on_unmatched_response(mut self, callback: fn(&CmriMessage)) -> Self {
+        self.on_unmatched_response = callback;
+        self
+    }
+
+    /// Checks the echo of every frame `send()` writes, for adapters which
+    /// hear their own transmissions on a shared bus, and resends after a
+    /// random backoff if another talker garbled it. Frames written by
+    /// `pump_tx()` aren't checked. Off by default
+    pub fn collision_detection(mut self, policy: CollisionPolicy) -> Self {
+        self.collision_policy = Some(policy);
+        self
+    }
+
+    /// Retries a `send()` whose write fails with an IO error, such as a
+    /// USB adapter briefly dropping out, after a growing delay. Off by
+    /// default
+    pub fn tx_retry(mut self, policy: RetryPolicy) -> Self {
+        self.tx_retry = Some(policy);
+        self
+    }
+
+    /// Reopens the transport with `opener` when a read or write fails
+    /// with an IO error, retrying as `policy` allows. Off by default
+    ///
+    /// ```no_run
+    /// # fn main() -> cmri::Result<()> {
+    /// use cmri::transport::reconnect;
+    /// use cmri::{CmriSocket, RetryPolicy};
+    /// use std::time::Duration;
+    ///
+    /// let timeout = Duration::from_millis(100);
+    /// let mut open = reconnect::tcp("192.168.1.50:4000", timeout);
+    /// let socket = CmriSocket::builder(open()?)
+    ///     .reconnect(open, RetryPolicy::default())
+    ///     .build();
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn reconnect(mut self, opener: Opener, policy: RetryPolicy) -> Self {
+        self.reconnect = Some((opener, policy));
+        self
+    }
+
+    /// Called as the transport is lost and reopened
+    pub fn on_connection_change(
+        mut self,
+        callback: fn(ConnectionState),
+    ) -> Self {
+        self.on_connection_change = callback;
+        self
+    }
+
+    /// Least quiet time to leave between the end of one frame on the bus
+    /// and the start of the next one sent, for nodes which drop frames
+    /// that follow too closely. `send()` waits for the gap and `pump_tx()`
+    /// holds back the next frame until it has passed. Off by default
+    pub fn min_frame_gap(mut self, gap: Duration) -> Self {
+        self.frame_gap = gap;
+        self
+    }
+
+    /// Baud rate and framing of the line, so that the frame gap is timed
+    /// from when a written frame has left the UART rather than from when
+    /// the write returned
+    pub fn line_config(mut self, config: SerialConfig) -> Self {
+        self.line_config = Some(config);
+        self
+    }
+
+    /// Sets the frame gap to a number of byte times on the given line,
+    /// along with `line_config()`
+    pub fn frame_gap_bytes(self, config: SerialConfig, bytes: u32) -> Self {
+        let gap = Duration::from_micros(bytes as u64 * config.byte_time());
+        self.line_config(config).min_frame_gap(gap)
+    }
+
+    /// How cautiously to regain sync after noise on the line, for buses
+    /// where payloads often look like a preamble. `SyncGuard::IdleTime`
+    /// is in microseconds. Off by default
+    pub fn sync_guard(mut self, guard: SyncGuard) -> Self {
+        self.sync_guard = guard;
+        self
+    }
+
+    pub fn build(self) -> CmriSocket {
+        let on_connection_change = self.on_connection_change;
+        let mut state = CmriStateMachine::new();
+        state.sync_guard(self.sync_guard);
+        CmriSocket {
+            duplex: self.duplex,
+            transport: self.transport,
+            rx_buffer: CmriMessage::new(),
+            tx_buffer: [0; TX_BUFFER_LEN],
+            tx_switch: self.tx_switch,
+            rx_callback: self.rx_callback,
+            handlers: HashMap::new(),
+            state,
+            stats: SocketStats::default(),
+            echo_window: None,
+            last_sent: None,
+            read_timeout: self.read_timeout,
+            poll_retries: self.poll_retries,
+            turnaround: self.turnaround,
+            line: self.line,
+            health: HashMap::new(),
+            health_policy: self.health_policy,
+            on_status_change: self.on_status_change,
+            tx_queue: TxQueue::new(self.tx_queue_len, self.starvation_limit),
+            on_tx_complete: self.on_tx_complete,
+            broadcast_address: self.broadcast_address,
+            latency: if self.trace_latency {
+                Some(LatencyTracker::default())
+            } else {
+                None
+            },
+            on_unmatched_response: self.on_unmatched_response,
+            collision_policy: self.collision_policy,
+            backoff: Backoff::new(),
+            tx_retry: self.tx_retry,
+            reconnector: self.reconnect.map(|(opener, policy)| {
+                Reconnector::new(opener, policy, on_connection_change)
+            }),
+            session: 0,
+            frame_gap: self.frame_gap,
+            line_config: self.line_config,
+            quiet_from: None,
+            clock: StdClock::new(),
+        }
+    }
+}
+
 impl CmriSocket {
+    /// Shorthand for a socket with the default builder settings
     pub fn new(
         duplex: Duplex,
         transport: Box<dyn ReadWrite>,
-        rx_callback: fn(&CmriMessage),
+        rx_callback: RxHandler,
     ) -> Self {
-        CmriSocket {
-            duplex,
-            transport,
-            rx_buffer: CmriMessage::new(),
-            tx_buffer: [0; TX_BUFFER_LEN],
-            tx_switch: |_| {},
-            rx_callback,
-            state: CmriStateMachine::new(),
+        Self::builder(transport)
+            .duplex(duplex)
+            .rx_callback(rx_callback)
+            .build()
+    }
+
+    pub fn builder(transport: Box<dyn ReadWrite>) -> CmriSocketBuilder {
+        CmriSocketBuilder::new(transport)
+    }
+
+    /// Connects to a TCP bridge, such as JMRI's network port or a serial
+    /// to Ethernet adapter. The link is full duplex and a Poll gives up
+    /// after the usual response timeout
+    ///
+    /// ```no_run
+    /// # fn main() -> cmri::Result<()> {
+    /// let mut socket = cmri::CmriSocket::tcp_client("192.168.1.50:4000")?;
+    /// let inputs = socket.poll(0x41)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn tcp_client<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+        Ok(Self::builder(Box::new(stream))
+            .duplex(Duplex::Full)
+            .read_timeout(RESPONSE_TIMEOUT)
+            .build())
+    }
+
+    /// Opens a serial port as 8N2 at the given baud rate, for a USB RS485
+    /// adapter which handles the line turnaround itself
+    ///
+    /// ```no_run
+    /// # fn main() -> cmri::Result<()> {
+    /// let mut socket = cmri::CmriSocket::serial("/dev/ttyUSB0", 19200)?;
+    /// let inputs = socket.poll(0x41)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "serial")]
+    pub fn serial(path: &str, baud: u32) -> Result<Self> {
+        let config = crate::SerialConfig::new(baud);
+        let port =
+            crate::transport::serial::SerialTransport::open(path, &config)?;
+        Ok(Self::builder(Box::new(port))
+            .read_timeout(RESPONSE_TIMEOUT)
+            .build())
+    }
+
+    /// Enables echo suppression for half-duplex adapters which hear their
+    /// own transmissions. Any received frame identical to the last frame
+    /// sent, arriving within `window` of sending it, is dropped. `None`
+    /// disables suppression.
+    pub fn echo_suppression(&mut self, window: Option<Duration>) {
+        self.echo_window = window;
+        self.last_sent = None;
+    }
+
+    /// Counters since creation or the last `reset_stats()`
+    pub fn stats(&self) -> SocketStats {
+        SocketStats {
+            rx: *self.state.stats(),
+            tx_queue_peak: self.tx_queue.peak(),
+            ..self.stats
         }
     }
 
+    pub fn reset_stats(&mut self) {
+        self.stats = SocketStats::default();
+        self.state.reset_stats();
+        self.tx_queue.reset_peak();
+    }
+
+    /// Frames which recently failed to decode, stamped with microseconds
+    /// since the socket was built
+    pub fn quarantine(&self) -> &Quarantine {
+        self.state.quarantine()
+    }
+
     pub fn duplex(&self) -> Duplex {
         self.duplex
     }
@@ -58,43 +527,621 @@ impl CmriSocket {
         self.tx_switch = tx_switch;
     }
 
+    /// Registers a handler for messages to the given address, replacing
+    /// any existing handler for that address
+    pub fn on_message(&mut self, addr: u8, handler: RxHandler) {
+        self.handlers.insert(addr, handler);
+    }
+
+    /// Removes the handler for an address so that its messages go to the
+    /// default handler again
+    pub fn remove_handler(&mut self, addr: u8) {
+        self.handlers.remove(&addr);
+    }
+
+    /// Replaces the handler used for addresses without their own handler
+    pub fn default_handler(&mut self, handler: RxHandler) {
+        self.rx_callback = handler;
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip(self, msg),
+            fields(
+                address = ?msg.address,
+                message_type = ?msg.message_type,
+                len = msg.len,
+            ),
+            err(Debug),
+        )
+    )]
     pub fn send(&mut self, msg: &CmriMessage) -> Result<()> {
+        let res = self.send_with_retries(msg);
+        if matches!(res, Err(Error::IoError(_))) && self.reconnect()? {
+            return self.send_with_retries(msg);
+        }
+        res
+    }
+
+    /// Sends one frame, retrying writes as the retry policy allows
+    fn send_with_retries(&mut self, msg: &CmriMessage) -> Result<()> {
+        let policy = match self.tx_retry {
+            Some(policy) => policy,
+            None => return self.send_once(msg),
+        };
+        let mut attempt = 0;
+        loop {
+            match self.send_once(msg) {
+                Err(Error::IoError(_)) if attempt < policy.retries => {
+                    stats::bump(&mut self.stats.tx_retries);
+                    attempt += 1;
+                    thread::sleep(policy.delay(attempt));
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// As `send()`, but a frame which still can't be written once the
+    /// retries and any reconnection have run out is kept on the TX queue
+    /// rather than lost. Returns its queue ID if it was queued, in which
+    /// case it goes out from `pump_tx()`, usually after `set_transport()`
+    /// has replaced the failed transport
+    pub fn send_or_queue(&mut self, msg: &CmriMessage) -> Result<Option<u32>> {
+        match self.send(msg) {
+            Ok(()) => Ok(None),
+            Err(Error::IoError(_)) => self.enqueue(msg).map(Some),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Swaps in a new transport, such as a serial port reopened after the
+    /// adapter was unplugged, and returns the old one. Any half-received
+    /// frame is dropped, and a queued frame which was partly written
+    /// starts again from the beginning
+    pub fn set_transport(
+        &mut self,
+        transport: Box<dyn ReadWrite>,
+    ) -> Box<dyn ReadWrite> {
+        self.state.clear();
+        self.tx_queue.rewind();
+        core::mem::replace(&mut self.transport, transport)
+    }
+
+    /// Number of times the transport has been reopened after an IO
+    /// error. Anything set up on the nodes before it last changed may
+    /// have been lost
+    pub fn session(&self) -> u32 {
+        self.session
+    }
+
+    /// Reopens the transport if reconnection is set up, returning whether
+    /// it was
+    fn reconnect(&mut self) -> Result<bool> {
+        let transport = match &mut self.reconnector {
+            Some(reconnector) => reconnector.reconnect()?,
+            None => return Ok(false),
+        };
+        self.set_transport(transport);
+        self.session = self.session.wrapping_add(1);
+        Ok(true)
+    }
+
+    /// The error to report after an IO error, once the transport has been
+    /// reopened if reconnection is set up. If reopening fails, that error
+    /// is reported instead
+    fn io_failed(&mut self, e: std::io::Error) -> Error {
+        match self.reconnect() {
+            Ok(_) => e.into(),
+            Err(reopen) => reopen,
+        }
+    }
+
+    /// Sends one frame, resending it after collisions if detection is on
+    fn send_once(&mut self, msg: &CmriMessage) -> Result<()> {
+        let policy = match self.collision_policy {
+            Some(policy) => policy,
+            None => return self.transmit(msg),
+        };
+        let mut attempt = 0;
+        loop {
+            match self.transmit(msg) {
+                Err(Error::BusCollision) if attempt < policy.retries => {
+                    stats::bump(&mut self.stats.collisions);
+                    attempt += 1;
+                    thread::sleep(self.backoff.delay(attempt, &policy));
+                }
+                Err(Error::BusCollision) => {
+                    stats::bump(&mut self.stats.collisions);
+                    return Err(Error::BusCollision);
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Writes one frame, checking its echo if collision detection is on
+    fn transmit(&mut self, msg: &CmriMessage) -> Result<()> {
+        let gap = self.gap_remaining();
+        if gap > Duration::from_secs(0) {
+            thread::sleep(gap);
+        }
         // encode message to tx buffer
-        msg.encode(&mut self.tx_buffer)?;
+        let frame = msg.encode_slice(&mut self.tx_buffer)?;
+        let len = frame.len();
+        let half_duplex = self.duplex == Duplex::Half;
+
+        if half_duplex {
+            // Anything half-received is lost once we start transmitting
+            self.state.clear();
+            // Toggle TX enable line
+            match &mut self.line {
+                Some(line) => line.begin_tx()?,
+                None => (self.tx_switch)(true),
+            }
+        }
+
+        // Write the data, releasing the line even if that fails
+        let written = match self.transport.write_all(frame) {
+            Ok(()) => self.transport.flush(),
+            Err(e) => Err(e),
+        };
+        if written.is_ok() {
+            self.frame_sent(msg, len);
+        }
+        let released = if half_duplex {
+            self.release_line(len)
+        } else {
+            Ok(())
+        };
+        written?;
+        released?;
 
-        // Toggle TX enable line
-        (self.tx_switch)(true);
+        if self.collision_policy.is_some() {
+            let frame = self.tx_buffer.get(..len).unwrap_or_default();
+            check_echo(&mut *self.transport, frame)?;
+        }
+        Ok(())
+    }
 
-        // Write the data
-        self.transport.write_all(&self.tx_buffer)?;
-        self.transport.flush()?;
+    /// Sends a Set to the broadcast address so that every node which
+    /// accepts broadcasts updates its outputs at once. No node responds
+    pub fn broadcast_set(&mut self, outputs: &[u8]) -> Result<()> {
+        let addr = self.broadcast_address.wire()?;
+        let msg = MessageBuilder::set(addr, outputs).build()?;
+        self.send(&msg)
+    }
 
-        // Toggle TX enable again
-        (self.tx_switch)(false);
+    /// Updates the counters after a whole frame has gone out
+    fn frame_sent(&mut self, msg: &CmriMessage, len: usize) {
+        stats::bump(&mut self.stats.frames_sent);
+        self.stats.bytes_sent = self.stats.bytes_sent.wrapping_add(len as u32);
+        self.stats.last_activity = Some(Instant::now());
+        self.frame_ended(len);
+        if self.echo_window.is_some() {
+            self.last_sent = Some((*msg, Instant::now()));
+        }
+        if let (Some(tracker), Some(MessageType::Poll), Some(addr)) =
+            (&mut self.latency, msg.message_type, msg.address)
+        {
+            tracker.poll_sent(addr, Instant::now());
+        }
+    }
+
+    /// Starts timing the frame gap from the end of a frame, where `len`
+    /// is the number of bytes just handed to the transport
+    fn frame_ended(&mut self, len: usize) {
+        if self.frame_gap == Duration::from_secs(0) {
+            return;
+        }
+        let on_wire = self
+            .line_config
+            .map(|c| Duration::from_micros(len as u64 * c.byte_time()))
+            .unwrap_or_default();
+        self.quiet_from = Some(Instant::now() + on_wire);
+    }
+
+    /// Time left before the bus has been quiet for the frame gap
+    fn gap_remaining(&self) -> Duration {
+        self.quiet_from
+            .map(|t| {
+                (t + self.frame_gap).saturating_duration_since(Instant::now())
+            })
+            .unwrap_or_default()
+    }
+
+    /// Matches a received Get against the Poll which asked for it
+    fn correlate(&mut self) {
+        let tracker = match &mut self.latency {
+            Some(tracker) => tracker,
+            None => return,
+        };
+        let addr = match (self.rx_buffer.message_type, self.rx_buffer.address) {
+            (Some(MessageType::Get), Some(addr)) => addr,
+            _ => return,
+        };
+        match tracker.response(addr, Instant::now()) {
+            #[cfg(feature = "tracing")]
+            Some((poll, latency)) => tracing::debug!(
+                id = poll.id,
+                node = poll.node,
+                latency = ?latency,
+                "response matched"
+            ),
+            #[cfg(not(feature = "tracing"))]
+            Some(_) => {}
+            None => (self.on_unmatched_response)(&self.rx_buffer),
+        }
+    }
+
+    /// Round-trip times per node since creation or the last
+    /// `reset_latency()`. Empty unless latency tracing is on
+    pub fn latency_report(&self) -> LatencyReport {
+        self.latency
+            .as_ref()
+            .map(|t| t.report().clone())
+            .unwrap_or_default()
+    }
+
+    pub fn reset_latency(&mut self) {
+        if let Some(tracker) = &mut self.latency {
+            tracker.reset();
+        }
+    }
+
+    /// The Poll to a node which is still waiting for a response
+    pub fn outstanding_poll(&self, addr: u8) -> Option<Correlation> {
+        self.latency.as_ref().and_then(|t| t.outstanding(addr))
+    }
 
+    /// Lets the UART finish sending a frame of `len` bytes, then toggles
+    /// TX enable off again
+    fn release_line(&mut self, len: usize) -> Result<()> {
+        if let Some(line) = &mut self.line {
+            return line.end_tx(len);
+        }
+        if self.turnaround > Duration::from_secs(0) {
+            thread::sleep(self.turnaround);
+        }
+        (self.tx_switch)(false);
         Ok(())
     }
 
-    /// Blocking RX
+    /// Encodes a message onto the TX queue without writing anything,
+    /// returning an ID which is passed to the `on_tx_complete` callback
+    /// once `pump_tx()` has written it. Set and Init messages are sent
+    /// ahead of any queued Polls
+    pub fn enqueue(&mut self, msg: &CmriMessage) -> Result<u32> {
+        let frame = msg.encode_slice(&mut self.tx_buffer)?;
+        self.tx_queue.push(msg, frame)
+    }
+
+    /// Writes as much of the TX queue as the transport accepts without
+    /// blocking, returning the number of frames completed. A transport
+    /// returning `WouldBlock` or accepting nothing leaves the rest of the
+    /// queue for the next call; a partly written frame carries on from
+    /// where it stopped, and a frame due before the minimum frame gap has
+    /// passed waits for a later call. In half duplex mode the TX switch is
+    /// held on while a frame is in progress and the turnaround delay
+    /// still applies after each frame.
+    pub fn pump_tx(&mut self) -> Result<usize> {
+        let half_duplex = self.duplex == Duplex::Half;
+        let mut completed = 0;
+
+        loop {
+            let gap = self.gap_remaining();
+            let frame = match self.tx_queue.front_mut() {
+                Some(frame) => frame,
+                None => break,
+            };
+            if frame.written == 0 && gap > Duration::from_secs(0) {
+                return Ok(completed);
+            }
+            if frame.written == 0 && half_duplex {
+                self.state.clear();
+                match &mut self.line {
+                    Some(line) => line.begin_tx()?,
+                    None => (self.tx_switch)(true),
+                }
+            }
+            while let Some(rest) = frame
+                .bytes
+                .get(frame.written..)
+                .filter(|rest| !rest.is_empty())
+            {
+                match self.transport.write(rest) {
+                    Ok(0) => return Ok(completed),
+                    Ok(n) => frame.written += n,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                        return Ok(completed)
+                    }
+                    Err(e) => return Err(self.io_failed(e)),
+                }
+            }
+            match self.transport.flush() {
+                Err(e) if e.kind() != ErrorKind::WouldBlock => {
+                    return Err(self.io_failed(e))
+                }
+                _ => {}
+            }
+
+            if let Some(frame) = self.tx_queue.complete() {
+                self.frame_sent(&frame.msg, frame.bytes.len());
+                if half_duplex {
+                    self.release_line(frame.bytes.len())?;
+                }
+                (self.on_tx_complete)(frame.id);
+                completed += 1;
+            }
+        }
+        Ok(completed)
+    }
+
+    /// Number of frames waiting in the TX queue, including one which has
+    /// been partly written
+    pub fn tx_pending(&self) -> usize {
+        self.tx_queue.depth().total()
+    }
+
+    /// True if `enqueue()` would report `Error::QueueFull`
+    pub fn tx_queue_full(&self) -> bool {
+        self.tx_queue.is_full()
+    }
+
+    /// Frames waiting in the TX queue in each priority class
+    pub fn tx_queue_depth(&self) -> TxQueueDepth {
+        self.tx_queue.depth()
+    }
+
+    /// Blocking RX. In full duplex mode the TX queue keeps being pumped
+    /// between bytes, so queued frames go out while waiting for a message
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(self), err(Debug))
+    )]
     pub fn receive(&mut self) -> Result<()> {
+        let deadline = self.read_timeout.map(|t| Instant::now() + t);
+        self.receive_until(deadline)
+    }
+
+    /// As `receive()`, but giving up at `deadline` rather than once the
+    /// read timeout has passed
+    fn receive_until(&mut self, deadline: Option<Instant>) -> Result<()> {
         let mut tmp_buffer = [0_u8];
+        let mut events = SocketEvents::default();
 
         loop {
-            self.transport.read(&mut tmp_buffer)?;
-            if self.state.process(tmp_buffer[0])? == RxState::Complete {
+            if let Some(deadline) = deadline {
+                if Instant::now() >= deadline {
+                    return Err(Error::Timeout);
+                }
+            }
+            if self.duplex == Duplex::Full && self.tx_pending() > 0 {
+                self.pump_tx()?;
+            }
+            if let Err(e) = self.transport.read_exact(&mut tmp_buffer) {
+                return Err(match e.kind() {
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+                        Error::Timeout
+                    }
+                    _ => self.io_failed(e),
+                });
+            }
+            stats::bump(&mut self.stats.bytes_received);
+            self.stats.last_activity = Some(Instant::now());
+            self.state.set_time(self.clock.now());
+            if self.state.process_with_events(tmp_buffer[0], &mut events)?
+                == RxState::Complete
+            {
+                if self.is_echo(&self.state.message) {
+                    stats::bump(&mut self.stats.echoes_suppressed);
+                    self.last_sent = None;
+                    continue;
+                }
                 self.rx_buffer = self.state.message;
+                self.frame_ended(0);
+                if let (Duplex::Half, Some(line)) =
+                    (self.duplex, &mut self.line)
+                {
+                    line.end_rx();
+                }
+                self.correlate();
                 break;
             }
         }
         Ok(())
     }
 
+    /// Polls a node and waits for its Get response, resending the Poll up
+    /// to the configured number of retries if the read times out without
+    /// a Get which decodes. A node which never answers is recorded as
+    /// having missed the poll
+    pub fn poll(&mut self, addr: u8) -> Result<CmriMessage> {
+        let poll = MessageBuilder::poll(addr).build()?;
+        for _ in 0..=self.poll_retries {
+            self.send(&poll)?;
+            match self.receive_response(addr) {
+                Ok(msg) => {
+                    self.record_response(addr);
+                    return Ok(msg);
+                }
+                Err(Error::Timeout) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        self.record_miss(addr);
+        Err(Error::NoResponse)
+    }
+
+    /// Polls several nodes without waiting for each to answer before
+    /// polling the next, for full duplex links with a long round trip
+    /// such as a TCP bridge. Up to `window` Polls are outstanding at
+    /// once, and Gets are matched to them by address as they arrive.
+    /// When a read times out every outstanding node is polled again, up
+    /// to the configured number of retries. Frames which fail to decode
+    /// are skipped. Returns each node's Get in the order given, or `None`
+    /// for a node which never answered
+    pub fn poll_pipelined(
+        &mut self,
+        addrs: &[u8],
+        window: usize,
+    ) -> Result<Vec<(u8, Option<CmriMessage>)>> {
+        let mut results: Vec<(u8, Option<CmriMessage>)> =
+            addrs.iter().map(|addr| (*addr, None)).collect();
+        // Index into `results` and the number of Polls sent so far
+        let mut waiting: VecDeque<(usize, u8)> =
+            (0..addrs.len()).map(|n| (n, 0)).collect();
+        let mut outstanding: Vec<(usize, u8)> = Vec::new();
+        // Renewed whenever a Poll goes out or a Get comes back
+        let mut deadline = None;
+        loop {
+            while outstanding.len() < window.max(1) {
+                let (n, sent) = match waiting.pop_front() {
+                    Some(next) => next,
+                    None => break,
+                };
+                let addr = addrs.get(n).copied().unwrap_or_default();
+                self.send(&MessageBuilder::poll(addr).build()?)?;
+                outstanding.push((n, sent + 1));
+                deadline = self.read_timeout.map(|t| Instant::now() + t);
+            }
+            if outstanding.is_empty() {
+                return Ok(results);
+            }
+            match self.receive_until(deadline) {
+                Ok(())
+                    if self.rx_buffer.message_type
+                        == Some(MessageType::Get) =>
+                {
+                    let addr = self.rx_buffer.address;
+                    let pos = outstanding
+                        .iter()
+                        .position(|(n, _)| addrs.get(*n).copied() == addr);
+                    if let Some(pos) = pos {
+                        let (n, _) = outstanding.remove(pos);
+                        if let Some(result) = results.get_mut(n) {
+                            result.1 = Some(self.rx_buffer);
+                            self.record_response(result.0);
+                        }
+                        deadline =
+                            self.read_timeout.map(|t| Instant::now() + t);
+                    }
+                }
+                Ok(()) => {}
+                Err(Error::Timeout) => {
+                    for (n, sent) in outstanding.drain(..) {
+                        if sent <= self.poll_retries {
+                            waiting.push_back((n, sent));
+                        } else if let Some(addr) = addrs.get(n) {
+                            self.record_miss(*addr);
+                        }
+                    }
+                }
+                Err(e @ Error::IoError(_)) | Err(e @ Error::Disconnected) => {
+                    return Err(e)
+                }
+                // A frame which failed to decode
+                Err(_) => {}
+            }
+        }
+    }
+
+    fn record_response(&mut self, addr: u8) {
+        let policy = self.health_policy;
+        let health = self.health.entry(addr).or_default();
+        let change = health.record_response(Instant::now(), &policy);
+        self.status_changed(addr, change);
+    }
+
+    fn record_miss(&mut self, addr: u8) {
+        let policy = self.health_policy;
+        let change = self.health.entry(addr).or_default().record_miss(&policy);
+        self.status_changed(addr, change);
+    }
+
+    fn status_changed(&self, addr: u8, change: Option<NodeStatus>) {
+        if let Some(status) = change {
+            (self.on_status_change)(addr, status);
+        }
+    }
+
+    /// Status of a node, or `None` if it has never been polled
+    pub fn node_status(&self, addr: u8) -> Option<NodeStatus> {
+        self.health.get(&addr).map(|h| h.status)
+    }
+
+    /// Full liveness details of a node, or `None` if it has never been
+    /// polled
+    pub fn node_health(&self, addr: u8) -> Option<NodeHealth> {
+        self.health.get(&addr).copied()
+    }
+
+    /// Receives until a Get arrives from the given address. A frame which
+    /// fails to decode, such as a garbled reply or part of a collision, is
+    /// skipped, and reading carries on until the read timeout has passed
+    fn receive_response(&mut self, addr: u8) -> Result<CmriMessage> {
+        let deadline = self.read_timeout.map(|t| Instant::now() + t);
+        loop {
+            match self.receive_until(deadline) {
+                Ok(()) => {}
+                Err(e @ Error::Timeout)
+                | Err(e @ Error::IoError(_))
+                | Err(e @ Error::Disconnected) => return Err(e),
+                // A frame which failed to decode
+                Err(_) => continue,
+            }
+            if self.rx_buffer.address == Some(addr)
+                && self.rx_buffer.message_type == Some(MessageType::Get)
+            {
+                return Ok(self.rx_buffer);
+            }
+        }
+    }
+
+    /// True if the message matches the last one we sent and arrived
+    /// within the echo window
+    fn is_echo(&self, msg: &CmriMessage) -> bool {
+        match (self.echo_window, &self.last_sent) {
+            (Some(window), Some((sent, at))) => {
+                at.elapsed() <= window && sent == msg
+            }
+            _ => false,
+        }
+    }
+
+    /// The last message received
+    pub fn message(&self) -> &CmriMessage {
+        &self.rx_buffer
+    }
+
+    /// Passes the last received message to the handler for its address,
+    /// or to the default handler if there isn't one, and sends the
+    /// handler's reply if it gives one. In half duplex mode the reply
+    /// goes out like any other `send()`, with the TX switch and
+    /// turnaround delay around it
+    pub fn dispatch(&mut self) -> Result<()> {
+        let handler = self
+            .rx_buffer
+            .address
+            .and_then(|addr| self.handlers.get(&addr))
+            .unwrap_or(&self.rx_callback);
+        match handler(&self.rx_buffer) {
+            Some(reply) => self.send(&reply),
+            None => Ok(()),
+        }
+    }
+
     /// Calls the blocking RX in a loop, calling the callback
     pub fn receive_loop(&mut self) -> ! {
         loop {
             if self.receive().is_ok() {
-                // process a message if one arrive successfully
-                (self.rx_callback)(&self.rx_buffer);
+                // process a message if one arrive successfully. A reply
+                // which fails to send is lost, as the next Poll will ask
+                // again
+                let _ = self.dispatch();
             }
         }
     }
@@ -103,42 +1150,121 @@ impl CmriSocket {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::MessageType;
+    use crate::{MessageBuilder, MessageType};
     use std::println;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::vec::Vec;
 
-    struct TestTransport;
-    impl Write for TestTransport {
+    /// Stand-in for a bus adapter. Reads hear back what was written when
+    /// `echo` is set, then whatever is in `rx`, and block once there's
+    /// nothing left
+    #[derive(Default)]
+    struct MockTransport {
+        echo: bool,
+        echoed: Vec<u8>,
+        rx: Vec<u8>,
+        /// Everything written, which a test can keep a clone of
+        tx: Arc<Mutex<Vec<u8>>>,
+        /// Echoed frames to garble the address byte of, as if another
+        /// talker was on the bus
+        collisions: usize,
+        /// Writes to fail before any succeed
+        write_failures: u32,
+        /// Bytes accepted, a few at a time, before writes block
+        write_budget: Option<usize>,
+    }
+    impl Write for MockTransport {
         fn write(
             &mut self,
             buf: &[u8],
         ) -> core::result::Result<usize, std::io::Error> {
-            println!("Writing {} bytes: {:?}", buf.len(), buf);
-            Ok(buf.len())
+            if self.write_failures > 0 {
+                self.write_failures -= 1;
+                return Err(ErrorKind::BrokenPipe.into());
+            }
+            let len = match self.write_budget.as_mut() {
+                Some(budget) => {
+                    let len = buf.len().min(*budget).min(3);
+                    if len == 0 {
+                        return Err(ErrorKind::WouldBlock.into());
+                    }
+                    *budget -= len;
+                    len
+                }
+                None => buf.len(),
+            };
+            self.tx.lock().unwrap().extend_from_slice(&buf[..len]);
+            if self.echo {
+                let start = self.echoed.len();
+                self.echoed.extend_from_slice(&buf[..len]);
+                if self.collisions > 0 {
+                    self.collisions -= 1;
+                    self.echoed[start + 3] ^= 0x5a;
+                }
+            }
+            Ok(len)
         }
         fn flush(&mut self) -> core::result::Result<(), std::io::Error> {
-            println!("flush");
             Ok(())
         }
     }
 
-    impl Read for TestTransport {
+    impl Read for MockTransport {
         fn read(
             &mut self,
             buf: &mut [u8],
         ) -> core::result::Result<usize, std::io::Error> {
-            for ele in buf.iter_mut() {
-                *ele = 5;
+            let source = if self.echoed.is_empty() {
+                &mut self.rx
+            } else {
+                &mut self.echoed
+            };
+            if source.is_empty() {
+                return Err(ErrorKind::WouldBlock.into());
             }
-            Ok(buf.len())
+            let len = buf.len().min(source.len());
+            buf[..len].copy_from_slice(&source[..len]);
+            source.drain(..len);
+            Ok(len)
         }
     }
 
+    #[test]
+    fn tcp_client() {
+        use crate::NodeDriver;
+        use std::net::TcpListener;
+
+        // A bridge with one node behind it
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let bridge = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut node = NodeDriver::new(0x41, 1).unwrap();
+            node.set_inputs(&[0x5a]).unwrap();
+            let mut byte = [0_u8];
+            while stream.read_exact(&mut byte).is_ok() {
+                if let crate::Action::Transmit(reply) = node.process(byte[0], 0)
+                {
+                    stream.write_all(reply).unwrap();
+                    return;
+                }
+            }
+        });
+
+        let mut socket = CmriSocket::tcp_client(addr).unwrap();
+        assert_eq!(socket.duplex(), Duplex::Full);
+        assert_eq!(socket.poll(0x41).unwrap().data(), [0x5a]);
+        bridge.join().unwrap();
+    }
+
     #[test]
     fn send_message() {
-        let transport = TestTransport;
+        let transport = MockTransport::default();
         let mut socket =
             CmriSocket::new(Duplex::Half, Box::new(transport), |msg| {
                 println!("addr: {:?}", msg.address);
+                None
             });
 
         let p = [1, 2, 3];
@@ -149,15 +1275,16 @@ mod test {
             .payload(&p)
             .unwrap();
 
-        socket.send(&msg).unwrap();
+        socket.send(msg).unwrap();
     }
 
     #[test]
     fn send_message_with_tx_toggle() {
-        let transport = TestTransport;
+        let transport = MockTransport::default();
         let mut socket =
             CmriSocket::new(Duplex::Half, Box::new(transport), |msg| {
                 println!("addr: {:?}", msg.address);
+                None
             });
         socket.tx_switch(|tx| {
             println!("Setting TX mode to `{}`...", tx);
@@ -171,6 +1298,523 @@ mod test {
             .payload(&p)
             .unwrap();
 
-        socket.send(&msg).unwrap();
+        socket.send(msg).unwrap();
+    }
+
+    #[test]
+    fn dispatch_by_address() {
+        static NODE_A: AtomicUsize = AtomicUsize::new(0);
+        static NODE_B: AtomicUsize = AtomicUsize::new(0);
+        static DEFAULT: AtomicUsize = AtomicUsize::new(0);
+
+        let mut bytes = Vec::new();
+        for addr in [0x41, 0x42, 0x43, 0x41].iter() {
+            let mut buf = [0_u8; TX_BUFFER_LEN];
+            let msg = MessageBuilder::poll(*addr).build().unwrap();
+            let len = msg.encode(&mut buf).unwrap();
+            bytes.extend_from_slice(&buf[..len]);
+        }
+        let transport = MockTransport {
+            rx: bytes,
+            ..MockTransport::default()
+        };
+        let mut socket =
+            CmriSocket::new(Duplex::Half, Box::new(transport), |_| {
+                DEFAULT.fetch_add(1, Ordering::SeqCst);
+                None
+            });
+        socket.on_message(0x41, |_| {
+            NODE_A.fetch_add(1, Ordering::SeqCst);
+            None
+        });
+        socket.on_message(0x42, |_| {
+            NODE_B.fetch_add(1, Ordering::SeqCst);
+            None
+        });
+
+        for _ in 0..4 {
+            socket.receive().unwrap();
+            socket.dispatch().unwrap();
+        }
+        assert_eq!(NODE_A.load(Ordering::SeqCst), 2);
+        assert_eq!(NODE_B.load(Ordering::SeqCst), 1);
+        assert_eq!(DEFAULT.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn handler_replies_to_poll() {
+        static TX_ON: AtomicUsize = AtomicUsize::new(0);
+
+        let mut bytes = [0_u8; TX_BUFFER_LEN];
+        let poll = MessageBuilder::poll(0x41).build().unwrap();
+        let len = poll.encode(&mut bytes).unwrap();
+        let tx = Arc::new(Mutex::new(Vec::new()));
+        let transport = MockTransport {
+            rx: bytes[..len].to_vec(),
+            tx: tx.clone(),
+            ..MockTransport::default()
+        };
+        let mut socket = CmriSocket::builder(Box::new(transport))
+            .rx_callback(|msg| match msg.message_type {
+                Some(MessageType::Poll) => {
+                    MessageBuilder::get(msg.address?, &[0xa5]).build().ok()
+                }
+                _ => None,
+            })
+            .tx_switch(|on| {
+                if on {
+                    TX_ON.fetch_add(1, Ordering::SeqCst);
+                }
+            })
+            .build();
+
+        socket.receive().unwrap();
+        socket.dispatch().unwrap();
+
+        let reply = MessageBuilder::get(0x41, &[0xa5]).build().unwrap();
+        let len = reply.encode(&mut bytes).unwrap();
+        assert_eq!(tx.lock().unwrap()[..], bytes[..len]);
+        assert_eq!(TX_ON.load(Ordering::SeqCst), 1);
+        assert_eq!(socket.stats().frames_sent, 1);
+    }
+
+    #[test]
+    fn echo_is_suppressed() {
+        let mut reply = [0_u8; TX_BUFFER_LEN];
+        let msg = MessageBuilder::get(0x41, &[1, 2]).build().unwrap();
+        let len = msg.encode(&mut reply).unwrap();
+        let transport = MockTransport {
+            echo: true,
+            rx: reply[..len].to_vec(),
+            ..MockTransport::default()
+        };
+        let mut socket =
+            CmriSocket::new(Duplex::Half, Box::new(transport), |_| None);
+        socket.echo_suppression(Some(Duration::from_secs(1)));
+
+        let poll = MessageBuilder::poll(0x41).build().unwrap();
+        socket.send(&poll).unwrap();
+        socket.receive().unwrap();
+
+        // The echoed poll was skipped and the reply received
+        assert_eq!(socket.rx_buffer.message_type, Some(MessageType::Get));
+        assert_eq!(socket.stats().echoes_suppressed, 1);
+    }
+
+    #[test]
+    fn echo_is_received_without_suppression() {
+        let transport = MockTransport {
+            echo: true,
+            ..MockTransport::default()
+        };
+        let mut socket =
+            CmriSocket::new(Duplex::Half, Box::new(transport), |_| None);
+
+        let poll = MessageBuilder::poll(0x41).build().unwrap();
+        socket.send(&poll).unwrap();
+        socket.receive().unwrap();
+        assert_eq!(socket.rx_buffer.message_type, Some(MessageType::Poll));
+    }
+
+    fn colliding_socket(collisions: usize) -> CmriSocket {
+        let transport = MockTransport {
+            echo: true,
+            collisions,
+            ..MockTransport::default()
+        };
+        CmriSocket::builder(Box::new(transport))
+            .collision_detection(CollisionPolicy {
+                retries: 2,
+                slot: Duration::from_micros(10),
+            })
+            .build()
+    }
+
+    #[test]
+    fn resend_after_collision() {
+        let mut socket = colliding_socket(2);
+        let poll = MessageBuilder::poll(0x41).build().unwrap();
+        socket.send(&poll).unwrap();
+        assert_eq!(socket.stats().collisions, 2);
+        assert_eq!(socket.stats().frames_sent, 3);
+    }
+
+    #[test]
+    fn give_up_after_collision_retries() {
+        let mut socket = colliding_socket(5);
+        let poll = MessageBuilder::poll(0x41).build().unwrap();
+        assert_eq!(socket.send(&poll), Err(Error::BusCollision));
+        assert_eq!(socket.stats().collisions, 3);
+        assert_eq!(socket.stats().frames_sent, 3);
+
+        // Without an echo there's nothing to compare against
+        let mut socket =
+            CmriSocket::builder(Box::new(MockTransport::default()))
+                .collision_detection(CollisionPolicy::default())
+                .build();
+        assert_eq!(socket.send(&poll), Err(Error::Timeout));
+    }
+
+    #[test]
+    fn poll_with_response() {
+        let mut reply = [0_u8; TX_BUFFER_LEN];
+        let msg = MessageBuilder::get(0x41, &[7]).build().unwrap();
+        let len = msg.encode(&mut reply).unwrap();
+        let transport = MockTransport {
+            echo: true,
+            rx: reply[..len].to_vec(),
+            ..MockTransport::default()
+        };
+        let mut socket = CmriSocket::builder(Box::new(transport))
+            .read_timeout(Duration::from_secs(1))
+            .build();
+        let response = socket.poll(0x41).unwrap();
+        assert_eq!(response.payload[..response.len], [7]);
+    }
+
+    #[test]
+    fn poll_retries_then_gives_up() {
+        static TX_TOGGLES: AtomicUsize = AtomicUsize::new(0);
+        let mut socket =
+            CmriSocket::builder(Box::new(MockTransport::default()))
+                .read_timeout(Duration::from_millis(10))
+                .poll_retries(2)
+                .tx_switch(|_| {
+                    TX_TOGGLES.fetch_add(1, Ordering::SeqCst);
+                })
+                .build();
+        assert_eq!(socket.poll(0x41).unwrap_err(), Error::NoResponse);
+        assert_eq!(socket.stats().frames_sent, 3);
+        // On and off for each of the three attempts
+        assert_eq!(TX_TOGGLES.load(Ordering::SeqCst), 6);
+    }
+
+    #[test]
+    fn node_liveness() {
+        use crate::sim::{Behaviour, VirtualBus, VirtualNode};
+        static OFFLINE: AtomicUsize = AtomicUsize::new(0);
+
+        let bus = VirtualBus::new();
+        bus.add_node(VirtualNode::new(0x41, 1, Behaviour::Manual).unwrap());
+        let mut socket = CmriSocket::builder(Box::new(bus))
+            .read_timeout(Duration::from_millis(10))
+            .on_status_change(|addr, status| {
+                if addr == 0x42 && status == NodeStatus::Offline {
+                    OFFLINE.fetch_add(1, Ordering::SeqCst);
+                }
+            })
+            .build();
+
+        assert_eq!(socket.node_status(0x41), None);
+        socket.poll(0x41).unwrap();
+        assert_eq!(socket.node_status(0x41), Some(NodeStatus::Online));
+        assert!(socket.node_health(0x41).unwrap().last_seen.is_some());
+
+        socket.poll(0x42).unwrap_err();
+        assert_eq!(socket.node_status(0x42), Some(NodeStatus::Degraded));
+        socket.poll(0x42).unwrap_err();
+        socket.poll(0x42).unwrap_err();
+        assert_eq!(socket.node_status(0x42), Some(NodeStatus::Offline));
+        assert_eq!(socket.node_health(0x42).unwrap().missed_polls, 3);
+        assert_eq!(OFFLINE.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn garbled_replies() {
+        // A Get with no payload, which doesn't decode
+        let garbled = [0xff, 0xff, 0x02, 0x41, b'R', 0x03];
+        let socket = |rx: Vec<u8>| {
+            CmriSocket::builder(Box::new(MockTransport {
+                rx,
+                ..MockTransport::default()
+            }))
+            .read_timeout(Duration::from_millis(10))
+            .poll_retries(2)
+            .build()
+        };
+
+        let good = MessageBuilder::get(0x41, &[7]).build().unwrap();
+        let mut rx = garbled.to_vec();
+        rx.extend(good.encode_iter().unwrap());
+        let mut s = socket(rx);
+        assert_eq!(s.poll(0x41).unwrap().data(), [7]);
+        assert_eq!(s.stats().frames_sent, 1);
+        assert_eq!(s.node_status(0x41), Some(NodeStatus::Online));
+
+        // Nothing but garbage counts as no answer
+        let mut s = socket(garbled.repeat(3));
+        assert_eq!(s.poll(0x41), Err(Error::NoResponse));
+        assert_eq!(s.stats().frames_sent, 3);
+        assert_eq!(s.node_health(0x41).unwrap().missed_polls, 1);
+
+        let good = MessageBuilder::get(0x42, &[7]).build().unwrap();
+        let mut rx = garbled.to_vec();
+        rx.extend(good.encode_iter().unwrap());
+        let mut s = socket(rx);
+        let results = s.poll_pipelined(&[0x41, 0x42], 2).unwrap();
+        assert_eq!(results[0], (0x41, None));
+        assert_eq!(results[1].1.unwrap().data(), [7]);
+        assert_eq!(s.node_health(0x41).unwrap().missed_polls, 1);
+    }
+
+    #[test]
+    fn full_duplex_skips_tx_switch() {
+        static TX_TOGGLES: AtomicUsize = AtomicUsize::new(0);
+        let mut socket =
+            CmriSocket::builder(Box::new(MockTransport::default()))
+                .duplex(Duplex::Full)
+                .tx_switch(|_| {
+                    TX_TOGGLES.fetch_add(1, Ordering::SeqCst);
+                })
+                .build();
+        let poll = MessageBuilder::poll(0x41).build().unwrap();
+        socket.send(&poll).unwrap();
+        assert_eq!(TX_TOGGLES.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn queued_tx() {
+        static COMPLETED: AtomicUsize = AtomicUsize::new(0);
+        let transport = MockTransport {
+            write_budget: Some(10),
+            ..MockTransport::default()
+        };
+        let mut socket = CmriSocket::builder(Box::new(transport))
+            .tx_queue_len(2)
+            .on_tx_complete(|id| {
+                COMPLETED.fetch_add(id as usize + 1, Ordering::SeqCst);
+            })
+            .build();
+
+        // Each Poll frame is 6 bytes
+        let poll = MessageBuilder::poll(0x41).build().unwrap();
+        assert_eq!(socket.enqueue(&poll), Ok(0));
+        assert_eq!(socket.enqueue(&poll), Ok(1));
+        assert_eq!(socket.enqueue(&poll), Err(Error::QueueFull));
+        assert_eq!(socket.stats().frames_sent, 0);
+
+        // Only the first frame fits, and the second is left part-written
+        assert_eq!(socket.pump_tx(), Ok(1));
+        assert_eq!(COMPLETED.load(Ordering::SeqCst), 1);
+        assert_eq!(socket.tx_pending(), 1);
+        assert_eq!(socket.pump_tx(), Ok(0));
+        assert_eq!(socket.stats().frames_sent, 1);
+        assert_eq!(socket.stats().tx_queue_peak, 2);
+    }
+
+    #[test]
+    fn frame_gap() {
+        let gap = Duration::from_millis(20);
+        let mut socket =
+            CmriSocket::builder(Box::new(MockTransport::default()))
+                .min_frame_gap(gap)
+                .build();
+        let poll = MessageBuilder::poll(0x41).build().unwrap();
+        let start = Instant::now();
+        socket.send(&poll).unwrap();
+        socket.send(&poll).unwrap();
+        assert!(start.elapsed() >= gap);
+
+        // The queue holds the next frame back instead of waiting
+        socket.enqueue(&poll).unwrap();
+        socket.enqueue(&poll).unwrap();
+        assert_eq!(socket.pump_tx(), Ok(0));
+        thread::sleep(gap);
+        assert_eq!(socket.pump_tx(), Ok(1));
+        assert_eq!(socket.pump_tx(), Ok(0));
+        assert_eq!(socket.tx_pending(), 1);
+
+        // 5730us per 10 bytes at 19200
+        let config = SerialConfig::new(19200);
+        let socket = CmriSocket::builder(Box::new(MockTransport::default()))
+            .frame_gap_bytes(config, 10)
+            .build();
+        assert_eq!(socket.frame_gap, Duration::from_micros(5730));
+    }
+
+    #[test]
+    fn tx_retry() {
+        static TX_ON: AtomicUsize = AtomicUsize::new(0);
+        let policy = RetryPolicy {
+            retries: 2,
+            initial_backoff: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        };
+        let poll = MessageBuilder::poll(0x41).build().unwrap();
+        let mut socket = CmriSocket::builder(Box::new(MockTransport {
+            write_failures: 2,
+            ..MockTransport::default()
+        }))
+        .tx_retry(policy)
+        .tx_switch(|on| {
+            if on {
+                TX_ON.fetch_add(1, Ordering::SeqCst);
+            } else {
+                TX_ON.fetch_sub(1, Ordering::SeqCst);
+            }
+        })
+        .build();
+        assert_eq!(socket.send(&poll), Ok(()));
+        assert_eq!(socket.stats().tx_retries, 2);
+        assert_eq!(socket.stats().frames_sent, 1);
+        // The line was let go after each failed write
+        assert_eq!(TX_ON.load(Ordering::SeqCst), 0);
+
+        socket.set_transport(Box::new(MockTransport {
+            write_failures: 3,
+            ..MockTransport::default()
+        }));
+        assert!(matches!(socket.send(&poll), Err(Error::IoError(_))));
+        assert_eq!(socket.stats().tx_retries, 4);
+    }
+
+    #[test]
+    fn queued_until_reconnected() {
+        let poll = MessageBuilder::poll(0x41).build().unwrap();
+        let mut socket = CmriSocket::builder(Box::new(MockTransport {
+            write_failures: 1,
+            ..MockTransport::default()
+        }))
+        .build();
+        assert_eq!(socket.send_or_queue(&poll), Ok(Some(0)));
+        assert_eq!(socket.tx_pending(), 1);
+
+        // Part of the frame goes out before the adapter is unplugged
+        socket.set_transport(Box::new(MockTransport {
+            write_budget: Some(3),
+            ..MockTransport::default()
+        }));
+        assert_eq!(socket.pump_tx(), Ok(0));
+
+        socket.set_transport(Box::new(MockTransport {
+            echo: true,
+            ..MockTransport::default()
+        }));
+        assert_eq!(socket.pump_tx(), Ok(1));
+        assert_eq!(socket.send_or_queue(&poll), Ok(None));
+
+        // Both frames arrive whole
+        socket.receive().unwrap();
+        socket.receive().unwrap();
+        assert_eq!(socket.message().message_type, Some(MessageType::Poll));
+        assert_eq!(socket.stats().frames_sent, 2);
+    }
+
+    #[test]
+    fn queued_tx_round_trip() {
+        let mut socket = CmriSocket::builder(Box::new(MockTransport {
+            echo: true,
+            ..MockTransport::default()
+        }))
+        .build();
+        let set = MessageBuilder::set(0x41, &[1, 2]).build().unwrap();
+        socket.enqueue(&set).unwrap();
+        socket.enqueue(&set).unwrap();
+        assert_eq!(socket.pump_tx(), Ok(2));
+        assert_eq!(socket.tx_pending(), 0);
+
+        socket.receive().unwrap();
+        assert_eq!(socket.message().payload[..2], [1, 2]);
+        assert_eq!(socket.stats().frames_sent, 2);
+    }
+
+    #[test]
+    fn full_duplex_receive_pumps_tx() {
+        let mut reply = [0_u8; TX_BUFFER_LEN];
+        let msg = MessageBuilder::get(0x41, &[7]).build().unwrap();
+        let len = msg.encode(&mut reply).unwrap();
+        let mut socket = CmriSocket::builder(Box::new(MockTransport {
+            echo: true,
+            rx: reply[..len].to_vec(),
+            ..MockTransport::default()
+        }))
+        .duplex(Duplex::Full)
+        .build();
+
+        let poll = MessageBuilder::poll(0x41).build().unwrap();
+        socket.enqueue(&poll).unwrap();
+        socket.receive().unwrap();
+        assert_eq!(socket.tx_pending(), 0);
+        assert_eq!(socket.message().message_type, Some(MessageType::Poll));
+        socket.receive().unwrap();
+        assert_eq!(socket.message().message_type, Some(MessageType::Get));
+    }
+
+    #[test]
+    fn broadcast_set() {
+        let transport = MockTransport {
+            echo: true,
+            ..MockTransport::default()
+        };
+        let mut socket = CmriSocket::builder(Box::new(transport))
+            .broadcast_address(Address::Ua(127))
+            .build();
+        socket.broadcast_set(&[1, 2]).unwrap();
+        socket.receive().unwrap();
+        assert_eq!(socket.message().address, Some(0xc0));
+        assert_eq!(socket.message().message_type, Some(MessageType::Set));
+    }
+
+    #[test]
+    fn socket_stats() {
+        let transport = MockTransport::default();
+        let mut socket =
+            CmriSocket::new(Duplex::Half, Box::new(transport), |_| None);
+        assert_eq!(socket.stats(), SocketStats::default());
+
+        let mut msg = CmriMessage::new();
+        let msg = msg.address(1).message_type(MessageType::Poll);
+        socket.send(msg).unwrap();
+        socket.send(msg).unwrap();
+
+        let stats = socket.stats();
+        assert_eq!(stats.frames_sent, 2);
+        assert_eq!(stats.bytes_sent, 12);
+        assert!(stats.last_activity.is_some());
+
+        socket.reset_stats();
+        assert_eq!(socket.stats(), SocketStats::default());
+    }
+
+    #[test]
+    fn poll_latency() {
+        static UNMATCHED: AtomicUsize = AtomicUsize::new(0);
+        let mut replies = Vec::new();
+        for _ in 0..2 {
+            let msg = MessageBuilder::get(0x41, &[7]).build().unwrap();
+            let mut reply = [0_u8; TX_BUFFER_LEN];
+            let len = msg.encode(&mut reply).unwrap();
+            replies.extend_from_slice(&reply[..len]);
+        }
+        let transport = MockTransport {
+            echo: true,
+            rx: replies,
+            ..MockTransport::default()
+        };
+        let mut socket = CmriSocket::builder(Box::new(transport))
+            .read_timeout(Duration::from_secs(1))
+            .trace_latency(true)
+            .on_unmatched_response(|_| {
+                UNMATCHED.fetch_add(1, Ordering::SeqCst);
+            })
+            .build();
+
+        let poll = MessageBuilder::poll(0x41).build().unwrap();
+        socket.send(&poll).unwrap();
+        assert_eq!(socket.outstanding_poll(0x41).unwrap().node, 0x41);
+        socket.receive_response(0x41).unwrap();
+        assert_eq!(socket.outstanding_poll(0x41), None);
+
+        // The second Get has no Poll to match
+        socket.receive_response(0x41).unwrap();
+        assert_eq!(UNMATCHED.load(Ordering::SeqCst), 1);
+
+        let report = socket.latency_report();
+        assert_eq!(report.unmatched, 1);
+        assert_eq!(report.nodes[&0x41].samples, 1);
+        assert!(report.nodes[&0x41].mean().is_some());
+
+        socket.reset_latency();
+        assert_eq!(socket.latency_report(), LatencyReport::default());
     }
 }
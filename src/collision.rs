@@ -0,0 +1,137 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Collision detection for a multi-drop half duplex bus. An RS485 adapter
+// whose receiver stays enabled hears everything it sends, so after each
+// frame the socket reads the echo back and compares it with what was
+// written. If another talker was driving the bus at the same time the
+// echo comes back garbled, and the frame is resent after a random
+// backoff so that the two talkers are unlikely to collide again.
+
+use crate::{Error, Result};
+use std::io::{ErrorKind, Read};
+use std::time::{Duration, SystemTime};
+
+/// How to check for collisions and when to give up resending
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CollisionPolicy {
+    /// Resends after a collision before reporting `Error::BusCollision`
+    pub retries: u8,
+    /// Before the nth resend, wait a random number of slots from 0 to
+    /// 2^n - 1
+    pub slot: Duration,
+}
+
+impl Default for CollisionPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            slot: Duration::from_millis(2),
+        }
+    }
+}
+
+/// Random delays for resending after a collision
+#[derive(Clone, Debug)]
+pub(crate) struct Backoff {
+    /// xorshift state, never zero
+    rng: u32,
+}
+
+impl Backoff {
+    pub(crate) fn new() -> Self {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+        Self {
+            rng: now.map_or(1, |t| t.subsec_nanos()) | 1,
+        }
+    }
+
+    /// How long to wait before resend number `attempt`, counting from 1
+    pub(crate) fn delay(
+        &mut self,
+        attempt: u8,
+        policy: &CollisionPolicy,
+    ) -> Duration {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        // Capped so that the window doesn't grow without limit
+        let window = 1_u32 << attempt.min(8);
+        policy.slot * (self.rng % window)
+    }
+}
+
+/// Reads back the echo of a frame which has just been sent. Bytes which
+/// differ from the frame, or a read failing part way through, mean that
+/// something else was talking at the same time. No echo at all gives
+/// `Error::Timeout`, since the adapter probably doesn't echo
+pub(crate) fn check_echo<R: Read + ?Sized>(
+    transport: &mut R,
+    frame: &[u8],
+) -> Result<()> {
+    let mut byte = [0_u8];
+    for (n, sent) in frame.iter().enumerate() {
+        match transport.read_exact(&mut byte) {
+            Ok(()) if byte == [*sent] => {}
+            Ok(()) => {
+                // Skip the rest of the echo so it isn't taken for the
+                // start of the next one
+                for _ in n + 1..frame.len() {
+                    if transport.read_exact(&mut byte).is_err() {
+                        break;
+                    }
+                }
+                return Err(Error::BusCollision);
+            }
+            Err(e)
+                if n == 0
+                    && (e.kind() == ErrorKind::WouldBlock
+                        || e.kind() == ErrorKind::TimedOut) =>
+            {
+                return Err(Error::Timeout)
+            }
+            Err(_) => return Err(Error::BusCollision),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn echo_comparison() {
+        let frame = [0xff, 0xff, 0x02, 0x41, 0x50, 0x03];
+        let mut echo = Cursor::new(frame);
+        assert_eq!(check_echo(&mut echo, &frame), Ok(()));
+
+        let mut garbled = Cursor::new([0xff, 0xff, 0x02, 0x43, 0x50, 0x03]);
+        assert_eq!(check_echo(&mut garbled, &frame), Err(Error::BusCollision));
+        assert_eq!(garbled.position(), 6);
+
+        // Cut off part way through
+        let mut short = Cursor::new([0xff, 0xff]);
+        assert_eq!(check_echo(&mut short, &frame), Err(Error::BusCollision));
+    }
+
+    #[test]
+    fn backoff_stays_in_window() {
+        let policy = CollisionPolicy {
+            retries: 5,
+            slot: Duration::from_millis(1),
+        };
+        let mut backoff = Backoff::new();
+        for attempt in 1..=5 {
+            for _ in 0..20 {
+                let delay = backoff.delay(attempt, &policy);
+                assert!(delay < Duration::from_millis(1 << attempt));
+            }
+        }
+    }
+}
@@ -0,0 +1,336 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Bus commissioning: poll every address a few times and look for signs
+// of two nodes set to the same one. Such nodes both answer each Poll, so
+// the controller sees either two Gets, or one Get followed by garbage
+// where the second node talked over the end of the first. Nodes with
+// different card layouts give Gets of different lengths, and if they
+// answer at slightly different speeds the response time wanders from one
+// Poll to the next as first one and then the other wins. A Get from an
+// address that wasn't polled means a node with its address switches
+// misread, or one answering on behalf of another.
+//
+// `scan()` collects all of that into a `BusReport`, which prints as a
+// table for a commissioning tool.
+
+use crate::{Address, CmriSocket, Error, MessageBuilder, MessageType, Result};
+use core::fmt;
+use std::collections::BTreeSet;
+use std::format;
+use std::string::{String, ToString};
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+
+/// How thoroughly `scan()` probes each address
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ScanOptions {
+    /// Polls sent to each address
+    pub polls: u8,
+    /// Spread of response times beyond which two nodes may be taking
+    /// turns to answer
+    pub jitter_limit: Duration,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self {
+            polls: 3,
+            jitter_limit: Duration::from_millis(5),
+        }
+    }
+}
+
+/// Sign that more than one node is answering at an address
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Conflict {
+    /// More than one Get came back for a single Poll
+    DoubledResponse,
+    /// Bytes around the responses failed to decode
+    Garbled,
+    /// Gets of different lengths came back
+    LengthMismatch,
+    /// Some Polls were answered and some weren't
+    Intermittent,
+    /// Response times varied by more than the jitter limit
+    Jitter,
+    /// A Get came back from this other unit address
+    WrongAddress(u8),
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Conflict::DoubledResponse => write!(f, "doubled responses"),
+            Conflict::Garbled => write!(f, "garbled responses"),
+            Conflict::LengthMismatch => write!(f, "input lengths differ"),
+            Conflict::Intermittent => write!(f, "intermittent"),
+            Conflict::Jitter => write!(f, "response time varies"),
+            Conflict::WrongAddress(ua) => write!(f, "answered by UA {}", ua),
+        }
+    }
+}
+
+/// What was heard at one unit address
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AddressReport {
+    pub ua: u8,
+    pub polls: u8,
+    /// Polls answered by at least one Get
+    pub answered: u8,
+    /// Gets received, which is more than `answered` if two nodes replied
+    pub gets: u32,
+    /// Distinct input lengths seen
+    pub input_lens: BTreeSet<usize>,
+    /// Fastest and slowest time from Poll to Get
+    pub response_time: Option<(Duration, Duration)>,
+    /// Bytes which didn't decode while waiting for responses
+    pub garbled_bytes: u32,
+    pub conflicts: Vec<Conflict>,
+}
+
+impl AddressReport {
+    /// True if anything at all was heard at this address
+    pub fn occupied(&self) -> bool {
+        self.gets > 0 || self.garbled_bytes > 0
+    }
+
+    fn record_time(&mut self, time: Duration) {
+        self.response_time = Some(match self.response_time {
+            Some((min, max)) => (min.min(time), max.max(time)),
+            None => (time, time),
+        });
+    }
+
+    fn find_conflicts(&mut self, options: &ScanOptions) {
+        if self.gets > u32::from(self.answered) {
+            self.conflicts.push(Conflict::DoubledResponse);
+        }
+        if self.garbled_bytes > 0 {
+            self.conflicts.push(Conflict::Garbled);
+        }
+        if self.input_lens.len() > 1 {
+            self.conflicts.push(Conflict::LengthMismatch);
+        }
+        if self.answered > 0 && self.answered < self.polls {
+            self.conflicts.push(Conflict::Intermittent);
+        }
+        if let Some((min, max)) = self.response_time {
+            if max - min > options.jitter_limit {
+                self.conflicts.push(Conflict::Jitter);
+            }
+        }
+        self.conflicts.sort_unstable();
+        self.conflicts.dedup();
+    }
+}
+
+/// Result of scanning a bus
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BusReport {
+    /// Number of addresses polled
+    pub scanned: usize,
+    /// Every address at which something was heard, in the order scanned
+    pub addresses: Vec<AddressReport>,
+}
+
+impl BusReport {
+    /// Unit addresses with a single well-behaved node
+    pub fn nodes(&self) -> Vec<u8> {
+        self.addresses
+            .iter()
+            .filter(|a| a.conflicts.is_empty())
+            .map(|a| a.ua)
+            .collect()
+    }
+
+    /// Addresses showing any sign of a conflict
+    pub fn conflicts(&self) -> impl Iterator<Item = &AddressReport> {
+        self.addresses.iter().filter(|a| !a.conflicts.is_empty())
+    }
+}
+
+impl fmt::Display for BusReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, " UA  answered  gets  inputs  response      findings")?;
+        for a in &self.addresses {
+            let lens: Vec<_> =
+                a.input_lens.iter().map(|len| len.to_string()).collect();
+            let time = match a.response_time {
+                Some((min, max)) => format!(
+                    "{:.1}-{:.1}ms",
+                    min.as_secs_f64() * 1000.0,
+                    max.as_secs_f64() * 1000.0
+                ),
+                None => String::from("-"),
+            };
+            let findings: Vec<_> =
+                a.conflicts.iter().map(|c| c.to_string()).collect();
+            writeln!(
+                f,
+                "{:>3}  {:>4}/{:<3}  {:>4}  {:<6}  {:<12}  {}",
+                a.ua,
+                a.answered,
+                a.polls,
+                a.gets,
+                lens.join(","),
+                time,
+                if findings.is_empty() {
+                    String::from("ok")
+                } else {
+                    findings.join(", ")
+                }
+            )?;
+        }
+        write!(
+            f,
+            "{} of {} addresses occupied, {} with conflicts",
+            self.addresses.len(),
+            self.scanned,
+            self.conflicts().count()
+        )
+    }
+}
+
+/// Polls each unit address in turn and reports what answered. The socket
+/// needs a read timeout, which is how long each Poll is listened to for
+/// after the last frame heard
+pub fn scan<I>(
+    socket: &mut CmriSocket,
+    uas: I,
+    options: &ScanOptions,
+) -> Result<BusReport>
+where
+    I: IntoIterator<Item = u8>,
+{
+    let mut report = BusReport::default();
+    for ua in uas {
+        report.scanned += 1;
+        let address = probe(socket, ua, options)?;
+        if address.occupied() {
+            report.addresses.push(address);
+        }
+    }
+    Ok(report)
+}
+
+/// Polls one unit address `options.polls` times
+pub fn probe(
+    socket: &mut CmriSocket,
+    ua: u8,
+    options: &ScanOptions,
+) -> Result<AddressReport> {
+    let addr = Address::Ua(ua).wire()?;
+    let poll = MessageBuilder::poll(addr).build()?;
+    let mut report = AddressReport {
+        ua,
+        polls: options.polls,
+        ..AddressReport::default()
+    };
+    for _ in 0..options.polls {
+        let before = socket.stats().rx;
+        socket.send(&poll)?;
+        let sent_at = Instant::now();
+        let mut gets = 0;
+        loop {
+            match socket.receive() {
+                Ok(()) => {
+                    let msg = socket.message();
+                    if msg.message_type != Some(MessageType::Get) {
+                        continue;
+                    }
+                    if msg.address == Some(addr) {
+                        if gets == 0 {
+                            report.record_time(sent_at.elapsed());
+                        }
+                        gets += 1;
+                        report.input_lens.insert(msg.len);
+                    } else if let Some(other) = msg.to_ua() {
+                        report.conflicts.push(Conflict::WrongAddress(other));
+                    }
+                }
+                Err(Error::Timeout) => break,
+                Err(e @ Error::IoError(_)) | Err(e @ Error::Disconnected) => {
+                    return Err(e)
+                }
+                // A frame which failed to decode
+                Err(_) => report.garbled_bytes += 1,
+            }
+        }
+        let after = socket.stats().rx;
+        report.garbled_bytes +=
+            after.bytes_discarded.wrapping_sub(before.bytes_discarded)
+                + after.resyncs.wrapping_sub(before.resyncs);
+        if gets > 0 {
+            report.answered += 1;
+        }
+        report.gets += gets;
+    }
+    report.find_conflicts(options);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sim::{Behaviour, VirtualBus, VirtualNode};
+    use std::boxed::Box;
+
+    fn node(addr: u8, inputs: usize) -> VirtualNode {
+        VirtualNode::new(addr, inputs, Behaviour::Manual).unwrap()
+    }
+
+    #[test]
+    fn finds_shared_addresses() {
+        let bus = VirtualBus::new();
+        bus.add_node(node(0x41, 1));
+        bus.add_node(node(0x42, 1));
+        bus.add_node(node(0x42, 3));
+        let mut socket = CmriSocket::builder(Box::new(bus.clone()))
+            .read_timeout(Duration::from_millis(5))
+            .build();
+
+        let options = ScanOptions::default();
+        let report = scan(&mut socket, 0..4, &options).unwrap();
+        assert_eq!(report.scanned, 4);
+        assert_eq!(report.nodes(), [0]);
+
+        let single = &report.addresses[0];
+        assert_eq!((single.answered, single.gets), (3, 3));
+        assert_eq!(single.input_lens.iter().collect::<Vec<_>>(), [&1]);
+
+        let shared: Vec<_> = report.conflicts().collect();
+        assert_eq!(shared.len(), 1);
+        assert_eq!(shared[0].ua, 1);
+        assert_eq!(shared[0].gets, 6);
+        assert_eq!(
+            shared[0].conflicts,
+            [Conflict::DoubledResponse, Conflict::LengthMismatch]
+        );
+
+        let table = report.to_string();
+        assert!(table.contains("doubled responses, input lengths differ"));
+        assert!(table.ends_with("2 of 4 addresses occupied, 1 with conflicts"));
+    }
+
+    #[test]
+    fn intermittent_and_jittery() {
+        let mut report = AddressReport {
+            polls: 3,
+            answered: 2,
+            gets: 2,
+            ..AddressReport::default()
+        };
+        report.record_time(Duration::from_millis(2));
+        report.record_time(Duration::from_millis(20));
+        report.find_conflicts(&ScanOptions::default());
+        assert_eq!(
+            report.conflicts,
+            [Conflict::Intermittent, Conflict::Jitter]
+        );
+    }
+}
@@ -0,0 +1,172 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Known-good frames in the form written by other C/MRI implementations,
+// covering each message type, address offsets and escaping. Running
+// `verify_compat()` before connecting to a live layout checks that this
+// build decodes them and encodes them back to exactly the same bytes.
+//
+// JMRI also escapes 0x02 in payloads, which this crate doesn't need to,
+// so none of the frames here have a 0x02 payload byte.
+
+use crate::{CmriStateMachine, MessageType, RxState, TX_BUFFER_LEN};
+
+/// Implementation a golden frame comes from
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub enum Source {
+    /// The ArduinoCMRI library, acting as a node
+    ArduinoCmri,
+    /// JMRI, acting as the controller
+    Jmri,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct GoldenFrame {
+    pub source: Source,
+    pub description: &'static str,
+    /// The frame as it appears on the wire
+    pub bytes: &'static [u8],
+    /// Address byte as sent on the wire
+    pub address: u8,
+    pub message_type: MessageType,
+    /// Unescaped payload
+    pub payload: &'static [u8],
+}
+
+const GOLDEN_FRAMES: [GoldenFrame; 6] = [
+    GoldenFrame {
+        source: Source::Jmri,
+        description: "Poll to UA 0",
+        bytes: &[0xff, 0xff, 0x02, 0x41, 0x50, 0x03],
+        address: 0x41,
+        message_type: MessageType::Poll,
+        payload: &[],
+    },
+    GoldenFrame {
+        source: Source::Jmri,
+        description: "Init for an SMINI at UA 1 with no delay",
+        bytes: &[0xff, 0xff, 0x02, 0x42, 0x49, 0x4d, 0x00, 0x00, 0x00, 0x03],
+        address: 0x42,
+        message_type: MessageType::Init,
+        payload: &[0x4d, 0x00, 0x00, 0x00],
+    },
+    GoldenFrame {
+        source: Source::Jmri,
+        description: "Set to an SMINI at UA 0 with escaped bytes",
+        bytes: &[
+            0xff, 0xff, 0x02, 0x41, 0x54, 0x01, 0x10, 0x03, 0x10, 0x10, 0x00,
+            0xff, 0x80, 0x03,
+        ],
+        address: 0x41,
+        message_type: MessageType::Set,
+        payload: &[0x01, 0x03, 0x10, 0x00, 0xff, 0x80],
+    },
+    GoldenFrame {
+        source: Source::Jmri,
+        description: "Set to UA 127, the highest address",
+        bytes: &[0xff, 0xff, 0x02, 0xc0, 0x54, 0x55, 0xaa, 0x03],
+        address: 0xc0,
+        message_type: MessageType::Set,
+        payload: &[0x55, 0xaa],
+    },
+    GoldenFrame {
+        source: Source::ArduinoCmri,
+        description: "Get from UA 0 with 24 inputs",
+        bytes: &[0xff, 0xff, 0x02, 0x41, 0x52, 0x01, 0x00, 0x80, 0x03],
+        address: 0x41,
+        message_type: MessageType::Get,
+        payload: &[0x01, 0x00, 0x80],
+    },
+    GoldenFrame {
+        source: Source::ArduinoCmri,
+        description: "Get from UA 3 with an escaped stop byte",
+        bytes: &[0xff, 0xff, 0x02, 0x44, 0x52, 0x10, 0x03, 0xff, 0x00, 0x03],
+        address: 0x44,
+        message_type: MessageType::Get,
+        payload: &[0x03, 0xff, 0x00],
+    },
+];
+
+/// Frames as written by ArduinoCMRI and JMRI, one or more for each
+/// message type
+pub fn golden_frames() -> &'static [GoldenFrame] {
+    &GOLDEN_FRAMES
+}
+
+/// Decodes every golden frame and encodes it again, returning the first
+/// frame which doesn't decode to the expected message or doesn't encode
+/// back to exactly the same bytes
+pub fn verify_compat() -> Result<(), &'static GoldenFrame> {
+    for frame in golden_frames() {
+        if !round_trips(frame) {
+            return Err(frame);
+        }
+    }
+    Ok(())
+}
+
+fn round_trips(frame: &GoldenFrame) -> bool {
+    let mut state = CmriStateMachine::new();
+    let (last, rest) = match frame.bytes.split_last() {
+        Some(split) => split,
+        None => return false,
+    };
+    for byte in rest {
+        if state.process(*byte) != Ok(RxState::Listening) {
+            return false;
+        }
+    }
+    if state.process(*last) != Ok(RxState::Complete) {
+        return false;
+    }
+
+    let msg = state.message();
+    if msg.address != Some(frame.address)
+        || msg.message_type != Some(frame.message_type)
+        || msg.data() != frame.payload
+    {
+        return false;
+    }
+
+    let mut buf = [0_u8; TX_BUFFER_LEN];
+    match msg.encode(&mut buf) {
+        Ok(len) => buf.get(..len) == Some(frame.bytes),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn golden_frames_round_trip() {
+        assert_eq!(verify_compat(), Ok(()));
+        for t in [
+            MessageType::Init,
+            MessageType::Set,
+            MessageType::Get,
+            MessageType::Poll,
+        ]
+        .iter()
+        {
+            assert!(golden_frames().iter().any(|f| f.message_type == *t));
+        }
+    }
+
+    #[test]
+    fn detects_mismatch() {
+        let mut frame = golden_frames()[2];
+        assert!(round_trips(&frame));
+        // Unescaped stop byte in the payload ends the frame early
+        frame.bytes = &[0xff, 0xff, 0x02, 0x41, 0x54, 0x03, 0x03];
+        frame.payload = &[0x03];
+        assert!(!round_trips(&frame));
+    }
+}
@@ -0,0 +1,486 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Layout descriptions which can be kept in a TOML or JSON file alongside
+// the controller. A layout lists the nodes on the bus, and from it the
+// controller gets the Init message for each node and a roster of how
+// many bytes to expect from each. For example:
+//
+//     [[nodes]]
+//     address = 0
+//     name = "yard"
+//     node_type = "smini"
+//     poll_interval_ms = 100
+//
+//     [nodes.inputs]
+//     yard_throat_occupied = 3
+//
+//     [nodes.outputs]
+//     yard_throat_turnout = 0
+//
+// A `ConfigWatcher` lets a running gateway pick up edits to the layout
+// file: each `check()` looks at the file's modification time, and if it
+// has changed, loads the new layout and reports which nodes need a new
+// Init message.
+
+use crate::card::{card_type_bits, CARDS_PER_CARD_TYPE_BYTE};
+use crate::payload::{CardType, InitPayload};
+use crate::{Address, BitOrder, CmriMessage, Error, MessageBuilder, NodeType};
+use crate::{Result, MAX_PAYLOAD_LEN, MAX_UA};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::format;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::string::String;
+use std::time::{Duration, SystemTime};
+use std::vec;
+use std::vec::Vec;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct LayoutConfig {
+    #[serde(default)]
+    pub nodes: Vec<NodeConfig>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NodeConfig {
+    /// Node address as set on the node's switches, from 0 to 127
+    pub address: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub node_type: NodeType,
+    /// Transmit delay in units of 10us
+    #[serde(default)]
+    pub transmit_delay: u16,
+    /// How often the controller should poll this node
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub poll_interval_ms: Option<u64>,
+    /// Card in each slot, for USIC and SUSIC nodes
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cards: Vec<CardType>,
+    /// Input names and their bit numbers within the node's inputs
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub inputs: BTreeMap<String, usize>,
+    /// Output names and their bit numbers within the node's outputs
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub outputs: BTreeMap<String, usize>,
+    /// How the node's firmware numbers the bits within each byte
+    #[serde(default, skip_serializing_if = "BitOrder::is_lsb_first")]
+    pub bit_order: BitOrder,
+}
+
+/// Differences between two layouts. Nodes are given by address as sent
+/// on the wire
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LayoutChanges {
+    pub added: Vec<u8>,
+    /// Nodes whose Init message is different
+    pub changed: Vec<u8>,
+    pub removed: Vec<u8>,
+}
+
+/// Keeps a layout loaded from a file, reloading it when the file changes
+pub struct ConfigWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    layout: LayoutConfig,
+}
+
+/// What the controller needs to know to talk to one node
+#[derive(Clone, Debug, PartialEq)]
+pub struct RosterEntry {
+    /// Address as sent on the wire
+    pub address: u8,
+    pub name: Option<String>,
+    pub node_type: NodeType,
+    pub input_bytes: usize,
+    pub output_bytes: usize,
+    pub poll_interval: Option<Duration>,
+}
+
+impl LayoutConfig {
+    pub fn from_toml(s: &str) -> Result<Self> {
+        let config: Self = toml::from_str(s).map_err(config_error)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string(self).map_err(config_error)
+    }
+
+    pub fn from_json(s: &str) -> Result<Self> {
+        let config: Self = serde_json::from_str(s).map_err(config_error)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).map_err(config_error)
+    }
+
+    /// Loads a layout file, as JSON if its name ends in `.json` and as
+    /// TOML otherwise
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => Self::from_json(&contents),
+            _ => Self::from_toml(&contents),
+        }
+    }
+
+    /// Which nodes have been added, removed or need a different Init
+    /// message in `new`. Changes to names, poll intervals and I/O
+    /// mappings don't need an Init so aren't listed
+    pub fn changes(&self, new: &LayoutConfig) -> Result<LayoutChanges> {
+        let mut changes = LayoutChanges::default();
+        for node in new.nodes.iter() {
+            match self.node(node.address) {
+                None => changes.added.push(node.wire_address()?),
+                Some(old) if old.init_payload()? != node.init_payload()? => {
+                    changes.changed.push(node.wire_address()?)
+                }
+                Some(_) => {}
+            }
+        }
+        for node in self.nodes.iter() {
+            if new.node(node.address).is_none() {
+                changes.removed.push(node.wire_address()?);
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Checks that every node has a valid, unique address and a card
+    /// layout which fits in an Init message
+    pub fn validate(&self) -> Result<()> {
+        for (i, node) in self.nodes.iter().enumerate() {
+            if node.address > MAX_UA {
+                return Err(Error::ConfigError(format!(
+                    "node address {} is out of range",
+                    node.address
+                )));
+            }
+            if self.nodes.iter().take(i).any(|n| n.address == node.address) {
+                return Err(Error::ConfigError(format!(
+                    "node address {} is used more than once",
+                    node.address
+                )));
+            }
+            node.init_payload()?;
+        }
+        Ok(())
+    }
+
+    /// Init messages for every node, in the order they are listed
+    pub fn init_messages(&self) -> Result<Vec<CmriMessage>> {
+        self.nodes.iter().map(NodeConfig::init_message).collect()
+    }
+
+    pub fn roster(&self) -> Result<Vec<RosterEntry>> {
+        self.nodes.iter().map(NodeConfig::roster_entry).collect()
+    }
+
+    /// Looks up a node by its configured address
+    pub fn node(&self, address: u8) -> Option<&NodeConfig> {
+        self.nodes.iter().find(|n| n.address == address)
+    }
+}
+
+impl NodeConfig {
+    /// Address as sent on the wire. Fails with `Error::OutOfBounds` if
+    /// the node's address isn't a valid unit address
+    pub fn wire_address(&self) -> Result<u8> {
+        Address::Ua(self.address).wire()
+    }
+
+    /// Node definition parameters to send in the node's Init message
+    pub fn init_payload(&self) -> Result<Vec<u8>> {
+        let [delay_hi, delay_lo] = self.transmit_delay.to_be_bytes();
+        let mut payload = vec![u8::from(self.node_type), delay_hi, delay_lo, 0];
+        match self.node_type {
+            NodeType::Usic | NodeType::Susic => {
+                for chunk in self.cards.chunks(CARDS_PER_CARD_TYPE_BYTE) {
+                    payload.push(chunk.iter().enumerate().fold(
+                        0,
+                        |byte, (n, card)| {
+                            byte | card_type_bits(*card) << (2 * n)
+                        },
+                    ));
+                }
+                let num_sets = (payload.len() - 4) as u8;
+                if let Some(byte) = payload.get_mut(3) {
+                    *byte = num_sets;
+                }
+            }
+            NodeType::Smini | NodeType::Cpnode => {
+                if !self.cards.is_empty() {
+                    return Err(Error::ConfigError(format!(
+                        "node {} has fixed I/O and cannot list cards",
+                        self.address
+                    )));
+                }
+            }
+            NodeType::Unknown(_) => {
+                return Err(Error::ConfigError(format!(
+                    "node {} has an unknown node type",
+                    self.address
+                )));
+            }
+        }
+        if payload.len() > MAX_PAYLOAD_LEN {
+            return Err(Error::DataTooLong);
+        }
+        Ok(payload)
+    }
+
+    pub fn init_message(&self) -> Result<CmriMessage> {
+        MessageBuilder::init(self.wire_address()?, &self.init_payload()?)
+            .build()
+    }
+
+    pub fn roster_entry(&self) -> Result<RosterEntry> {
+        let payload = self.init_payload()?;
+        let init = InitPayload::parse(&payload)?;
+        Ok(RosterEntry {
+            address: self.wire_address()?,
+            name: self.name.clone(),
+            node_type: self.node_type,
+            input_bytes: init.input_bytes(),
+            output_bytes: init.output_bytes(),
+            poll_interval: self.poll_interval_ms.map(Duration::from_millis),
+        })
+    }
+}
+
+impl LayoutChanges {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.changed.is_empty()
+            && self.removed.is_empty()
+    }
+
+    /// Init messages for the added and changed nodes in `layout`
+    pub fn init_messages(
+        &self,
+        layout: &LayoutConfig,
+    ) -> Result<Vec<CmriMessage>> {
+        layout
+            .nodes
+            .iter()
+            .filter(|n| {
+                n.wire_address().is_ok_and(|address| {
+                    self.added.contains(&address)
+                        || self.changed.contains(&address)
+                })
+            })
+            .map(NodeConfig::init_message)
+            .collect()
+    }
+}
+
+impl ConfigWatcher {
+    /// Loads the layout file, failing if it can't be read or isn't valid
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+        let modified = modified(&path);
+        let layout = LayoutConfig::load(&path)?;
+        Ok(Self {
+            path,
+            modified,
+            layout,
+        })
+    }
+
+    /// The layout as last loaded successfully
+    pub fn layout(&self) -> &LayoutConfig {
+        &self.layout
+    }
+
+    /// Reloads the layout if the file has changed since it was last
+    /// looked at, returning what changed. If the new file can't be
+    /// loaded the error is returned and the old layout is kept until the
+    /// file changes again, so a half-saved edit doesn't stop the gateway
+    pub fn check(&mut self) -> Result<Option<LayoutChanges>> {
+        let modified = modified(&self.path);
+        if modified == self.modified {
+            return Ok(None);
+        }
+        self.modified = modified;
+        let layout = LayoutConfig::load(&self.path)?;
+        let changes = self.layout.changes(&layout)?;
+        self.layout = layout;
+        Ok(Some(changes))
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+pub(crate) fn config_error<E: core::fmt::Display>(e: E) -> Error {
+    Error::ConfigError(format!("{}", e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MessageType;
+
+    const LAYOUT: &str = r#"
+        [[nodes]]
+        address = 0
+        name = "yard"
+        node_type = "smini"
+        poll_interval_ms = 100
+
+        [nodes.outputs]
+        yard_throat_turnout = 0
+
+        [[nodes]]
+        address = 5
+        node_type = "susic"
+        transmit_delay = 2
+        cards = ["input", "input", "output", "output", "input"]
+
+        [nodes.inputs]
+        platform_1 = 33
+    "#;
+
+    #[test]
+    fn load_toml() {
+        let layout = LayoutConfig::from_toml(LAYOUT).unwrap();
+        assert_eq!(layout.nodes.len(), 2);
+        assert_eq!(layout.nodes[0].name.as_deref(), Some("yard"));
+        assert_eq!(layout.nodes[1].inputs["platform_1"], 33);
+
+        let roster = layout.roster().unwrap();
+        assert_eq!(roster[0].address, 65);
+        assert_eq!(roster[0].input_bytes, 3);
+        assert_eq!(roster[0].output_bytes, 6);
+        assert_eq!(roster[0].poll_interval, Some(Duration::from_millis(100)));
+        assert_eq!(roster[1].address, 70);
+        assert_eq!(roster[1].input_bytes, 12);
+        assert_eq!(roster[1].output_bytes, 8);
+    }
+
+    #[test]
+    fn init_messages() {
+        let layout = LayoutConfig::from_toml(LAYOUT).unwrap();
+        let msgs = layout.init_messages().unwrap();
+        assert_eq!(msgs[0].message_type, Some(MessageType::Init));
+        assert_eq!(msgs[0].payload[..msgs[0].len], [b'M', 0, 0, 0]);
+        assert_eq!(
+            msgs[1].payload[..msgs[1].len],
+            [b'X', 0, 2, 2, 0b1010_0101, 0b0000_0001]
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let layout = LayoutConfig::from_toml(LAYOUT).unwrap();
+        let toml = layout.to_toml().unwrap();
+        assert_eq!(LayoutConfig::from_toml(&toml).unwrap(), layout);
+        let json = layout.to_json().unwrap();
+        assert_eq!(LayoutConfig::from_json(&json).unwrap(), layout);
+    }
+
+    #[test]
+    fn layout_changes() {
+        let old = LayoutConfig::from_toml(LAYOUT).unwrap();
+        let mut new = old.clone();
+        assert!(old.changes(&new).unwrap().is_empty());
+
+        new.nodes[0].name = None;
+        new.nodes[1].transmit_delay = 5;
+        new.nodes.remove(0);
+        new.nodes.push(NodeConfig {
+            address: 9,
+            name: None,
+            node_type: NodeType::Cpnode,
+            transmit_delay: 0,
+            poll_interval_ms: None,
+            cards: Vec::new(),
+            inputs: BTreeMap::new(),
+            outputs: BTreeMap::new(),
+            bit_order: BitOrder::default(),
+        });
+        let changes = old.changes(&new).unwrap();
+        assert_eq!(changes.added, [74]);
+        assert_eq!(changes.changed, [70]);
+        assert_eq!(changes.removed, [65]);
+
+        let inits = changes.init_messages(&new).unwrap();
+        let addresses: Vec<_> = inits.iter().map(|m| m.address).collect();
+        assert_eq!(addresses, [Some(70), Some(74)]);
+    }
+
+    #[test]
+    fn watch_a_layout_file() {
+        let path = std::env::temp_dir()
+            .join(format!("cmri-layout-{}.toml", std::process::id()));
+        fs::write(&path, LAYOUT).unwrap();
+        let mut watcher = ConfigWatcher::open(&path).unwrap();
+        assert_eq!(watcher.layout().nodes.len(), 2);
+        assert_eq!(watcher.check(), Ok(None));
+
+        // Make sure the modification time moves on
+        let edited = LAYOUT.replace("transmit_delay = 2", "transmit_delay = 3");
+        let later = SystemTime::now() + Duration::from_secs(2);
+        fs::write(&path, edited).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|f| f.set_modified(later))
+            .unwrap();
+        let changes = watcher.check().unwrap().unwrap();
+        assert_eq!(changes.changed, [70]);
+        assert_eq!(watcher.layout().nodes[1].transmit_delay, 3);
+
+        // A broken edit keeps the old layout
+        fs::write(&path, "nodes = 3").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|f| f.set_modified(later + Duration::from_secs(2)))
+            .unwrap();
+        assert!(watcher.check().is_err());
+        assert_eq!(watcher.check(), Ok(None));
+        assert_eq!(watcher.layout().nodes[1].transmit_delay, 3);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reject_bad_layouts() {
+        let dup = r#"{"nodes": [
+            {"address": 1, "node_type": "smini"},
+            {"address": 1, "node_type": "cpnode"}
+        ]}"#;
+        assert!(matches!(
+            LayoutConfig::from_json(dup),
+            Err(Error::ConfigError(_))
+        ));
+
+        let range = r#"{"nodes": [{"address": 128, "node_type": "smini"}]}"#;
+        assert!(LayoutConfig::from_json(range).is_err());
+
+        let cards = r#"{"nodes": [
+            {"address": 1, "node_type": "smini", "cards": ["input"]}
+        ]}"#;
+        assert!(LayoutConfig::from_json(cards).is_err());
+
+        // Edited after validation, past where the wire address overflows
+        let mut layout = LayoutConfig::from_toml(LAYOUT).unwrap();
+        assert_eq!(layout.nodes[0].wire_address(), Ok(65));
+        layout.nodes[0].address = 200;
+        assert_eq!(layout.nodes[0].wire_address(), Err(Error::OutOfBounds));
+        assert_eq!(layout.init_messages().err(), Some(Error::OutOfBounds));
+    }
+}
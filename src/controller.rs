@@ -0,0 +1,1047 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Polling controller which can be driven from other threads. The
+// `Controller` is owned by the I/O thread along with the socket: each
+// `step()` sends Sets for any outputs changed since the last step, then
+// polls the next node in turn. Any number of `ControllerHandle`s can be
+// cloned across threads to change outputs, which are passed to the I/O
+// thread over a channel, and to read the inputs from each node's most
+// recent Get, which are kept in a shared cache.
+//
+// Each Get is compared with the node's previous one, and every input
+// which changed is reported as an `InputChanged` edge to a callback on
+// the I/O thread and to any handles which have subscribed. A node's
+// first Get is compared with all inputs off, so inputs which are already
+// on are reported as rising.
+//
+// Nodes given an Init payload are brought up by `initialise()`, which
+// follows the usual C/MRI start-up: send each node its Init, give it time
+// to configure itself, then poll it and check that it reports as many
+// input bytes as the Init set up.
+//
+// For testing wiring, output bits can be forced on or off from a handle.
+// A forced bit is sent as forced whatever the application asks for, and
+// the application's own value takes over again once the force is
+// released. Snapshots hold the application's outputs, not the forces.
+
+use crate::bits::{input_changes, InputChanged};
+use crate::payload::InitPayload;
+use crate::sync::mpsc::{self, Receiver, Sender};
+use crate::sync::{Arc, Mutex, MutexGuard};
+use crate::{Address, CmriSocket, Error, MessageBuilder, NodeType, Result};
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::Duration;
+use std::vec::Vec;
+
+/// Last Get payload from each node, keyed by address byte
+type InputCache = Arc<Mutex<BTreeMap<u8, Vec<u8>>>>;
+type Subscribers = Arc<Mutex<Vec<Sender<InputChanged>>>>;
+/// Every forced output, kept up to date by the controller
+type Forces = Arc<Mutex<Vec<ForcedOutput>>>;
+
+enum Command {
+    SetOutputs {
+        node: u8,
+        outputs: Vec<u8>,
+    },
+    SetOutput {
+        node: u8,
+        bit: usize,
+        value: bool,
+    },
+    /// Forces a bit, or releases it if `value` is `None`
+    Force {
+        node: u8,
+        bit: usize,
+        value: Option<bool>,
+    },
+    ReleaseAll,
+}
+
+/// Output image for one node
+#[derive(Default)]
+struct NodeOutputs {
+    /// As commanded by the application
+    outputs: Vec<u8>,
+    /// Bits held on or off whatever the application commands
+    forces: BTreeMap<usize, bool>,
+    /// Changed since the last Set was sent
+    dirty: bool,
+}
+
+impl NodeOutputs {
+    /// Outputs to send, with the forces applied
+    fn wire(&self) -> Vec<u8> {
+        let mut wire = self.outputs.clone();
+        for (bit, value) in self.forces.iter() {
+            set_bit(&mut wire, *bit, *value);
+        }
+        wire
+    }
+}
+
+/// Sets one bit, growing the image to reach it
+fn set_bit(bytes: &mut Vec<u8>, bit: usize, value: bool) {
+    let byte = bit / 8;
+    if byte >= bytes.len() {
+        bytes.resize(byte + 1, 0);
+    }
+    if let Some(byte) = bytes.get_mut(byte) {
+        if value {
+            *byte |= 1 << (bit % 8);
+        } else {
+            *byte &= !(1 << (bit % 8));
+        }
+    }
+}
+
+/// An output bit held on or off for testing
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ForcedOutput {
+    /// Address byte of the node
+    pub node: u8,
+    pub bit: usize,
+    pub value: bool,
+}
+
+/// How `Controller::initialise()` brings each node up
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct InitSequence {
+    /// Number of times each Init is sent, for noisy buses
+    pub repeats: u8,
+    /// Pause after each node's Inits so that it can configure itself
+    /// before being polled
+    pub delay: Duration,
+    /// Poll each node after its Init and check the size of its Get
+    pub verify: bool,
+}
+
+impl Default for InitSequence {
+    fn default() -> Self {
+        Self {
+            repeats: 1,
+            delay: Duration::from_millis(10),
+            verify: true,
+        }
+    }
+}
+
+/// I/O side of the controller, owned by whichever thread drives the bus
+pub struct Controller {
+    commands: Receiver<Command>,
+    handle: ControllerHandle,
+    /// Nodes to poll, by address byte, in polling order
+    nodes: Vec<u8>,
+    outputs: BTreeMap<u8, NodeOutputs>,
+    /// Init payload for each node which has one
+    inits: BTreeMap<u8, Vec<u8>>,
+    next_poll: usize,
+    /// Polls outstanding at once in `step()`, or 1 to poll one node per
+    /// step and wait for each answer
+    pipeline_window: usize,
+    on_input_change: fn(InputChanged),
+    /// Socket session in which the nodes were last initialised, and how,
+    /// so that they can be brought back up after a reconnection
+    initialised: Option<(u32, InitSequence)>,
+    /// Nodes whose Init failed when they were last initialised
+    init_failures: Vec<(u8, Error)>,
+}
+
+/// Cloneable handle for changing outputs and reading inputs from any
+/// thread
+#[derive(Clone)]
+pub struct ControllerHandle {
+    commands: Sender<Command>,
+    inputs: InputCache,
+    subscribers: Subscribers,
+    forces: Forces,
+}
+
+impl Controller {
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::channel();
+        Self {
+            commands: rx,
+            handle: ControllerHandle {
+                commands: tx,
+                inputs: Arc::new(Mutex::new(BTreeMap::new())),
+                subscribers: Arc::new(Mutex::new(Vec::new())),
+                forces: Arc::new(Mutex::new(Vec::new())),
+            },
+            nodes: Vec::new(),
+            outputs: BTreeMap::new(),
+            inits: BTreeMap::new(),
+            next_poll: 0,
+            pipeline_window: 1,
+            on_input_change: |_| {},
+            initialised: None,
+            init_failures: Vec::new(),
+        }
+    }
+
+    /// A controller for SMINIs at the given unit addresses, each with no
+    /// searchlight signals, ready for `initialise()`
+    ///
+    /// ```no_run
+    /// # fn main() -> cmri::Result<()> {
+    /// use cmri::{CmriSocket, Controller, InitSequence};
+    ///
+    /// let mut socket = CmriSocket::tcp_client("192.168.1.50:4000")?;
+    /// let mut controller = Controller::with_smini_nodes(&[1, 2, 3])?;
+    /// controller.initialise(&mut socket, &InitSequence::default())?;
+    /// loop {
+    ///     controller.step(&mut socket)?;
+    /// }
+    /// # }
+    /// ```
+    pub fn with_smini_nodes(uas: &[u8]) -> Result<Self> {
+        let mut controller = Self::new();
+        for ua in uas {
+            let init = [u8::from(NodeType::Smini), 0, 0, 0];
+            controller.add_node_with_init(Address::Ua(*ua).wire()?, &init)?;
+        }
+        Ok(controller)
+    }
+
+    /// Called on the I/O thread for every input edge
+    pub fn on_input_change(&mut self, callback: fn(InputChanged)) {
+        self.on_input_change = callback;
+    }
+
+    /// For full duplex links, makes each `step()` poll every node with up
+    /// to `window` Polls outstanding at once rather than polling one node
+    /// and waiting for its answer. A window of 1, the default, turns this
+    /// off again
+    pub fn pipeline(&mut self, window: usize) {
+        self.pipeline_window = window.max(1);
+    }
+
+    pub fn handle(&self) -> ControllerHandle {
+        self.handle.clone()
+    }
+
+    /// Adds a node to the polling cycle, by address byte
+    pub fn add_node(&mut self, node: u8) {
+        if !self.nodes.contains(&node) {
+            self.nodes.push(node);
+        }
+    }
+
+    /// Adds a node to the polling cycle along with the node definition
+    /// parameters to send it in `initialise()`
+    pub fn add_node_with_init(&mut self, node: u8, init: &[u8]) -> Result<()> {
+        InitPayload::parse(init)?;
+        self.inits.insert(node, init.to_vec());
+        self.add_node(node);
+        Ok(())
+    }
+
+    pub fn remove_node(&mut self, node: u8) {
+        self.nodes.retain(|n| *n != node);
+        self.outputs.remove(&node);
+        self.inits.remove(&node);
+        self.handle.lock_inputs().remove(&node);
+        self.publish_forces();
+    }
+
+    /// Nodes in the polling cycle
+    pub fn nodes(&self) -> &[u8] {
+        &self.nodes
+    }
+
+    /// Applies output changes queued by handles. Returns the number of
+    /// changes applied
+    pub fn apply_commands(&mut self) -> usize {
+        let mut count = 0;
+        // The controller holds a sender itself, so this only stops when
+        // the queue is empty
+        while let Ok(command) = self.commands.try_recv() {
+            self.apply(command);
+            count += 1;
+        }
+        count
+    }
+
+    /// Sends a Set to every node whose outputs have changed. Returns the
+    /// number of Sets sent
+    pub fn send_outputs(&mut self, socket: &mut CmriSocket) -> Result<usize> {
+        let mut count = 0;
+        for (node, image) in self.outputs.iter_mut().filter(|(_, i)| i.dirty) {
+            let msg = MessageBuilder::set(*node, &image.wire()).build()?;
+            socket.send(&msg)?;
+            image.dirty = false;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Polls the next node in the cycle and caches its inputs. Returns the
+    /// node polled, or `None` if there are no nodes. A node which doesn't
+    /// answer keeps its last inputs; the socket's health tracking
+    /// records the miss
+    pub fn poll_next(&mut self, socket: &mut CmriSocket) -> Result<Option<u8>> {
+        let node = match self.nodes.get(self.next_poll) {
+            Some(node) => *node,
+            None => match self.nodes.first() {
+                Some(node) => {
+                    self.next_poll = 0;
+                    *node
+                }
+                None => return Ok(None),
+            },
+        };
+        self.next_poll += 1;
+        match socket.poll(node) {
+            Ok(msg) => {
+                self.update_inputs(node, msg.data());
+                Ok(Some(node))
+            }
+            Err(Error::NoResponse) => Ok(Some(node)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Polls every node in one pass, keeping up to `window` Polls
+    /// outstanding, and caches their inputs. Returns the number of nodes
+    /// which answered
+    pub fn poll_all(
+        &mut self,
+        socket: &mut CmriSocket,
+        window: usize,
+    ) -> Result<usize> {
+        let results = socket.poll_pipelined(&self.nodes, window)?;
+        let mut answered = 0;
+        for (node, msg) in results {
+            if let Some(msg) = msg {
+                self.update_inputs(node, msg.data());
+                answered += 1;
+            }
+        }
+        Ok(answered)
+    }
+
+    /// Sends every node its Init, in polling order. All nodes are tried
+    /// even if one fails, and the first failure is returned; every
+    /// failure is kept in `init_failures()`. If the socket later
+    /// reconnects, `step()` does this again
+    pub fn initialise(
+        &mut self,
+        socket: &mut CmriSocket,
+        sequence: &InitSequence,
+    ) -> Result<()> {
+        self.initialised = Some((socket.session(), *sequence));
+        self.init_failures.clear();
+        let nodes: Vec<u8> = self
+            .nodes
+            .iter()
+            .copied()
+            .filter(|n| self.inits.contains_key(n))
+            .collect();
+        for node in nodes {
+            if let Err(e) = self.initialise_node(socket, node, sequence) {
+                self.init_failures.push((node, e));
+            }
+        }
+        match self.init_failures.first() {
+            Some((_, e)) => Err(e.clone()),
+            None => Ok(()),
+        }
+    }
+
+    /// Nodes whose Init failed the last time the nodes were initialised,
+    /// with the reason, in polling order
+    pub fn init_failures(&self) -> &[(u8, Error)] {
+        &self.init_failures
+    }
+
+    /// Sends one node its Init. When verifying, a node which answers with
+    /// the wrong number of input bytes gives `Error::InitMismatch`. A
+    /// node added without an Init payload gives `Error::NoInitPayload`
+    pub fn initialise_node(
+        &mut self,
+        socket: &mut CmriSocket,
+        node: u8,
+        sequence: &InitSequence,
+    ) -> Result<()> {
+        let payload =
+            self.inits.get(&node).ok_or(Error::NoInitPayload { node })?;
+        let init = MessageBuilder::init(node, payload).build()?;
+        let expected = InitPayload::parse(payload)?.input_bytes();
+        for _ in 0..sequence.repeats.max(1) {
+            socket.send(&init)?;
+        }
+        thread::sleep(sequence.delay);
+        if !sequence.verify {
+            return Ok(());
+        }
+        let msg = socket.poll(node)?;
+        self.update_inputs(node, msg.data());
+        if msg.len != expected {
+            return Err(Error::InitMismatch {
+                node,
+                expected,
+                got: msg.len,
+            });
+        }
+        Ok(())
+    }
+
+    /// Every node's current output image, including changes not yet sent
+    /// but without any forces
+    #[cfg(feature = "config")]
+    pub fn snapshot(&self) -> crate::OutputSnapshot {
+        crate::OutputSnapshot {
+            nodes: self
+                .outputs
+                .iter()
+                .map(|(node, image)| (*node, image.outputs.clone()))
+                .collect(),
+        }
+    }
+
+    /// Replaces the output images with those from a snapshot. Every node
+    /// in it is sent a Set on the next step, whether or not its outputs
+    /// differ, since after a restart the node's state isn't known
+    #[cfg(feature = "config")]
+    pub fn restore(&mut self, snapshot: &crate::OutputSnapshot) {
+        for (node, outputs) in snapshot.nodes.iter() {
+            let image = self.outputs.entry(*node).or_default();
+            image.outputs = outputs.clone();
+            image.dirty = true;
+        }
+    }
+
+    /// Brings the nodes back up after the socket has reconnected, as they
+    /// may have been power cycled: sends every Init again and marks all
+    /// outputs to be sent on the next step
+    pub fn resume(
+        &mut self,
+        socket: &mut CmriSocket,
+        sequence: &InitSequence,
+    ) -> Result<()> {
+        for image in self.outputs.values_mut() {
+            image.dirty = true;
+        }
+        self.initialise(socket, sequence)
+    }
+
+    /// One pass of the I/O loop: resume after a reconnection, apply
+    /// queued output changes, send them to the bus and poll the next
+    /// node, or every node when pipelining. A node which can't be
+    /// initialised again after a reconnection doesn't stop the loop; it
+    /// is left in `init_failures()` for the application to deal with
+    pub fn step(&mut self, socket: &mut CmriSocket) -> Result<()> {
+        if let Some((session, sequence)) = self.initialised {
+            if session != socket.session() {
+                // Failures are recorded per node
+                let _ = self.resume(socket, &sequence);
+            }
+        }
+        self.apply_commands();
+        self.send_outputs(socket)?;
+        if self.pipeline_window > 1 {
+            self.poll_all(socket, self.pipeline_window)?;
+        } else {
+            self.poll_next(socket)?;
+        }
+        Ok(())
+    }
+
+    /// Caches a node's new inputs and reports any edges
+    fn update_inputs(&self, node: u8, inputs: &[u8]) {
+        let old = self
+            .handle
+            .lock_inputs()
+            .insert(node, inputs.to_vec())
+            .unwrap_or_default();
+        let mut subscribers = self.handle.lock_subscribers();
+        for change in input_changes(node, &old, inputs) {
+            (self.on_input_change)(change);
+            subscribers.retain(|s| s.send(change).is_ok());
+        }
+    }
+
+    fn apply(&mut self, command: Command) {
+        let node = match command {
+            Command::SetOutputs { node, .. }
+            | Command::SetOutput { node, .. }
+            | Command::Force { node, .. } => node,
+            Command::ReleaseAll => {
+                for image in self.outputs.values_mut() {
+                    let before = image.wire();
+                    image.forces.clear();
+                    image.dirty |= image.wire() != before;
+                }
+                self.publish_forces();
+                return;
+            }
+        };
+        let image = self.outputs.entry(node).or_default();
+        // Only a change to what goes on the wire needs a Set, so a
+        // command to a forced bit waits for the force to be released
+        let before = image.wire();
+        let forced = match command {
+            Command::SetOutputs { outputs, .. } => {
+                image.outputs = outputs;
+                false
+            }
+            Command::SetOutput { bit, value, .. } => {
+                set_bit(&mut image.outputs, bit, value);
+                false
+            }
+            Command::Force { bit, value, .. } => {
+                match value {
+                    Some(value) => image.forces.insert(bit, value),
+                    None => image.forces.remove(&bit),
+                };
+                true
+            }
+            Command::ReleaseAll => false,
+        };
+        image.dirty |= image.wire() != before;
+        if forced {
+            self.publish_forces();
+        }
+    }
+
+    /// Updates the forces seen by handles
+    fn publish_forces(&self) {
+        let forces = self.outputs.iter().flat_map(|(node, image)| {
+            image.forces.iter().map(move |(bit, value)| ForcedOutput {
+                node: *node,
+                bit: *bit,
+                value: *value,
+            })
+        });
+        *self.handle.lock_forces() = forces.collect();
+    }
+}
+
+impl Default for Controller {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ControllerHandle {
+    /// Replaces all of a node's outputs. They are sent on the
+    /// controller's next step if they differ from the current outputs
+    pub fn set_outputs(&self, node: u8, outputs: &[u8]) -> Result<()> {
+        self.send(Command::SetOutputs {
+            node,
+            outputs: outputs.to_vec(),
+        })
+    }
+
+    /// Changes one output bit, leaving the node's other outputs alone
+    pub fn set_output(&self, node: u8, bit: usize, value: bool) -> Result<()> {
+        self.send(Command::SetOutput { node, bit, value })
+    }
+
+    /// Holds one output bit on or off, whatever the application sets it
+    /// to, until it is released
+    pub fn force_output(
+        &self,
+        node: u8,
+        bit: usize,
+        value: bool,
+    ) -> Result<()> {
+        self.send(Command::Force {
+            node,
+            bit,
+            value: Some(value),
+        })
+    }
+
+    /// Hands an output bit back to the application
+    pub fn release_output(&self, node: u8, bit: usize) -> Result<()> {
+        self.send(Command::Force {
+            node,
+            bit,
+            value: None,
+        })
+    }
+
+    /// Releases every forced output
+    pub fn release_all(&self) -> Result<()> {
+        self.send(Command::ReleaseAll)
+    }
+
+    /// Outputs currently forced, by node and bit, as of the controller's
+    /// last step
+    pub fn forced(&self) -> Vec<ForcedOutput> {
+        self.lock_forces().clone()
+    }
+
+    /// Inputs from the node's most recent Get, or `None` if it hasn't
+    /// answered a Poll yet
+    pub fn inputs(&self, node: u8) -> Option<Vec<u8>> {
+        self.lock_inputs().get(&node).cloned()
+    }
+
+    /// One input bit from the node's most recent Get
+    pub fn input(&self, node: u8, bit: usize) -> Option<bool> {
+        let inputs = self.lock_inputs();
+        let byte = inputs.get(&node)?.get(bit / 8)?;
+        Some(byte & (1 << (bit % 8)) != 0)
+    }
+
+    /// Receives every input edge from now on. The subscription ends when
+    /// the receiver is dropped
+    pub fn subscribe(&self) -> Receiver<InputChanged> {
+        let (tx, rx) = mpsc::channel();
+        self.lock_subscribers().push(tx);
+        rx
+    }
+
+    fn lock_subscribers(&self) -> MutexGuard<'_, Vec<Sender<InputChanged>>> {
+        self.subscribers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_forces(&self) -> MutexGuard<'_, Vec<ForcedOutput>> {
+        self.forces.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn send(&self, command: Command) -> Result<()> {
+        self.commands.send(command).map_err(|_| Error::Disconnected)
+    }
+
+    fn lock_inputs(&self) -> MutexGuard<'_, BTreeMap<u8, Vec<u8>>> {
+        // Each update replaces a whole entry, so a panic elsewhere can't
+        // leave the cache half-written
+        self.inputs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sim::{Behaviour, VirtualBus, VirtualNode};
+    use std::boxed::Box;
+    use std::thread;
+    use std::time::Duration;
+    use std::vec;
+
+    fn socket(bus: &VirtualBus) -> CmriSocket {
+        CmriSocket::builder(Box::new(bus.clone()))
+            .read_timeout(Duration::from_millis(10))
+            .build()
+    }
+
+    #[test]
+    fn handle_is_send_and_sync() {
+        fn send<T: Send>() {}
+        fn send_sync<T: Send + Sync>() {}
+        send::<Controller>();
+        send_sync::<ControllerHandle>();
+    }
+
+    #[test]
+    fn outputs_from_other_threads() {
+        let bus = VirtualBus::new();
+        let mirror = Behaviour::MirrorOutputs;
+        bus.add_node(VirtualNode::new(0x41, 2, mirror).unwrap());
+        let mut socket = socket(&bus);
+        let mut controller = Controller::new();
+        controller.add_node(0x41);
+
+        let handle = controller.handle();
+        thread::spawn(move || {
+            handle.set_outputs(0x41, &[0x01, 0x00]).unwrap();
+            handle.set_output(0x41, 9, true).unwrap();
+        })
+        .join()
+        .unwrap();
+
+        let handle = controller.handle();
+        assert_eq!(handle.inputs(0x41), None);
+        controller.step(&mut socket).unwrap();
+        assert_eq!(
+            bus.with_node(0x41, |n| n.outputs().to_vec()),
+            Some(vec![0x01, 0x02])
+        );
+        assert_eq!(handle.inputs(0x41), Some(vec![0x01, 0x02]));
+        assert_eq!(handle.input(0x41, 9), Some(true));
+        assert_eq!(handle.input(0x41, 8), Some(false));
+
+        // Nothing changed, so no Set is sent
+        handle.set_output(0x41, 0, true).unwrap();
+        assert_eq!(controller.apply_commands(), 1);
+        assert_eq!(controller.send_outputs(&mut socket), Ok(0));
+    }
+
+    #[test]
+    fn forced_outputs() {
+        let bus = VirtualBus::new();
+        let mirror = Behaviour::MirrorOutputs;
+        bus.add_node(VirtualNode::new(0x41, 2, mirror).unwrap());
+        let mut socket = socket(&bus);
+        let mut controller = Controller::new();
+        controller.add_node(0x41);
+        let outputs = || bus.with_node(0x41, |n| n.outputs().to_vec());
+
+        let handle = controller.handle();
+        handle.set_outputs(0x41, &[0x01, 0x00]).unwrap();
+        handle.force_output(0x41, 0, false).unwrap();
+        handle.force_output(0x41, 9, true).unwrap();
+        controller.step(&mut socket).unwrap();
+        assert_eq!(outputs(), Some(vec![0x00, 0x02]));
+        let forced = ForcedOutput {
+            node: 0x41,
+            bit: 9,
+            value: true,
+        };
+        assert_eq!(handle.forced().len(), 2);
+        assert_eq!(handle.forced()[1], forced);
+
+        // The application can't move a forced bit
+        handle.set_output(0x41, 9, false).unwrap();
+        controller.apply_commands();
+        assert_eq!(controller.send_outputs(&mut socket), Ok(0));
+
+        // Released bits go back to what the application last set
+        handle.release_output(0x41, 0).unwrap();
+        controller.step(&mut socket).unwrap();
+        assert_eq!(outputs(), Some(vec![0x01, 0x02]));
+        assert_eq!(handle.forced(), [forced]);
+        handle.release_all().unwrap();
+        controller.step(&mut socket).unwrap();
+        assert_eq!(outputs(), Some(vec![0x01, 0x00]));
+        assert!(handle.forced().is_empty());
+    }
+
+    #[test]
+    fn polls_in_turn() {
+        let bus = VirtualBus::new();
+        bus.add_node(VirtualNode::new(0x41, 1, Behaviour::Manual).unwrap());
+        bus.add_node(VirtualNode::new(0x42, 1, Behaviour::Manual).unwrap());
+        let mut socket = socket(&bus);
+        let mut controller = Controller::new();
+        assert_eq!(controller.poll_next(&mut socket), Ok(None));
+        for node in [0x41, 0x42, 0x43].iter() {
+            controller.add_node(*node);
+        }
+
+        let polled: Vec<_> = (0..4)
+            .map(|_| controller.poll_next(&mut socket).unwrap())
+            .collect();
+        assert_eq!(polled, [Some(0x41), Some(0x42), Some(0x43), Some(0x41)]);
+        // The missing node has no inputs
+        assert_eq!(controller.handle().inputs(0x43), None);
+        assert_eq!(controller.handle().inputs(0x42), Some(vec![0]));
+
+        controller.remove_node(0x42);
+        assert_eq!(controller.nodes(), [0x41, 0x43]);
+        assert_eq!(controller.handle().inputs(0x42), None);
+    }
+
+    #[test]
+    fn pipelined_polls() {
+        let bus = VirtualBus::new();
+        for addr in [0x41, 0x42].iter() {
+            let mut node =
+                VirtualNode::new(*addr, 1, Behaviour::Manual).unwrap();
+            node.driver_mut().set_inputs(&[*addr]).unwrap();
+            bus.add_node(node);
+        }
+        let mut socket = socket(&bus);
+        let mut controller = Controller::new();
+        for node in [0x41, 0x43, 0x42].iter() {
+            controller.add_node(*node);
+        }
+        controller.pipeline(2);
+
+        controller.step(&mut socket).unwrap();
+        assert_eq!(controller.handle().inputs(0x41), Some(vec![0x41]));
+        assert_eq!(controller.handle().inputs(0x42), Some(vec![0x42]));
+        assert_eq!(controller.handle().inputs(0x43), None);
+        // Each node which answered was polled once
+        assert_eq!(bus.with_node(0x41, |n| n.polls()), Some(1));
+        assert_eq!(bus.with_node(0x42, |n| n.polls()), Some(1));
+        let missed = socket.node_health(0x43).map(|h| h.missed_polls);
+        assert_eq!(missed, Some(1));
+    }
+
+    #[cfg(feature = "config")]
+    #[test]
+    fn snapshot_and_restore() {
+        let bus = VirtualBus::new();
+        bus.add_node(
+            VirtualNode::new(0x41, 2, Behaviour::MirrorOutputs).unwrap(),
+        );
+        let mut socket = socket(&bus);
+        let mut controller = Controller::new();
+        controller.add_node(0x41);
+        controller
+            .handle()
+            .set_outputs(0x41, &[0x05, 0x80])
+            .unwrap();
+        controller.step(&mut socket).unwrap();
+        let snapshot = controller.snapshot();
+        assert_eq!(snapshot.nodes.get(&0x41), Some(&vec![0x05, 0x80]));
+
+        // After a power cycle the node has forgotten its outputs
+        let bus = VirtualBus::new();
+        bus.add_node(
+            VirtualNode::new(0x41, 2, Behaviour::MirrorOutputs).unwrap(),
+        );
+        let mut socket = self::socket(&bus);
+        let mut controller = Controller::new();
+        controller.add_node(0x41);
+        controller.restore(&snapshot);
+        assert_eq!(controller.send_outputs(&mut socket), Ok(1));
+        assert_eq!(
+            bus.with_node(0x41, |n| n.outputs().to_vec()),
+            Some(vec![0x05, 0x80])
+        );
+        assert_eq!(controller.snapshot(), snapshot);
+    }
+
+    #[test]
+    fn resume_after_reconnecting() {
+        use crate::transport::reconnect::Opener;
+        use crate::RetryPolicy;
+
+        /// An adapter which has been unplugged
+        struct Unplugged;
+        impl std::io::Read for Unplugged {
+            fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::NotConnected.into())
+            }
+        }
+        impl std::io::Write for Unplugged {
+            fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::NotConnected.into())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let bus = VirtualBus::new();
+        let node = || VirtualNode::new(0x41, 3, Behaviour::MirrorOutputs);
+        bus.add_node(node().unwrap());
+        let reopen = bus.clone();
+        let opener: Opener = Box::new(move || Ok(Box::new(reopen.clone())));
+        let mut socket = CmriSocket::builder(Box::new(bus.clone()))
+            .read_timeout(Duration::from_millis(10))
+            .reconnect(opener, RetryPolicy::default())
+            .build();
+        // UA 5 is configured but missing from the bus
+        let mut controller = Controller::with_smini_nodes(&[0, 5]).unwrap();
+        let sequence = InitSequence {
+            delay: Duration::from_millis(0),
+            ..InitSequence::default()
+        };
+        assert_eq!(
+            controller.initialise(&mut socket, &sequence),
+            Err(Error::NoResponse)
+        );
+        controller.handle().set_outputs(0x41, &[0x0f; 6]).unwrap();
+        controller.step(&mut socket).unwrap();
+
+        // The adapter and the node both lose power
+        socket.set_transport(Box::new(Unplugged));
+        bus.with_node(0x41, |n| *n = node().unwrap());
+        controller.step(&mut socket).unwrap();
+        assert_eq!(socket.session(), 1);
+        assert_eq!(controller.init_failures(), [(0x46, Error::NoResponse)]);
+        assert_eq!(bus.with_node(0x41, |n| n.outputs().to_vec()), Some(vec![]));
+
+        controller.step(&mut socket).unwrap();
+        assert_eq!(
+            bus.with_node(0x41, |n| n.driver().node_type()),
+            Some(Some(NodeType::Smini))
+        );
+        assert_eq!(
+            bus.with_node(0x41, |n| n.outputs().to_vec()),
+            Some(vec![0x0f; 6])
+        );
+    }
+
+    #[test]
+    fn smini_nodes() {
+        let bus = VirtualBus::new();
+        for node in [0x41, 0x42].iter() {
+            bus.add_node(
+                VirtualNode::new(*node, 3, Behaviour::Manual).unwrap(),
+            );
+        }
+        let mut socket = socket(&bus);
+        let mut controller = Controller::with_smini_nodes(&[0, 1]).unwrap();
+        assert_eq!(controller.nodes(), [0x41, 0x42]);
+        let sequence = InitSequence {
+            delay: Duration::from_millis(0),
+            ..InitSequence::default()
+        };
+        controller.initialise(&mut socket, &sequence).unwrap();
+        assert_eq!(
+            bus.with_node(0x42, |n| n.driver().node_type()),
+            Some(Some(NodeType::Smini))
+        );
+
+        assert_eq!(
+            Controller::with_smini_nodes(&[200]).err(),
+            Some(Error::OutOfBounds)
+        );
+    }
+
+    #[test]
+    fn input_edges() {
+        let bus = VirtualBus::new();
+        let sequence = Behaviour::Sequence(vec![vec![0b01], vec![0b10]]);
+        bus.add_node(VirtualNode::new(0x41, 1, sequence).unwrap());
+        let mut socket = socket(&bus);
+        let mut controller = Controller::new();
+        controller.add_node(0x41);
+        let edges = controller.handle().subscribe();
+        let edge = |bit, rising| InputChanged {
+            node: 0x41,
+            bit,
+            rising,
+        };
+
+        controller.step(&mut socket).unwrap();
+        assert_eq!(edges.try_recv(), Ok(edge(0, true)));
+        assert!(edges.try_recv().is_err());
+
+        controller.step(&mut socket).unwrap();
+        let changes: Vec<_> =
+            std::iter::from_fn(|| edges.try_recv().ok()).collect();
+        assert_eq!(changes, [edge(0, false), edge(1, true)]);
+
+        // A dropped subscriber is forgotten
+        drop(edges);
+        controller.step(&mut socket).unwrap();
+        assert!(controller.handle().lock_subscribers().is_empty());
+    }
+
+    #[test]
+    fn init_handshake() {
+        let bus = VirtualBus::new();
+        bus.add_node(VirtualNode::new(0x41, 3, Behaviour::Manual).unwrap());
+        // Wrongly set up for two input bytes
+        bus.add_node(VirtualNode::new(0x42, 2, Behaviour::Manual).unwrap());
+        let mut socket = socket(&bus);
+        let mut controller = Controller::new();
+        let smini = [b'M', 0, 0, 0];
+        controller.add_node_with_init(0x41, &smini).unwrap();
+        controller.add_node_with_init(0x42, &smini).unwrap();
+        controller.add_node_with_init(0x43, &smini).unwrap();
+        assert_eq!(
+            controller.add_node_with_init(0x44, b"M"),
+            Err(Error::InitTooShort)
+        );
+
+        let sequence = InitSequence {
+            delay: Duration::from_millis(0),
+            ..InitSequence::default()
+        };
+        assert_eq!(
+            controller.initialise(&mut socket, &sequence),
+            Err(Error::InitMismatch {
+                node: 0x42,
+                expected: 3,
+                got: 2
+            })
+        );
+        assert_eq!(
+            controller.init_failures(),
+            [
+                (
+                    0x42,
+                    Error::InitMismatch {
+                        node: 0x42,
+                        expected: 3,
+                        got: 2
+                    }
+                ),
+                (0x43, Error::NoResponse)
+            ]
+        );
+        // Every node was sent its Init
+        assert_eq!(
+            bus.with_node(0x41, |n| n.driver().node_type()),
+            Some(Some(NodeType::Smini))
+        );
+        assert_eq!(controller.handle().inputs(0x41), Some(vec![0, 0, 0]));
+        assert_eq!(
+            controller.initialise_node(&mut socket, 0x43, &sequence),
+            Err(Error::NoResponse)
+        );
+
+        let quiet = InitSequence {
+            verify: false,
+            ..sequence
+        };
+        assert_eq!(
+            controller.initialise_node(&mut socket, 0x43, &quiet),
+            Ok(())
+        );
+
+        controller.add_node(0x45);
+        assert_eq!(
+            controller.initialise_node(&mut socket, 0x45, &quiet),
+            Err(Error::NoInitPayload { node: 0x45 })
+        );
+    }
+}
+
+#[cfg(all(test, cmri_loom))]
+mod loom_test {
+    use super::*;
+    use loom::thread;
+
+    #[test]
+    fn output_bits_from_two_threads() {
+        loom::model(|| {
+            let mut controller = Controller::new();
+            let threads: Vec<_> = [0, 9]
+                .iter()
+                .map(|&bit| {
+                    let handle = controller.handle();
+                    thread::spawn(move || {
+                        handle.set_output(0x41, bit, true).unwrap();
+                    })
+                })
+                .collect();
+            // Commands may be applied while the handles are still sending
+            controller.apply_commands();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            controller.apply_commands();
+
+            let image = &controller.outputs[&0x41];
+            assert_eq!(image.outputs, [0x01, 0x02]);
+            assert!(image.dirty);
+        });
+    }
+
+    #[test]
+    fn subscriber_sees_inputs_or_edge() {
+        loom::model(|| {
+            let controller = Controller::new();
+            let handle = controller.handle();
+            let reader = thread::spawn(move || {
+                let edges = handle.subscribe();
+                (edges, handle.inputs(0x41))
+            });
+            controller.update_inputs(0x41, &[0x01]);
+            let (edges, seen) = reader.join().unwrap();
+
+            // The cache is updated before subscribers are told, so a
+            // subscriber which saw the old inputs is always sent the edge
+            match seen {
+                Some(inputs) => assert_eq!(inputs, [0x01]),
+                None => assert!(edges.try_recv().is_ok()),
+            }
+        });
+    }
+}
@@ -0,0 +1,367 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Glue between an embedded-hal USART and the `NodeDriver`, for use on
+// Cortex-M parts such as the RP2040. Received bytes are pushed into a
+// heapless spsc queue from the USART interrupt and drained from the main
+// loop, so the only thing shared between the two is the queue.
+//
+// The interrupt handler needs a `'static` producer, so the queue has to
+// live for the whole program. Either make it an RTIC local resource, or
+// take it once with the cortex-m crate's `singleton!`:
+//
+// let queue = singleton!(: Queue<u8, RX_QUEUE_LEN> = Queue::new()).unwrap();
+// let (producer, consumer) = queue.split();
+//
+// with the producer moved into the interrupt handler, which calls
+// `on_rx_interrupt()`, and the consumer passed to `UsartNode::poll()`.
+// Keep the node's time with `UsartNode::poll_clock()` and a `FugitClock`
+// over the HAL's monotonic timer.
+//
+// Nodes on an RS485 bus whose transceiver isn't switched by the USART
+// itself can use a `PinLine` for the driver enable, passing it to
+// `UsartNode::poll_with_line()`.
+
+use crate::{Action, Clock, Error, NodeDriver, Result};
+use crate::{LineDiscipline, LineTiming, SerialConfig};
+use embedded_hal::blocking::delay::DelayUs;
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::serial;
+use heapless::spsc::{Consumer, Producer};
+
+/// Suggested length of the receive queue. This is enough to hold a few
+/// messages' worth of header while the main loop is busy elsewhere
+pub const RX_QUEUE_LEN: usize = 64;
+
+/// Call from the USART receive interrupt to move any received bytes into
+/// the queue
+pub fn on_rx_interrupt<R, const N: usize>(
+    serial: &mut R,
+    producer: &mut Producer<'_, u8, N>,
+) -> Result<()>
+where
+    R: serial::Read<u8>,
+{
+    loop {
+        match serial.read() {
+            Ok(byte) => producer.enqueue(byte).map_err(|_| Error::QueueFull)?,
+            Err(nb::Error::WouldBlock) => return Ok(()),
+            Err(nb::Error::Other(_)) => return Err(Error::SerialError),
+        }
+    }
+}
+
+/// A C/MRI node attached to the transmit half of a USART
+pub struct UsartNode<W> {
+    tx: W,
+    driver: NodeDriver,
+}
+
+impl<W> UsartNode<W>
+where
+    W: serial::Write<u8>,
+{
+    pub fn new(tx: W, driver: NodeDriver) -> Self {
+        Self { tx, driver }
+    }
+
+    pub fn driver(&self) -> &NodeDriver {
+        &self.driver
+    }
+
+    /// Mutable access to the driver so that inputs can be updated
+    pub fn driver_mut(&mut self) -> &mut NodeDriver {
+        &mut self.driver
+    }
+
+    /// Hands back the USART and driver
+    pub fn release(self) -> (W, NodeDriver) {
+        (self.tx, self.driver)
+    }
+
+    /// As `poll()`, taking the time from a clock such as a `FugitClock`
+    /// reading the HAL's monotonic timer
+    pub fn poll_clock<const N: usize>(
+        &mut self,
+        consumer: &mut Consumer<'_, u8, N>,
+        clock: &impl Clock,
+    ) -> Result<bool> {
+        self.poll(consumer, clock.now())
+    }
+
+    /// Processes every byte currently in the queue, transmitting any
+    /// responses. Returns true if the outputs have changed so that the
+    /// caller knows to update its hardware
+    pub fn poll<const N: usize>(
+        &mut self,
+        consumer: &mut Consumer<'_, u8, N>,
+        now: u64,
+    ) -> Result<bool> {
+        self.process_queue(consumer, now, None)
+    }
+
+    /// As `poll()`, switching the transceiver around each response
+    pub fn poll_with_line<const N: usize>(
+        &mut self,
+        consumer: &mut Consumer<'_, u8, N>,
+        now: u64,
+        line: &mut dyn LineDiscipline,
+    ) -> Result<bool> {
+        self.process_queue(consumer, now, Some(line))
+    }
+
+    fn process_queue<const N: usize>(
+        &mut self,
+        consumer: &mut Consumer<'_, u8, N>,
+        now: u64,
+        mut line: Option<&mut dyn LineDiscipline>,
+    ) -> Result<bool> {
+        let mut outputs_changed = false;
+        while let Some(byte) = consumer.dequeue() {
+            match self.driver.process(byte, now) {
+                Action::Transmit(bytes) => {
+                    if let Some(line) = line.as_mut() {
+                        line.end_rx();
+                        line.begin_tx()?;
+                    }
+                    for b in bytes.iter() {
+                        nb::block!(self.tx.write(*b))
+                            .map_err(|_| Error::SerialError)?;
+                    }
+                    nb::block!(self.tx.flush())
+                        .map_err(|_| Error::SerialError)?;
+                    if let Some(line) = line.as_mut() {
+                        line.end_tx(bytes.len())?;
+                    }
+                }
+                Action::OutputsChanged => outputs_changed = true,
+                Action::None => {}
+            }
+        }
+        Ok(outputs_changed)
+    }
+}
+
+/// RS485 driver enable on an embedded-hal output pin, which is high
+/// while transmitting
+pub struct PinLine<P, D> {
+    pin: P,
+    delay: D,
+    config: SerialConfig,
+    timing: LineTiming,
+}
+
+impl<P, D> PinLine<P, D>
+where
+    P: OutputPin,
+    D: DelayUs<u32>,
+{
+    /// Takes the pin, leaving the transceiver in receive mode. `config`
+    /// must match the USART's, as it sets how long the transceiver is
+    /// held in transmit
+    pub fn new(mut pin: P, delay: D, config: SerialConfig) -> Result<Self> {
+        pin.set_low().map_err(|_| Error::SerialError)?;
+        Ok(Self {
+            pin,
+            delay,
+            config,
+            timing: LineTiming::default(),
+        })
+    }
+
+    pub fn with_timing(mut self, timing: LineTiming) -> Self {
+        self.timing = timing;
+        self
+    }
+
+    /// Hands back the pin and delay
+    pub fn release(self) -> (P, D) {
+        (self.pin, self.delay)
+    }
+}
+
+impl<P, D> LineDiscipline for PinLine<P, D>
+where
+    P: OutputPin,
+    D: DelayUs<u32>,
+{
+    fn set_driver(&mut self, enabled: bool) -> Result<()> {
+        let res = if enabled {
+            self.pin.set_high()
+        } else {
+            self.pin.set_low()
+        };
+        res.map_err(|_| Error::SerialError)
+    }
+
+    fn delay_us(&mut self, us: u64) {
+        self.delay.delay_us(us.min(u32::MAX as u64) as u32);
+    }
+
+    fn timing(&self) -> LineTiming {
+        self.timing
+    }
+
+    fn config(&self) -> SerialConfig {
+        self.config
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{MessageBuilder, TX_BUFFER_LEN};
+    use heapless::spsc::Queue;
+    use heapless::Vec;
+
+    /// Serial port which hands out a fixed set of bytes and records
+    /// anything written to it
+    #[derive(Default)]
+    struct TestSerial {
+        rx: Vec<u8, TX_BUFFER_LEN>,
+        rx_pos: usize,
+        tx: Vec<u8, TX_BUFFER_LEN>,
+    }
+
+    impl serial::Read<u8> for TestSerial {
+        type Error = ();
+        fn read(&mut self) -> nb::Result<u8, ()> {
+            let byte = self.rx.get(self.rx_pos).ok_or(nb::Error::WouldBlock)?;
+            self.rx_pos += 1;
+            Ok(*byte)
+        }
+    }
+
+    impl serial::Write<u8> for TestSerial {
+        type Error = ();
+        fn write(&mut self, word: u8) -> nb::Result<(), ()> {
+            self.tx.push(word).map_err(|_| nb::Error::Other(()))
+        }
+        fn flush(&mut self) -> nb::Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    fn encoded(builder: MessageBuilder) -> Vec<u8, TX_BUFFER_LEN> {
+        let mut buf = [0_u8; TX_BUFFER_LEN];
+        let len = builder.build().unwrap().encode(&mut buf).unwrap();
+        Vec::from_slice(&buf[..len]).unwrap()
+    }
+
+    #[test]
+    fn poll_is_answered() {
+        let mut queue: Queue<u8, RX_QUEUE_LEN> = Queue::new();
+        let (mut producer, mut consumer) = queue.split();
+
+        let mut serial = TestSerial {
+            rx: encoded(MessageBuilder::poll(0x41)),
+            ..Default::default()
+        };
+        on_rx_interrupt(&mut serial, &mut producer).unwrap();
+
+        let mut driver = NodeDriver::new(0x41, 2).unwrap();
+        driver.set_inputs(&[1, 2]).unwrap();
+        let mut node = UsartNode::new(TestSerial::default(), driver);
+        assert_eq!(node.poll(&mut consumer, 0), Ok(false));
+
+        let (tx, _) = node.release();
+        assert_eq!(tx.tx, encoded(MessageBuilder::get(0x41, &[1, 2])));
+    }
+
+    #[test]
+    fn set_reports_outputs_changed() {
+        let mut queue: Queue<u8, RX_QUEUE_LEN> = Queue::new();
+        let (mut producer, mut consumer) = queue.split();
+
+        let mut serial = TestSerial {
+            rx: encoded(MessageBuilder::set(0x41, &[0xf0])),
+            ..Default::default()
+        };
+        on_rx_interrupt(&mut serial, &mut producer).unwrap();
+
+        let driver = NodeDriver::new(0x41, 2).unwrap();
+        let mut node = UsartNode::new(TestSerial::default(), driver);
+        assert_eq!(node.poll(&mut consumer, 0), Ok(true));
+        assert_eq!(node.driver().outputs(), [0xf0]);
+    }
+
+    /// Pin and delay which share a record of DE changes against time
+    #[derive(Default)]
+    struct TestPin {
+        now: u32,
+        changes: Vec<(u32, bool), 4>,
+    }
+
+    struct TestDe<'a>(&'a core::cell::RefCell<TestPin>);
+    struct TestDelay<'a>(&'a core::cell::RefCell<TestPin>);
+
+    impl OutputPin for TestDe<'_> {
+        type Error = ();
+        fn set_low(&mut self) -> core::result::Result<(), ()> {
+            let mut pin = self.0.borrow_mut();
+            let now = pin.now;
+            pin.changes.push((now, false)).map_err(|_| ())
+        }
+        fn set_high(&mut self) -> core::result::Result<(), ()> {
+            let mut pin = self.0.borrow_mut();
+            let now = pin.now;
+            pin.changes.push((now, true)).map_err(|_| ())
+        }
+    }
+
+    impl DelayUs<u32> for TestDelay<'_> {
+        fn delay_us(&mut self, us: u32) {
+            self.0.borrow_mut().now += us;
+        }
+    }
+
+    #[test]
+    fn response_keys_the_transceiver() {
+        let mut queue: Queue<u8, RX_QUEUE_LEN> = Queue::new();
+        let (mut producer, mut consumer) = queue.split();
+        let mut serial = TestSerial {
+            rx: encoded(MessageBuilder::poll(0x41)),
+            ..Default::default()
+        };
+        on_rx_interrupt(&mut serial, &mut producer).unwrap();
+
+        let record = core::cell::RefCell::new(TestPin::default());
+        let timing = LineTiming {
+            post_rx_guard: 1000,
+            ..LineTiming::default()
+        };
+        let config = SerialConfig::new(19200);
+        let mut line =
+            PinLine::new(TestDe(&record), TestDelay(&record), config)
+                .unwrap()
+                .with_timing(timing);
+        let driver = NodeDriver::new(0x41, 1).unwrap();
+        let mut node = UsartNode::new(TestSerial::default(), driver);
+        node.poll_with_line(&mut consumer, 0, &mut line).unwrap();
+
+        // A Get with one input byte is 7 bytes, held for 4 more
+        let release = 1000 + 11 * 573;
+        assert_eq!(
+            record.borrow().changes,
+            [(0, false), (1000, true), (release, false)]
+        );
+        assert_eq!(node.release().0.tx.len(), 7);
+    }
+
+    #[test]
+    fn full_queue() {
+        let mut queue: Queue<u8, 4> = Queue::new();
+        let (mut producer, _) = queue.split();
+
+        let mut serial = TestSerial {
+            rx: encoded(MessageBuilder::poll(0x41)),
+            ..Default::default()
+        };
+        let res = on_rx_interrupt(&mut serial, &mut producer);
+        assert_eq!(res, Err(Error::QueueFull));
+    }
+}
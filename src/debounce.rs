@@ -0,0 +1,138 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Debouncing for node inputs such as block detectors. Raw input bytes are
+// sampled at a regular interval and an input only changes state once it
+// has read the same new value for a set number of samples in a row. Bits
+// are numbered as in a payload, from the LSB of the first byte.
+//
+// Unchanged bytes cost one comparison per sample, so this is cheap enough
+// to run from a timer interrupt on an AVR.
+
+use crate::{Error, Result};
+
+pub struct Debouncer<const BYTES: usize> {
+    stable: [u8; BYTES],
+    /// Bits which differ from the stable state and are being counted
+    pending: [u8; BYTES],
+    /// Samples in a row each input has differed from its stable state
+    counts: [[u8; 8]; BYTES],
+    /// Samples needed for each input to change state
+    samples: [[u8; 8]; BYTES],
+}
+
+impl<const BYTES: usize> Debouncer<BYTES> {
+    /// Every input needs `samples` matching samples in a row to change
+    /// state. 0 and 1 both mean no debouncing. All inputs start off
+    pub fn new(samples: u8) -> Self {
+        Self {
+            stable: [0; BYTES],
+            pending: [0; BYTES],
+            counts: [[0; 8]; BYTES],
+            samples: [[samples; 8]; BYTES],
+        }
+    }
+
+    /// Every input needs to be stable for `time` to change state, when
+    /// sampled every `sample_period`. Both are in the caller's units
+    pub fn for_time(time: u32, sample_period: u32) -> Self {
+        let samples = time.div_ceil(sample_period.max(1));
+        Self::new(samples.min(u8::MAX as u32) as u8)
+    }
+
+    /// Sets the number of samples for one input, e.g. a longer time for a
+    /// block detector than for a push button
+    pub fn set_samples(&mut self, input: usize, samples: u8) -> Result<()> {
+        let slot = self
+            .samples
+            .get_mut(input / 8)
+            .and_then(|byte| byte.get_mut(input % 8))
+            .ok_or(Error::OutOfBounds)?;
+        *slot = samples;
+        Ok(())
+    }
+
+    /// Sets the stable state directly, e.g. from a first reading at
+    /// power-up
+    pub fn preset(&mut self, inputs: &[u8; BYTES]) {
+        self.stable = *inputs;
+        self.pending = [0; BYTES];
+        self.counts = [[0; 8]; BYTES];
+    }
+
+    /// Feeds in one sample of the raw inputs, returning the debounced
+    /// inputs
+    pub fn update(&mut self, raw: &[u8; BYTES]) -> &[u8; BYTES] {
+        let bytes = raw
+            .iter()
+            .zip(self.stable.iter_mut())
+            .zip(self.pending.iter_mut())
+            .zip(self.counts.iter_mut().zip(self.samples.iter()));
+        for (((raw, stable), pending), (counts, samples)) in bytes {
+            let changed = raw ^ *stable;
+            if changed == 0 && *pending == 0 {
+                continue;
+            }
+            // Inputs which have bounced back lose their count
+            let settled = *pending & !changed;
+            let bits = counts.iter_mut().zip(samples.iter());
+            for (bit, (count, samples)) in bits.enumerate() {
+                let mask = 1 << bit;
+                if settled & mask != 0 {
+                    *count = 0;
+                } else if changed & mask != 0 {
+                    *count = count.saturating_add(1);
+                    if *count >= *samples {
+                        *stable ^= mask;
+                        *count = 0;
+                    }
+                }
+            }
+            *pending = raw ^ *stable;
+        }
+        &self.stable
+    }
+
+    /// The debounced inputs
+    pub fn stable(&self) -> &[u8; BYTES] {
+        &self.stable
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn needs_consecutive_samples() {
+        let mut d = Debouncer::<2>::new(3);
+        assert_eq!(d.update(&[0x01, 0x80]), &[0, 0]);
+        assert_eq!(d.update(&[0x01, 0x80]), &[0, 0]);
+        assert_eq!(d.update(&[0x01, 0x80]), &[0x01, 0x80]);
+
+        // A bounce restarts the count
+        d.update(&[0x00, 0x80]);
+        d.update(&[0x01, 0x80]);
+        d.update(&[0x00, 0x80]);
+        d.update(&[0x00, 0x80]);
+        assert_eq!(d.stable(), &[0x01, 0x80]);
+        d.update(&[0x00, 0x80]);
+        assert_eq!(d.stable(), &[0x00, 0x80]);
+    }
+
+    #[test]
+    fn per_input_samples() {
+        let mut d = Debouncer::<1>::for_time(10, 5);
+        d.set_samples(1, 1).unwrap();
+        assert!(d.set_samples(8, 1).is_err());
+        assert_eq!(d.update(&[0x03]), &[0x02]);
+        assert_eq!(d.update(&[0x03]), &[0x03]);
+
+        d.preset(&[0x00]);
+        assert_eq!(d.stable(), &[0x00]);
+    }
+}
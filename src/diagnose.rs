@@ -0,0 +1,223 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Saying where and why a frame failed to decode. The state machine is
+// built to shrug off bad bytes and carry on, which is right for a live
+// bus but leaves little to go on when looking at a capture by hand.
+// `parse_frame()` decodes a single frame and on failure gives the offset
+// of the byte which broke it along with the reason. With `std`,
+// `explain()` goes through a whole capture and prints it as hex, one
+// frame per line, with each bad byte marked:
+//
+//     0000  ff ff 02 41 50 03
+//           UA 0 (0x41) Poll len 0
+//     0006  ff ff 02 41 51
+//                       ^^ bad message type
+
+use crate::events::{DiscardReason, ProtocolEvents};
+use crate::{CmriMessage, CmriStateMachine, Error, ResetReason, RxState};
+#[cfg(feature = "fmt")]
+use core::fmt;
+
+/// What was wrong with a frame
+#[derive(Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub enum FrameErrorKind {
+    /// Frame didn't start with two preamble bytes
+    BadPreamble,
+    /// Start byte was missing after the preamble
+    BadStart,
+    /// Message type byte was not recognised
+    BadType,
+    /// Payload was longer than `MAX_PAYLOAD_LEN`
+    Overflow,
+    /// Bytes ran out before the stop byte
+    Truncated,
+    /// Frame was well formed but made no sense, e.g. a Get with no inputs
+    Invalid(Error),
+}
+
+#[cfg(feature = "fmt")]
+impl fmt::Display for FrameErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameErrorKind::BadPreamble => write!(f, "missing preamble"),
+            FrameErrorKind::BadStart => write!(f, "missing start byte"),
+            FrameErrorKind::BadType => write!(f, "bad message type"),
+            FrameErrorKind::Overflow => write!(f, "payload too long"),
+            FrameErrorKind::Truncated => write!(f, "no stop byte"),
+            FrameErrorKind::Invalid(e) => write!(f, "invalid frame: {}", e),
+        }
+    }
+}
+
+/// Why a frame failed to decode, and where
+#[derive(Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct FrameError {
+    /// Offset of the byte which broke the frame, or its length if it was
+    /// truncated
+    pub offset: usize,
+    pub kind: FrameErrorKind,
+}
+
+#[cfg(feature = "fmt")]
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at byte {}", self.kind, self.offset)
+    }
+}
+
+/// Notes the first discard
+#[derive(Default)]
+struct FirstDiscard(Option<DiscardReason>);
+
+impl ProtocolEvents for FirstDiscard {
+    fn on_discard(&mut self, reason: DiscardReason) {
+        self.0.get_or_insert(reason);
+    }
+}
+
+/// Decodes the frame at the very start of `bytes`, returning it along
+/// with the number of bytes it took up
+pub fn parse_frame(
+    bytes: &[u8],
+) -> core::result::Result<(CmriMessage, usize), FrameError> {
+    let mut state = CmriStateMachine::new();
+    let mut discard = FirstDiscard::default();
+    for (offset, byte) in bytes.iter().enumerate() {
+        let kind = match state.process_with_events(*byte, &mut discard) {
+            Ok(RxState::Complete) => {
+                return Ok((*state.message(), offset + 1));
+            }
+            Err(_) if state.last_reset() == Some(ResetReason::Overflow) => {
+                FrameErrorKind::Overflow
+            }
+            Err(e) => FrameErrorKind::Invalid(e),
+            Ok(RxState::Listening) => match discard.0 {
+                None => continue,
+                Some(DiscardReason::BadStart) => FrameErrorKind::BadStart,
+                Some(DiscardReason::BadType) => FrameErrorKind::BadType,
+                // No address filter is set, so anything else is a byte
+                // where the preamble should be
+                Some(_) => FrameErrorKind::BadPreamble,
+            },
+        };
+        return Err(FrameError { offset, kind });
+    }
+    Err(FrameError {
+        offset: bytes.len(),
+        kind: FrameErrorKind::Truncated,
+    })
+}
+
+/// Prints a capture as hex, a frame to a line, with each frame decoded
+/// or its bad byte marked. Bytes outside any frame are shown as noise
+#[cfg(feature = "std")]
+pub fn explain(bytes: &[u8]) -> std::string::String {
+    use crate::CMRI_PREAMBLE_BYTE;
+    use core::fmt::Write;
+    use std::string::{String, ToString};
+
+    /// Offset and spacing before the first byte of a line
+    const INDENT: &str = "      ";
+
+    let mut out = String::new();
+    let mut pos = 0;
+    while let Some(rest) = bytes.get(pos..).filter(|rest| !rest.is_empty()) {
+        let noise = rest
+            .iter()
+            .position(|b| *b == CMRI_PREAMBLE_BYTE)
+            .unwrap_or(rest.len());
+        let (len, note) = if noise > 0 {
+            (noise, String::from("noise"))
+        } else {
+            match parse_frame(rest) {
+                Ok((msg, used)) => (used, msg.to_string()),
+                Err(e) => {
+                    // Up to and including the bad byte, which is marked
+                    let len = (e.offset + 1).min(rest.len());
+                    let mark = " ".repeat(3 * e.offset);
+                    (len, std::format!("{}^^ {}", mark, e.kind))
+                }
+            }
+        };
+        let _ = write!(out, "{:04x} ", pos);
+        for byte in rest.iter().take(len) {
+            let _ = write!(out, " {:02x}", byte);
+        }
+        let _ = writeln!(out, "\n{}{}", INDENT, note);
+        pos += len;
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{MessageType, MAX_PAYLOAD_LEN};
+
+    const POLL: [u8; 6] = [0xff, 0xff, 0x02, 0x41, b'P', 0x03];
+
+    fn kind(bytes: &[u8]) -> (usize, FrameErrorKind) {
+        let e = parse_frame(bytes).unwrap_err();
+        (e.offset, e.kind)
+    }
+
+    #[test]
+    fn error_positions() {
+        let (msg, used) = parse_frame(&[&POLL[..], &[0xff]].concat()).unwrap();
+        assert_eq!(msg.message_type, Some(MessageType::Poll));
+        assert_eq!(used, POLL.len());
+
+        assert_eq!(kind(&[0x00]), (0, FrameErrorKind::BadPreamble));
+        assert_eq!(kind(&[0xff, 0x02]), (1, FrameErrorKind::BadPreamble));
+        assert_eq!(kind(&[0xff, 0xff, 0x41]), (2, FrameErrorKind::BadStart));
+        let bad_type = [0xff, 0xff, 0x02, 0x41, b'Q', 0x03];
+        assert_eq!(kind(&bad_type), (4, FrameErrorKind::BadType));
+        assert_eq!(kind(&POLL[..5]), (5, FrameErrorKind::Truncated));
+
+        let mut long = POLL[..4].to_vec();
+        long.push(b'T');
+        long.extend(core::iter::repeat_n(0x30, MAX_PAYLOAD_LEN + 1));
+        let e = parse_frame(&long).unwrap_err();
+        assert_eq!(e.offset, 5 + MAX_PAYLOAD_LEN);
+        assert_eq!(e.kind, FrameErrorKind::Overflow);
+
+        let empty_get = [0xff, 0xff, 0x02, 0x41, b'R', 0x03];
+        let (offset, kind) = kind(&empty_get);
+        assert_eq!(offset, 5);
+        assert!(matches!(kind, FrameErrorKind::Invalid(_)));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn explain_capture() {
+        let capture = [
+            &[0x12, 0x34][..],
+            &POLL,
+            &[0xff, 0xff, 0x02, 0x41, b'Q'],
+            &POLL[..3],
+        ]
+        .concat();
+        let text = explain(&capture);
+        let lines: std::vec::Vec<_> = text.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "0000  12 34",
+                "      noise",
+                "0002  ff ff 02 41 50 03",
+                "      UA 0 (0x41) Poll len 0",
+                "0008  ff ff 02 41 51",
+                "                  ^^ bad message type",
+                "000d  ff ff 02",
+                "               ^^ no stop byte",
+            ]
+        );
+    }
+}
@@ -5,6 +5,7 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use crate::MessageType;
 #[cfg(feature = "std")]
 use std::format;
 #[cfg(feature = "std")]
@@ -12,18 +13,70 @@ use std::string::String;
 
 pub type Result<T> = core::result::Result<T, Error>;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
 pub enum Error {
     OutOfBounds,
     DataTooLong,
+    /// Encoded frame doesn't fit in the buffer given
+    BufferTooSmall,
     MissingAddress,
     MissingType,
     InvalidMessageType,
     InvalidNodeType,
+    /// Message type does not take a payload but one was given
+    UnexpectedPayload,
+    /// Message type requires a payload but none was given
+    EmptyPayload,
+    /// Init payload is missing the node definition parameters
+    InitTooShort,
+    /// Payload length doesn't fit the node's card size
+    CardSizeMismatch,
+    /// Received frame's payload is too short for its type
+    PayloadLengthInvalid {
+        mtype: MessageType,
+        len: usize,
+    },
+    /// I/O port is repeated, out of order or has no direction
+    InvalidPort,
+    /// No room left in a fixed-size queue
+    QueueFull,
+    /// Gave up waiting for a message
+    Timeout,
+    /// Node did not answer a Poll, even after retries
+    NoResponse,
+    /// Transport is not connected
+    Disconnected,
+    /// Datagram didn't hold exactly one complete frame
+    IncompleteFrame,
+    /// No network address is known for the node
+    UnknownPeer,
+    /// Another talker was on the bus while a frame was being sent
+    BusCollision,
+    /// Poll schedule needs more of the bus than is available
+    BusOverloaded,
+    /// Node's first Get after its Init wasn't the size the Init set up
+    InitMismatch {
+        node: u8,
+        expected: usize,
+        got: usize,
+    },
+    /// Node was added without an Init payload, so can't be initialised
+    NoInitPayload {
+        node: u8,
+    },
     #[cfg(feature = "std")]
     IoError(String),
+    #[cfg(feature = "cortex_m")]
+    SerialError,
+    /// Layout description could not be loaded or saved
+    #[cfg(feature = "config")]
+    ConfigError(String),
+    /// No I/O point, node or bus with that name
+    UnknownPoint,
 }
 
+#[cfg(feature = "fmt")]
 impl core::fmt::Display for Error {
     fn fmt(
         &self,
@@ -33,6 +86,9 @@ impl core::fmt::Display for Error {
     }
 }
 
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
 #[cfg(feature = "std")]
 impl From<std::io::Error> for Error {
     fn from(e: std::io::Error) -> Self {
@@ -0,0 +1,141 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::{CmriMessage, Error};
+
+/// Why the state machine threw away a byte or a partial frame
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub enum DiscardReason {
+    /// Byte received while waiting for a preamble
+    Idle,
+    /// Second preamble byte was missing
+    BadPreamble,
+    /// Start byte was missing
+    BadStart,
+    /// Message type byte was not recognised
+    BadType,
+    /// Frame was for another address
+    Filtered,
+}
+
+/// Why the state machine abandoned a partially received frame, for
+/// diagnostics via `CmriStateMachine::last_reset()`
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub enum ResetReason {
+    /// Second preamble byte was missing
+    BadPreamble,
+    /// Start byte was missing
+    BadStart,
+    /// Message type byte was not recognised
+    BadType,
+    /// Frame was for another address
+    Filtered,
+    /// Payload was longer than `MAX_PAYLOAD_LEN`
+    Overflow,
+    /// Some other decoding error; see `CmriStateMachine::last_error()`
+    Error,
+}
+
+impl ResetReason {
+    /// Discards which abandon a frame, as opposed to ignoring noise
+    pub(crate) fn from_discard(reason: DiscardReason) -> Option<Self> {
+        match reason {
+            DiscardReason::Idle => None,
+            DiscardReason::BadPreamble => Some(Self::BadPreamble),
+            DiscardReason::BadStart => Some(Self::BadStart),
+            DiscardReason::BadType => Some(Self::BadType),
+            DiscardReason::Filtered => Some(Self::Filtered),
+        }
+    }
+}
+
+/// Hooks called by `CmriStateMachine::process_with_events()` as frames
+/// are decoded. All methods do nothing by default, so implementors only
+/// need to provide the ones they care about, e.g. flashing an LED on
+/// `on_error()`.
+pub trait ProtocolEvents {
+    /// A preamble and start byte have been received
+    fn on_frame_start(&mut self) {}
+
+    /// A full frame has been decoded
+    fn on_frame_complete(&mut self, _message: &CmriMessage) {}
+
+    /// Decoding failed and the partial frame has been dropped
+    fn on_error(&mut self, _error: &Error) {}
+
+    /// A byte or partial frame has been discarded
+    fn on_discard(&mut self, _reason: DiscardReason) {}
+}
+
+/// Event handler which ignores everything
+#[derive(Copy, Clone, Default)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct NoEvents;
+
+impl ProtocolEvents for NoEvents {}
+
+/// Event handler which forwards everything to the `log` crate
+#[cfg(feature = "log")]
+#[derive(Copy, Clone, Default)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct LogEvents;
+
+#[cfg(feature = "log")]
+impl ProtocolEvents for LogEvents {
+    fn on_frame_start(&mut self) {
+        log::trace!("C/MRI frame start");
+    }
+
+    fn on_frame_complete(&mut self, message: &CmriMessage) {
+        log::debug!("C/MRI frame: {}", message);
+    }
+
+    fn on_error(&mut self, error: &Error) {
+        log::warn!("C/MRI decode error: {}", error);
+    }
+
+    fn on_discard(&mut self, reason: DiscardReason) {
+        match reason {
+            DiscardReason::Idle | DiscardReason::Filtered => {
+                log::trace!("C/MRI discard: {:?}", reason)
+            }
+            _ => log::debug!("C/MRI discard: {:?}", reason),
+        }
+    }
+}
+
+/// Event handler which emits `tracing` events
+#[cfg(feature = "tracing")]
+#[derive(Copy, Clone, Default)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct TracingEvents;
+
+#[cfg(feature = "tracing")]
+impl ProtocolEvents for TracingEvents {
+    fn on_frame_start(&mut self) {
+        tracing::trace!("frame start");
+    }
+
+    fn on_frame_complete(&mut self, message: &CmriMessage) {
+        tracing::debug!(
+            address = ?message.address,
+            message_type = ?message.message_type,
+            len = message.len,
+            "frame decoded"
+        );
+    }
+
+    fn on_error(&mut self, error: &Error) {
+        tracing::warn!(error = ?error, "decode error");
+    }
+
+    fn on_discard(&mut self, reason: DiscardReason) {
+        tracing::trace!(reason = ?reason, "discard");
+    }
+}
@@ -0,0 +1,282 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// A C API over the frame encoder and the decoding state machine, so that
+// layout software written in C, C++ or Java (through JNI or JNA) can use
+// the protocol core without reimplementing it. The build script writes a
+// matching header, cmri.h, into the build's OUT_DIR, and also to the path
+// in the CMRI_FFI_HEADER environment variable if it is set. Build a
+// library to link against with e.g.
+//
+//   cargo rustc --release --features ffi --crate-type cdylib
+//
+// Functions return a negative CMRI_ERR_* code on failure. Addresses and
+// message types are the bytes sent on the wire.
+
+use crate::{
+    CmriMessage, CmriStateMachine, Error, MessageType, RxState, MAX_PAYLOAD_LEN,
+};
+use alloc::boxed::Box;
+use core::convert::TryFrom;
+use core::slice;
+
+/// The state machine is waiting for more bytes
+pub const CMRI_LISTENING: i32 = 0;
+/// A whole frame has been received
+pub const CMRI_COMPLETE: i32 = 1;
+
+/// A pointer argument was null
+pub const CMRI_ERR_NULL: i32 = -1;
+/// The payload is too long, or a received frame overflowed
+pub const CMRI_ERR_DATA_TOO_LONG: i32 = -2;
+/// The output buffer is too small for the frame or payload
+pub const CMRI_ERR_BUFFER_TOO_SMALL: i32 = -3;
+/// The message type byte isn't one of I, T, R or P
+pub const CMRI_ERR_INVALID_TYPE: i32 = -4;
+/// No complete message has been received yet
+pub const CMRI_ERR_NO_MESSAGE: i32 = -5;
+/// Any other error
+pub const CMRI_ERR_OTHER: i32 = -99;
+
+/// Decoding state, created with `cmri_parser_new()` and freed with
+/// `cmri_parser_free()`
+pub struct CmriParser {
+    state: CmriStateMachine,
+    /// The last complete message
+    message: Option<CmriMessage>,
+}
+
+fn error_code(e: &Error) -> i32 {
+    match e {
+        Error::DataTooLong => CMRI_ERR_DATA_TOO_LONG,
+        Error::BufferTooSmall => CMRI_ERR_BUFFER_TOO_SMALL,
+        Error::InvalidMessageType => CMRI_ERR_INVALID_TYPE,
+        _ => CMRI_ERR_OTHER,
+    }
+}
+
+/// Largest payload a message can carry
+#[no_mangle]
+pub extern "C" fn cmri_max_payload_len() -> usize {
+    MAX_PAYLOAD_LEN
+}
+
+/// Encodes a frame into `out`, returning the number of bytes written
+///
+/// # Safety
+///
+/// `payload` must point to `len` readable bytes, or may be null if `len`
+/// is zero, and `out` must point to `out_len` writable bytes
+#[no_mangle]
+pub unsafe extern "C" fn cmri_encode(
+    address: u8,
+    message_type: u8,
+    payload: *const u8,
+    len: usize,
+    out: *mut u8,
+    out_len: usize,
+) -> isize {
+    if out.is_null() || (payload.is_null() && len > 0) {
+        return CMRI_ERR_NULL as isize;
+    }
+    let payload = match len {
+        0 => &[][..],
+        _ => slice::from_raw_parts(payload, len),
+    };
+    let out = slice::from_raw_parts_mut(out, out_len);
+    let encoded = MessageType::try_from(message_type).and_then(|t| {
+        let mut msg = CmriMessage::new();
+        msg.address(address).message_type(t).payload(payload)?;
+        msg.encode(out)
+    });
+    match encoded {
+        Ok(written) => written as isize,
+        Err(e) => error_code(&e) as isize,
+    }
+}
+
+/// A new state machine, to be freed with `cmri_parser_free()`
+#[no_mangle]
+pub extern "C" fn cmri_parser_new() -> *mut CmriParser {
+    Box::into_raw(Box::new(CmriParser {
+        state: CmriStateMachine::new(),
+        message: None,
+    }))
+}
+
+/// Frees a state machine from `cmri_parser_new()`. Null is ignored
+///
+/// # Safety
+///
+/// `parser` must have come from `cmri_parser_new()` and not already have
+/// been freed
+#[no_mangle]
+pub unsafe extern "C" fn cmri_parser_free(parser: *mut CmriParser) {
+    if !parser.is_null() {
+        drop(Box::from_raw(parser));
+    }
+}
+
+/// Feeds one received byte in, returning `CMRI_COMPLETE` once a whole
+/// frame has arrived. A bad byte abandons the frame being received and
+/// returns an error code; carry on feeding bytes to pick up the next one
+///
+/// # Safety
+///
+/// `parser` must be a live pointer from `cmri_parser_new()`
+#[no_mangle]
+pub unsafe extern "C" fn cmri_parser_process(
+    parser: *mut CmriParser,
+    byte: u8,
+) -> i32 {
+    let parser = match parser.as_mut() {
+        Some(parser) => parser,
+        None => return CMRI_ERR_NULL,
+    };
+    match parser.state.process(byte) {
+        Ok(RxState::Complete) => {
+            parser.message = Some(*parser.state.message());
+            CMRI_COMPLETE
+        }
+        Ok(RxState::Listening) => CMRI_LISTENING,
+        Err(e) => error_code(&e),
+    }
+}
+
+/// Address byte of the last complete message
+///
+/// # Safety
+///
+/// `parser` must be a live pointer from `cmri_parser_new()`
+#[no_mangle]
+pub unsafe extern "C" fn cmri_parser_address(parser: *const CmriParser) -> i32 {
+    match parser.as_ref() {
+        Some(CmriParser {
+            message: Some(msg), ..
+        }) => msg.address.map_or(CMRI_ERR_OTHER, i32::from),
+        Some(_) => CMRI_ERR_NO_MESSAGE,
+        None => CMRI_ERR_NULL,
+    }
+}
+
+/// Message type byte of the last complete message
+///
+/// # Safety
+///
+/// `parser` must be a live pointer from `cmri_parser_new()`
+#[no_mangle]
+pub unsafe extern "C" fn cmri_parser_type(parser: *const CmriParser) -> i32 {
+    match parser.as_ref() {
+        Some(CmriParser {
+            message: Some(msg), ..
+        }) => msg
+            .message_type
+            .map_or(CMRI_ERR_OTHER, |t| i32::from(u8::from(t))),
+        Some(_) => CMRI_ERR_NO_MESSAGE,
+        None => CMRI_ERR_NULL,
+    }
+}
+
+/// Copies the payload of the last complete message into `out`, returning
+/// its length
+///
+/// # Safety
+///
+/// `parser` must be a live pointer from `cmri_parser_new()`, and `out`
+/// must point to `out_len` writable bytes
+#[no_mangle]
+pub unsafe extern "C" fn cmri_parser_payload(
+    parser: *const CmriParser,
+    out: *mut u8,
+    out_len: usize,
+) -> isize {
+    let msg = match parser.as_ref() {
+        Some(CmriParser {
+            message: Some(msg), ..
+        }) => msg,
+        Some(_) => return CMRI_ERR_NO_MESSAGE as isize,
+        None => return CMRI_ERR_NULL as isize,
+    };
+    if out.is_null() {
+        return CMRI_ERR_NULL as isize;
+    }
+    let out = slice::from_raw_parts_mut(out, out_len);
+    let data = msg.data();
+    match out.get_mut(..data.len()) {
+        Some(dst) => {
+            dst.copy_from_slice(data);
+            data.len() as isize
+        }
+        None => CMRI_ERR_BUFFER_TOO_SMALL as isize,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::TX_BUFFER_LEN;
+    use core::ptr;
+
+    #[test]
+    fn encode_and_decode() {
+        let payload = [0x01, 0x03, 0x02];
+        let mut frame = [0_u8; TX_BUFFER_LEN];
+        let len = unsafe {
+            cmri_encode(
+                0x41,
+                b'T',
+                payload.as_ptr(),
+                payload.len(),
+                frame.as_mut_ptr(),
+                frame.len(),
+            )
+        };
+        assert_eq!(len, 10);
+
+        let parser = cmri_parser_new();
+        unsafe {
+            assert_eq!(cmri_parser_address(parser), CMRI_ERR_NO_MESSAGE);
+            let results: std::vec::Vec<i32> = frame[..len as usize]
+                .iter()
+                .map(|b| cmri_parser_process(parser, *b))
+                .collect();
+            assert_eq!(results.last(), Some(&CMRI_COMPLETE));
+            assert_eq!(cmri_parser_address(parser), 0x41);
+            assert_eq!(cmri_parser_type(parser), i32::from(b'T'));
+
+            let mut out = [0_u8; 3];
+            let n = cmri_parser_payload(parser, out.as_mut_ptr(), out.len());
+            assert_eq!(n, 3);
+            assert_eq!(out, payload);
+            let n = cmri_parser_payload(parser, out.as_mut_ptr(), 2);
+            assert_eq!(n, CMRI_ERR_BUFFER_TOO_SMALL as isize);
+            cmri_parser_free(parser);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let mut frame = [0_u8; 8];
+        unsafe {
+            let res =
+                cmri_encode(0x41, b'P', ptr::null(), 0, frame.as_mut_ptr(), 8);
+            assert_eq!(res, 6);
+            let res =
+                cmri_encode(0x41, b'Z', ptr::null(), 0, frame.as_mut_ptr(), 8);
+            assert_eq!(res, CMRI_ERR_INVALID_TYPE as isize);
+            let res =
+                cmri_encode(0x41, b'T', ptr::null(), 3, frame.as_mut_ptr(), 8);
+            assert_eq!(res, CMRI_ERR_NULL as isize);
+            let res =
+                cmri_encode(0x41, b'P', ptr::null(), 0, frame.as_mut_ptr(), 4);
+            assert_eq!(res, CMRI_ERR_BUFFER_TOO_SMALL as isize);
+            assert_eq!(cmri_parser_process(ptr::null_mut(), 0), CMRI_ERR_NULL);
+            cmri_parser_free(ptr::null_mut());
+        }
+        assert_eq!(cmri_max_payload_len(), MAX_PAYLOAD_LEN);
+    }
+}
@@ -0,0 +1,369 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Picking frames out of the traffic on a bus, so that a monitor only
+// shows the nodes being worked on and a gateway only passes on what its
+// clients should see. A `Rule` matches on unit address, message type and
+// payload bytes, and is built up a condition at a time:
+//
+//     Rule::addr(3..=10).mtype(MessageType::Set).byte(0, 0x1f)
+//
+// With `std`, a `Filter` is a list of rules each saying whether matching
+// frames pass or are dropped, the first matching rule deciding. Frames
+// which match no rule pass. Filters can also be written as text, a rule
+// to a line or separated by `;`, for command line tools:
+//
+//     drop type poll; pass addr 3-10 type set byte 0&0xf0=0x10; drop
+
+use crate::{Address, CmriMessage, MessageType};
+use core::ops::RangeInclusive;
+
+/// Most payload byte conditions one rule can hold
+pub const MAX_BYTE_MATCHES: usize = 4;
+
+/// Payload byte at `index`, masked, must equal `value`
+#[derive(Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+struct ByteMatch {
+    index: usize,
+    mask: u8,
+    value: u8,
+}
+
+/// Conditions a frame must meet, all of them, to match
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct Rule {
+    /// Lowest and highest unit address
+    addr: Option<(u8, u8)>,
+    mtype: Option<MessageType>,
+    bytes: [ByteMatch; MAX_BYTE_MATCHES],
+    byte_count: usize,
+}
+
+impl Rule {
+    /// Matches every frame
+    pub const fn any() -> Self {
+        Self {
+            addr: None,
+            mtype: None,
+            bytes: [ByteMatch {
+                index: 0,
+                mask: 0,
+                value: 0,
+            }; MAX_BYTE_MATCHES],
+            byte_count: 0,
+        }
+    }
+
+    /// Matches frames to or from the unit addresses in `range`. Frames
+    /// with no address or one outside the C/MRI range never match
+    pub fn addr(range: RangeInclusive<u8>) -> Self {
+        Self::any().addr_range(range)
+    }
+
+    /// Limits the rule to the unit addresses in `range`
+    pub fn addr_range(mut self, range: RangeInclusive<u8>) -> Self {
+        self.addr = Some((*range.start(), *range.end()));
+        self
+    }
+
+    /// Limits the rule to one message type
+    pub fn mtype(mut self, mtype: MessageType) -> Self {
+        self.mtype = Some(mtype);
+        self
+    }
+
+    /// Payload byte `index` must be `value`
+    pub fn byte(self, index: usize, value: u8) -> Self {
+        self.masked_byte(index, 0xff, value)
+    }
+
+    /// The bits of payload byte `index` picked out by `mask` must equal
+    /// those of `value`, e.g. to look at one input card bit. At most
+    /// `MAX_BYTE_MATCHES` byte conditions are kept; any more are ignored
+    pub fn masked_byte(mut self, index: usize, mask: u8, value: u8) -> Self {
+        debug_assert!(self.byte_count < MAX_BYTE_MATCHES);
+        if let Some(slot) = self.bytes.get_mut(self.byte_count) {
+            *slot = ByteMatch {
+                index,
+                mask,
+                value: value & mask,
+            };
+            self.byte_count += 1;
+        }
+        self
+    }
+
+    pub fn matches(&self, msg: &CmriMessage) -> bool {
+        if let Some((lo, hi)) = self.addr {
+            let ua = msg.address.and_then(|a| Address::Wire(a).ua().ok());
+            if !ua.is_some_and(|ua| (lo..=hi).contains(&ua)) {
+                return false;
+            }
+        }
+        if self.mtype.is_some() && msg.message_type != self.mtype {
+            return false;
+        }
+        let data = msg.data();
+        self.bytes
+            .get(..self.byte_count)
+            .unwrap_or_default()
+            .iter()
+            .all(|b| {
+                data.get(b.index)
+                    .is_some_and(|byte| byte & b.mask == b.value)
+            })
+    }
+}
+
+impl Default for Rule {
+    fn default() -> Self {
+        Self::any()
+    }
+}
+
+#[cfg(feature = "std")]
+pub use self::text::{Action, Filter};
+
+#[cfg(feature = "std")]
+mod text {
+    use super::{Rule, MAX_BYTE_MATCHES};
+    use crate::{CmriMessage, Error, MessageType, Result, MAX_UA};
+    use core::str::FromStr;
+    use std::io::{self, ErrorKind};
+    use std::string::String;
+    use std::vec::Vec;
+
+    /// What happens to a frame which matches a rule
+    #[derive(Copy, Clone, PartialEq, Eq)]
+    #[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+    pub enum Action {
+        Pass,
+        Drop,
+    }
+
+    /// Rules tried in order, the first to match deciding a frame's fate.
+    /// A filter with no rules passes everything
+    #[derive(Clone, Default, PartialEq)]
+    #[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+    pub struct Filter {
+        rules: Vec<(Action, Rule)>,
+    }
+
+    impl Filter {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Adds a rule passing frames it matches
+        pub fn pass(mut self, rule: Rule) -> Self {
+            self.rules.push((Action::Pass, rule));
+            self
+        }
+
+        /// Adds a rule dropping frames it matches
+        pub fn drop(mut self, rule: Rule) -> Self {
+            self.rules.push((Action::Drop, rule));
+            self
+        }
+
+        pub fn rules(&self) -> &[(Action, Rule)] {
+            &self.rules
+        }
+
+        pub fn allows(&self, msg: &CmriMessage) -> bool {
+            self.rules
+                .iter()
+                .find(|(_, rule)| rule.matches(msg))
+                .is_none_or(|(action, _)| *action == Action::Pass)
+        }
+    }
+
+    /// Reads rules separated by newlines or `;`, each an action followed
+    /// by any of:
+    ///
+    /// * `addr 3` or `addr 3-10`: unit addresses
+    /// * `type set`: a message type, by name or wire character
+    /// * `byte 0=0x1f` or `byte 0&0x0f=0x01`: a payload byte, masked
+    ///
+    /// Numbers are decimal, or hex or binary with `0x` or `0b` on the
+    /// front. Anything after a `#` on a line is ignored
+    impl FromStr for Filter {
+        type Err = Error;
+        fn from_str(s: &str) -> Result<Self> {
+            let mut filter = Self::new();
+            for line in s.lines() {
+                let line = line.split('#').next().unwrap_or("");
+                for rule in line.split(';').filter(|r| !r.trim().is_empty()) {
+                    filter.rules.push(parse_rule(rule)?);
+                }
+            }
+            Ok(filter)
+        }
+    }
+
+    /// Reads the conditions of a single rule, in the same form as a
+    /// `Filter` but with no action in front
+    impl FromStr for Rule {
+        type Err = Error;
+        fn from_str(s: &str) -> Result<Self> {
+            parse_conditions(s, s.split_whitespace())
+        }
+    }
+
+    fn parse_rule(text: &str) -> Result<(Action, Rule)> {
+        let mut words = text.split_whitespace();
+        let action = match words.next() {
+            Some("pass") => Action::Pass,
+            Some("drop") => Action::Drop,
+            _ => return Err(bad_rule(text, "should start with pass or drop")),
+        };
+        Ok((action, parse_conditions(text, words)?))
+    }
+
+    fn parse_conditions<'a>(
+        text: &str,
+        mut words: impl Iterator<Item = &'a str>,
+    ) -> Result<Rule> {
+        let mut rule = Rule::any();
+        while let Some(word) = words.next() {
+            let arg = words
+                .next()
+                .ok_or_else(|| bad_rule(text, "condition with no value"))?;
+            rule = match word {
+                "addr" => {
+                    let (lo, hi) = match arg.split_once('-') {
+                        Some((lo, hi)) => {
+                            (number(text, lo)?, number(text, hi)?)
+                        }
+                        None => (number(text, arg)?, number(text, arg)?),
+                    };
+                    if lo > hi || hi > MAX_UA {
+                        return Err(bad_rule(text, "bad address range"));
+                    }
+                    rule.addr_range(lo..=hi)
+                }
+                "type" => rule.mtype(
+                    MessageType::from_str(arg)
+                        .map_err(|_| bad_rule(text, "unknown message type"))?,
+                ),
+                "byte" => {
+                    if rule.byte_count == MAX_BYTE_MATCHES {
+                        return Err(bad_rule(text, "too many byte conditions"));
+                    }
+                    let (lhs, value) = arg
+                        .split_once('=')
+                        .ok_or_else(|| bad_rule(text, "byte needs a value"))?;
+                    let (index, mask) = match lhs.split_once('&') {
+                        Some((index, mask)) => (index, number(text, mask)?),
+                        None => (lhs, 0xff),
+                    };
+                    let index = index
+                        .parse()
+                        .map_err(|_| bad_rule(text, "bad byte index"))?;
+                    rule.masked_byte(index, mask, number(text, value)?)
+                }
+                _ => return Err(bad_rule(text, "unknown condition")),
+            };
+        }
+        Ok(rule)
+    }
+
+    fn number(text: &str, word: &str) -> Result<u8> {
+        let res = if let Some(hex) = word.strip_prefix("0x") {
+            u8::from_str_radix(hex, 16)
+        } else if let Some(bin) = word.strip_prefix("0b") {
+            u8::from_str_radix(bin, 2)
+        } else {
+            word.parse()
+        };
+        res.map_err(|_| bad_rule(text, "bad number"))
+    }
+
+    fn bad_rule(text: &str, why: &str) -> Error {
+        let mut msg = String::from("bad filter rule \"");
+        msg.push_str(text.trim());
+        msg.push_str("\": ");
+        msg.push_str(why);
+        io::Error::new(ErrorKind::InvalidData, msg).into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MessageBuilder;
+
+    fn set(addr: u8, data: &[u8]) -> CmriMessage {
+        MessageBuilder::set(addr, data).build().unwrap()
+    }
+
+    #[test]
+    fn rule_conditions() {
+        let rule = Rule::addr(3..=10).mtype(MessageType::Set).byte(0, 0x1f);
+        assert!(rule.matches(&set(0x41 + 3, &[0x1f, 0x00])));
+        assert!(rule.matches(&set(0x41 + 10, &[0x1f])));
+        assert!(!rule.matches(&set(0x41 + 11, &[0x1f])));
+        assert!(!rule.matches(&set(0x41 + 2, &[0x1f])));
+        assert!(!rule.matches(&set(0x41 + 3, &[0x1e])));
+        let poll = MessageBuilder::poll(0x41 + 3).build().unwrap();
+        assert!(!rule.matches(&poll));
+
+        // Bytes past the end of the payload never match
+        let rule = Rule::any().masked_byte(1, 0x04, 0xff);
+        assert!(rule.matches(&set(0x41, &[0x00, 0x04])));
+        assert!(!rule.matches(&set(0x41, &[0xff, 0xfb])));
+        assert!(!rule.matches(&set(0x41, &[0xff])));
+
+        assert!(Rule::default().matches(&poll));
+        assert!(!Rule::addr(0..=127).matches(&CmriMessage::new()));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn filter_text() {
+        let filter: Filter = "drop type poll # not interesting\n\
+             pass addr 3-10 type T byte 0&0xf0=0x10; drop"
+            .parse()
+            .unwrap();
+        let built = Filter::new()
+            .drop(Rule::any().mtype(MessageType::Poll))
+            .pass(
+                Rule::addr(3..=10)
+                    .mtype(MessageType::Set)
+                    .masked_byte(0, 0xf0, 0x10),
+            )
+            .drop(Rule::any());
+        assert_eq!(filter, built);
+
+        assert!(filter.allows(&set(0x41 + 4, &[0x1a])));
+        assert!(!filter.allows(&set(0x41 + 4, &[0x2a])));
+        assert!(!filter.allows(&MessageBuilder::poll(0x44).build().unwrap()));
+        assert!(Filter::new().allows(&CmriMessage::new()));
+
+        assert_eq!(
+            "addr 5 byte 2=0b101".parse::<Rule>().unwrap(),
+            Rule::addr(5..=5).byte(2, 5)
+        );
+        for bad in [
+            "addr 3",
+            "pass addr",
+            "pass addr 10-3",
+            "pass addr 200",
+            "pass type x",
+            "pass byte 0",
+            "pass byte x=1",
+            "pass colour red",
+            "pass byte 0=1 byte 1=1 byte 2=1 byte 3=1 byte 4=1",
+        ]
+        .iter()
+        {
+            assert!(bad.parse::<Filter>().is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,178 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Message-level adapters over std byte streams, so that a TCP stream or
+// serial port can be read with `for msg in FrameReader::new(stream)`.
+
+use crate::TX_BUFFER_LEN;
+use crate::{CmriMessage, CmriStateMachine, Error, Result, RxState};
+use std::io::{ErrorKind, Read, Write};
+
+/// Decodes frames from a byte stream. Iterating yields each complete
+/// message, or the error from a bad frame or a failed read, and ends when
+/// the stream does. Bytes are read one at a time, so wrap unbuffered
+/// streams in a `BufReader`.
+pub struct FrameReader<R> {
+    reader: R,
+    state: CmriStateMachine,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            state: CmriStateMachine::new(),
+        }
+    }
+
+    /// The underlying decoder, e.g. for its stats
+    pub fn state(&self) -> &CmriStateMachine {
+        &self.state
+    }
+
+    /// The underlying decoder, e.g. to set an address filter
+    pub fn state_mut(&mut self) -> &mut CmriStateMachine {
+        &mut self.state
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: Read> Iterator for FrameReader<R> {
+    type Item = Result<CmriMessage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut buf = [0_u8];
+        loop {
+            match self.reader.read(&mut buf) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    return Some(Err(match e.kind() {
+                        ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+                            Error::Timeout
+                        }
+                        _ => e.into(),
+                    }))
+                }
+            }
+            // The state machine resets itself on errors, so decoding can
+            // carry on from the next frame
+            match self.state.process(buf[0]) {
+                Ok(RxState::Listening) => {}
+                Ok(RxState::Complete) => {
+                    return Some(Ok(*self.state.message()))
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Encodes messages onto a byte stream, one whole frame per write
+pub struct FrameWriter<W> {
+    writer: W,
+    tx_buffer: [u8; TX_BUFFER_LEN],
+}
+
+impl<W: Write> FrameWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            tx_buffer: [0; TX_BUFFER_LEN],
+        }
+    }
+
+    /// Encodes and writes a message, then flushes the stream
+    pub fn write_msg(&mut self, msg: &CmriMessage) -> Result<()> {
+        let frame = msg.encode_slice(&mut self.tx_buffer)?;
+        self.writer.write_all(frame)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Address, MessageBuilder};
+    use std::io::Cursor;
+    use std::vec::Vec;
+
+    #[test]
+    fn write_then_read() {
+        let mut writer = FrameWriter::new(Vec::new());
+        writer
+            .write_msg(&MessageBuilder::poll(0x41).build().unwrap())
+            .unwrap();
+        writer
+            .write_msg(
+                &MessageBuilder::set(0x42, &[0x02, 0x10]).build().unwrap(),
+            )
+            .unwrap();
+
+        let mut reader = FrameReader::new(Cursor::new(writer.into_inner()));
+        let poll = reader.next().unwrap().unwrap();
+        assert_eq!(poll.address, Some(0x41));
+        let set = reader.next().unwrap().unwrap();
+        assert_eq!(set.payload[..set.len], [0x02, 0x10]);
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn bad_frames_are_skipped() {
+        let mut bytes = Vec::new();
+        // Bad message type
+        bytes.extend_from_slice(&[0xff, 0xff, 0x02, 0x41, b'Z', 0x03]);
+        let mut writer = FrameWriter::new(&mut bytes);
+        writer
+            .write_msg(&MessageBuilder::poll(0x43).build().unwrap())
+            .unwrap();
+
+        let results: Vec<_> = FrameReader::new(&bytes[..]).collect();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].as_ref().unwrap().address, Some(0x43));
+    }
+
+    #[test]
+    fn filtered_reader() {
+        let mut writer = FrameWriter::new(Vec::new());
+        for addr in 0x41..0x44 {
+            writer
+                .write_msg(&MessageBuilder::poll(addr).build().unwrap())
+                .unwrap();
+        }
+        let mut reader = FrameReader::new(Cursor::new(writer.into_inner()));
+        reader.state_mut().filter(Address::Wire(0x42)).unwrap();
+        let msgs: Vec<_> = reader.map(|m| m.unwrap()).collect();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].address, Some(0x42));
+    }
+}
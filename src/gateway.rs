@@ -0,0 +1,584 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Shares one serial bus between several clients, e.g. JMRI and a
+// monitoring tool both connected over TCP. Clients hand over complete
+// frames, which are queued and written to the bus one at a time so that
+// two clients can never interleave bytes. Every frame received from the
+// bus is sent to every client.
+//
+// In half duplex mode the bus is either transmitting or receiving, so
+// queued frames are written out in full before listening again. In full
+// duplex mode they go onto the socket's TX queue instead and are written
+// while the socket is receiving.
+//
+// An `AddressMap` can stand between the clients and the bus, so that
+// clients keep the node addresses they were set up with when nodes have
+// been moved, e.g. after merging two layouts onto one bus. Frames from
+// clients, including Inits from the layout file, are translated on their
+// way to the bus and frames from the bus on their way back.
+//
+// A `Filter` decides which frames are passed on at all, in either
+// direction, e.g. to keep a monitoring client from seeing every Poll or
+// to stop a client writing to nodes it has no business with. Rules see
+// the addresses clients use, not those on the bus.
+
+#[cfg(feature = "config")]
+use crate::config::{ConfigWatcher, LayoutChanges};
+use crate::sync::mpsc::{self, Receiver, Sender};
+use crate::sync::{Arc, Mutex};
+use crate::{AddressMap, CmriMessage, CmriSocket, Duplex, Error, Filter};
+use crate::{FrameReader, FrameWriter, Result};
+use std::io::BufReader;
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::TryRecvError;
+use std::thread;
+use std::vec::Vec;
+
+type Subscribers = Arc<Mutex<Vec<Sender<CmriMessage>>>>;
+
+/// Bus side of the gateway. This is owned by whichever thread drives the
+/// serial port
+pub struct Gateway {
+    to_bus: Receiver<CmriMessage>,
+    handle: GatewayHandle,
+    address_map: AddressMap,
+    filter: Filter,
+}
+
+/// Cloneable handle for connecting clients to a `Gateway`
+#[derive(Clone)]
+pub struct GatewayHandle {
+    to_bus: Sender<CmriMessage>,
+    subscribers: Subscribers,
+}
+
+/// One client's connection to the gateway
+pub struct GatewayClient {
+    to_bus: Sender<CmriMessage>,
+    from_bus: Receiver<CmriMessage>,
+}
+
+impl Gateway {
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::channel();
+        Self {
+            to_bus: rx,
+            handle: GatewayHandle {
+                to_bus: tx,
+                subscribers: Arc::new(Mutex::new(Vec::new())),
+            },
+            address_map: AddressMap::new(),
+            filter: Filter::new(),
+        }
+    }
+
+    /// Translates node addresses between clients and the bus
+    pub fn set_address_map(&mut self, map: AddressMap) {
+        self.address_map = map;
+    }
+
+    /// Limits the frames passed between clients and the bus. Frames the
+    /// filter doesn't allow are dropped silently
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.filter = filter;
+    }
+
+    pub fn handle(&self) -> GatewayHandle {
+        self.handle.clone()
+    }
+
+    /// Writes every frame queued by clients to the bus, in the order they
+    /// were queued. Returns the number of frames written, which leaves out
+    /// any the filter dropped
+    pub fn flush_to_bus(&self, socket: &mut CmriSocket) -> Result<usize> {
+        let mut count = 0;
+        loop {
+            match self.to_bus.try_recv() {
+                Ok(msg) if !self.filter.allows(&msg) => {}
+                Ok(msg) => {
+                    socket.send(&self.translate_to_bus(msg))?;
+                    count += 1;
+                }
+                // The gateway holds a sender itself, so this can only be
+                // Empty
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => {
+                    return Ok(count)
+                }
+            }
+        }
+    }
+
+    /// Moves frames queued by clients onto the socket's TX queue, for
+    /// full duplex operation. Frames which don't fit stay queued in the
+    /// gateway. Returns the number of frames moved
+    pub fn queue_to_bus(&self, socket: &mut CmriSocket) -> Result<usize> {
+        let mut count = 0;
+        while !socket.tx_queue_full() {
+            match self.to_bus.try_recv() {
+                Ok(msg) if !self.filter.allows(&msg) => {}
+                Ok(msg) => {
+                    socket.enqueue(&self.translate_to_bus(msg))?;
+                    count += 1;
+                }
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => {
+                    break
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    /// Sends a frame from the bus to every connected client, forgetting
+    /// any clients which have gone away
+    pub fn broadcast(&self, msg: &CmriMessage) {
+        let mut msg = *msg;
+        msg.address = msg.address.map(|a| self.address_map.from_bus(a));
+        if self.filter.allows(&msg) {
+            self.handle.broadcast(&msg);
+        }
+    }
+
+    fn translate_to_bus(&self, mut msg: CmriMessage) -> CmriMessage {
+        msg.address = msg.address.map(|a| self.address_map.to_bus(a));
+        msg
+    }
+
+    /// Reloads the layout if its file has changed and queues an Init for
+    /// every node which needs one, ahead of anything clients send after
+    /// this call
+    #[cfg(feature = "config")]
+    pub fn reload_config(
+        &self,
+        watcher: &mut ConfigWatcher,
+    ) -> Result<Option<LayoutChanges>> {
+        let changes = match watcher.check()? {
+            Some(changes) => changes,
+            None => return Ok(None),
+        };
+        for init in changes.init_messages(watcher.layout())? {
+            self.handle
+                .to_bus
+                .send(init)
+                .map_err(|_| Error::Disconnected)?;
+        }
+        Ok(Some(changes))
+    }
+
+    /// One pass of the bus loop: write out any queued frames, then wait
+    /// for a frame from the bus and broadcast it. The socket should have
+    /// a read timeout so that this returns regularly to service clients;
+    /// timeouts are not treated as errors. Nor are frames which fail to
+    /// decode, which are skipped and counted in the socket's
+    /// `stats().rx.resyncs`, so only I/O errors and disconnections stop
+    /// the loop.
+    pub fn step(&self, socket: &mut CmriSocket) -> Result<()> {
+        match socket.duplex() {
+            Duplex::Half => {
+                self.flush_to_bus(socket)?;
+            }
+            Duplex::Full => {
+                self.queue_to_bus(socket)?;
+                socket.pump_tx()?;
+            }
+        }
+        match socket.receive() {
+            Ok(()) => {
+                self.broadcast(socket.message());
+                Ok(())
+            }
+            Err(e @ Error::IoError(_)) | Err(e @ Error::Disconnected) => Err(e),
+            // Timed out, or a garbled frame
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+impl Default for Gateway {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GatewayHandle {
+    /// Connects a new client
+    pub fn connect(&self) -> GatewayClient {
+        let (tx, rx) = mpsc::channel();
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.push(tx);
+        }
+        GatewayClient {
+            to_bus: self.to_bus.clone(),
+            from_bus: rx,
+        }
+    }
+
+    fn broadcast(&self, msg: &CmriMessage) {
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.retain(|s| s.send(*msg).is_ok());
+        }
+    }
+
+    /// Connects a TCP client, spawning one thread to decode frames from
+    /// the client and one to forward bus frames back to it. Both threads
+    /// exit when the connection drops.
+    pub fn serve_tcp(&self, stream: TcpStream) -> Result<()> {
+        let GatewayClient { to_bus, from_bus } = self.connect();
+        let rx_stream = stream.try_clone()?;
+        let tx_shutdown = stream.try_clone()?;
+        let tx_stream = stream;
+
+        thread::spawn(move || {
+            for msg in FrameReader::new(BufReader::new(rx_stream)) {
+                match msg {
+                    Ok(msg) => {
+                        if to_bus.send(msg).is_err() {
+                            break;
+                        }
+                    }
+                    Err(Error::IoError(_)) => break,
+                    // Bad frames from one client shouldn't drop it
+                    Err(_) => {}
+                }
+            }
+            // Wake up the writer thread's next write
+            let _ = tx_shutdown.shutdown(Shutdown::Both);
+        });
+
+        thread::spawn(move || {
+            let mut writer = FrameWriter::new(tx_stream);
+            while let Ok(msg) = from_bus.recv() {
+                if let Err(Error::IoError(_)) = writer.write_msg(&msg) {
+                    break;
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+impl GatewayClient {
+    /// Queues a frame for the bus
+    pub fn send(&self, msg: &CmriMessage) -> Result<()> {
+        self.to_bus.send(*msg).map_err(|_| Error::Disconnected)
+    }
+
+    /// Blocks until a frame arrives from the bus
+    pub fn recv(&self) -> Result<CmriMessage> {
+        self.from_bus.recv().map_err(|_| Error::Disconnected)
+    }
+
+    /// Returns a frame from the bus if one is waiting
+    pub fn try_recv(&self) -> Option<CmriMessage> {
+        self.from_bus.try_recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{MessageBuilder, TX_BUFFER_LEN};
+    use std::boxed::Box;
+    use std::io::{Cursor, ErrorKind, Read, Write};
+    use std::time::Duration;
+
+    /// Bus which records writes into a shared buffer and reads from a
+    /// fixed buffer
+    struct TestBus {
+        written: Arc<Mutex<Vec<u8>>>,
+        to_read: Cursor<Vec<u8>>,
+    }
+
+    impl Write for TestBus {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.written.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for TestBus {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.to_read.read(buf)? {
+                0 => Err(ErrorKind::WouldBlock.into()),
+                n => Ok(n),
+            }
+        }
+    }
+
+    fn encode(msg: &CmriMessage) -> Vec<u8> {
+        let mut buf = [0_u8; TX_BUFFER_LEN];
+        let len = msg.encode(&mut buf).unwrap();
+        buf[..len].to_vec()
+    }
+
+    fn socket(to_read: Vec<u8>) -> (CmriSocket, Arc<Mutex<Vec<u8>>>) {
+        socket_with_duplex(to_read, Duplex::Half)
+    }
+
+    fn socket_with_duplex(
+        to_read: Vec<u8>,
+        duplex: Duplex,
+    ) -> (CmriSocket, Arc<Mutex<Vec<u8>>>) {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let bus = TestBus {
+            written: written.clone(),
+            to_read: Cursor::new(to_read),
+        };
+        let socket = CmriSocket::builder(Box::new(bus))
+            .duplex(duplex)
+            .read_timeout(Duration::from_millis(10))
+            .tx_queue_len(1)
+            .build();
+        (socket, written)
+    }
+
+    #[test]
+    fn frames_are_not_interleaved() {
+        let gateway = Gateway::new();
+        let a = gateway.handle().connect();
+        let b = gateway.handle().connect();
+
+        let set_a = MessageBuilder::set(0x41, &[1, 2, 3]).build().unwrap();
+        let set_b = MessageBuilder::set(0x42, &[4, 5, 6]).build().unwrap();
+        a.send(&set_a).unwrap();
+        b.send(&set_b).unwrap();
+        a.send(&set_a).unwrap();
+
+        let (mut socket, written) = socket(Vec::new());
+        gateway.step(&mut socket).unwrap();
+
+        let mut expected = encode(&set_a);
+        expected.extend(encode(&set_b));
+        expected.extend(encode(&set_a));
+        assert_eq!(*written.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn full_duplex_uses_tx_queue() {
+        let gateway = Gateway::new();
+        let client = gateway.handle().connect();
+        let set_a = MessageBuilder::set(0x41, &[1]).build().unwrap();
+        let set_b = MessageBuilder::set(0x42, &[2]).build().unwrap();
+        client.send(&set_a).unwrap();
+        client.send(&set_b).unwrap();
+
+        let reply = MessageBuilder::get(0x41, &[9]).build().unwrap();
+        let (mut socket, written) =
+            socket_with_duplex(encode(&reply), Duplex::Full);
+        gateway.step(&mut socket).unwrap();
+        assert_eq!(client.recv().unwrap().payload[0], 9);
+        // The socket's queue only holds one frame, so the second waits in
+        // the gateway for the next step
+        assert_eq!(*written.lock().unwrap(), encode(&set_a));
+
+        gateway.step(&mut socket).unwrap();
+        let mut expected = encode(&set_a);
+        expected.extend(encode(&set_b));
+        assert_eq!(*written.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn handles_are_send_and_sync() {
+        fn send<T: Send>() {}
+        fn send_sync<T: Send + Sync>() {}
+        send::<Gateway>();
+        send::<GatewayClient>();
+        send_sync::<GatewayHandle>();
+    }
+
+    #[test]
+    fn bus_frames_go_to_every_client() {
+        let gateway = Gateway::new();
+        let a = gateway.handle().connect();
+        let b = gateway.handle().connect();
+        // A client which has gone away shouldn't stop the others
+        drop(gateway.handle().connect());
+
+        let reply = MessageBuilder::get(0x41, &[9]).build().unwrap();
+        let (mut socket, _) = socket(encode(&reply));
+        gateway.step(&mut socket).unwrap();
+
+        assert_eq!(a.recv().unwrap().payload[0], 9);
+        assert_eq!(b.recv().unwrap().payload[0], 9);
+        assert!(a.try_recv().is_none());
+    }
+
+    #[test]
+    fn garbled_frames_are_skipped() {
+        let gateway = Gateway::new();
+        let client = gateway.handle().connect();
+        // A Get with no payload, which doesn't decode
+        let mut to_read = std::vec![0xff, 0xff, 0x02, 0x41, b'R', 0x03];
+        let reply = MessageBuilder::get(0x41, &[9]).build().unwrap();
+        to_read.extend(encode(&reply));
+        let (mut socket, _) = socket(to_read);
+
+        gateway.step(&mut socket).unwrap();
+        assert!(client.try_recv().is_none());
+        assert_eq!(socket.stats().rx.resyncs, 1);
+        gateway.step(&mut socket).unwrap();
+        assert_eq!(client.recv().unwrap().payload[0], 9);
+    }
+
+    #[test]
+    fn translated_addresses() {
+        let mut gateway = Gateway::new();
+        let mut map = AddressMap::new();
+        map.map_range(1..=10, 30).unwrap();
+        gateway.set_address_map(map);
+        let client = gateway.handle().connect();
+        // A client which still thinks the node is at UA 1
+        let set = MessageBuilder::set(b'A' + 1, &[0x03]).build().unwrap();
+        client.send(&set).unwrap();
+
+        let reply = MessageBuilder::get(b'A' + 30, &[9]).build().unwrap();
+        let (mut socket, written) = socket(encode(&reply));
+        gateway.step(&mut socket).unwrap();
+        let moved = MessageBuilder::set(b'A' + 30, &[0x03]).build().unwrap();
+        assert_eq!(*written.lock().unwrap(), encode(&moved));
+        let msg = client.recv().unwrap();
+        assert_eq!(msg.address, Some(b'A' + 1));
+        assert_eq!(
+            encode(&msg),
+            encode(&MessageBuilder::get(b'A' + 1, &[9]).build().unwrap())
+        );
+    }
+
+    #[test]
+    fn filtered_frames() {
+        use crate::{MessageType, Rule};
+
+        let mut gateway = Gateway::new();
+        let mut map = AddressMap::new();
+        map.map(2, 20).unwrap();
+        gateway.set_address_map(map);
+        gateway.set_filter(
+            Filter::new()
+                .drop(Rule::any().mtype(MessageType::Poll))
+                .pass(Rule::addr(1..=2))
+                .drop(Rule::any()),
+        );
+        let client = gateway.handle().connect();
+        for addr in [b'A' + 2, b'A' + 3].iter() {
+            let set = MessageBuilder::set(*addr, &[0x03]).build().unwrap();
+            client.send(&set).unwrap();
+        }
+        client
+            .send(&MessageBuilder::poll(b'A' + 1).build().unwrap())
+            .unwrap();
+
+        // Rules go by the client's addresses, so the Get from UA 20 is
+        // seen as UA 2 and passed
+        let reply = MessageBuilder::get(b'A' + 20, &[9]).build().unwrap();
+        let (mut socket, written) = socket(encode(&reply));
+        assert_eq!(gateway.flush_to_bus(&mut socket).unwrap(), 1);
+        let moved = MessageBuilder::set(b'A' + 20, &[0x03]).build().unwrap();
+        assert_eq!(*written.lock().unwrap(), encode(&moved));
+        gateway.step(&mut socket).unwrap();
+        assert_eq!(client.recv().unwrap().address, Some(b'A' + 2));
+
+        let other = MessageBuilder::get(b'A' + 3, &[9]).build().unwrap();
+        gateway.broadcast(&other);
+        assert!(client.try_recv().is_none());
+    }
+
+    #[cfg(feature = "config")]
+    #[test]
+    fn reload_sends_inits() {
+        use std::time::SystemTime;
+
+        let path = std::env::temp_dir()
+            .join(std::format!("cmri-gateway-{}.json", std::process::id()));
+        let layout = |delay: u16| {
+            std::format!(
+                r#"{{"nodes": [{{"address": 0, "node_type": "smini",
+                    "transmit_delay": {}}}]}}"#,
+                delay
+            )
+        };
+        std::fs::write(&path, layout(0)).unwrap();
+        let mut watcher = ConfigWatcher::open(&path).unwrap();
+        let gateway = Gateway::new();
+        assert_eq!(gateway.reload_config(&mut watcher), Ok(None));
+
+        std::fs::write(&path, layout(7)).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|f| {
+                f.set_modified(SystemTime::now() + Duration::from_secs(2))
+            })
+            .unwrap();
+        let changes = gateway.reload_config(&mut watcher).unwrap().unwrap();
+        assert_eq!(changes.changed, [0x41]);
+
+        let (mut socket, written) = socket(Vec::new());
+        gateway.step(&mut socket).unwrap();
+        let init = watcher.layout().nodes[0].init_message().unwrap();
+        assert_eq!(*written.lock().unwrap(), encode(&init));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
+
+#[cfg(all(test, cmri_loom))]
+mod loom_test {
+    use super::*;
+    use crate::MessageBuilder;
+    use loom::thread;
+
+    #[test]
+    fn clients_send_concurrently() {
+        loom::model(|| {
+            let gateway = Gateway::new();
+            let senders: Vec<_> = [0x41, 0x42]
+                .iter()
+                .map(|&addr| {
+                    let client = gateway.handle().connect();
+                    thread::spawn(move || {
+                        let msg = MessageBuilder::poll(addr).build().unwrap();
+                        client.send(&msg).unwrap();
+                    })
+                })
+                .collect();
+            for sender in senders {
+                sender.join().unwrap();
+            }
+
+            let mut addrs: Vec<_> = (0..2)
+                .map(|_| gateway.to_bus.try_recv().unwrap().address.unwrap())
+                .collect();
+            addrs.sort_unstable();
+            assert_eq!(addrs, [0x41, 0x42]);
+            assert!(gateway.to_bus.try_recv().is_err());
+        });
+    }
+
+    #[test]
+    fn connect_during_broadcast() {
+        loom::model(|| {
+            let gateway = Gateway::new();
+            let early = gateway.handle().connect();
+            let handle = gateway.handle();
+            let late = thread::spawn(move || handle.connect());
+
+            let msg = MessageBuilder::get(0x41, &[9]).build().unwrap();
+            gateway.broadcast(&msg);
+            let late = late.join().unwrap();
+
+            // A client connected before the broadcast always gets it; one
+            // connected during it gets it at most once
+            assert_eq!(early.try_recv().map(|m| m.payload[0]), Some(9));
+            let _ = late.try_recv();
+            assert!(late.try_recv().is_none());
+        });
+    }
+}
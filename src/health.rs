@@ -0,0 +1,161 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Per-node liveness tracking for the controller side. Every Poll either
+// gets a response or is missed, and a node's status moves between
+// Online, Degraded and Offline based on runs of misses and responses.
+// An Offline node has to answer several polls in a row before it counts
+// as Online again, so a flaky node doesn't flap between states.
+
+use std::time::Instant;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NodeStatus {
+    /// Answering polls
+    Online,
+    /// Has missed some polls but not enough to be written off
+    Degraded,
+    /// Has missed too many polls in a row
+    Offline,
+}
+
+/// Thresholds for moving between statuses
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct HealthPolicy {
+    /// Consecutive missed polls before an Online node is Degraded
+    pub degraded_after: u32,
+    /// Consecutive missed polls before a node is Offline
+    pub offline_after: u32,
+    /// Consecutive responses before an Offline node is Online again
+    pub recover_after: u32,
+}
+
+impl Default for HealthPolicy {
+    fn default() -> Self {
+        Self {
+            degraded_after: 1,
+            offline_after: 3,
+            recover_after: 2,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct NodeHealth {
+    pub status: NodeStatus,
+    /// Polls missed since the last response
+    pub missed_polls: u32,
+    /// Responses since the last missed poll
+    pub responses: u32,
+    /// Time of the last response, if there has been one
+    pub last_seen: Option<Instant>,
+}
+
+impl NodeHealth {
+    /// A node which hasn't been polled yet is assumed to be Online
+    pub fn new() -> Self {
+        Self {
+            status: NodeStatus::Online,
+            missed_polls: 0,
+            responses: 0,
+            last_seen: None,
+        }
+    }
+
+    /// Records a response to a poll, returning the new status if it has
+    /// changed
+    pub fn record_response(
+        &mut self,
+        now: Instant,
+        policy: &HealthPolicy,
+    ) -> Option<NodeStatus> {
+        self.missed_polls = 0;
+        self.responses = self.responses.saturating_add(1);
+        self.last_seen = Some(now);
+        let status = match self.status {
+            NodeStatus::Offline if self.responses < policy.recover_after => {
+                NodeStatus::Offline
+            }
+            _ => NodeStatus::Online,
+        };
+        self.transition(status)
+    }
+
+    /// Records a poll which got no response, returning the new status if
+    /// it has changed
+    pub fn record_miss(&mut self, policy: &HealthPolicy) -> Option<NodeStatus> {
+        self.responses = 0;
+        self.missed_polls = self.missed_polls.saturating_add(1);
+        let status = if self.missed_polls >= policy.offline_after {
+            NodeStatus::Offline
+        } else if self.missed_polls >= policy.degraded_after
+            && self.status == NodeStatus::Online
+        {
+            NodeStatus::Degraded
+        } else {
+            self.status
+        };
+        self.transition(status)
+    }
+
+    fn transition(&mut self, status: NodeStatus) -> Option<NodeStatus> {
+        if status == self.status {
+            return None;
+        }
+        self.status = status;
+        Some(status)
+    }
+}
+
+impl Default for NodeHealth {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn degrade_and_go_offline() {
+        let policy = HealthPolicy::default();
+        let mut h = NodeHealth::new();
+        assert_eq!(h.record_response(Instant::now(), &policy), None);
+        assert!(h.last_seen.is_some());
+
+        assert_eq!(h.record_miss(&policy), Some(NodeStatus::Degraded));
+        assert_eq!(h.record_miss(&policy), None);
+        assert_eq!(h.record_miss(&policy), Some(NodeStatus::Offline));
+        assert_eq!(h.missed_polls, 3);
+
+        // A single response from a Degraded node brings it straight back
+        let mut h = NodeHealth::new();
+        h.record_miss(&policy);
+        let res = h.record_response(Instant::now(), &policy);
+        assert_eq!(res, Some(NodeStatus::Online));
+    }
+
+    #[test]
+    fn recovery_needs_several_responses() {
+        let policy = HealthPolicy::default();
+        let mut h = NodeHealth::new();
+        for _ in 0..3 {
+            h.record_miss(&policy);
+        }
+        assert_eq!(h.status, NodeStatus::Offline);
+
+        assert_eq!(h.record_response(Instant::now(), &policy), None);
+        // A miss while recovering starts the count again
+        assert_eq!(h.record_miss(&policy), None);
+        assert_eq!(h.record_response(Instant::now(), &policy), None);
+        assert_eq!(
+            h.record_response(Instant::now(), &policy),
+            Some(NodeStatus::Online)
+        );
+    }
+}
@@ -0,0 +1,146 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Heap-backed messages for targets with an allocator. A `CmriMessage`
+// always carries a full `MAX_PAYLOAD_LEN` array, which adds up when
+// messages are held across await points in nested async tasks; a
+// `HeapMessage` only allocates what the payload uses.
+
+use crate::{encode_frame, CmriMessage, Error, MessageType, Result};
+use crate::{MAX_PAYLOAD_LEN, TX_BUFFER_LEN};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct HeapMessage {
+    pub address: Option<u8>,
+    pub message_type: Option<MessageType>,
+    pub payload: Vec<u8>,
+}
+
+impl HeapMessage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn address(&mut self, addr: u8) -> &mut Self {
+        self.address = Some(addr);
+        self
+    }
+
+    pub fn message_type(&mut self, t: MessageType) -> &mut Self {
+        self.message_type = Some(t);
+        self
+    }
+
+    /// Replace the payload. It may be no longer than `MAX_PAYLOAD_LEN`,
+    /// the same as for a `CmriMessage`
+    pub fn payload(&mut self, payload: &[u8]) -> Result<&mut Self> {
+        if payload.len() > MAX_PAYLOAD_LEN {
+            return Err(Error::DataTooLong);
+        }
+        self.payload.clear();
+        self.payload.extend_from_slice(payload);
+        Ok(self)
+    }
+
+    /// Encode the message into a newly allocated buffer
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        if self.payload.len() > MAX_PAYLOAD_LEN {
+            return Err(Error::DataTooLong);
+        }
+        let mut buf = Vec::with_capacity(TX_BUFFER_LEN);
+        encode_frame(
+            self.address,
+            self.message_type,
+            &self.payload,
+            |bytes| {
+                buf.extend_from_slice(bytes);
+                Ok(())
+            },
+        )?;
+        Ok(buf)
+    }
+}
+
+impl From<&CmriMessage> for HeapMessage {
+    fn from(msg: &CmriMessage) -> Self {
+        Self {
+            address: msg.address,
+            message_type: msg.message_type,
+            payload: msg.data().to_vec(),
+        }
+    }
+}
+
+impl PartialEq<CmriMessage> for HeapMessage {
+    fn eq(&self, other: &CmriMessage) -> bool {
+        self.address == other.address
+            && self.message_type == other.message_type
+            && self.payload.len() == other.len
+            && self.payload == other.data()
+    }
+}
+
+impl PartialEq<HeapMessage> for CmriMessage {
+    fn eq(&self, other: &HeapMessage) -> bool {
+        other == self
+    }
+}
+
+impl TryFrom<&HeapMessage> for CmriMessage {
+    type Error = Error;
+    fn try_from(msg: &HeapMessage) -> Result<Self> {
+        let mut out = CmriMessage::new();
+        out.payload(&msg.payload)?;
+        out.address = msg.address;
+        out.message_type = msg.message_type;
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MessageBuilder;
+
+    #[test]
+    fn encodes_like_a_cmri_message() {
+        let msg = MessageBuilder::set(0x41, &[0x01, 0x03, 0x10])
+            .build()
+            .unwrap();
+        let mut buf = [0_u8; TX_BUFFER_LEN];
+        let len = msg.encode(&mut buf).unwrap();
+
+        let heap = HeapMessage::from(&msg);
+        assert_eq!(heap.payload, [0x01, 0x03, 0x10]);
+        assert_eq!(heap, msg);
+        assert_eq!(msg, heap);
+        assert_eq!(heap.encode().unwrap(), buf[..len]);
+        assert_eq!(HeapMessage::new().encode(), Err(Error::MissingAddress));
+    }
+
+    #[test]
+    fn convert_back() {
+        let mut heap = HeapMessage::new();
+        heap.address(0x42)
+            .message_type(MessageType::Get)
+            .payload(&[1, 2])
+            .unwrap();
+        let mut msg = CmriMessage::try_from(&heap).unwrap();
+        assert_eq!(msg.address, Some(0x42));
+        assert_eq!(msg.payload[..msg.len], [1, 2]);
+        msg.len = 1;
+        assert_ne!(heap, msg);
+
+        heap.payload = alloc::vec![0; MAX_PAYLOAD_LEN + 1];
+        assert_eq!(heap.encode(), Err(Error::DataTooLong));
+        assert!(CmriMessage::try_from(&heap).is_err());
+        assert!(heap.payload(&[0; MAX_PAYLOAD_LEN + 1]).is_err());
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// cpNode I/O layout. A cpNode has its own onboard ports and can be
+// extended with IOX boards on I2C, each an MCP23017 with two 8-bit banks.
+// Every port is either an input or an output. Get messages carry one
+// byte per input port and Set messages one byte per output port, in the
+// order the ports are listed here: onboard ports first, then the IOX
+// banks.
+
+use crate::payload::CardType;
+use crate::{Error, Result};
+
+/// Onboard ports on a cpNode
+pub const CPNODE_ONBOARD_PORTS: usize = 2;
+/// IOX boards use the MCP23017's eight I2C addresses
+pub const IOX_FIRST_ADDRESS: u8 = 0x20;
+pub const IOX_LAST_ADDRESS: u8 = 0x27;
+/// Onboard ports plus two banks on each of eight IOX boards
+pub const MAX_IOX_PORTS: usize = CPNODE_ONBOARD_PORTS + 16;
+
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub enum Bank {
+    A,
+    B,
+}
+
+/// Where a port lives
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub enum PortLocation {
+    Onboard(u8),
+    Iox { address: u8, bank: Bank },
+}
+
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct IoxPort {
+    pub location: PortLocation,
+    /// `CardType::Input` or `CardType::Output`
+    pub direction: CardType,
+}
+
+/// Ports on a cpNode and its IOX boards, in payload order
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct IoxMap {
+    ports: [Option<IoxPort>; MAX_IOX_PORTS],
+    len: usize,
+}
+
+impl IoxMap {
+    pub fn new() -> Self {
+        Self {
+            ports: [None; MAX_IOX_PORTS],
+            len: 0,
+        }
+    }
+
+    /// Adds an onboard port. These must come before any IOX ports
+    pub fn onboard(
+        &mut self,
+        port: u8,
+        direction: CardType,
+    ) -> Result<&mut Self> {
+        if port as usize >= CPNODE_ONBOARD_PORTS {
+            return Err(Error::OutOfBounds);
+        }
+        if self
+            .ports()
+            .any(|p| matches!(p.location, PortLocation::Iox { .. }))
+        {
+            return Err(Error::InvalidPort);
+        }
+        self.push(PortLocation::Onboard(port), direction)
+    }
+
+    /// Adds a bank on an IOX board
+    pub fn expander(
+        &mut self,
+        address: u8,
+        bank: Bank,
+        direction: CardType,
+    ) -> Result<&mut Self> {
+        if !(IOX_FIRST_ADDRESS..=IOX_LAST_ADDRESS).contains(&address) {
+            return Err(Error::OutOfBounds);
+        }
+        self.push(PortLocation::Iox { address, bank }, direction)
+    }
+
+    fn push(
+        &mut self,
+        location: PortLocation,
+        direction: CardType,
+    ) -> Result<&mut Self> {
+        if direction == CardType::None
+            || self.ports().any(|p| p.location == location)
+        {
+            return Err(Error::InvalidPort);
+        }
+        let slot = self.ports.get_mut(self.len).ok_or(Error::DataTooLong)?;
+        *slot = Some(IoxPort {
+            location,
+            direction,
+        });
+        self.len += 1;
+        Ok(self)
+    }
+
+    /// Every port, in payload order
+    pub fn ports(&self) -> impl Iterator<Item = IoxPort> + '_ {
+        self.ports.iter().take(self.len).flatten().copied()
+    }
+
+    /// Ports of one direction, in the order their bytes appear in the
+    /// payload
+    pub fn ports_of(
+        &self,
+        direction: CardType,
+    ) -> impl Iterator<Item = PortLocation> + '_ {
+        self.ports()
+            .filter(move |p| p.direction == direction)
+            .map(|p| p.location)
+    }
+
+    /// Bytes the node reports when polled
+    pub fn input_bytes(&self) -> usize {
+        self.ports_of(CardType::Input).count()
+    }
+
+    /// Bytes the node expects in a Set message
+    pub fn output_bytes(&self) -> usize {
+        self.ports_of(CardType::Output).count()
+    }
+}
+
+impl Default for IoxMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn build_map() {
+        let mut map = IoxMap::new();
+        map.onboard(0, CardType::Input)
+            .unwrap()
+            .onboard(1, CardType::Output)
+            .unwrap()
+            .expander(0x20, Bank::A, CardType::Input)
+            .unwrap()
+            .expander(0x20, Bank::B, CardType::Input)
+            .unwrap();
+        assert_eq!(map.input_bytes(), 3);
+        assert_eq!(map.output_bytes(), 1);
+
+        let inputs: std::vec::Vec<_> = map.ports_of(CardType::Input).collect();
+        assert_eq!(
+            inputs[1..],
+            [
+                PortLocation::Iox {
+                    address: 0x20,
+                    bank: Bank::A
+                },
+                PortLocation::Iox {
+                    address: 0x20,
+                    bank: Bank::B
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn reject_bad_ports() {
+        let mut map = IoxMap::new();
+        assert!(map.onboard(2, CardType::Input).is_err());
+        assert!(map.expander(0x28, Bank::A, CardType::Input).is_err());
+        assert!(map.expander(0x21, Bank::A, CardType::None).is_err());
+        map.expander(0x21, Bank::A, CardType::Input).unwrap();
+        // Duplicates and onboard ports after IOX ports
+        assert!(map.expander(0x21, Bank::A, CardType::Output).is_err());
+        assert!(map.onboard(0, CardType::Input).is_err());
+    }
+}
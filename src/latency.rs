@@ -0,0 +1,163 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Poll/response correlation for debugging slow or confused nodes. Each
+// Poll sent is given a correlation record, and the next Get from the same
+// node is matched against it to give a round-trip time. A Get with no
+// Poll outstanding is counted as unmatched, which usually means two
+// controllers on one bus or a node answering at the wrong address.
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+/// A Poll waiting for its response
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Correlation {
+    /// Increases by one for each Poll sent
+    pub id: u32,
+    /// Address byte of the polled node
+    pub node: u8,
+    pub sent_at: Instant,
+}
+
+/// Round-trip times for one node
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct NodeLatency {
+    /// Polls answered
+    pub samples: u32,
+    pub min: Duration,
+    pub max: Duration,
+    pub total: Duration,
+    /// Polls superseded by another Poll before a response arrived
+    pub unanswered: u32,
+}
+
+impl NodeLatency {
+    pub fn mean(&self) -> Option<Duration> {
+        if self.samples == 0 {
+            return None;
+        }
+        Some(self.total / self.samples)
+    }
+
+    fn record(&mut self, latency: Duration) {
+        if self.samples == 0 || latency < self.min {
+            self.min = latency;
+        }
+        self.max = self.max.max(latency);
+        self.total += latency;
+        self.samples = self.samples.saturating_add(1);
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LatencyReport {
+    /// Keyed by address byte
+    pub nodes: BTreeMap<u8, NodeLatency>,
+    /// Gets which didn't follow a Poll to the same node
+    pub unmatched: u32,
+}
+
+#[derive(Default)]
+pub(crate) struct LatencyTracker {
+    outstanding: HashMap<u8, Correlation>,
+    next_id: u32,
+    report: LatencyReport,
+}
+
+impl LatencyTracker {
+    /// Records a Poll going out, replacing any older Poll to the same
+    /// node which hasn't been answered
+    pub(crate) fn poll_sent(&mut self, node: u8, now: Instant) -> Correlation {
+        let correlation = Correlation {
+            id: self.next_id,
+            node,
+            sent_at: now,
+        };
+        self.next_id = self.next_id.wrapping_add(1);
+        if self.outstanding.insert(node, correlation).is_some() {
+            let stats = self.report.nodes.entry(node).or_default();
+            stats.unanswered = stats.unanswered.saturating_add(1);
+        }
+        correlation
+    }
+
+    /// Matches a Get against the outstanding Poll to its node, returning
+    /// the Poll and the round-trip time. `None` means the Get was
+    /// unmatched
+    pub(crate) fn response(
+        &mut self,
+        node: u8,
+        now: Instant,
+    ) -> Option<(Correlation, Duration)> {
+        let correlation = match self.outstanding.remove(&node) {
+            Some(c) => c,
+            None => {
+                self.report.unmatched = self.report.unmatched.saturating_add(1);
+                return None;
+            }
+        };
+        let latency = now.saturating_duration_since(correlation.sent_at);
+        self.report.nodes.entry(node).or_default().record(latency);
+        Some((correlation, latency))
+    }
+
+    pub(crate) fn outstanding(&self, node: u8) -> Option<Correlation> {
+        self.outstanding.get(&node).copied()
+    }
+
+    pub(crate) fn report(&self) -> &LatencyReport {
+        &self.report
+    }
+
+    /// Clears the report, keeping any Polls still waiting for a response
+    pub(crate) fn reset(&mut self) {
+        self.report = LatencyReport::default();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn matches_responses() {
+        let mut tracker = LatencyTracker::default();
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+
+        let first = tracker.poll_sent(0x41, start);
+        tracker.poll_sent(0x42, start);
+        assert_eq!(tracker.outstanding(0x41), Some(first));
+        assert_eq!(
+            tracker.response(0x41, start + ms(10)),
+            Some((first, ms(10)))
+        );
+        assert_eq!(tracker.outstanding(0x41), None);
+
+        let second = tracker.poll_sent(0x41, start + ms(20));
+        assert_eq!(second.id, first.id + 2);
+        tracker.response(0x41, start + ms(50));
+        // A second Get for the same Poll is unmatched
+        assert_eq!(tracker.response(0x41, start + ms(60)), None);
+
+        // Re-polling before a response counts as unanswered
+        tracker.poll_sent(0x42, start + ms(70));
+
+        let report = tracker.report();
+        assert_eq!(report.unmatched, 1);
+        let node = report.nodes[&0x41];
+        assert_eq!((node.min, node.max), (ms(10), ms(30)));
+        assert_eq!(node.mean(), Some(ms(20)));
+        assert_eq!(report.nodes[&0x42].unanswered, 1);
+        assert_eq!(report.nodes[&0x42].mean(), None);
+
+        tracker.reset();
+        assert_eq!(tracker.report(), &LatencyReport::default());
+        assert!(tracker.outstanding(0x42).is_some());
+    }
+}
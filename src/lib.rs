@@ -6,37 +6,216 @@
 // copied, modified, or distributed except according to those terms.
 
 #![no_std]
+// Nothing outside the tests should be able to panic on a bad index, so
+// that a malformed frame can't take down a node
+#![deny(clippy::indexing_slicing)]
 
 #[cfg(any(feature = "std", test))]
 extern crate std;
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+pub use address::{Address, AddressMap, MAX_UA};
+pub use bits::{input_changes, BitOrder, InputChanged};
+pub use builder::MessageBuilder;
+pub use card::{expected_get_len, expected_set_len, Card, CardSet, CardSize};
+pub use clock::Clock;
+#[cfg(feature = "std")]
+pub use clock::StdClock;
 use core::convert::TryFrom;
+pub use diagnose::{parse_frame, FrameError, FrameErrorKind};
 pub use error::{Error, Result};
+#[cfg(feature = "log")]
+pub use events::LogEvents;
+#[cfg(feature = "tracing")]
+pub use events::TracingEvents;
+pub use events::{DiscardReason, NoEvents, ProtocolEvents, ResetReason};
+#[cfg(feature = "std")]
+pub use filter::Filter;
+pub use filter::Rule;
+pub use line::{LineDiscipline, LineTiming};
+pub use node_driver::{Action, NodeDriver, Tick, WatchdogEvent};
 pub use node_types::*;
-
+pub use payload::DecodedMessage;
+pub use quarantine::{Quarantine, QuarantinedFrame};
+pub use rx_ring::RxRing;
+pub use serial_config::{ControlLine, SerialConfig, TxEnable};
+pub use stats::{Stats, NOISE_HISTORY_LEN};
+#[cfg(feature = "state-trace")]
+pub use transitions::{Transition, TransitionHook};
+
+pub mod address;
+pub mod bits;
+pub mod builder;
+pub mod card;
+pub mod clock;
+pub mod compat;
+pub mod debounce;
+pub mod diagnose;
 pub mod error;
+pub mod events;
+pub mod filter;
+pub mod iox;
+pub mod line;
+pub mod node_driver;
 pub mod node_types;
+pub mod payload;
+pub mod quarantine;
+pub mod rx_ring;
+pub mod serial_config;
+pub mod stats;
+// Its panics print the values which didn't match
+#[cfg(feature = "fmt")]
+pub mod testing;
+pub mod timing;
+#[cfg(feature = "state-trace")]
+pub mod transitions;
+
+#[cfg(feature = "alloc")]
+pub mod heap;
+#[cfg(feature = "alloc")]
+pub use heap::HeapMessage;
+
+#[cfg(feature = "ffi")]
+pub mod ffi;
+
+#[cfg(feature = "wasm")]
+pub mod wasm;
 
+#[cfg(feature = "std")]
+pub mod bus_load;
 #[cfg(feature = "std")]
 pub mod cmri_socket;
 #[cfg(feature = "std")]
-pub use cmri_socket::{CmriSocket, Duplex};
+pub mod collision;
+#[cfg(feature = "std")]
+pub mod commission;
+#[cfg(feature = "std")]
+pub mod controller;
+#[cfg(feature = "std")]
+pub mod health;
+#[cfg(feature = "std")]
+pub mod latency;
+#[cfg(feature = "std")]
+pub mod retry;
+#[cfg(feature = "std")]
+pub mod transport;
+#[cfg(feature = "std")]
+pub mod tx_queue;
+#[cfg(feature = "std")]
+pub use bus_load::{BusEstimate, NodeLoad, UtilisationMeter};
+#[cfg(feature = "std")]
+pub use cmri_socket::{
+    CmriSocket, CmriSocketBuilder, Duplex, RxHandler, SocketStats,
+};
+#[cfg(feature = "std")]
+pub use collision::CollisionPolicy;
+#[cfg(feature = "std")]
+pub use commission::{BusReport, ScanOptions};
+#[cfg(feature = "std")]
+pub use controller::{
+    Controller, ControllerHandle, ForcedOutput, InitSequence,
+};
+#[cfg(feature = "std")]
+pub use health::{HealthPolicy, NodeHealth, NodeStatus};
+#[cfg(feature = "std")]
+pub use latency::{Correlation, LatencyReport, NodeLatency};
+#[cfg(feature = "std")]
+pub use retry::RetryPolicy;
+#[cfg(feature = "std")]
+pub use tx_queue::{TxPriority, TxQueueDepth};
+#[cfg(feature = "std")]
+pub mod frame;
+#[cfg(feature = "std")]
+pub use frame::{FrameReader, FrameWriter};
+#[cfg(feature = "std")]
+pub mod gateway;
+#[cfg(feature = "std")]
+pub use gateway::{Gateway, GatewayClient, GatewayHandle};
+#[cfg(feature = "std")]
+pub mod multi_bus;
+#[cfg(feature = "std")]
+pub use multi_bus::{
+    BusError, BusId, BusInputChanged, BusPoint, MultiBus, MultiBusHandle,
+};
+#[cfg(feature = "std")]
+pub mod replay;
+#[cfg(feature = "std")]
+pub mod sim;
+#[cfg(feature = "std")]
+mod sync;
+
+#[cfg(feature = "config")]
+pub mod config;
+#[cfg(feature = "config")]
+pub use config::{
+    ConfigWatcher, LayoutChanges, LayoutConfig, NodeConfig, RosterEntry,
+};
+#[cfg(feature = "config")]
+pub mod registry;
+#[cfg(feature = "config")]
+pub use registry::{IoPoint, IoRegistry};
+#[cfg(feature = "config")]
+pub mod snapshot;
+#[cfg(feature = "config")]
+pub use snapshot::OutputSnapshot;
+
+#[cfg(feature = "ws-bridge")]
+pub mod ws_bridge;
+#[cfg(feature = "ws-bridge")]
+pub use ws_bridge::WsBridge;
+
+#[cfg(feature = "python")]
+pub mod python;
+
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "mqtt")]
+pub use mqtt::MqttAdapter;
+
+#[cfg(feature = "serial-async")]
+pub mod async_serial;
+#[cfg(feature = "serial-async")]
+pub use async_serial::{AsyncCmriPort, AsyncSerial};
 
 #[cfg(feature = "arduino")]
 pub mod arduino;
 #[cfg(feature = "arduino")]
-pub use arduino::CmriProcessor;
+pub use arduino::{ArduinoCmri, CmriProcessor};
+
+#[cfg(feature = "heapless")]
+pub mod message_queue;
+#[cfg(feature = "heapless")]
+pub use message_queue::QueuedStateMachine;
+#[cfg(feature = "heapless")]
+pub mod payload_vec;
+#[cfg(feature = "heapless")]
+pub use payload_vec::Payload;
+
+#[cfg(feature = "cortex_m")]
+pub mod cortex_m;
+#[cfg(feature = "cortex_m")]
+pub use cortex_m::UsartNode;
 
 /// This is the length calculated from
 /// https://github.com/madleech/ArduinoCMRI/blob/master/CMRI.h
 /// (64 i/o cards @ 32 bits each + packet type and address bytes)
+///
+/// This is the unescaped payload length, so a fully loaded SUSIC fits
+/// exactly. The `large-payloads` feature doubles it for non-standard
+/// nodes, at the cost of extra RAM for every message and buffer.
 //const RX_BUFFER_LEN: usize = 258;
+#[cfg(not(feature = "large-payloads"))]
 pub const MAX_PAYLOAD_LEN: usize = 256;
+#[cfg(feature = "large-payloads")]
+pub const MAX_PAYLOAD_LEN: usize = 512;
 /// * Payload is MAX_PAYLOAD_LEN
 /// * Headers are 2x PREAMBLE and a START: 3
 /// * Address and type: 2
 /// * Trailers are 1x STOP: 1
 /// * Then some unknown number of escape bytes, up to MAX_PAYLOAD_LEN
+///
 /// Implementations may be be able to get away with a smaller buffer if
 /// memory is highly constrained
 pub const TX_BUFFER_LEN: usize = 2 * MAX_PAYLOAD_LEN + 3 + 2 + 1;
@@ -46,8 +225,15 @@ const CMRI_START_BYTE: u8 = 0x02;
 const CMRI_STOP_BYTE: u8 = 0x03;
 const CMRI_ESCAPE_BYTE: u8 = 0x10;
 
+/// Node addresses go over the wire as UA + 65, so UA 0 is 'A'
+const ADDRESS_OFFSET: u8 = 65;
+/// Number of payload bytes per line in the verbose hexdump
+#[cfg(feature = "fmt")]
+const HEXDUMP_WIDTH: usize = 16;
+
 /// Possible states of the C/MRI system
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
 pub enum CmriState {
     Idle,
     Attn,
@@ -58,21 +244,44 @@ pub enum CmriState {
     Escape,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
 pub enum MessageType {
     /// Initialisation
-    Init = 'I' as isize,
+    Init,
     /// Controller -> Node
-    Set = 'T' as isize,
+    Set,
     /// Node -> Controller
-    Get = 'R' as isize,
+    Get,
     /// Controller requests status from node
-    Poll = 'P' as isize,
+    Poll,
+    /// A type byte this crate doesn't know, e.g. from a firmware
+    /// extension. Only produced by `from_lossy()`
+    Unknown(u8),
 }
 
-impl TryFrom<u8> for MessageType {
-    type Error = Error;
-    fn try_from(t: u8) -> Result<Self> {
+impl MessageType {
+    /// Every type this crate knows, in the order they're usually sent
+    pub const ALL: [MessageType; 4] = [
+        MessageType::Init,
+        MessageType::Set,
+        MessageType::Poll,
+        MessageType::Get,
+    ];
+
+    /// The type byte as it appears on the wire, e.g. 'T' for Set
+    pub fn as_char(self) -> char {
+        u8::from(self) as char
+    }
+
+    /// Decodes a type byte, rejecting anything unrecognised. The same as
+    /// `try_from()`
+    pub fn try_from_strict(t: u8) -> Result<Self> {
         use MessageType::*;
         match t as char {
             'I' => Ok(Init),
@@ -82,23 +291,104 @@ impl TryFrom<u8> for MessageType {
             _ => Err(Error::InvalidMessageType),
         }
     }
+
+    /// Decodes a type byte, keeping an unrecognised one as `Unknown`
+    pub fn from_lossy(t: u8) -> Self {
+        Self::try_from_strict(t).unwrap_or(MessageType::Unknown(t))
+    }
+
+    /// Fewest payload bytes a frame of this type can sensibly carry. A
+    /// Set or Get with no data is almost always a framing error, and an
+    /// Init needs at least its node definition parameters
+    pub fn min_payload_len(self) -> usize {
+        use MessageType::*;
+        match self {
+            Init => 4,
+            Set | Get => 1,
+            Poll | Unknown(_) => 0,
+        }
+    }
 }
 
+impl TryFrom<u8> for MessageType {
+    type Error = Error;
+    fn try_from(t: u8) -> Result<Self> {
+        Self::try_from_strict(t)
+    }
+}
+
+impl From<MessageType> for u8 {
+    fn from(t: MessageType) -> u8 {
+        use MessageType::*;
+        match t {
+            Init => b'I',
+            Set => b'T',
+            Get => b'R',
+            Poll => b'P',
+            Unknown(t) => t,
+        }
+    }
+}
+
+/// Accepts a type's name or its wire character in any case, so "Set",
+/// "set" and "T" are all `Set`
+#[cfg(feature = "std")]
+impl core::str::FromStr for MessageType {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let wire = match s.as_bytes() {
+            [c] => Some(c.to_ascii_uppercase()),
+            _ => None,
+        };
+        MessageType::ALL
+            .iter()
+            .copied()
+            .find(|t| {
+                wire == Some(u8::from(*t))
+                    || s.eq_ignore_ascii_case(&std::format!("{}", t))
+            })
+            .ok_or(Error::InvalidMessageType)
+    }
+}
+
+#[cfg(feature = "fmt")]
 impl core::fmt::Display for MessageType {
     fn fmt(
         &self,
         fmt: &mut core::fmt::Formatter<'_>,
     ) -> core::result::Result<(), core::fmt::Error> {
-        write!(fmt, "{:?}", self)
+        match self {
+            MessageType::Unknown(t) => write!(fmt, "Unknown(0x{:02x})", t),
+            _ => write!(fmt, "{:?}", self),
+        }
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
 pub enum RxState {
     Listening,
     Complete,
 }
 
+/// What the state machine needs to see before trusting a preamble once
+/// it has lost sync, e.g. on joining a live bus part way through a frame.
+/// Payloads can contain 0xFF 0xFF 0x02, so without a guard the tail of
+/// one frame can be taken for the start of another. Once a frame has
+/// been decoded the following preamble is trusted again
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub enum SyncGuard {
+    /// Accept any preamble
+    Off,
+    /// Only accept a preamble which follows a quiet line for at least
+    /// this many ticks of the time given to `set_time()`
+    IdleTime(u64),
+    /// Only accept a preamble of at least this many 0xFF bytes, for
+    /// senders which lead each frame with extra preamble bytes
+    PreambleRun(u8),
+}
+
 /// Main state machine, including decoding logic
 pub struct CmriStateMachine {
     state: CmriState,
@@ -106,6 +396,80 @@ pub struct CmriStateMachine {
     /// If set, decoding will only accept messages directed at this
     /// address and discard all others
     address_filter: Option<u8>,
+    /// Address of broadcast messages to accept despite the filter
+    broadcast: Option<u8>,
+    /// Decode frames with unrecognised type bytes rather than discard
+    /// them
+    accept_unknown_types: bool,
+    /// Bytes most recently discarded while idle
+    noise: stats::NoiseHistory,
+    /// Reject frames whose payload is too short for their type
+    check_payload_lengths: bool,
+    stats: Stats,
+    /// Bytes received so far in the current frame, including preamble
+    frame_bytes: usize,
+    last_reset: Option<ResetReason>,
+    last_error: Option<Error>,
+    /// Start of the frame being received, for the quarantine
+    head: [u8; quarantine::QUARANTINE_BYTES],
+    quarantine: Quarantine,
+    /// Time given to `set_time()`, for stamping quarantined frames
+    now: u64,
+    sync_guard: SyncGuard,
+    /// A frame has been decoded since the last noise or error, so the
+    /// next preamble can be trusted
+    synced: bool,
+    /// Time of the last byte, if `set_time()` has been called
+    last_byte_at: Option<u64>,
+    /// 0xFF bytes in a row up to the last byte
+    preamble_run: u8,
+    #[cfg(feature = "raw-capture")]
+    raw: RawFrame,
+    #[cfg(feature = "state-trace")]
+    transitions: transitions::TransitionLog,
+}
+
+/// The bytes of the frame being received, exactly as they came off the
+/// wire
+#[cfg(feature = "raw-capture")]
+struct RawFrame {
+    /// Big enough for a frame with every payload byte escaped
+    bytes: [u8; TX_BUFFER_LEN],
+    /// May pass the end of `bytes` if a sender escaped bytes which didn't
+    /// need it, in which case the frame isn't kept
+    len: usize,
+    complete: bool,
+}
+
+#[cfg(feature = "raw-capture")]
+impl RawFrame {
+    fn new() -> Self {
+        Self {
+            bytes: [0; TX_BUFFER_LEN],
+            len: 0,
+            complete: false,
+        }
+    }
+
+    fn start(&mut self) {
+        self.len = 0;
+        self.complete = false;
+    }
+
+    fn extend(&mut self, bytes: &[u8]) {
+        let end = self.len + bytes.len();
+        if let Some(dst) = self.bytes.get_mut(self.len..end) {
+            dst.copy_from_slice(bytes);
+        }
+        self.len = end;
+    }
+
+    fn frame(&self) -> Option<&[u8]> {
+        match self.complete {
+            true => self.bytes.get(..self.len),
+            false => None,
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -126,13 +490,33 @@ impl CmriMessage {
         }
     }
 
+    /// Sets the address byte as sent on the wire
     pub fn address(&mut self, addr: u8) -> &mut Self {
         self.address = Some(addr);
         self
     }
 
+    /// A new message addressed to the node with this unit address
+    pub fn from_ua(ua: u8) -> Result<Self> {
+        let mut msg = Self::new();
+        msg.address(Address::Ua(ua).wire()?);
+        Ok(msg)
+    }
+
+    /// Unit address of the node this message is to or from, or `None` if
+    /// the address is missing or out of range
+    pub fn to_ua(&self) -> Option<u8> {
+        self.address.and_then(|addr| Address::Wire(addr).ua().ok())
+    }
+
+    /// The used part of the payload buffer
+    pub fn data(&self) -> &[u8] {
+        self.payload.get(..self.len).unwrap_or(&[])
+    }
+
     pub fn payload(&mut self, payload: &[u8]) -> Result<&mut Self> {
         payload_from_slice(&mut self.payload, payload)?;
+        self.len = payload.len();
         Ok(self)
     }
 
@@ -143,11 +527,9 @@ impl CmriMessage {
 
     /// Push a byte onto the payload
     fn push(&mut self, byte: u8) -> Result<()> {
-        if self.len == MAX_PAYLOAD_LEN {
-            // Buffer is full, which is problematic
-            return Err(Error::DataTooLong);
-        }
-        self.payload[self.len] = byte;
+        // Fails if the buffer is full, which is problematic
+        let slot = self.payload.get_mut(self.len).ok_or(Error::DataTooLong)?;
+        *slot = byte;
         self.len += 1;
         Ok(())
     }
@@ -158,45 +540,311 @@ impl CmriMessage {
         self.payload.iter_mut().for_each(|x| *x = 0);
     }
 
-    /// Encode the message into a transmit buffer
-    pub fn encode(&self, buf: &mut [u8; TX_BUFFER_LEN]) -> Result<()> {
+    /// Encode the message into a transmit buffer, returning the number
+    /// of bytes written. The buffer can be any length, but a frame which
+    /// doesn't fit gives `Error::BufferTooSmall`. A `TX_BUFFER_LEN`
+    /// buffer fits any message, and a `len` past the end of the payload
+    /// gives `Error::DataTooLong`
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize> {
         let mut pos: usize = 0;
+        let data = self.encode_data()?;
+        encode_frame(self.address, self.message_type, data, |bytes| {
+            let end = pos + bytes.len();
+            if end > TX_BUFFER_LEN {
+                return Err(Error::DataTooLong);
+            }
+            buf.get_mut(pos..end)
+                .ok_or(Error::BufferTooSmall)?
+                .copy_from_slice(bytes);
+            pos = end;
+            Ok(())
+        })?;
+        Ok(pos)
+    }
+
+    /// Number of bytes `encode()` will write, for sizing a tight buffer
+    pub fn encoded_len(&self) -> usize {
+        let escapes = self.data().iter().filter(|b| needs_escape(**b)).count();
+        // Two preambles, start, address and type, then the stop byte
+        5 + self.len + escapes + 1
+    }
+
+    /// As `encoded_len()`, but first checks that `encode()` would accept
+    /// the message, giving the error it would otherwise fail with. Only
+    /// `Error::BufferTooSmall` is left to depend on the buffer
+    pub fn try_encoded_len(&self) -> Result<usize> {
+        frame_header(self.address, self.message_type)?;
+        self.encode_data()?;
+        Ok(self.encoded_len())
+    }
+
+    /// Encodes the message a byte at a time without a transmit buffer,
+    /// e.g. for a UART interrupt handler to pull the next byte from
+    pub fn encode_iter(&self) -> Result<EncodeIter<'_>> {
+        EncodeIter::new(self.address, self.message_type, self.encode_data()?)
+    }
 
-        // Two PREAMBLEs
-        buf[pos] = CMRI_PREAMBLE_BYTE;
-        pos += 1;
-        buf[pos] = CMRI_PREAMBLE_BYTE;
-        pos += 1;
-
-        // One START
-        buf[pos] = CMRI_START_BYTE;
-        pos += 1;
-
-        // One ADDRESS
-        buf[pos] = self.address.ok_or(Error::MissingAddress)?;
-        pos += 1;
-
-        // One TYPE
-        buf[pos] = self.message_type.ok_or(Error::MissingType)? as u8;
-        pos += 1;
-
-        // Insert the PAYLOAD
-        for payload_byte in self.payload[..self.len].iter() {
-            if needs_escape(*payload_byte) {
-                buf[pos] = CMRI_ESCAPE_BYTE;
-                pos += 1;
+    /// Encodes the message across several buffers in turn, such as the
+    /// two free regions of a DMA ring buffer, returning the number of
+    /// bytes written. Nothing is written unless the whole frame fits
+    pub fn encode_into_chunks(
+        &self,
+        chunks: &mut [&mut [u8]],
+    ) -> Result<usize> {
+        let frame = self.encode_iter()?;
+        let space: usize = chunks.iter().map(|chunk| chunk.len()).sum();
+        if frame.len() > space {
+            return Err(Error::BufferTooSmall);
+        }
+        let len = frame.len();
+        let slots = chunks.iter_mut().flat_map(|chunk| chunk.iter_mut());
+        for (slot, byte) in slots.zip(frame) {
+            *slot = byte;
+        }
+        Ok(len)
+    }
+
+    /// The payload to be encoded. `len` can be set to anything, so rather
+    /// than quietly sending an empty frame this refuses one which is too
+    /// long for the payload array
+    fn encode_data(&self) -> Result<&[u8]> {
+        self.payload.get(..self.len).ok_or(Error::DataTooLong)
+    }
+
+    /// Encodes into the buffer, returning just the frame
+    pub(crate) fn encode_slice<'a>(
+        &self,
+        buf: &'a mut [u8],
+    ) -> Result<&'a [u8]> {
+        let len = self.encode(buf)?;
+        buf.get(..len).ok_or(Error::BufferTooSmall)
+    }
+}
+
+/// Writes out a whole frame, escaping the payload. Runs of bytes which
+/// don't need escaping are passed to `put` in one go, so that escape-heavy
+/// payloads cost little more than plain ones.
+fn encode_frame(
+    address: Option<u8>,
+    message_type: Option<MessageType>,
+    payload: &[u8],
+    mut put: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<()> {
+    put(&frame_header(address, message_type)?)?;
+    for run in payload.split_inclusive(|byte| needs_escape(*byte)) {
+        match run.split_last() {
+            Some((&last, plain)) if needs_escape(last) => {
+                if !plain.is_empty() {
+                    put(plain)?;
+                }
+                put(&[CMRI_ESCAPE_BYTE, last])?;
             }
+            _ => put(run)?,
+        }
+    }
+    put(&[CMRI_STOP_BYTE])
+}
 
-            buf[pos] = *payload_byte;
-            pos += 1;
+/// Two PREAMBLEs, START, ADDRESS and TYPE
+fn frame_header(
+    address: Option<u8>,
+    message_type: Option<MessageType>,
+) -> Result<[u8; 5]> {
+    let address = address.ok_or(Error::MissingAddress)?;
+    let message_type = message_type.ok_or(Error::MissingType)?;
+    Ok([
+        CMRI_PREAMBLE_BYTE,
+        CMRI_PREAMBLE_BYTE,
+        CMRI_START_BYTE,
+        address,
+        u8::from(message_type),
+    ])
+}
+
+/// The bytes of an encoded frame, one at a time, from
+/// `CmriMessage::encode_iter()`
+#[derive(Clone)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct EncodeIter<'a> {
+    /// Two PREAMBLEs, START, ADDRESS and TYPE
+    header: [u8; 5],
+    header_pos: usize,
+    payload: core::slice::Iter<'a, u8>,
+    /// Payload byte to send after the escape byte just sent
+    escaped: Option<u8>,
+    stopped: bool,
+}
+
+impl<'a> EncodeIter<'a> {
+    fn new(
+        address: Option<u8>,
+        message_type: Option<MessageType>,
+        payload: &'a [u8],
+    ) -> Result<Self> {
+        Ok(Self {
+            header: frame_header(address, message_type)?,
+            header_pos: 0,
+            payload: payload.iter(),
+            escaped: None,
+            stopped: false,
+        })
+    }
+}
+
+impl Iterator for EncodeIter<'_> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if let Some(byte) = self.header.get(self.header_pos) {
+            self.header_pos += 1;
+            return Some(*byte);
+        }
+        if let Some(byte) = self.escaped.take() {
+            return Some(byte);
+        }
+        match self.payload.next() {
+            Some(&byte) if needs_escape(byte) => {
+                self.escaped = Some(byte);
+                Some(CMRI_ESCAPE_BYTE)
+            }
+            Some(&byte) => Some(byte),
+            None if !self.stopped => {
+                self.stopped = true;
+                Some(CMRI_STOP_BYTE)
+            }
+            None => None,
         }
+    }
 
-        // One STOP
-        buf[pos] = CMRI_STOP_BYTE;
-        //pos += 1;
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let payload = self.payload.as_slice();
+        let escapes = payload.iter().filter(|b| needs_escape(**b)).count();
+        let len = self.header.len().saturating_sub(self.header_pos)
+            + usize::from(self.escaped.is_some())
+            + payload.len()
+            + escapes
+            + usize::from(!self.stopped);
+        (len, Some(len))
+    }
+}
+
+impl ExactSizeIterator for EncodeIter<'_> {}
+
+impl core::iter::FusedIterator for EncodeIter<'_> {}
+
+#[cfg(feature = "fmt")]
+impl CmriMessage {
+    /// Single-line summary: address, type, length and payload bytes
+    pub fn fmt_compact(
+        &self,
+        fmt: &mut core::fmt::Formatter<'_>,
+    ) -> core::fmt::Result {
+        self.fmt_address(fmt)?;
+        self.fmt_type(fmt)?;
+        write!(fmt, " len {}", self.len)?;
+        if self.len > 0 {
+            write!(fmt, ":")?;
+            for byte in self.data().iter() {
+                write!(fmt, " {:02x}", byte)?;
+            }
+        }
+        Ok(())
+    }
 
+    /// Multi-line summary with the payload as a hexdump, wrapped at 16
+    /// bytes per line and prefixed with the offset
+    pub fn fmt_verbose(
+        &self,
+        fmt: &mut core::fmt::Formatter<'_>,
+    ) -> core::fmt::Result {
+        self.fmt_address(fmt)?;
+        self.fmt_type(fmt)?;
+        write!(fmt, " len {}", self.len)?;
+        for (line, chunk) in self.data().chunks(HEXDUMP_WIDTH).enumerate() {
+            write!(fmt, "\n{:04x}:", line * HEXDUMP_WIDTH)?;
+            for byte in chunk.iter() {
+                write!(fmt, " {:02x}", byte)?;
+            }
+        }
         Ok(())
     }
+
+    fn fmt_address(
+        &self,
+        fmt: &mut core::fmt::Formatter<'_>,
+    ) -> core::fmt::Result {
+        match self.address {
+            Some(addr) => match addr.checked_sub(ADDRESS_OFFSET) {
+                Some(ua) => write!(fmt, "UA {} (0x{:02x})", ua, addr),
+                None => write!(fmt, "UA ? (0x{:02x})", addr),
+            },
+            None => write!(fmt, "UA ? (none)"),
+        }
+    }
+
+    fn fmt_type(
+        &self,
+        fmt: &mut core::fmt::Formatter<'_>,
+    ) -> core::fmt::Result {
+        match self.message_type {
+            Some(t) => write!(fmt, " {}", t),
+            None => write!(fmt, " (no type)"),
+        }
+    }
+}
+
+/// Compact by default; use the alternate flag (`{:#}`) for the verbose
+/// hexdump
+#[cfg(feature = "fmt")]
+impl core::fmt::Display for CmriMessage {
+    fn fmt(
+        &self,
+        fmt: &mut core::fmt::Formatter<'_>,
+    ) -> core::result::Result<(), core::fmt::Error> {
+        if fmt.alternate() {
+            self.fmt_verbose(fmt)
+        } else {
+            self.fmt_compact(fmt)
+        }
+    }
+}
+
+/// Only shows the used part of the payload buffer
+#[cfg(any(feature = "fmt", test))]
+impl core::fmt::Debug for CmriMessage {
+    fn fmt(
+        &self,
+        fmt: &mut core::fmt::Formatter<'_>,
+    ) -> core::result::Result<(), core::fmt::Error> {
+        fmt.debug_struct("CmriMessage")
+            .field("address", &self.address)
+            .field("message_type", &self.message_type)
+            .field("payload", &self.data())
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+/// Messages are equal if their headers and the used part of their
+/// payloads match; whatever is left in the buffer past `len` is ignored
+impl PartialEq for CmriMessage {
+    fn eq(&self, other: &Self) -> bool {
+        self.address == other.address
+            && self.message_type == other.message_type
+            && self.len == other.len
+            && self.data() == other.data()
+    }
+}
+
+impl Eq for CmriMessage {}
+
+impl core::hash::Hash for CmriMessage {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        self.address.hash(state);
+        self.message_type.hash(state);
+        self.len.hash(state);
+        self.data().hash(state);
+    }
 }
 
 impl CmriStateMachine {
@@ -205,6 +853,25 @@ impl CmriStateMachine {
             state: CmriState::Idle,
             message: CmriMessage::new(),
             address_filter: None,
+            broadcast: None,
+            accept_unknown_types: false,
+            noise: stats::NoiseHistory::default(),
+            check_payload_lengths: true,
+            stats: Stats::default(),
+            frame_bytes: 0,
+            last_reset: None,
+            last_error: None,
+            head: [0; quarantine::QUARANTINE_BYTES],
+            quarantine: Quarantine::default(),
+            now: 0,
+            sync_guard: SyncGuard::Off,
+            synced: false,
+            last_byte_at: None,
+            preamble_run: 0,
+            #[cfg(feature = "raw-capture")]
+            raw: RawFrame::new(),
+            #[cfg(feature = "state-trace")]
+            transitions: transitions::TransitionLog::default(),
         }
     }
 
@@ -215,8 +882,31 @@ impl CmriStateMachine {
 
     /// Sets an address filter so that the state machine will only
     /// accept messages targeted at us
-    pub fn filter(&mut self, addr: u8) {
-        self.address_filter = Some(addr);
+    pub fn filter(&mut self, addr: Address) -> Result<()> {
+        self.address_filter = Some(addr.wire()?);
+        Ok(())
+    }
+
+    /// Also accept messages sent to this broadcast address when a filter
+    /// is set. `None` stops accepting broadcasts
+    pub fn accept_broadcast(&mut self, addr: Option<Address>) -> Result<()> {
+        self.broadcast = addr.map(Address::wire).transpose()?;
+        Ok(())
+    }
+
+    /// Decode frames whose type byte isn't recognised as
+    /// `MessageType::Unknown` instead of discarding them, so that a
+    /// monitor can show them
+    pub fn accept_unknown_types(&mut self, accept: bool) {
+        self.accept_unknown_types = accept;
+    }
+
+    /// Whether a frame whose payload is shorter than
+    /// `MessageType::min_payload_len()` gives
+    /// `Error::PayloadLengthInvalid` rather than being delivered. On by
+    /// default; turn it off to see such frames, e.g. in a bus monitor
+    pub fn check_payload_lengths(&mut self, check: bool) {
+        self.check_payload_lengths = check;
     }
 
     /// Gets a reference to the decoded message
@@ -227,20 +917,264 @@ impl CmriStateMachine {
     pub fn clear(&mut self) {
         self.message.clear();
         self.state = CmriState::Idle;
+        self.frame_bytes = 0;
+    }
+
+    /// The frame currently being received, if any. Useful for seeing how
+    /// far a frame got before a node went quiet
+    pub fn partial_message(&self) -> Option<&CmriMessage> {
+        match self.state {
+            CmriState::Idle => None,
+            _ => Some(&self.message),
+        }
+    }
+
+    /// Number of bytes received so far in the current frame, including
+    /// the preamble, start, address and type bytes
+    pub fn bytes_in_frame(&self) -> usize {
+        self.frame_bytes
+    }
+
+    /// True part way through a frame, for deciding whether a read
+    /// timeout means a quiet bus or a frame cut short
+    pub fn in_frame(&self) -> bool {
+        self.state != CmriState::Idle
+    }
+
+    /// Fewest further bytes which could complete a frame. Only a lower
+    /// bound, since the payload length isn't known until the stop byte
+    pub fn bytes_needed(&self) -> usize {
+        use CmriState::*;
+        let payload = match self.message.message_type {
+            Some(t) if self.check_payload_lengths => {
+                t.min_payload_len().saturating_sub(self.message.len)
+            }
+            _ => 0,
+        };
+        match self.state {
+            // Two preambles, start, address, type and stop
+            Idle => 6,
+            Attn => 5,
+            Start => 4,
+            Addr => 3,
+            Type => 2,
+            Data => payload + 1,
+            // The escaped byte counts towards the payload
+            Escape => payload.max(1) + 1,
+        }
+    }
+
+    /// Why the last partial frame was thrown away
+    pub fn last_reset(&self) -> Option<ResetReason> {
+        self.last_reset
+    }
+
+    /// The last error returned by `process()`
+    pub fn last_error(&self) -> Option<&Error> {
+        self.last_error.as_ref()
+    }
+
+    /// Decoding counters since creation or the last `reset_stats()`
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Also forgets the recent inter-frame noise
+    pub fn reset_stats(&mut self) {
+        self.stats.reset();
+        self.noise = stats::NoiseHistory::default();
+    }
+
+    /// Up to the last `NOISE_HISTORY_LEN` bytes ignored between frames,
+    /// oldest first. Along with `Stats::bytes_discarded` this shows
+    /// whether a bus which isn't delivering frames is silent or noisy
+    pub fn recent_noise(&self) -> impl Iterator<Item = u8> + '_ {
+        self.noise.iter()
+    }
+
+    /// Frames which recently failed to decode, as received
+    pub fn quarantine(&self) -> &Quarantine {
+        &self.quarantine
+    }
+
+    pub fn clear_quarantine(&mut self) {
+        self.quarantine.clear();
+    }
+
+    /// Sets the time of the bytes about to be processed, in ticks of the
+    /// caller's choosing, e.g. from a `Clock`. It is stamped on frames
+    /// put into quarantine and used by `SyncGuard::IdleTime`
+    pub fn set_time(&mut self, now: u64) {
+        self.now = now;
+    }
+
+    /// Sets how cautiously to regain sync after noise. Off by default
+    pub fn sync_guard(&mut self, guard: SyncGuard) {
+        self.sync_guard = guard;
+    }
+
+    /// Whether a preamble byte received while idle may start a frame
+    fn preamble_allowed(&self) -> bool {
+        match self.sync_guard {
+            SyncGuard::Off => true,
+            _ if self.synced => true,
+            SyncGuard::IdleTime(gap) => self
+                .last_byte_at
+                .is_none_or(|at| self.now.saturating_sub(at) >= gap),
+            // The byte after this one completes the preamble
+            SyncGuard::PreambleRun(len) => self.preamble_run + 2 >= len,
+        }
+    }
+
+    /// Notes the time and any run of 0xFF, after bytes have been processed
+    fn bytes_seen(&mut self, bytes: &[u8]) {
+        let run = bytes.iter().rev().take_while(|b| **b == CMRI_PREAMBLE_BYTE);
+        let run = run.count().min(u8::MAX as usize) as u8;
+        self.preamble_run = if usize::from(run) == bytes.len() {
+            self.preamble_run.saturating_add(run)
+        } else {
+            run
+        };
+        self.last_byte_at = Some(self.now);
+    }
+
+    /// Abandon a partially received frame
+    fn resync<E: ProtocolEvents>(
+        &mut self,
+        reason: DiscardReason,
+        events: &mut E,
+    ) {
+        stats::bump(&mut self.stats.resyncs);
+        events.on_discard(reason);
+        self.last_reset = ResetReason::from_discard(reason);
+        self.synced = false;
+        // A missing preamble or start byte is usually just line noise
+        if reason == DiscardReason::BadType {
+            self.quarantine_frame(ResetReason::BadType);
+        }
+        self.clear();
+    }
+
+    /// Abandon a partially received frame because of an error
+    fn fail<E: ProtocolEvents>(&mut self, e: Error, events: &mut E) -> Error {
+        stats::bump(&mut self.stats.resyncs);
+        events.on_error(&e);
+        let reason = match e {
+            Error::DataTooLong => ResetReason::Overflow,
+            _ => ResetReason::Error,
+        };
+        self.last_reset = Some(reason);
+        self.last_error = Some(e.clone());
+        self.synced = false;
+        self.quarantine_frame(reason);
+        self.clear();
+        e
+    }
+
+    /// Keeps the start of the frame being dropped, including the byte
+    /// just received
+    fn quarantine_frame(&mut self, reason: ResetReason) {
+        let len = self.frame_bytes + 1;
+        self.quarantine.record(self.now, reason, &self.head, len);
     }
 
     /// Main process function. Takes in bytes off the wire and builds up
     /// a message in the receive buffer
     pub fn process(&mut self, byte: u8) -> Result<RxState> {
+        self.process_with_events(byte, &mut NoEvents)
+    }
+
+    /// As `process()`, but calls the given hooks as frames are decoded
+    pub fn process_with_events<E: ProtocolEvents>(
+        &mut self,
+        byte: u8,
+        events: &mut E,
+    ) -> Result<RxState> {
+        #[cfg(feature = "raw-capture")]
+        let starting = self.state == CmriState::Idle;
+        #[cfg(feature = "state-trace")]
+        let from = self.state;
+        if let Some(slot) = self.head.get_mut(self.frame_bytes) {
+            *slot = byte;
+        }
+        let res = self.step(byte, events);
+        self.bytes_seen(&[byte]);
+        #[cfg(feature = "raw-capture")]
+        self.capture(byte, starting, &res);
+        #[cfg(feature = "state-trace")]
+        self.transitions.record(from, self.state, byte);
+        if self.state == CmriState::Idle {
+            self.frame_bytes = 0;
+        } else {
+            self.frame_bytes += 1;
+        }
+        res
+    }
+
+    /// Keeps a copy of each byte of a frame, from its first preamble on
+    #[cfg(feature = "raw-capture")]
+    fn capture(&mut self, byte: u8, starting: bool, res: &Result<RxState>) {
+        if starting {
+            if self.state == CmriState::Idle {
+                // Line noise between frames
+                return;
+            }
+            self.raw.start();
+        }
+        self.raw.extend(&[byte]);
+        self.raw.complete = *res == Ok(RxState::Complete);
+    }
+
+    /// The bytes of the last complete frame as received, including the
+    /// preamble, escapes and stop byte. `None` once the next frame has
+    /// started, or if the frame was too long to keep
+    #[cfg(feature = "raw-capture")]
+    pub fn raw_frame(&self) -> Option<&[u8]> {
+        self.raw.frame()
+    }
+
+    /// Calls `hook` on every change of state from now on, or stops
+    /// calling it if `None`
+    #[cfg(feature = "state-trace")]
+    pub fn transition_hook(&mut self, hook: Option<TransitionHook>) {
+        self.transitions.hook = hook;
+    }
+
+    /// Up to the last `TRANSITION_LOG_LEN` changes of state, oldest
+    /// first. Payload bytes which leave the state machine in `Data`
+    /// aren't changes and don't appear
+    #[cfg(feature = "state-trace")]
+    pub fn transitions(&self) -> impl Iterator<Item = Transition> + '_ {
+        self.transitions.iter()
+    }
+
+    #[cfg(feature = "state-trace")]
+    pub fn clear_transitions(&mut self) {
+        self.transitions.clear();
+    }
+
+    fn step<E: ProtocolEvents>(
+        &mut self,
+        byte: u8,
+        events: &mut E,
+    ) -> Result<RxState> {
         use CmriState::*;
         match self.state {
             Idle => {
                 // Idle to Attn if byte is PREAMBLE
-                if byte == CMRI_PREAMBLE_BYTE {
+                if byte == CMRI_PREAMBLE_BYTE && self.preamble_allowed() {
                     self.clear();
                     self.state = Attn;
+                } else {
+                    // Ignore other bytes while Idle
+                    if byte == CMRI_PREAMBLE_BYTE {
+                        stats::bump(&mut self.stats.preambles_ignored);
+                    }
+                    stats::bump(&mut self.stats.bytes_discarded);
+                    self.noise.record(byte);
+                    events.on_discard(DiscardReason::Idle);
+                    self.synced = false;
                 }
-                // Ignore other bytes while Idle
             }
             Attn => {
                 // Attn to Start if byte is PREAMBLE
@@ -248,24 +1182,32 @@ impl CmriStateMachine {
                     self.state = Start;
                 } else {
                     // Otherwise discard and reset to Idle
-                    self.clear();
+                    self.resync(DiscardReason::BadPreamble, events);
                 }
             }
             Start => {
                 // start byte must be valid
                 if byte == CMRI_START_BYTE {
                     self.state = Addr;
+                    events.on_frame_start();
+                } else if byte == CMRI_PREAMBLE_BYTE
+                    && matches!(self.sync_guard, SyncGuard::PreambleRun(_))
+                {
+                    // Longer preambles are expected
                 } else {
                     // Otherwise discard and reset to Idle
-                    self.clear();
+                    self.resync(DiscardReason::BadStart, events);
                 }
             }
             Addr => {
                 // Take the next byte as-is for an address
                 if let Some(addr) = self.address_filter {
                     // A filter has been defined
-                    if addr != byte {
+                    if addr != byte && self.broadcast != Some(byte) {
                         // Not our address, discard the message
+                        stats::bump(&mut self.stats.frames_filtered);
+                        events.on_discard(DiscardReason::Filtered);
+                        self.last_reset = Some(ResetReason::Filtered);
                         self.clear();
                         return Ok(RxState::Listening);
                     }
@@ -276,12 +1218,17 @@ impl CmriStateMachine {
             }
             Type => {
                 // Decode the message type and reset if it is invalid
-                if let Ok(mtype) = MessageType::try_from(byte) {
+                let mtype = if self.accept_unknown_types {
+                    Ok(MessageType::from_lossy(byte))
+                } else {
+                    MessageType::try_from_strict(byte)
+                };
+                if let Ok(mtype) = mtype {
                     self.message.message_type = Some(mtype);
                     self.state = Data;
                 } else {
                     // Invalid message type; reset
-                    self.clear();
+                    self.resync(DiscardReason::BadType, events);
                 }
             }
             Data => {
@@ -290,19 +1237,34 @@ impl CmriStateMachine {
                         // escape the next byte; do not push the escape
                         // byte
                         //self.push(byte)?;
+                        stats::bump(&mut self.stats.escape_bytes);
                         self.state = Escape;
                     }
                     CMRI_STOP_BYTE => {
                         // end transmission
+                        if let Some(t) = self.message.message_type {
+                            let len = self.message.len;
+                            if self.check_payload_lengths
+                                && len < t.min_payload_len()
+                            {
+                                let e = Error::PayloadLengthInvalid {
+                                    mtype: t,
+                                    len,
+                                };
+                                return Err(self.fail(e, events));
+                            }
+                            self.stats.count_message(t);
+                        }
                         self.state = Idle;
+                        self.synced = true;
+                        events.on_frame_complete(&self.message);
                         return Ok(RxState::Complete);
                     }
                     _ => {
                         // any other byte we take as data
                         if let Err(e) = self.message.push(byte) {
                             // Reset the state machine so that we can start afresh
-                            self.clear();
-                            return Err(e);
+                            return Err(self.fail(e, events));
                         }
                     }
                 }
@@ -311,14 +1273,96 @@ impl CmriStateMachine {
                 // Escape the next byte, so accept it as data.
                 if let Err(e) = self.message.push(byte) {
                     // Error writing message -> reset state machine
-                    self.clear();
-                    return Err(e);
+                    return Err(self.fail(e, events));
                 }
                 self.state = Data;
             }
         }
         Ok(RxState::Listening)
     }
+
+    /// Processes a buffer of bytes, stopping early when a message is
+    /// complete or an error occurs. Returns the number of bytes consumed
+    /// along with the result from the last of them, so call it again
+    /// with the rest of the buffer to carry on. Runs of payload bytes are
+    /// copied in one go, which is much faster than `process()` for large
+    /// payloads.
+    pub fn process_buf(&mut self, buf: &[u8]) -> (usize, Result<RxState>) {
+        self.process_buf_with_events(buf, &mut NoEvents)
+    }
+
+    /// As `process_buf()`, but calls the given hooks as frames are
+    /// decoded
+    pub fn process_buf_with_events<E: ProtocolEvents>(
+        &mut self,
+        buf: &[u8],
+        events: &mut E,
+    ) -> (usize, Result<RxState>) {
+        let mut pos = 0;
+        while let Some(rest) = buf.get(pos..) {
+            let byte = match rest.first() {
+                Some(byte) => *byte,
+                None => break,
+            };
+            match self.state {
+                CmriState::Data => {
+                    // Copy everything up to the next control byte, as
+                    // long as it fits. Anything else goes byte by byte
+                    let run = rest
+                        .iter()
+                        .position(|b| needs_escape(*b))
+                        .unwrap_or(rest.len());
+                    let len = self.message.len;
+                    let dst = self.message.payload.get_mut(len..len + run);
+                    if let (true, Some(dst), Some(src)) =
+                        (run > 0, dst, rest.get(..run))
+                    {
+                        dst.copy_from_slice(src);
+                        #[cfg(feature = "raw-capture")]
+                        self.raw.extend(src);
+                        let head = self.head.iter_mut().skip(self.frame_bytes);
+                        head.zip(src).for_each(|(slot, b)| *slot = *b);
+                        self.bytes_seen(src);
+                        self.message.len += run;
+                        self.frame_bytes += run;
+                        pos += run;
+                        continue;
+                    }
+                }
+                CmriState::Idle => {
+                    // Skip line noise up to the next preamble
+                    let run = rest
+                        .iter()
+                        .position(|b| *b == CMRI_PREAMBLE_BYTE)
+                        .unwrap_or(rest.len());
+                    if run > 0 {
+                        self.stats.bytes_discarded =
+                            self.stats.bytes_discarded.wrapping_add(run as u32);
+                        rest.iter()
+                            .take(run)
+                            .for_each(|b| self.noise.record(*b));
+                        (0..run).for_each(|_| {
+                            events.on_discard(DiscardReason::Idle)
+                        });
+                        if let Some(noise) = rest.get(..run) {
+                            self.bytes_seen(noise);
+                        }
+                        self.synced = false;
+                        pos += run;
+                        continue;
+                    }
+                }
+                _ => {}
+            }
+
+            let res = self.process_with_events(byte, events);
+            pos += 1;
+            if res != Ok(RxState::Listening) {
+                return (pos, res);
+            }
+        }
+        (pos, Ok(RxState::Listening))
+    }
 }
 
 impl Default for CmriStateMachine {
@@ -500,6 +1544,44 @@ mod test {
         assert_eq!(s.state, Idle);
     }
 
+    #[test]
+    fn unit_addresses() {
+        let msg = CmriMessage::from_ua(2).unwrap();
+        assert_eq!(msg.address, Some(0x43));
+        assert_eq!(msg.to_ua(), Some(2));
+        assert!(CmriMessage::from_ua(MAX_UA + 1).is_err());
+        assert_eq!(CmriMessage::new().to_ua(), None);
+
+        let mut s = CmriStateMachine::new();
+        assert_eq!(s.filter(Address::Wire(0x20)), Err(Error::OutOfBounds));
+        s.filter(Address::Ua(2)).unwrap();
+        assert_eq!(s.address_filter, Some(0x43));
+    }
+
+    #[test]
+    fn broadcast_passes_filter() {
+        let frame = |addr| {
+            let mut buf = [0_u8; TX_BUFFER_LEN];
+            let msg = MessageBuilder::set(addr, &[1]).build().unwrap();
+            let len = msg.encode(&mut buf).unwrap();
+            (buf, len)
+        };
+        let mut s = CmriStateMachine::new();
+        s.filter(Address::Ua(5)).unwrap();
+        s.accept_broadcast(Some(Address::BROADCAST)).unwrap();
+        for (addr, expected) in
+            [(0x41, true), (0x46, true), (0x47, false)].iter()
+        {
+            let (buf, len) = frame(*addr);
+            let res = s.process_buf(&buf[..len]).1;
+            assert_eq!(res == Ok(Complete), *expected);
+        }
+
+        s.accept_broadcast(None).unwrap();
+        let (buf, len) = frame(0x41);
+        assert_eq!(s.process_buf(&buf[..len]).1, Ok(Listening));
+    }
+
     #[test]
     fn address_filter() {
         // Initial check to see that no-filter works
@@ -508,7 +1590,7 @@ mod test {
 
         // Make a state machine with a filter
         let mut s = CmriStateMachine::new();
-        s.filter(0x64);
+        s.filter(Address::Wire(0x64)).unwrap();
 
         // Send the same address
         s.process(CMRI_PREAMBLE_BYTE).unwrap();
@@ -520,7 +1602,7 @@ mod test {
 
         // Make a new state machine
         let mut s = CmriStateMachine::new();
-        s.filter(0x64);
+        s.filter(Address::Wire(0x64)).unwrap();
 
         assert!(s.address_filter.is_some());
 
@@ -542,7 +1624,7 @@ mod test {
             CMRI_PREAMBLE_BYTE,
             CMRI_START_BYTE,
             0x86, // Address
-            Init as u8, // Type
+            u8::from(Init), // Type
             0x41, 0x41, 0x41, 0x41, // Message
             CMRI_STOP_BYTE,
         ];
@@ -553,7 +1635,7 @@ mod test {
             CMRI_PREAMBLE_BYTE,
             CMRI_START_BYTE,
             0xa2, // Address
-            Init as u8, // Type
+            u8::from(Init), // Type
             0x41, 0x41, 0x41, 0x41, // Message
             CMRI_STOP_BYTE,
         ];
@@ -571,7 +1653,7 @@ mod test {
         assert_eq!(m.payload[..(m.len)], [0x41, 0x41, 0x41, 0x41]);
 
         // Enable a filter
-        s.filter(0x86);
+        s.filter(Address::Wire(0x86)).unwrap();
         // Decode the message, excluding final stop byte
         for byte in message[..message.len() - 1].iter() {
             s.process(*byte).unwrap();
@@ -594,6 +1676,396 @@ mod test {
         assert_eq!(m.len, 0);
     }
 
+    #[test]
+    fn decode_stats() {
+        let mut s = CmriStateMachine::new();
+        s.filter(Address::Wire(0x41)).unwrap();
+
+        // Junk while idle, then a broken preamble
+        for byte in [0x01, 0x02, CMRI_PREAMBLE_BYTE, 0x03].iter() {
+            s.process(*byte).unwrap();
+        }
+        assert_eq!(s.stats().bytes_discarded, 2);
+        assert_eq!(s.stats().resyncs, 1);
+        assert!(s.recent_noise().eq([0x01, 0x02].iter().copied()));
+
+        // A message for someone else
+        for byte in
+            [CMRI_PREAMBLE_BYTE, CMRI_PREAMBLE_BYTE, CMRI_START_BYTE].iter()
+        {
+            s.process(*byte).unwrap();
+        }
+        s.process(0x42).unwrap();
+        assert_eq!(s.stats().frames_filtered, 1);
+
+        // A message for us with an escaped byte
+        #[rustfmt::skip]
+        let message = [
+            CMRI_PREAMBLE_BYTE,
+            CMRI_PREAMBLE_BYTE,
+            CMRI_START_BYTE,
+            0x41,
+            u8::from(Set),
+            CMRI_ESCAPE_BYTE, CMRI_STOP_BYTE,
+            CMRI_STOP_BYTE,
+        ];
+        for byte in message.iter() {
+            s.process(*byte).unwrap();
+        }
+        assert_eq!(s.stats().frames_decoded, 1);
+        assert_eq!(s.stats().escape_bytes, 1);
+        assert_eq!(s.stats().message_count(Set), 1);
+        assert_eq!(s.stats().message_count(Poll), 0);
+
+        s.reset_stats();
+        assert_eq!(*s.stats(), Stats::default());
+    }
+
+    #[test]
+    fn inter_frame_noise() {
+        let mut s = CmriStateMachine::new();
+        assert_eq!(s.recent_noise().count(), 0);
+
+        let noise: std::vec::Vec<u8> = (1..=20).collect();
+        assert_eq!(s.process_buf(&noise), (20, Ok(RxState::Listening)));
+        for byte in noise.iter() {
+            s.process(*byte).unwrap();
+        }
+        assert!(s.recent_noise().eq(13..=20));
+        assert_eq!(s.stats().bytes_discarded, 40);
+
+        s.reset_stats();
+        assert_eq!(s.recent_noise().count(), 0);
+    }
+
+    #[test]
+    fn partial_frame_diagnostics() {
+        let mut s = CmriStateMachine::new();
+        assert!(s.partial_message().is_none());
+
+        for byte in
+            [CMRI_PREAMBLE_BYTE, CMRI_PREAMBLE_BYTE, CMRI_START_BYTE].iter()
+        {
+            s.process(*byte).unwrap();
+        }
+        s.process(0x41).unwrap();
+        s.process(u8::from(Set)).unwrap();
+        assert_eq!(s.bytes_needed(), 2);
+        s.process(0x01).unwrap();
+        assert_eq!(s.bytes_in_frame(), 6);
+        assert!(s.in_frame());
+        assert_eq!(s.bytes_needed(), 1);
+        let partial = s.partial_message().unwrap();
+        assert_eq!(partial.address, Some(0x41));
+        assert_eq!(partial.payload[..partial.len], [0x01]);
+
+        // Too much payload
+        for _ in 0..MAX_PAYLOAD_LEN {
+            let _ = s.process(0x01);
+        }
+        assert_eq!(s.last_reset(), Some(ResetReason::Overflow));
+        assert_eq!(s.last_error(), Some(&Error::DataTooLong));
+        assert_eq!(s.bytes_in_frame(), 0);
+        assert!(s.partial_message().is_none());
+        assert!(!s.in_frame());
+        assert_eq!(s.bytes_needed(), 6);
+
+        // Bad start byte
+        for byte in [CMRI_PREAMBLE_BYTE, CMRI_PREAMBLE_BYTE, 0x00].iter() {
+            s.process(*byte).unwrap();
+        }
+        assert_eq!(s.last_reset(), Some(ResetReason::BadStart));
+        // Errors stick around until the next one
+        assert_eq!(s.last_error(), Some(&Error::DataTooLong));
+    }
+
+    #[test]
+    fn quarantine_bad_frames() {
+        const HEADER: [u8; 4] = [
+            CMRI_PREAMBLE_BYTE,
+            CMRI_PREAMBLE_BYTE,
+            CMRI_START_BYTE,
+            0x41,
+        ];
+        for bulk in [false, true].iter() {
+            let mut s = CmriStateMachine::new();
+            s.set_time(100);
+            // Bad type byte, after some noise and a frame which decodes
+            let mut bytes = std::vec![0x00, 0x12];
+            bytes.extend_from_slice(&HEADER);
+            bytes.extend_from_slice(&[u8::from(Poll), CMRI_STOP_BYTE]);
+            bytes.extend_from_slice(&HEADER);
+            bytes.push(b'Q');
+            decode_all(&mut s, &bytes, *bulk);
+            let frame = *s.quarantine().last().unwrap();
+            assert_eq!((frame.at, frame.reason), (100, ResetReason::BadType));
+            assert_eq!(frame.bytes(), [&HEADER[..], b"Q"].concat());
+
+            // Overflow, of which only the start is kept
+            s.set_time(200);
+            let mut bytes = HEADER.to_vec();
+            bytes.push(u8::from(Set));
+            bytes.extend((0..=MAX_PAYLOAD_LEN).map(|n| n as u8 | 0x40));
+            decode_all(&mut s, &bytes, *bulk);
+            let frame = *s.quarantine().last().unwrap();
+            assert_eq!((frame.at, frame.reason), (200, ResetReason::Overflow));
+            assert_eq!(frame.len, 5 + MAX_PAYLOAD_LEN + 1);
+            assert!(frame.truncated());
+            assert_eq!(frame.bytes()[..5], bytes[..5]);
+            assert_eq!(frame.bytes(), &bytes[..quarantine::QUARANTINE_BYTES]);
+
+            // Noise and a missing start byte aren't kept
+            decode_all(&mut s, &[0xff, 0xff, 0x00, 0x12], *bulk);
+            assert_eq!(s.quarantine().len(), 2);
+            s.clear_quarantine();
+            assert!(s.quarantine().is_empty());
+        }
+    }
+
+    #[test]
+    fn sync_guard() {
+        // Joining the bus part way through a Set whose payload ends with
+        // something that looks like a Poll to UA 0
+        const TAIL: [u8; 7] = [0x12, 0xff, 0xff, 0x02, 0x41, b'P', 0x03];
+        const POLL: [u8; 6] = [0xff, 0xff, 0x02, 0x41, b'P', 0x03];
+        for bulk in [false, true].iter() {
+            let mut s = CmriStateMachine::new();
+            assert_eq!(decode_all(&mut s, &TAIL, *bulk), [Ok(std::vec![])]);
+
+            // The false preamble comes straight after other bytes
+            let mut s = CmriStateMachine::new();
+            s.sync_guard(SyncGuard::IdleTime(1000));
+            s.set_time(0);
+            assert!(decode_all(&mut s, &TAIL, *bulk).is_empty());
+            assert_eq!(s.stats().preambles_ignored, 2);
+            s.set_time(5000);
+            assert_eq!(decode_all(&mut s, &POLL, *bulk).len(), 1);
+            // Back in sync, so frames can follow straight on
+            assert_eq!(decode_all(&mut s, &POLL, *bulk).len(), 1);
+
+            let mut s = CmriStateMachine::new();
+            s.sync_guard(SyncGuard::PreambleRun(3));
+            assert!(decode_all(&mut s, &TAIL, *bulk).is_empty());
+            let long = [&[0xff][..], &POLL].concat();
+            assert_eq!(decode_all(&mut s, &long, *bulk).len(), 1);
+            assert_eq!(decode_all(&mut s, &POLL, *bulk).len(), 1);
+            // Noise loses sync again
+            let noisy = [&[0x00][..], &POLL].concat();
+            assert!(decode_all(&mut s, &noisy, *bulk).is_empty());
+            assert_eq!(decode_all(&mut s, &long, *bulk).len(), 1);
+        }
+    }
+
+    /// Runs a stream through the state machine, returning every
+    /// completed message and error
+    fn decode_all(
+        s: &mut CmriStateMachine,
+        bytes: &[u8],
+        bulk: bool,
+    ) -> std::vec::Vec<Result<std::vec::Vec<u8>>> {
+        let mut out = std::vec::Vec::new();
+        let mut record =
+            |res: Result<RxState>, s: &CmriStateMachine| match res {
+                Ok(Complete) => out
+                    .push(Ok(s.message().payload[..s.message().len].to_vec())),
+                Ok(Listening) => {}
+                Err(e) => out.push(Err(e)),
+            };
+        if bulk {
+            let mut buf = bytes;
+            while !buf.is_empty() {
+                let (n, res) = s.process_buf(buf);
+                buf = &buf[n..];
+                record(res, s);
+            }
+        } else {
+            for byte in bytes.iter() {
+                let res = s.process(*byte);
+                record(res, s);
+            }
+        }
+        out
+    }
+
+    #[cfg(feature = "raw-capture")]
+    #[test]
+    fn capture_raw_frames() {
+        let m = MessageBuilder::set(0x41, &[0x01, CMRI_STOP_BYTE, 0x02, 0x04])
+            .build()
+            .unwrap();
+        let mut frame = [0_u8; TX_BUFFER_LEN];
+        let len = m.encode(&mut frame).unwrap();
+        let frame = &frame[..len];
+        let mut bytes = std::vec![0x00, 0x55];
+        bytes.extend_from_slice(frame);
+
+        let mut s = CmriStateMachine::new();
+        for byte in bytes.iter() {
+            assert_eq!(s.raw_frame(), None);
+            s.process(*byte).unwrap();
+        }
+        assert_eq!(s.raw_frame(), Some(frame));
+        // Noise between frames leaves it alone
+        s.process(0x00).unwrap();
+        assert_eq!(s.raw_frame(), Some(frame));
+        s.process(CMRI_PREAMBLE_BYTE).unwrap();
+        assert_eq!(s.raw_frame(), None);
+
+        // Payload runs copied by process_buf are captured too
+        let mut s = CmriStateMachine::new();
+        let mut buf = &bytes[..];
+        while !buf.is_empty() {
+            let (n, _) = s.process_buf(buf);
+            buf = &buf[n..];
+        }
+        assert_eq!(s.raw_frame(), Some(frame));
+    }
+
+    #[cfg(feature = "state-trace")]
+    #[test]
+    fn state_transitions() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        use CmriState::*;
+
+        static HOOK_CALLS: AtomicUsize = AtomicUsize::new(0);
+        fn count(_: CmriState, _: CmriState, _: u8) {
+            HOOK_CALLS.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let mut s = CmriStateMachine::new();
+        s.transition_hook(Some(count));
+        // Noise, then a Set with an escaped payload byte
+        for byte in [0x00, 0xff, 0xff, 0x02, 0x41, b'T', 0x10, 0x03, 0x03] {
+            s.process(byte).unwrap();
+        }
+        let seen: std::vec::Vec<_> =
+            s.transitions().map(|t| (t.from, t.to, t.byte)).collect();
+        assert_eq!(
+            seen,
+            [
+                (Idle, Attn, 0xff),
+                (Attn, Start, 0xff),
+                (Start, Addr, 0x02),
+                (Addr, Type, 0x41),
+                (Type, Data, b'T'),
+                (Data, Escape, 0x10),
+                (Escape, Data, 0x03),
+                (Data, Idle, 0x03),
+            ]
+        );
+        assert_eq!(HOOK_CALLS.load(Ordering::Relaxed), 8);
+
+        // Only the newest are kept
+        s.transition_hook(None);
+        for _ in 0..transitions::TRANSITION_LOG_LEN {
+            s.process(0xff).unwrap();
+            s.process(0x00).unwrap();
+        }
+        assert_eq!(s.transitions().count(), transitions::TRANSITION_LOG_LEN);
+        assert_eq!(
+            s.transitions().last(),
+            Some(Transition {
+                from: Attn,
+                to: Idle,
+                byte: 0x00
+            })
+        );
+        assert_eq!(HOOK_CALLS.load(Ordering::Relaxed), 8);
+        s.clear_transitions();
+        assert_eq!(s.transitions().count(), 0);
+    }
+
+    #[test]
+    fn process_buf_matches_process() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1313);
+
+        let mut bytes = std::vec::Vec::new();
+        let mut tx_buffer = [0_u8; TX_BUFFER_LEN];
+        for _ in 0..200 {
+            // Noise, then a frame with a random payload which will often
+            // contain bytes that need escaping
+            for _ in 0..rng.gen_range(0..4) {
+                bytes.push(rng.gen());
+            }
+            let mut m = CmriMessage::new();
+            let len = rng.gen_range(0..20);
+            let payload: std::vec::Vec<u8> =
+                (0..len).map(|_| rng.gen_range(0..20)).collect();
+            m.address(0x41).message_type(Set).payload(&payload).unwrap();
+            let len = m.encode(&mut tx_buffer).unwrap();
+            bytes.extend_from_slice(&tx_buffer[..len]);
+        }
+        // An overlong payload, followed by a good frame
+        bytes.extend_from_slice(&[
+            CMRI_PREAMBLE_BYTE,
+            CMRI_PREAMBLE_BYTE,
+            CMRI_START_BYTE,
+            0x41,
+            u8::from(Set),
+        ]);
+        bytes.extend_from_slice(&[0x55; MAX_PAYLOAD_LEN + 10]);
+        let len = MessageBuilder::poll(0x41)
+            .build()
+            .unwrap()
+            .encode(&mut tx_buffer)
+            .unwrap();
+        bytes.extend_from_slice(&tx_buffer[..len]);
+
+        // Some of the random payloads are empty
+        let mut bytewise = CmriStateMachine::new();
+        bytewise.check_payload_lengths(false);
+        let mut bulk = CmriStateMachine::new();
+        bulk.check_payload_lengths(false);
+        let expected = decode_all(&mut bytewise, &bytes, false);
+        let actual = decode_all(&mut bulk, &bytes, true);
+        assert_eq!(actual, expected);
+        assert_eq!(bulk.stats(), bytewise.stats());
+        assert_eq!(bulk.stats().frames_decoded, 201);
+        assert!(actual.contains(&Err(Error::DataTooLong)));
+    }
+
+    #[test]
+    fn protocol_events() {
+        #[derive(Default)]
+        struct Recorder {
+            starts: usize,
+            completes: usize,
+            discards: std::vec::Vec<DiscardReason>,
+        }
+        impl ProtocolEvents for Recorder {
+            fn on_frame_start(&mut self) {
+                self.starts += 1;
+            }
+            fn on_frame_complete(&mut self, message: &CmriMessage) {
+                assert_eq!(message.address, Some(0x41));
+                self.completes += 1;
+            }
+            fn on_discard(&mut self, reason: DiscardReason) {
+                self.discards.push(reason);
+            }
+        }
+
+        #[rustfmt::skip]
+        let bytes = [
+            0x00, // noise
+            CMRI_PREAMBLE_BYTE, CMRI_PREAMBLE_BYTE, 0x00, // bad start
+            CMRI_PREAMBLE_BYTE, CMRI_PREAMBLE_BYTE, CMRI_START_BYTE,
+            0x41, u8::from(Poll), CMRI_STOP_BYTE,
+        ];
+        let mut s = CmriStateMachine::new();
+        let mut events = Recorder::default();
+        for byte in bytes.iter() {
+            s.process_with_events(*byte, &mut events).unwrap();
+        }
+        assert_eq!(events.starts, 1);
+        assert_eq!(events.completes, 1);
+        assert_eq!(
+            events.discards,
+            [DiscardReason::Idle, DiscardReason::BadStart]
+        );
+    }
+
     #[test]
     fn buffer_overrun() {
         let mut s = CmriStateMachine::new();
@@ -621,7 +2093,8 @@ mod test {
         };
 
         let mut tx_buffer = [0_u8; TX_BUFFER_LEN];
-        m.encode(&mut tx_buffer).unwrap();
+        let len = m.encode(&mut tx_buffer).unwrap();
+        assert_eq!(len, 9);
 
         assert_eq!(
             tx_buffer[..9],
@@ -629,8 +2102,8 @@ mod test {
                 CMRI_PREAMBLE_BYTE,
                 CMRI_PREAMBLE_BYTE,
                 CMRI_START_BYTE,
-                0x58,      // Address
-                Set as u8, // Type
+                0x58,          // Address
+                u8::from(Set), // Type
                 0x41,
                 0x41,
                 0x43,
@@ -640,7 +2113,298 @@ mod test {
     }
 
     #[test]
-    fn encode_a_worst_case_message() {}
+    fn encode_a_worst_case_message() {
+        // Every payload byte needs escaping, so the encoded frame fills
+        // the whole transmit buffer
+        let mut m = CmriMessage::new();
+        m.address(0x41).message_type(Set);
+        for n in 0..MAX_PAYLOAD_LEN {
+            let byte = if n % 2 == 0 {
+                CMRI_STOP_BYTE
+            } else {
+                CMRI_ESCAPE_BYTE
+            };
+            m.push(byte).unwrap();
+        }
+
+        let mut tx_buffer = [0_u8; TX_BUFFER_LEN];
+        let len = m.encode(&mut tx_buffer).unwrap();
+        assert_eq!(len, TX_BUFFER_LEN);
+        assert_eq!(tx_buffer[5..9], [0x10, 0x03, 0x10, 0x10]);
+        assert_eq!(tx_buffer[TX_BUFFER_LEN - 1], CMRI_STOP_BYTE);
+
+        // And it decodes back to the same payload
+        let mut s = CmriStateMachine::new();
+        let mut res = Ok(Listening);
+        for byte in tx_buffer.iter() {
+            res = s.process(*byte);
+        }
+        assert_eq!(res, Ok(Complete));
+        assert_eq!(s.message().len, MAX_PAYLOAD_LEN);
+        assert_eq!(s.message().payload[..], m.payload[..]);
+    }
+
+    #[test]
+    fn full_susic_fits() {
+        // 64 cards at 32 bits each
+        let outputs = [0x55_u8; 64 * 4];
+        let m = MessageBuilder::set(0x41, &outputs).build().unwrap();
+        let mut tx_buffer = [0_u8; TX_BUFFER_LEN];
+        assert_eq!(m.encode(&mut tx_buffer), Ok(64 * 4 + 6));
+    }
+
+    #[test]
+    fn short_payloads() {
+        let empty_set = [0xff, 0xff, 0x02, 0x41, u8::from(Set), CMRI_STOP_BYTE];
+        let mut s = CmriStateMachine::new();
+        let (_, res) = s.process_buf(&empty_set);
+        assert_eq!(
+            res,
+            Err(Error::PayloadLengthInvalid { mtype: Set, len: 0 })
+        );
+        assert_eq!(s.state(), Idle);
+        assert_eq!(s.stats().set_messages, 0);
+
+        // Polls never have a payload
+        let poll = [0xff, 0xff, 0x02, 0x41, u8::from(Poll), CMRI_STOP_BYTE];
+        assert_eq!(s.process_buf(&poll), (poll.len(), Ok(Complete)));
+
+        let short_init = [0xff, 0xff, 0x02, 0x41, u8::from(Init), b'M', 0x03];
+        let (_, res) = s.process_buf(&short_init);
+        assert_eq!(
+            res,
+            Err(Error::PayloadLengthInvalid {
+                mtype: Init,
+                len: 1
+            })
+        );
+
+        s.check_payload_lengths(false);
+        assert_eq!(s.process_buf(&empty_set), (empty_set.len(), Ok(Complete)));
+        assert!(s.message().data().is_empty());
+    }
+
+    #[test]
+    fn unknown_message_types() {
+        assert_eq!(MessageType::try_from(b'P'), Ok(Poll));
+        assert_eq!(
+            MessageType::try_from_strict(b'Q'),
+            Err(Error::InvalidMessageType)
+        );
+        assert_eq!(MessageType::from_lossy(b'Q'), MessageType::Unknown(b'Q'));
+        assert_eq!(u8::from(MessageType::from_lossy(b'Q')), b'Q');
+        assert_eq!(NodeType::from_lossy(b'Z'), NodeType::Unknown(b'Z'));
+        assert_eq!(NodeType::from_lossy(b'M'), NodeType::Smini);
+        assert_eq!(MessageType::Unknown(b'Q').as_char(), 'Q');
+
+        let frame = [0xff, 0xff, 0x02, 0x41, b'Q', 0x01, CMRI_STOP_BYTE];
+        let mut s = CmriStateMachine::new();
+        let (_, res) = s.process_buf(&frame);
+        assert_eq!(res, Ok(Listening));
+        assert_eq!(s.last_reset(), Some(ResetReason::BadType));
+
+        s.accept_unknown_types(true);
+        assert_eq!(s.process_buf(&frame), (frame.len(), Ok(Complete)));
+        let m = s.message();
+        assert_eq!(m.message_type, Some(MessageType::Unknown(b'Q')));
+        assert_eq!(m.data(), [0x01]);
+        assert_eq!(s.stats().unknown_messages, 1);
+
+        // And re-encodes to the same frame
+        let mut buf = [0_u8; TX_BUFFER_LEN];
+        let len = m.encode(&mut buf).unwrap();
+        assert_eq!(buf[..len], frame);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn message_types_from_str() {
+        for t in MessageType::ALL.iter() {
+            assert_eq!(MessageType::try_from(t.as_char() as u8), Ok(*t));
+            assert_eq!(std::format!("{}", t).parse(), Ok(*t));
+        }
+        assert_eq!(Set.as_char(), 'T');
+        assert_eq!("poll".parse(), Ok(Poll));
+        assert_eq!("I".parse(), Ok(Init));
+        assert_eq!("r".parse(), Ok(Get));
+        assert_eq!("Q".parse::<MessageType>(), Err(Error::InvalidMessageType));
+        assert_eq!("".parse::<MessageType>(), Err(Error::InvalidMessageType));
+        assert_eq!("ts".parse::<MessageType>(), Err(Error::InvalidMessageType));
+    }
+
+    #[test]
+    fn encode_a_byte_at_a_time() {
+        let m = MessageBuilder::set(0x41, &[0x01, CMRI_STOP_BYTE, 0x02])
+            .build()
+            .unwrap();
+        let mut buf = [0_u8; TX_BUFFER_LEN];
+        let len = m.encode(&mut buf).unwrap();
+
+        let mut iter = m.encode_iter().unwrap();
+        assert_eq!(iter.len(), len);
+        for (n, expected) in buf[..len].iter().enumerate() {
+            assert_eq!(iter.next(), Some(*expected));
+            assert_eq!(iter.len(), len - n - 1);
+        }
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next(), None);
+
+        assert_eq!(
+            CmriMessage::new().encode_iter().unwrap_err(),
+            Error::MissingAddress
+        );
+    }
+
+    #[test]
+    fn encode_runs_between_escapes() {
+        // Escapes at the start, middle and end, back to back, or not at
+        // all, all come out the same as a byte at a time
+        let payloads: [&[u8]; 5] = [
+            &[0x01, 0x02, 0x04],
+            &[CMRI_ESCAPE_BYTE, 0x01],
+            &[0x01, CMRI_STOP_BYTE],
+            &[0x01, CMRI_STOP_BYTE, CMRI_ESCAPE_BYTE, 0x02, 0x03],
+            &[CMRI_STOP_BYTE; 8],
+        ];
+        for payload in payloads.iter() {
+            let m = MessageBuilder::set(0x41, payload).build().unwrap();
+            let mut buf = [0_u8; TX_BUFFER_LEN];
+            let len = m.encode(&mut buf).unwrap();
+            let bytes: std::vec::Vec<u8> = m.encode_iter().unwrap().collect();
+            assert_eq!(buf[..len], bytes[..]);
+            assert_eq!(len, m.encoded_len());
+        }
+    }
+
+    #[test]
+    fn encode_into_split_buffers() {
+        let m = MessageBuilder::set(0x41, &[0x01, 0x02, 0x03])
+            .build()
+            .unwrap();
+        let mut whole = [0_u8; TX_BUFFER_LEN];
+        let len = m.encode(&mut whole).unwrap();
+
+        // The end and start of a ring buffer
+        let (mut tail, mut head) = ([0_u8; 4], [0_u8; 8]);
+        assert_eq!(m.encode_into_chunks(&mut [&mut tail, &mut head]), Ok(len));
+        assert_eq!(tail[..], whole[..4]);
+        assert_eq!(head[..len - 4], whole[4..len]);
+
+        let (mut tail, mut head) = ([0_u8; 4], [0_u8; 4]);
+        assert_eq!(
+            m.encode_into_chunks(&mut [&mut tail, &mut head]),
+            Err(Error::BufferTooSmall)
+        );
+        assert_eq!(tail, [0; 4]);
+    }
+
+    #[test]
+    fn encode_into_a_tight_buffer() {
+        let m = MessageBuilder::set(0x41, &[0x01, CMRI_STOP_BYTE])
+            .build()
+            .unwrap();
+        assert_eq!(m.encoded_len(), 9);
+
+        let mut buf = [0_u8; 9];
+        assert_eq!(m.encode(&mut buf), Ok(9));
+        assert_eq!(buf[5..], [0x01, CMRI_ESCAPE_BYTE, 0x03, CMRI_STOP_BYTE]);
+
+        let mut buf = [0_u8; 8];
+        assert_eq!(m.encode(&mut buf), Err(Error::BufferTooSmall));
+        assert_eq!(m.encode(&mut []), Err(Error::BufferTooSmall));
+    }
+
+    #[test]
+    fn encode_at_the_transmit_buffer_limit() {
+        let mut m = CmriMessage::new();
+        m.address(0x41).message_type(Set);
+        m.payload(&[CMRI_ESCAPE_BYTE; MAX_PAYLOAD_LEN - 1]).unwrap();
+        let mut buf = [0_u8; TX_BUFFER_LEN];
+        assert_eq!(m.encode(&mut buf), Ok(TX_BUFFER_LEN - 2));
+
+        // One more escaped byte fills the buffer exactly
+        m.push(CMRI_STOP_BYTE).unwrap();
+        assert_eq!(m.encoded_len(), TX_BUFFER_LEN);
+        assert_eq!(m.encode(&mut buf), Ok(TX_BUFFER_LEN));
+        assert_eq!(
+            m.encode(&mut buf[..TX_BUFFER_LEN - 1]),
+            Err(Error::BufferTooSmall)
+        );
+        assert_eq!(m.push(0x01), Err(Error::DataTooLong));
+
+        // Hand-set lengths past the end of the payload are refused
+        // rather than encoded as an empty frame
+        m.len = MAX_PAYLOAD_LEN + 1;
+        assert_eq!(m.encode(&mut buf), Err(Error::DataTooLong));
+        assert_eq!(m.encode_iter().unwrap_err(), Error::DataTooLong);
+        assert_eq!(
+            m.encode_into_chunks(&mut [&mut buf[..]]),
+            Err(Error::DataTooLong)
+        );
+        assert_eq!(m.try_encoded_len(), Err(Error::DataTooLong));
+        m.len = MAX_PAYLOAD_LEN;
+        assert_eq!(m.try_encoded_len(), Ok(TX_BUFFER_LEN));
+        assert_eq!(
+            CmriMessage::new().try_encoded_len(),
+            Err(Error::MissingAddress)
+        );
+    }
+
+    #[test]
+    fn equality_ignores_unused_payload() {
+        use core::hash::{Hash, Hasher};
+        use std::collections::hash_map::DefaultHasher;
+        fn hash(msg: &CmriMessage) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            msg.hash(&mut hasher);
+            hasher.finish()
+        }
+
+        let a = MessageBuilder::set(0x41, &[1, 2]).build().unwrap();
+        let mut b = MessageBuilder::set(0x41, &[1, 2, 3]).build().unwrap();
+        assert_ne!(a, b);
+        b.len = 2;
+        assert_eq!(a, b);
+        assert_eq!(hash(&a), hash(&b));
+
+        b.message_type(Init);
+        assert_ne!(a, b);
+        b.message_type(Set).address(0x42);
+        assert_ne!(a, b);
+    }
+
+    #[cfg(feature = "fmt")]
+    #[test]
+    fn display_message() {
+        use std::format;
+
+        let mut m = CmriMessage::new();
+        m.address(0x42)
+            .message_type(Set)
+            .payload(&[1, 0xab])
+            .unwrap();
+        assert_eq!(format!("{}", m), "UA 1 (0x42) Set len 2: 01 ab");
+
+        let mut m = CmriMessage::new();
+        m.address(0x41)
+            .message_type(Get)
+            .payload(&[0x55; 18])
+            .unwrap();
+        assert_eq!(
+            format!("{:#}", m),
+            "UA 0 (0x41) Get len 18\n\
+             0000: 55 55 55 55 55 55 55 55 55 55 55 55 55 55 55 55\n\
+             0010: 55 55"
+        );
+
+        let m = CmriMessage::new();
+        assert_eq!(format!("{}", m), "UA ? (none) (no type) len 0");
+        assert_eq!(
+            format!("{:?}", m),
+            "CmriMessage { address: None, message_type: None, \
+             payload: [], len: 0 }"
+        );
+    }
 
     #[test]
     fn test_payload_from_slice() {
@@ -659,14 +2423,16 @@ mod test {
     }
 
     /// Utility function to produce a state machine in the "accepting
-    /// data" state to make testing later states easier
+    /// data" state to make testing later states easier. Payload lengths
+    /// aren't checked, so any number of data bytes makes a frame
     fn get_to_data_section(addr: u8) -> Result<CmriStateMachine> {
         let mut s = CmriStateMachine::new();
+        s.check_payload_lengths(false);
         s.process(CMRI_PREAMBLE_BYTE)?;
         s.process(CMRI_PREAMBLE_BYTE)?;
         s.process(CMRI_START_BYTE)?;
         s.process(addr)?; // Address
-        s.process(Init as u8)?; // Message type
+        s.process(u8::from(Init))?; // Message type
 
         Ok(s)
     }
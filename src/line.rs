@@ -0,0 +1,160 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Half duplex line turnaround. On an RS485 bus whoever is about to talk
+// enables their transceiver's driver (DE), sends, and releases the driver
+// once the last stop bit has left the UART. Enabling it late clips the
+// preamble; releasing it early clips the stop byte, and releasing it late
+// tramples on the start of the reply.
+//
+// `LineDiscipline` keeps that sequence in one place. An implementation
+// only has to switch its pin and wait; the provided methods do the
+// timing:
+//
+//     begin_tx:  pre-TX guard, DE on, driver settle time
+//     end_tx:    hold for the frame plus a margin of byte times, DE off
+//     end_rx:    post-RX guard before anything is sent back
+//
+// Implementations are provided for a Raspberry Pi GPIO pin, an
+// embedded-hal `OutputPin` and a serial port's RTS or DTR line.
+
+use crate::{timing, Result, SerialConfig};
+
+/// Guard and hold times for turning the line around. Times are in
+/// microseconds
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct LineTiming {
+    /// Quiet time before enabling the driver
+    pub pre_tx_guard: u64,
+    /// Time for the transceiver to start driving the bus after it is
+    /// enabled, before the first byte goes out
+    pub driver_settle: u64,
+    /// Byte times to keep driving past the end of the frame, to allow for
+    /// bytes still in the UART's own buffer
+    pub hold_bytes: u64,
+    /// Quiet time after a frame has been received before replying, for
+    /// the far end to release its driver
+    pub post_rx_guard: u64,
+}
+
+impl LineTiming {
+    /// No guard times, holding for `timing::TURNAROUND_BYTES` after each
+    /// frame
+    pub const fn new() -> Self {
+        Self {
+            pre_tx_guard: 0,
+            driver_settle: 0,
+            hold_bytes: timing::TURNAROUND_BYTES,
+            post_rx_guard: 0,
+        }
+    }
+
+    /// Time to keep driving after a frame of `len` bytes has been written
+    pub fn hold_time(&self, len: usize, config: &SerialConfig) -> u64 {
+        (len as u64 + self.hold_bytes) * config.byte_time()
+    }
+}
+
+impl Default for LineTiming {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Control of a half duplex transceiver's driver enable
+pub trait LineDiscipline {
+    /// Enables the driver when `enabled`, otherwise releases the bus
+    fn set_driver(&mut self, enabled: bool) -> Result<()>;
+
+    /// Waits for `us` microseconds
+    fn delay_us(&mut self, us: u64);
+
+    fn timing(&self) -> LineTiming;
+
+    /// Settings of the line, for working out how long a frame takes
+    fn config(&self) -> SerialConfig;
+
+    /// Takes the bus ready to write a frame
+    fn begin_tx(&mut self) -> Result<()> {
+        let timing = self.timing();
+        self.delay_us(timing.pre_tx_guard);
+        self.set_driver(true)?;
+        self.delay_us(timing.driver_settle);
+        Ok(())
+    }
+
+    /// Waits for a frame of `len` bytes, which has just been written, to
+    /// leave the UART
+    fn hold(&mut self, len: usize) {
+        let hold = self.timing().hold_time(len, &self.config());
+        self.delay_us(hold);
+    }
+
+    /// Releases the bus once a frame of `len` bytes has gone out
+    fn end_tx(&mut self, len: usize) -> Result<()> {
+        self.hold(len);
+        self.set_driver(false)
+    }
+
+    /// Call once a frame has been received, before sending anything back
+    fn end_rx(&mut self) {
+        let guard = self.timing().post_rx_guard;
+        self.delay_us(guard);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Records each step as DE changes and time passes
+    #[derive(Default)]
+    struct TestLine {
+        now: u64,
+        /// Time and new state of each DE change
+        changes: [(u64, bool); 4],
+        count: usize,
+    }
+
+    impl LineDiscipline for TestLine {
+        fn set_driver(&mut self, enabled: bool) -> Result<()> {
+            self.changes[self.count] = (self.now, enabled);
+            self.count += 1;
+            Ok(())
+        }
+
+        fn delay_us(&mut self, us: u64) {
+            self.now += us;
+        }
+
+        fn timing(&self) -> LineTiming {
+            LineTiming {
+                pre_tx_guard: 100,
+                driver_settle: 10,
+                post_rx_guard: 500,
+                ..LineTiming::default()
+            }
+        }
+
+        fn config(&self) -> SerialConfig {
+            SerialConfig::new(19200)
+        }
+    }
+
+    #[test]
+    fn turnaround_sequence() {
+        let mut line = TestLine::default();
+        line.begin_tx().unwrap();
+        assert_eq!(line.now, 110);
+        line.end_tx(6).unwrap();
+        line.end_rx();
+        // 573us per byte, for the frame and four more
+        assert_eq!(line.changes[..2], [(100, true), (110 + 10 * 573, false)]);
+        assert_eq!(line.now, 110 + 10 * 573 + 500);
+    }
+}
@@ -0,0 +1,130 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::{
+    Address, CmriMessage, CmriState, CmriStateMachine, Error, Result, RxState,
+};
+use heapless::Deque;
+
+/// State machine which keeps up to `N` decoded messages instead of
+/// overwriting the last one, so that a slow caller does not lose frames
+/// arriving in a burst
+pub struct QueuedStateMachine<const N: usize> {
+    state: CmriStateMachine,
+    queue: Deque<CmriMessage, N>,
+}
+
+impl<const N: usize> QueuedStateMachine<N> {
+    pub fn new() -> Self {
+        Self {
+            state: CmriStateMachine::new(),
+            queue: Deque::new(),
+        }
+    }
+
+    /// Returns the current state of the decoder
+    pub fn state(&self) -> CmriState {
+        self.state.state()
+    }
+
+    /// Sets an address filter on the underlying state machine
+    pub fn filter(&mut self, addr: Address) -> Result<()> {
+        self.state.filter(addr)
+    }
+
+    /// Takes in bytes off the wire. Completed messages are added to the
+    /// back of the queue. If the queue is full then the new message is
+    /// discarded and `Error::QueueFull` is returned.
+    pub fn process(&mut self, byte: u8) -> Result<RxState> {
+        let res = self.state.process(byte)?;
+        if res == RxState::Complete {
+            self.queue
+                .push_back(*self.state.message())
+                .map_err(|_| Error::QueueFull)?;
+        }
+        Ok(res)
+    }
+
+    /// Removes the oldest message from the queue
+    pub fn pop_message(&mut self) -> Option<CmriMessage> {
+        self.queue.pop_front()
+    }
+
+    /// Number of messages waiting in the queue
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Resets the decoder and empties the queue
+    pub fn clear(&mut self) {
+        self.state.clear();
+        self.queue.clear();
+    }
+}
+
+impl<const N: usize> Default for QueuedStateMachine<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{MessageBuilder, TX_BUFFER_LEN};
+
+    fn feed<const N: usize>(
+        s: &mut QueuedStateMachine<N>,
+        builder: MessageBuilder,
+    ) -> Result<RxState> {
+        let mut buf = [0_u8; TX_BUFFER_LEN];
+        let len = builder.build().unwrap().encode(&mut buf).unwrap();
+        let mut res = Ok(RxState::Listening);
+        for byte in buf[..len].iter() {
+            res = s.process(*byte);
+        }
+        res
+    }
+
+    #[test]
+    fn messages_are_queued_in_order() {
+        let mut s = QueuedStateMachine::<4>::new();
+        assert!(s.is_empty());
+
+        feed(&mut s, MessageBuilder::set(0x41, &[1])).unwrap();
+        feed(&mut s, MessageBuilder::set(0x42, &[2])).unwrap();
+        feed(&mut s, MessageBuilder::poll(0x43)).unwrap();
+        assert_eq!(s.len(), 3);
+
+        let m = s.pop_message().unwrap();
+        assert_eq!(m.address, Some(0x41));
+        assert_eq!(m.payload[..m.len], [1]);
+        let m = s.pop_message().unwrap();
+        assert_eq!(m.address, Some(0x42));
+        assert_eq!(m.payload[..m.len], [2]);
+        let m = s.pop_message().unwrap();
+        assert_eq!(m.address, Some(0x43));
+        assert!(s.pop_message().is_none());
+    }
+
+    #[test]
+    fn full_queue() {
+        let mut s = QueuedStateMachine::<1>::new();
+        let res = feed(&mut s, MessageBuilder::set(0x41, &[1]));
+        assert_eq!(res, Ok(RxState::Complete));
+        let res = feed(&mut s, MessageBuilder::set(0x42, &[2]));
+        assert_eq!(res, Err(Error::QueueFull));
+
+        // The first message is kept
+        assert_eq!(s.len(), 1);
+        assert_eq!(s.pop_message().unwrap().address, Some(0x41));
+    }
+}
@@ -0,0 +1,478 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// MQTT glue for home automation systems. Input changes from Get messages
+// are published one bit per topic, and output topics are subscribed to
+// and turned into Set messages:
+//
+//   cmri/node/3/input/12 = 1     published when UA 3's input 12 turns on
+//   cmri/node/3/output/5 = 1     sets UA 3's output 5
+//
+// Inputs are published retained so that a new subscriber sees the current
+// state straight away. The MQTT protocol itself comes from rumqttc, using
+// a clean session and QoS 0. Like the WebSocket bridge, everything runs
+// on the caller's thread: rumqttc's event loop is only driven from the
+// adapter's own methods, and `poll_outputs()` waits at most `POLL_WAIT`
+// for the broker.
+//
+// If the connection drops, or the broker sends something rumqttc won't
+// accept such as a packet over `MAX_PACKET_LEN`, the call that saw it
+// returns an error and the next call reconnects and subscribes again.
+
+use crate::{CmriMessage, CmriSocket, Error, MessageBuilder, MessageType};
+use crate::{Result, ADDRESS_OFFSET};
+use rumqttc::{Client, Connection, Event, MqttOptions, Outgoing, Packet};
+use rumqttc::{QoS, RecvTimeoutError, SubscribeReasonCode};
+use std::collections::{BTreeMap, HashMap};
+use std::format;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::string::{String, ToString};
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+
+/// Default topic prefix
+pub const DEFAULT_PREFIX: &str = "cmri";
+/// How long the broker waits without hearing from us before disconnecting
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+/// How long to wait for the broker to accept the connection and the
+/// subscription
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait for a publish to be sent
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long `poll_outputs()` waits for the broker when nothing has arrived
+const POLL_WAIT: Duration = Duration::from_millis(1);
+/// Largest packet accepted from the broker
+const MAX_PACKET_LEN: usize = 4096;
+/// Requests which can be queued for rumqttc's event loop
+const QUEUE_LEN: usize = 16;
+
+pub struct MqttAdapter {
+    client: Client,
+    connection: Connection,
+    prefix: String,
+    /// Last input bytes published for each node, keyed by UA
+    inputs: HashMap<u8, Vec<u8>>,
+    /// Output bytes for each node, keyed by UA, as built up from output
+    /// topics
+    outputs: BTreeMap<u8, Vec<u8>>,
+    /// UAs whose outputs have changed since the last `poll_outputs()`
+    changed: Vec<u8>,
+    /// Whether the broker has accepted the output topic subscription on
+    /// the current connection
+    subscribed: bool,
+    /// Publishes queued but not yet sent
+    unsent: usize,
+}
+
+impl MqttAdapter {
+    /// Connects to a broker and subscribes to the output topics under
+    /// `prefix`. Fails if the broker refuses either
+    pub fn connect<A: ToSocketAddrs>(
+        broker: A,
+        client_id: &str,
+        prefix: &str,
+    ) -> Result<Self> {
+        let broker = broker
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::IoError(String::from("no broker address")))?;
+        let host = match broker {
+            SocketAddr::V4(addr) => addr.ip().to_string(),
+            SocketAddr::V6(addr) => format!("[{}]", addr.ip()),
+        };
+        let mut options = MqttOptions::new(client_id, host, broker.port());
+        options
+            .set_keep_alive(KEEP_ALIVE)
+            .set_clean_session(true)
+            .set_max_packet_size(MAX_PACKET_LEN, MAX_PACKET_LEN);
+        let (client, connection) = Client::new(options, QUEUE_LEN);
+
+        let mut adapter = Self {
+            client,
+            connection,
+            prefix: String::from(prefix),
+            inputs: HashMap::new(),
+            outputs: BTreeMap::new(),
+            changed: Vec::new(),
+            subscribed: false,
+            unsent: 0,
+        };
+        adapter.run_until(CONNECT_TIMEOUT, |adapter| adapter.subscribed)?;
+        Ok(adapter)
+    }
+
+    /// Sets how many output bytes a node has, so that a Set sent after
+    /// an output topic changes covers all of them. Otherwise a node's
+    /// Sets only go up to the highest output set over MQTT
+    pub fn set_output_len(&mut self, ua: u8, bytes: usize) {
+        self.outputs.entry(ua).or_default().resize(bytes, 0);
+    }
+
+    /// Publishes any inputs which have changed since the node's last Get,
+    /// or all of them the first time the node is seen. Returns the number
+    /// of topics published. Anything other than a Get is ignored
+    pub fn publish_inputs(&mut self, msg: &CmriMessage) -> Result<usize> {
+        let ua = match (msg.message_type, msg.to_ua()) {
+            (Some(MessageType::Get), Some(ua)) => ua,
+            _ => return Ok(0),
+        };
+        let new = msg.data();
+        // If publishing fails part way, everything is published next time
+        let old = self.inputs.remove(&ua).unwrap_or_default();
+        let mut published = 0;
+        for (n, byte) in new.iter().enumerate() {
+            let changed = match old.get(n) {
+                Some(old) => byte ^ old,
+                None => 0xff,
+            };
+            for bit in (0..8).filter(|bit| changed & (1 << bit) != 0) {
+                let topic = format!(
+                    "{}/node/{}/input/{}",
+                    self.prefix,
+                    ua,
+                    n * 8 + bit
+                );
+                let value = if byte & (1 << bit) != 0 { "1" } else { "0" };
+                self.publish(&topic, value.as_bytes(), true)?;
+                published += 1;
+            }
+        }
+        self.inputs.insert(ua, new.to_vec());
+        Ok(published)
+    }
+
+    /// Publishes to any topic, for things like status messages. Waits
+    /// until it has been sent
+    pub fn publish(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        retain: bool,
+    ) -> Result<()> {
+        self.client
+            .try_publish(topic, QoS::AtMostOnce, retain, payload)
+            .map_err(|e| Error::IoError(format!("{}", e)))?;
+        self.unsent += 1;
+        self.run_until(SEND_TIMEOUT, |adapter| adapter.unsent == 0)
+    }
+
+    /// Handles whatever the broker has sent, returning a Set message for
+    /// each node whose outputs have changed. Also keeps the connection
+    /// alive, and reconnects if it has dropped. Waits at most `POLL_WAIT`
+    /// for the broker when nothing has arrived
+    pub fn poll_outputs(&mut self) -> Result<Vec<CmriMessage>> {
+        while self.next_event(POLL_WAIT)? {}
+
+        let changed = core::mem::take(&mut self.changed);
+        changed
+            .into_iter()
+            .filter_map(|ua| Some((ua, self.outputs.get(&ua)?)))
+            .map(|(ua, outputs)| {
+                MessageBuilder::set(ua + ADDRESS_OFFSET, outputs).build()
+            })
+            .collect()
+    }
+
+    /// Sends a Set for every node whose outputs have changed, returning
+    /// how many were sent
+    pub fn forward_outputs(
+        &mut self,
+        socket: &mut CmriSocket,
+    ) -> Result<usize> {
+        let sets = self.poll_outputs()?;
+        for msg in &sets {
+            socket.send(msg)?;
+        }
+        Ok(sets.len())
+    }
+
+    /// Drives the connection until `done` returns true, failing if that
+    /// takes longer than `timeout`
+    fn run_until(
+        &mut self,
+        timeout: Duration,
+        done: fn(&Self) -> bool,
+    ) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        while !done(self) {
+            let wait = deadline
+                .checked_duration_since(Instant::now())
+                .ok_or(Error::Timeout)?;
+            if let Err(e) = self.next_event(wait) {
+                // Anything still queued goes out after reconnecting, if
+                // at all, so nothing is waited for any more
+                self.unsent = 0;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles the next event from the connection, waiting up to `wait`
+    /// for one. Returns false if there wasn't one
+    fn next_event(&mut self, wait: Duration) -> Result<bool> {
+        let event = match self.connection.recv_timeout(wait) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => return Ok(false),
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(Error::Disconnected)
+            }
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                self.subscribed = false;
+                return Err(Error::IoError(format!("{}", e)));
+            }
+        };
+        match event {
+            // A clean session starts with no subscriptions, including
+            // after reconnecting
+            Event::Incoming(Packet::ConnAck(_)) => self
+                .client
+                .try_subscribe(
+                    format!("{}/node/+/output/+", self.prefix),
+                    QoS::AtMostOnce,
+                )
+                .map_err(|e| Error::IoError(format!("{}", e)))?,
+            Event::Incoming(Packet::SubAck(ack)) => {
+                if ack.return_codes.contains(&SubscribeReasonCode::Failure) {
+                    return Err(Error::IoError(String::from(
+                        "broker refused the output topic subscription",
+                    )));
+                }
+                self.subscribed = true;
+            }
+            Event::Incoming(Packet::Publish(publish)) => {
+                if let Some(ua) =
+                    self.apply_publish(&publish.topic, &publish.payload)
+                {
+                    if !self.changed.contains(&ua) {
+                        self.changed.push(ua);
+                    }
+                }
+            }
+            Event::Outgoing(Outgoing::Publish(_)) => {
+                self.unsent = self.unsent.saturating_sub(1);
+            }
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    /// Updates the output state from a publish, returning the UA if it
+    /// was to an output topic
+    fn apply_publish(&mut self, topic: &str, payload: &[u8]) -> Option<u8> {
+        let (ua, output) = parse_output_topic(&self.prefix, topic)?;
+        let value = match payload {
+            b"1" | b"on" | b"ON" | b"true" => true,
+            b"0" | b"off" | b"OFF" | b"false" => false,
+            _ => return None,
+        };
+        let outputs = self.outputs.entry(ua).or_default();
+        let byte = output / 8;
+        if byte >= outputs.len() {
+            outputs.resize(byte + 1, 0);
+        }
+        let byte = outputs.get_mut(byte)?;
+        let mask = 1 << (output % 8);
+        if value {
+            *byte |= mask;
+        } else {
+            *byte &= !mask;
+        }
+        Some(ua)
+    }
+}
+
+/// Splits `{prefix}/node/{ua}/output/{n}` into the UA and output number
+fn parse_output_topic(prefix: &str, topic: &str) -> Option<(u8, usize)> {
+    let rest = topic.strip_prefix(prefix)?.strip_prefix("/node/")?;
+    let mut parts = rest.split('/');
+    let ua = parts.next()?.parse::<u8>().ok()?;
+    if parts.next()? != "output" {
+        return None;
+    }
+    let output = parts.next()?.parse::<usize>().ok()?;
+    if parts.next().is_some() || crate::Address::Ua(ua).ua().is_err() {
+        return None;
+    }
+    Some((ua, output))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    const CONNACK: u8 = 0x20;
+    const PUBLISH: u8 = 0x30;
+    const SUBSCRIBE: u8 = 0x82;
+    const SUBACK: u8 = 0x90;
+
+    /// Builds a packet from its first header byte and body
+    fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+        let mut packet = std::vec![header];
+        let mut len = body.len();
+        loop {
+            let mut byte = (len % 128) as u8;
+            len /= 128;
+            if len > 0 {
+                byte |= 0x80;
+            }
+            packet.push(byte);
+            if len == 0 {
+                break;
+            }
+        }
+        packet.extend_from_slice(body);
+        packet
+    }
+
+    /// Reads a packet, returning its first header byte and body
+    fn read_packet(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+        let mut byte = [0_u8];
+        stream.read_exact(&mut byte).ok()?;
+        let header = byte[0];
+        let mut len = 0;
+        for shift in (0..4).map(|n| n * 7) {
+            stream.read_exact(&mut byte).ok()?;
+            len |= ((byte[0] & 0x7f) as usize) << shift;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = std::vec![0; len];
+        stream.read_exact(&mut body).ok()?;
+        Some((header, body))
+    }
+
+    fn publish_packet(topic: &str, payload: &[u8]) -> Vec<u8> {
+        let mut body = (topic.len() as u16).to_be_bytes().to_vec();
+        body.extend_from_slice(topic.as_bytes());
+        body.extend_from_slice(payload);
+        packet(PUBLISH, &body)
+    }
+
+    /// Accepts a connection and answers its CONNECT and SUBSCRIBE, with
+    /// `granted` as the SUBACK return code
+    fn accept(listener: &TcpListener, granted: u8) -> TcpStream {
+        let (mut stream, _) = listener.accept().unwrap();
+        read_packet(&mut stream).unwrap();
+        stream.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+        let (header, body) = read_packet(&mut stream).unwrap();
+        assert_eq!(header, SUBSCRIBE);
+        assert!(body.ends_with(b"cmri/node/+/output/+\0"));
+        // Echo the packet ID
+        let suback = [SUBACK, 3, body[0], body[1], granted];
+        stream.write_all(&suback).unwrap();
+        stream
+    }
+
+    #[test]
+    fn output_topics() {
+        assert_eq!(
+            parse_output_topic("cmri", "cmri/node/3/output/12"),
+            Some((3, 12))
+        );
+        assert_eq!(parse_output_topic("cmri", "cmri/node/3/input/12"), None);
+        assert_eq!(parse_output_topic("cmri", "cmri/node/128/output/1"), None);
+        assert_eq!(parse_output_topic("cmri", "other/node/3/output/1"), None);
+    }
+
+    #[test]
+    fn publish_and_subscribe() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let broker_addr = listener.local_addr().unwrap();
+        let broker = thread::spawn(move || {
+            let mut stream = accept(&listener, 0);
+            stream
+                .write_all(&publish_packet("cmri/node/1/output/9", b"1"))
+                .unwrap();
+            let mut packets = Vec::new();
+            while let Some(packet) = read_packet(&mut stream) {
+                packets.push(packet);
+            }
+            packets
+        });
+
+        let mut mqtt =
+            MqttAdapter::connect(broker_addr, "test", DEFAULT_PREFIX).unwrap();
+        mqtt.set_output_len(1, 3);
+
+        let get = MessageBuilder::get(0x41, &[0b0000_0101]).build().unwrap();
+        assert_eq!(mqtt.publish_inputs(&get).unwrap(), 8);
+        let get = MessageBuilder::get(0x41, &[0b0000_0100]).build().unwrap();
+        assert_eq!(mqtt.publish_inputs(&get).unwrap(), 1);
+
+        let mut sets = Vec::new();
+        while sets.is_empty() {
+            sets = mqtt.poll_outputs().unwrap();
+        }
+        assert_eq!(sets[0].address, Some(0x42));
+        assert_eq!(sets[0].payload[..sets[0].len], [0, 0x02, 0]);
+
+        drop(mqtt);
+        let packets = broker.join().unwrap();
+        assert_eq!(packets.len(), 9);
+        // Retained publish of input 0 turning off
+        let (header, body) = packets.last().unwrap();
+        assert_eq!(*header, PUBLISH | 1);
+        assert!(body.ends_with(b"cmri/node/0/input/00"));
+    }
+
+    #[test]
+    fn subscription_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let broker_addr = listener.local_addr().unwrap();
+        let broker = thread::spawn(move || accept(&listener, 0x80));
+
+        assert!(matches!(
+            MqttAdapter::connect(broker_addr, "test", DEFAULT_PREFIX),
+            Err(Error::IoError(_))
+        ));
+        broker.join().unwrap();
+    }
+
+    #[test]
+    fn oversized_packet() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let broker_addr = listener.local_addr().unwrap();
+        let broker = thread::spawn(move || {
+            let mut stream = accept(&listener, 0);
+            let long = publish_packet("cmri/node/1/output/1", &[b'1'; 5000]);
+            // The client may hang up part way through
+            let _ = stream.write_all(&long);
+            let mut stream = accept(&listener, 0);
+            stream
+                .write_all(&publish_packet("cmri/node/1/output/9", b"1"))
+                .unwrap();
+            stream
+        });
+
+        let mut mqtt =
+            MqttAdapter::connect(broker_addr, "test", DEFAULT_PREFIX).unwrap();
+
+        // The oversized packet fails one poll, then the adapter
+        // reconnects and carries on
+        let start = Instant::now();
+        let mut errors = 0;
+        let mut sets = Vec::new();
+        while sets.is_empty() && start.elapsed() < CONNECT_TIMEOUT {
+            match mqtt.poll_outputs() {
+                Ok(new) => sets = new,
+                Err(_) => errors += 1,
+            }
+        }
+        assert_eq!(errors, 1);
+        assert_eq!(sets.len(), 1);
+        assert_eq!(sets[0].payload[..sets[0].len], [0, 0x02]);
+        broker.join().unwrap();
+    }
+}
@@ -0,0 +1,384 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Several RS-485 buses run from one I/O thread, which owns the sockets
+// and so must also create the `MultiBus`. Each bus is a socket and
+// a `Controller` of its own, with its own nodes and polling order, so a
+// slow or broken bus only holds up its own nodes. `MultiBus` steps them
+// all in turn and gathers their input edges, tagged with the bus they
+// came from, for the application.
+//
+// Application code can name points once, wherever they are, and then
+// use the names through a `MultiBusHandle` from any thread without
+// caring which bus a point is on.
+
+use crate::bits::InputChanged;
+use crate::health::NodeStatus;
+use crate::sync::mpsc::{self, Receiver, Sender};
+use crate::sync::{Arc, Mutex, MutexGuard};
+use crate::{
+    CmriSocket, Controller, ControllerHandle, Error, InitSequence, Result,
+    SocketStats,
+};
+use core::fmt;
+use std::collections::BTreeMap;
+use std::string::String;
+use std::vec::Vec;
+
+/// Identifies one bus
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BusId(pub u8);
+
+impl fmt::Display for BusId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bus {}", self.0)
+    }
+}
+
+/// An input edge and the bus it happened on
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BusInputChanged {
+    pub bus: BusId,
+    pub change: InputChanged,
+}
+
+/// An error from one bus
+#[derive(Clone, Debug, PartialEq)]
+pub struct BusError {
+    pub bus: BusId,
+    pub error: Error,
+}
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.bus, self.error)
+    }
+}
+
+/// A bit on a node on a bus
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BusPoint {
+    pub bus: BusId,
+    /// Address byte of the node
+    pub node: u8,
+    pub bit: usize,
+}
+
+#[derive(Default)]
+struct Names {
+    inputs: BTreeMap<String, BusPoint>,
+    outputs: BTreeMap<String, BusPoint>,
+}
+
+struct Bus {
+    id: BusId,
+    socket: CmriSocket,
+    controller: Controller,
+    /// Input edges from the controller, to be tagged and passed on
+    edges: Receiver<InputChanged>,
+}
+
+/// Every bus, owned by the thread which drives them
+pub struct MultiBus {
+    buses: Vec<Bus>,
+    handle: MultiBusHandle,
+    on_input_change: fn(BusInputChanged),
+}
+
+/// Cloneable handle for changing outputs and reading inputs on any bus
+/// from any thread
+#[derive(Clone)]
+pub struct MultiBusHandle {
+    controllers: Arc<Mutex<BTreeMap<BusId, ControllerHandle>>>,
+    names: Arc<Mutex<Names>>,
+    subscribers: Arc<Mutex<Vec<Sender<BusInputChanged>>>>,
+}
+
+impl MultiBus {
+    pub fn new() -> Self {
+        Self {
+            buses: Vec::new(),
+            handle: MultiBusHandle {
+                controllers: Arc::new(Mutex::new(BTreeMap::new())),
+                names: Arc::new(Mutex::new(Names::default())),
+                subscribers: Arc::new(Mutex::new(Vec::new())),
+            },
+            on_input_change: |_| {},
+        }
+    }
+
+    /// Adds a bus, replacing any other with the same id. The controller
+    /// brings its own nodes and polling order
+    pub fn add_bus(
+        &mut self,
+        id: BusId,
+        socket: CmriSocket,
+        controller: Controller,
+    ) {
+        self.remove_bus(id);
+        let handle = controller.handle();
+        let edges = handle.subscribe();
+        lock(&self.handle.controllers).insert(id, handle);
+        self.buses.push(Bus {
+            id,
+            socket,
+            controller,
+            edges,
+        });
+    }
+
+    pub fn remove_bus(&mut self, id: BusId) {
+        self.buses.retain(|bus| bus.id != id);
+        lock(&self.handle.controllers).remove(&id);
+    }
+
+    /// Buses in the order they are stepped
+    pub fn buses(&self) -> impl Iterator<Item = BusId> + '_ {
+        self.buses.iter().map(|bus| bus.id)
+    }
+
+    /// One bus's socket and controller, e.g. for adding nodes
+    pub fn bus_mut(
+        &mut self,
+        id: BusId,
+    ) -> Option<(&mut CmriSocket, &mut Controller)> {
+        let bus = self.buses.iter_mut().find(|bus| bus.id == id)?;
+        Some((&mut bus.socket, &mut bus.controller))
+    }
+
+    pub fn handle(&self) -> MultiBusHandle {
+        self.handle.clone()
+    }
+
+    /// Called on the I/O thread for every input edge on any bus
+    pub fn on_input_change(&mut self, callback: fn(BusInputChanged)) {
+        self.on_input_change = callback;
+    }
+
+    /// Names an input for `MultiBusHandle::named_input()`
+    pub fn name_input(&mut self, name: &str, point: BusPoint) {
+        self.handle.lock_names().inputs.insert(name.into(), point);
+    }
+
+    /// Names an output for `MultiBusHandle::set_named_output()`
+    pub fn name_output(&mut self, name: &str, point: BusPoint) {
+        self.handle.lock_names().outputs.insert(name.into(), point);
+    }
+
+    /// Initialises the nodes on every bus. All buses are tried even if
+    /// one fails, and the first failure is returned
+    pub fn initialise(
+        &mut self,
+        sequence: &InitSequence,
+    ) -> core::result::Result<(), BusError> {
+        self.each_bus(|bus| {
+            bus.controller.initialise(&mut bus.socket, sequence)
+        })
+    }
+
+    /// One pass of the I/O loop on every bus, then passes on the input
+    /// edges. All buses are stepped even if one fails, and the first
+    /// failure is returned
+    pub fn step(&mut self) -> core::result::Result<(), BusError> {
+        let result = self.each_bus(|bus| bus.controller.step(&mut bus.socket));
+        let mut subscribers = lock(&self.handle.subscribers);
+        for bus in self.buses.iter() {
+            while let Ok(change) = bus.edges.try_recv() {
+                let change = BusInputChanged {
+                    bus: bus.id,
+                    change,
+                };
+                (self.on_input_change)(change);
+                subscribers.retain(|s| s.send(change).is_ok());
+            }
+        }
+        result
+    }
+
+    /// Socket statistics for every bus
+    pub fn stats(&self) -> Vec<(BusId, SocketStats)> {
+        self.buses
+            .iter()
+            .map(|bus| (bus.id, bus.socket.stats()))
+            .collect()
+    }
+
+    pub fn node_status(&self, bus: BusId, node: u8) -> Option<NodeStatus> {
+        let bus = self.buses.iter().find(|b| b.id == bus)?;
+        bus.socket.node_status(node)
+    }
+
+    fn each_bus(
+        &mut self,
+        mut f: impl FnMut(&mut Bus) -> Result<()>,
+    ) -> core::result::Result<(), BusError> {
+        let mut result = Ok(());
+        for bus in self.buses.iter_mut() {
+            if let (Err(error), true) = (f(bus), result.is_ok()) {
+                result = Err(BusError { bus: bus.id, error });
+            }
+        }
+        result
+    }
+}
+
+impl Default for MultiBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MultiBusHandle {
+    /// The controller handle for one bus
+    pub fn bus(&self, id: BusId) -> Option<ControllerHandle> {
+        lock(&self.controllers).get(&id).cloned()
+    }
+
+    /// Changes one output bit on a bus. Fails with `Error::UnknownPoint`
+    /// if there is no such bus
+    pub fn set_output(&self, point: BusPoint, value: bool) -> Result<()> {
+        let bus = self.bus(point.bus).ok_or(Error::UnknownPoint)?;
+        bus.set_output(point.node, point.bit, value)
+    }
+
+    /// One input bit from the node's most recent Get
+    pub fn input(&self, point: BusPoint) -> Option<bool> {
+        self.bus(point.bus)?.input(point.node, point.bit)
+    }
+
+    /// Changes a named output, wherever it is
+    pub fn set_named_output(&self, name: &str, value: bool) -> Result<()> {
+        let point = self.lock_names().outputs.get(name).copied();
+        self.set_output(point.ok_or(Error::UnknownPoint)?, value)
+    }
+
+    /// Last known state of a named input
+    pub fn named_input(&self, name: &str) -> Result<bool> {
+        let point = self.lock_names().inputs.get(name).copied();
+        let point = point.ok_or(Error::UnknownPoint)?;
+        self.input(point).ok_or(Error::NoResponse)
+    }
+
+    /// Receives every input edge on every bus from now on
+    pub fn subscribe(&self) -> Receiver<BusInputChanged> {
+        let (tx, rx) = mpsc::channel();
+        lock(&self.subscribers).push(tx);
+        rx
+    }
+
+    fn lock_names(&self) -> MutexGuard<'_, Names> {
+        lock(&self.names)
+    }
+}
+
+/// Every lock here guards something replaced or pushed in one go, so a
+/// panic elsewhere can't leave it half-written
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sim::{Behaviour, VirtualBus, VirtualNode};
+    use std::boxed::Box;
+    use std::time::Duration;
+    use std::vec;
+
+    fn bus(inputs: &[u8]) -> (VirtualBus, CmriSocket, Controller) {
+        let bus = VirtualBus::new();
+        let mut node =
+            VirtualNode::new(0x41, 1, Behaviour::MirrorOutputs).unwrap();
+        node.driver_mut().set_inputs(inputs).unwrap();
+        bus.add_node(node);
+        let socket = CmriSocket::builder(Box::new(bus.clone()))
+            .read_timeout(Duration::from_millis(5))
+            .build();
+        let mut controller = Controller::new();
+        controller.add_node(0x41);
+        (bus, socket, controller)
+    }
+
+    #[test]
+    fn handle_is_send_and_sync() {
+        fn send_sync<T: Send + Sync>() {}
+        send_sync::<MultiBusHandle>();
+    }
+
+    #[test]
+    fn two_buses() {
+        let (east, socket, controller) = bus(&[0x00]);
+        let mut buses = MultiBus::new();
+        buses.add_bus(BusId(1), socket, controller);
+        let (west, socket, controller) = bus(&[0x04]);
+        buses.add_bus(BusId(2), socket, controller);
+        assert!(buses.buses().eq([BusId(1), BusId(2)].iter().copied()));
+
+        // The same node address on each bus
+        let lamp = BusPoint {
+            bus: BusId(2),
+            node: 0x41,
+            bit: 0,
+        };
+        buses.name_output("lamp", lamp);
+        buses.name_input("lamp_lit", lamp);
+        let handle = buses.handle();
+        let edges = handle.subscribe();
+        handle.set_named_output("lamp", true).unwrap();
+        buses.step().unwrap();
+
+        assert_eq!(
+            east.with_node(0x41, |n| n.outputs().to_vec()),
+            Some(vec![])
+        );
+        assert_eq!(
+            west.with_node(0x41, |n| n.outputs().to_vec()),
+            Some(vec![0x01])
+        );
+        assert_eq!(handle.named_input("lamp_lit"), Ok(true));
+        let edges: Vec<_> = std::iter::from_fn(|| edges.try_recv().ok())
+            .map(|e| (e.bus, e.change.bit))
+            .collect();
+        assert_eq!(edges, [(BusId(2), 0)]);
+
+        let stats = buses.stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[1].0, BusId(2));
+        assert_eq!(stats[1].1.rx.get_messages, 1);
+
+        assert_eq!(
+            handle.set_named_output("nothing", true),
+            Err(Error::UnknownPoint)
+        );
+        buses.remove_bus(BusId(2));
+        assert_eq!(handle.set_output(lamp, true), Err(Error::UnknownPoint));
+    }
+
+    #[test]
+    fn one_bus_failing() {
+        let (_east, socket, controller) = bus(&[0x00]);
+        let mut buses = MultiBus::new();
+        buses.add_bus(BusId(1), socket, controller);
+        let (_west, socket, mut controller) = bus(&[0x00]);
+        controller
+            .add_node_with_init(0x41, &[b'M', 0, 0, 0])
+            .unwrap();
+        buses.add_bus(BusId(2), socket, controller);
+        // A SMINI Init asks for three input bytes, but the node has one
+        let sequence = InitSequence {
+            delay: Duration::from_millis(0),
+            ..InitSequence::default()
+        };
+        let e = buses.initialise(&sequence).unwrap_err();
+        assert_eq!(e.bus, BusId(2));
+        buses.step().unwrap();
+        assert_eq!(
+            buses.handle().bus(BusId(1)).unwrap().inputs(0x41),
+            Some(vec![0])
+        );
+    }
+}
@@ -0,0 +1,558 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::clock::Clock;
+use crate::debounce::Debouncer;
+use crate::{
+    Address, CmriMessage, CmriStateMachine, Error, MessageType, NodeType,
+    Result, RxState, MAX_PAYLOAD_LEN, TX_BUFFER_LEN,
+};
+use core::convert::TryFrom;
+
+// Pure protocol logic for a C/MRI node. Bytes off the wire go in along
+// with the current time, and the driver says what the node should do
+// next. Nothing in here touches hardware, so the same logic can be run
+// on a microcontroller or in a desktop test.
+
+/// What the caller should do after feeding a byte into the driver
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub enum Action<'a> {
+    /// Nothing to do
+    None,
+    /// Write these bytes out onto the bus
+    Transmit(&'a [u8]),
+    /// The controller has sent new output states; read them from
+    /// `NodeDriver::outputs()`
+    OutputsChanged,
+}
+
+/// Most outputs which can be configured as pulsed
+pub const MAX_PULSED_OUTPUTS: usize = 16;
+
+/// What happened during a `NodeDriver::tick()`
+#[derive(Copy, Clone, Default, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct Tick {
+    pub watchdog: Option<WatchdogEvent>,
+    /// A pulsed output has ended its pulse; read the outputs from
+    /// `NodeDriver::outputs()`
+    pub outputs_changed: bool,
+}
+
+/// Watchdog changes reported in a `Tick`
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub enum WatchdogEvent {
+    /// The controller has gone quiet and the outputs have been set to the
+    /// safe state; read them from `NodeDriver::outputs()`
+    Fired,
+    /// The controller has been heard from again. The outputs stay in the
+    /// safe state until it sends a Set
+    Recovered,
+}
+
+/// Drives the outputs to a safe state if the controller goes quiet
+struct Watchdog {
+    timeout: u64,
+    safe_state: [u8; MAX_PAYLOAD_LEN],
+    safe_len: usize,
+    /// Time of the first tick, for when nothing has been received yet
+    armed_at: Option<u64>,
+    tripped: bool,
+    recovered: bool,
+}
+
+/// An output which turns on for a fixed time when the controller sets
+/// it, e.g. one coil of a twin-coil turnout motor
+#[derive(Copy, Clone)]
+struct PulsedOutput {
+    bit: usize,
+    duration: u64,
+    /// Last state sent by the controller, to spot rising edges
+    commanded: bool,
+    /// Time to turn the output off, while a pulse is in progress
+    off_at: Option<u64>,
+}
+
+pub struct NodeDriver {
+    address: u8,
+    state: CmriStateMachine,
+    node_type: Option<NodeType>,
+    inputs: [u8; MAX_PAYLOAD_LEN],
+    input_len: usize,
+    outputs: [u8; MAX_PAYLOAD_LEN],
+    output_len: usize,
+    tx_buffer: [u8; TX_BUFFER_LEN],
+    /// Timestamp of the last message addressed to us, in whatever units
+    /// the caller's clock uses
+    last_message: Option<u64>,
+    watchdog: Option<Watchdog>,
+    pulsed: [Option<PulsedOutput>; MAX_PULSED_OUTPUTS],
+}
+
+impl NodeDriver {
+    /// Create a driver for a node at the given address, reporting
+    /// `input_len` bytes of inputs when polled
+    pub fn new(address: u8, input_len: usize) -> Result<Self> {
+        if input_len > MAX_PAYLOAD_LEN {
+            return Err(Error::DataTooLong);
+        }
+        let mut state = CmriStateMachine::new();
+        state.filter(Address::Wire(address))?;
+
+        Ok(Self {
+            address,
+            state,
+            node_type: None,
+            inputs: [0; MAX_PAYLOAD_LEN],
+            input_len,
+            outputs: [0; MAX_PAYLOAD_LEN],
+            output_len: 0,
+            tx_buffer: [0; TX_BUFFER_LEN],
+            last_message: None,
+            watchdog: None,
+            pulsed: [None; MAX_PULSED_OUTPUTS],
+        })
+    }
+
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    /// Node type sent by the controller in its last Init message
+    pub fn node_type(&self) -> Option<NodeType> {
+        self.node_type
+    }
+
+    /// Output bytes from the most recent Set message
+    pub fn outputs(&self) -> &[u8] {
+        self.outputs.get(..self.output_len).unwrap_or_default()
+    }
+
+    /// Input bytes which will be reported on the next Poll
+    pub fn inputs(&self) -> &[u8] {
+        self.inputs.get(..self.input_len).unwrap_or_default()
+    }
+
+    /// Mutable access to the input bytes so that sensors can be updated
+    pub fn inputs_mut(&mut self) -> &mut [u8] {
+        self.inputs.get_mut(..self.input_len).unwrap_or_default()
+    }
+
+    /// Replace the input bytes
+    pub fn set_inputs(&mut self, inputs: &[u8]) -> Result<()> {
+        if inputs.len() != self.input_len {
+            return Err(Error::OutOfBounds);
+        }
+        self.inputs
+            .get_mut(..self.input_len)
+            .ok_or(Error::OutOfBounds)?
+            .copy_from_slice(inputs);
+        Ok(())
+    }
+
+    /// Acts on Set messages sent to this broadcast address as well as our
+    /// own. Other broadcast messages are ignored, and in particular a
+    /// broadcast Poll is never answered
+    pub fn accept_broadcast(&mut self, addr: Option<Address>) -> Result<()> {
+        self.state.accept_broadcast(addr)
+    }
+
+    /// Feeds a sample of the raw inputs through a debouncer and reports
+    /// the debounced inputs on the next Poll. The debouncer must cover
+    /// exactly this node's input bytes
+    pub fn sample_inputs<const N: usize>(
+        &mut self,
+        raw: &[u8; N],
+        debouncer: &mut Debouncer<N>,
+    ) -> Result<()> {
+        self.set_inputs(debouncer.update(raw))
+    }
+
+    /// Time at which the last message for this node was received
+    pub fn last_message(&self) -> Option<u64> {
+        self.last_message
+    }
+
+    /// Arms the watchdog: if nothing is received from the controller for
+    /// `timeout` (in the same units as the times passed to `process()`
+    /// and `tick()`), the outputs are replaced with `safe_state`
+    pub fn set_watchdog(
+        &mut self,
+        timeout: u64,
+        safe_state: &[u8],
+    ) -> Result<()> {
+        if safe_state.len() > MAX_PAYLOAD_LEN {
+            return Err(Error::DataTooLong);
+        }
+        let mut watchdog = Watchdog {
+            timeout,
+            safe_state: [0; MAX_PAYLOAD_LEN],
+            safe_len: safe_state.len(),
+            armed_at: None,
+            tripped: false,
+            recovered: false,
+        };
+        watchdog
+            .safe_state
+            .get_mut(..safe_state.len())
+            .ok_or(Error::DataTooLong)?
+            .copy_from_slice(safe_state);
+        self.watchdog = Some(watchdog);
+        Ok(())
+    }
+
+    pub fn clear_watchdog(&mut self) {
+        self.watchdog = None;
+    }
+
+    /// Makes an output pulse on for `duration` (in the caller's time
+    /// units) each time the controller turns it on, rather than follow
+    /// the controller's level. `output` counts bits from the start of the
+    /// Set payload
+    pub fn set_pulsed(&mut self, output: usize, duration: u64) -> Result<()> {
+        if output >= MAX_PAYLOAD_LEN * 8 {
+            return Err(Error::OutOfBounds);
+        }
+        let pulse = PulsedOutput {
+            bit: output,
+            duration,
+            commanded: false,
+            off_at: None,
+        };
+        let slot = match self
+            .pulsed
+            .iter()
+            .position(|p| matches!(p, Some(p) if p.bit == output))
+        {
+            Some(slot) => slot,
+            None => self
+                .pulsed
+                .iter()
+                .position(Option::is_none)
+                .ok_or(Error::QueueFull)?,
+        };
+        *self.pulsed.get_mut(slot).ok_or(Error::QueueFull)? = Some(pulse);
+        Ok(())
+    }
+
+    /// Makes an output follow the controller's level again
+    pub fn clear_pulsed(&mut self, output: usize) {
+        for slot in self.pulsed.iter_mut() {
+            if matches!(slot, Some(p) if p.bit == output) {
+                *slot = None;
+            }
+        }
+    }
+
+    /// As `tick()`, taking the time from a clock. Watchdog timeouts and
+    /// pulse durations are then in that clock's ticks; see
+    /// `Clock::ticks_from_millis()`
+    pub fn tick_clock(&mut self, clock: &impl Clock) -> Tick {
+        self.tick(clock.now())
+    }
+
+    /// Call regularly from the caller's timer to end output pulses and
+    /// run the watchdog
+    pub fn tick(&mut self, now: u64) -> Tick {
+        let outputs_changed = self.end_pulses(now);
+        let watchdog = self.tick_watchdog(now);
+        Tick {
+            watchdog,
+            outputs_changed: outputs_changed
+                && watchdog != Some(WatchdogEvent::Fired),
+        }
+    }
+
+    /// Turns off pulsed outputs whose time is up, returning true if any
+    /// were
+    fn end_pulses(&mut self, now: u64) -> bool {
+        let mut changed = false;
+        for pulse in self.pulsed.iter_mut().flatten() {
+            match pulse.off_at {
+                Some(off_at) if now >= off_at => {
+                    pulse.off_at = None;
+                    if let Some(byte) = self.outputs.get_mut(pulse.bit / 8) {
+                        *byte &= !(1 << (pulse.bit % 8));
+                    }
+                    changed = true;
+                }
+                _ => {}
+            }
+        }
+        changed
+    }
+
+    /// Starts pulses for pulsed outputs which the controller has just
+    /// turned on, and holds the rest off
+    fn start_pulses(&mut self, now: u64) {
+        for pulse in self.pulsed.iter_mut().flatten() {
+            let (byte, mask) = (pulse.bit / 8, 1 << (pulse.bit % 8));
+            let output = self
+                .outputs
+                .get_mut(..self.output_len)
+                .and_then(|outputs| outputs.get_mut(byte));
+            let on = matches!(output, Some(&mut o) if o & mask != 0);
+            if on && !pulse.commanded {
+                pulse.off_at = Some(now.saturating_add(pulse.duration));
+            } else if !on {
+                pulse.off_at = None;
+            }
+            pulse.commanded = on;
+            if let Some(output) = output.filter(|_| pulse.off_at.is_none()) {
+                *output &= !mask;
+            }
+        }
+    }
+
+    fn tick_watchdog(&mut self, now: u64) -> Option<WatchdogEvent> {
+        let watchdog = self.watchdog.as_mut()?;
+        if watchdog.recovered {
+            watchdog.recovered = false;
+            return Some(WatchdogEvent::Recovered);
+        }
+        let armed_at = *watchdog.armed_at.get_or_insert(now);
+        let last = self.last_message.map_or(armed_at, |t| t.max(armed_at));
+        if watchdog.tripped || now.saturating_sub(last) < watchdog.timeout {
+            return None;
+        }
+        watchdog.tripped = true;
+        let len = watchdog.safe_len;
+        if let (Some(outputs), Some(safe_state)) =
+            (self.outputs.get_mut(..len), watchdog.safe_state.get(..len))
+        {
+            outputs.copy_from_slice(safe_state);
+        }
+        self.output_len = len;
+        // The safe state overrides any pulses in progress
+        for pulse in self.pulsed.iter_mut().flatten() {
+            pulse.off_at = None;
+        }
+        Some(WatchdogEvent::Fired)
+    }
+
+    /// Feed a received byte into the driver along with the current time
+    pub fn process(&mut self, byte: u8, now: u64) -> Action<'_> {
+        match self.state.process(byte) {
+            Ok(RxState::Complete) => {
+                self.last_message = Some(now);
+                if let Some(watchdog) = self.watchdog.as_mut() {
+                    if watchdog.tripped {
+                        watchdog.tripped = false;
+                        watchdog.recovered = true;
+                    }
+                }
+                let message = *self.state.message();
+                self.handle(&message, now)
+            }
+            // Malformed messages are dropped by the state machine, so
+            // there is nothing for the node to do
+            Ok(RxState::Listening) | Err(_) => Action::None,
+        }
+    }
+
+    fn handle(&mut self, message: &CmriMessage, now: u64) -> Action<'_> {
+        use MessageType::*;
+        let broadcast = message.address != Some(self.address);
+        if broadcast && message.message_type != Some(Set) {
+            return Action::None;
+        }
+        match message.message_type {
+            Some(Init) => {
+                if message.len > 0 {
+                    self.node_type =
+                        NodeType::try_from(message.payload[0]).ok();
+                }
+                Action::None
+            }
+            Some(Set) => {
+                self.outputs = message.payload;
+                self.output_len = message.len;
+                self.start_pulses(now);
+                Action::OutputsChanged
+            }
+            Some(Poll) => {
+                let mut response = CmriMessage::new();
+                response.address(self.address).message_type(Get);
+                response.payload = self.inputs;
+                response.len = self.input_len;
+                match response.encode_slice(&mut self.tx_buffer) {
+                    Ok(frame) => Action::Transmit(frame),
+                    Err(_) => Action::None,
+                }
+            }
+            // Get messages are for the controller, not us
+            Some(Get) | Some(Unknown(_)) | None => Action::None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MessageBuilder;
+
+    /// Feeds an entire message into the driver, returning the action
+    /// produced by the final byte
+    fn feed<'a>(
+        driver: &'a mut NodeDriver,
+        message: &CmriMessage,
+        now: u64,
+    ) -> Action<'a> {
+        let mut buf = [0_u8; TX_BUFFER_LEN];
+        let len = message.encode(&mut buf).unwrap();
+        for byte in buf[..len - 1].iter() {
+            assert_eq!(driver.process(*byte, now), Action::None);
+        }
+        driver.process(buf[len - 1], now)
+    }
+
+    #[test]
+    fn init_records_node_type() {
+        let mut d = NodeDriver::new(0x41, 3).unwrap();
+        let config = [u8::from(NodeType::Smini), 0, 0, 0];
+        let m = MessageBuilder::init(0x41, &config).build().unwrap();
+        assert_eq!(feed(&mut d, &m, 5), Action::None);
+        assert_eq!(d.node_type(), Some(NodeType::Smini));
+        assert_eq!(d.last_message(), Some(5));
+    }
+
+    #[test]
+    fn set_changes_outputs() {
+        let mut d = NodeDriver::new(0x41, 3).unwrap();
+        let m = MessageBuilder::set(0x41, &[1, 2, 3, 4]).build().unwrap();
+        assert_eq!(feed(&mut d, &m, 0), Action::OutputsChanged);
+        assert_eq!(d.outputs(), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn poll_transmits_inputs() {
+        let mut d = NodeDriver::new(0x41, 2).unwrap();
+        d.set_inputs(&[0xaa, 0x55]).unwrap();
+        let m = MessageBuilder::poll(0x41).build().unwrap();
+        let expected =
+            MessageBuilder::get(0x41, &[0xaa, 0x55]).build().unwrap();
+        let mut buf = [0_u8; TX_BUFFER_LEN];
+        let len = expected.encode(&mut buf).unwrap();
+        assert_eq!(feed(&mut d, &m, 0), Action::Transmit(&buf[..len]));
+    }
+
+    #[test]
+    fn ignores_other_addresses() {
+        let mut d = NodeDriver::new(0x41, 2).unwrap();
+        let m = MessageBuilder::poll(0x42).build().unwrap();
+        assert_eq!(feed(&mut d, &m, 7), Action::None);
+        assert_eq!(d.last_message(), None);
+    }
+
+    #[test]
+    fn broadcast_set() {
+        let mut d = NodeDriver::new(0x43, 2).unwrap();
+        d.accept_broadcast(Some(Address::BROADCAST)).unwrap();
+        let m = MessageBuilder::set(0x41, &[9]).build().unwrap();
+        assert_eq!(feed(&mut d, &m, 0), Action::OutputsChanged);
+        assert_eq!(d.outputs(), [9]);
+
+        // Nobody answers a broadcast Poll
+        let m = MessageBuilder::poll(0x41).build().unwrap();
+        assert_eq!(feed(&mut d, &m, 0), Action::None);
+    }
+
+    #[test]
+    fn watchdog() {
+        let mut d = NodeDriver::new(0x41, 2).unwrap();
+        d.set_watchdog(100, &[0xff, 0x00]).unwrap();
+        let m = MessageBuilder::set(0x41, &[1, 2]).build().unwrap();
+
+        assert_eq!(d.tick(0), Tick::default());
+        feed(&mut d, &m, 50);
+        assert_eq!(d.tick(149), Tick::default());
+        assert_eq!(d.tick(150).watchdog, Some(WatchdogEvent::Fired));
+        assert_eq!(d.outputs(), [0xff, 0x00]);
+        assert_eq!(d.tick(1000), Tick::default());
+
+        let poll = MessageBuilder::poll(0x41).build().unwrap();
+        feed(&mut d, &poll, 1001);
+        assert_eq!(d.tick(1002).watchdog, Some(WatchdogEvent::Recovered));
+        assert_eq!(d.tick(1003), Tick::default());
+        assert_eq!(d.outputs(), [0xff, 0x00]);
+
+        d.clear_watchdog();
+        assert_eq!(d.tick(5000), Tick::default());
+    }
+
+    #[test]
+    fn watchdog_from_clock() {
+        /// A 32kHz timer which only moves when told to
+        struct TestClock(core::cell::Cell<u64>);
+        impl Clock for TestClock {
+            fn tick_hz(&self) -> u32 {
+                32_768
+            }
+            fn now(&self) -> u64 {
+                self.0.get()
+            }
+        }
+
+        let clock = TestClock(core::cell::Cell::new(0));
+        let mut d = NodeDriver::new(0x41, 1).unwrap();
+        d.set_watchdog(clock.ticks_from_millis(500), &[0xaa])
+            .unwrap();
+        assert_eq!(d.tick_clock(&clock), Tick::default());
+        clock.0.set(16_000);
+        assert_eq!(d.tick_clock(&clock), Tick::default());
+        clock.0.set(16_384);
+        assert_eq!(d.tick_clock(&clock).watchdog, Some(WatchdogEvent::Fired));
+    }
+
+    #[test]
+    fn debounced_inputs() {
+        let mut d = NodeDriver::new(0x41, 1).unwrap();
+        let mut debouncer = Debouncer::new(2);
+        d.sample_inputs(&[0x01], &mut debouncer).unwrap();
+        assert_eq!(d.inputs(), [0x00]);
+        d.sample_inputs(&[0x01], &mut debouncer).unwrap();
+        assert_eq!(d.inputs(), [0x01]);
+
+        let mut wrong = Debouncer::new(2);
+        assert!(d.sample_inputs(&[0, 0], &mut wrong).is_err());
+    }
+
+    #[test]
+    fn pulsed_outputs() {
+        let mut d = NodeDriver::new(0x41, 1).unwrap();
+        d.set_pulsed(1, 20).unwrap();
+        d.set_pulsed(1, 10).unwrap();
+        assert!(d.set_pulsed(MAX_PAYLOAD_LEN * 8, 10).is_err());
+        let set = |bits| MessageBuilder::set(0x41, &[bits]).build().unwrap();
+
+        feed(&mut d, &set(0x03), 100);
+        assert_eq!(d.outputs(), [0x03]);
+        assert_eq!(d.tick(109), Tick::default());
+        assert!(d.tick(110).outputs_changed);
+        assert_eq!(d.outputs(), [0x01]);
+
+        // Holding the output on doesn't start another pulse
+        feed(&mut d, &set(0x03), 120);
+        assert_eq!(d.outputs(), [0x01]);
+        feed(&mut d, &set(0x01), 130);
+        feed(&mut d, &set(0x03), 140);
+        assert_eq!(d.outputs(), [0x03]);
+
+        d.clear_pulsed(1);
+        assert_eq!(d.tick(200), Tick::default());
+        assert_eq!(d.outputs(), [0x03]);
+    }
+
+    #[test]
+    fn set_inputs_wrong_length() {
+        let mut d = NodeDriver::new(0x41, 2).unwrap();
+        assert_eq!(d.set_inputs(&[1, 2, 3]), Err(Error::OutOfBounds));
+        assert!(NodeDriver::new(0x41, MAX_PAYLOAD_LEN + 1).is_err());
+    }
+}
@@ -5,24 +5,35 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use crate::card::CardSize;
 use crate::error::Error;
 use core::convert::TryFrom;
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
 pub enum NodeType {
     /// Classic USICand for SUSIC using 24 bit input/output cards.
-    Usic = 'N' as isize,
+    Usic,
     /// SUSIC using32 bit input/output cards.
-    Susic = 'X' as isize,
+    Susic,
     /// SMINI with fixed 24 inputs and 48 outputs
-    Smini = 'M' as isize,
+    Smini,
     /// CPNODEwith 16 to 144input/outputs using8 bit cards.
-    Cpnode = 'C' as isize,
+    Cpnode,
+    /// A node definition parameter this crate doesn't know. Only
+    /// produced by `from_lossy()`
+    Unknown(u8),
 }
 
-impl TryFrom<u8> for NodeType {
-    type Error = Error;
-    fn try_from(nt: u8) -> Result<Self, Error> {
+impl NodeType {
+    /// Decodes a node definition parameter, rejecting anything
+    /// unrecognised. The same as `try_from()`
+    pub fn try_from_strict(nt: u8) -> Result<Self, Error> {
         use NodeType::*;
         match nt as char {
             'N' => Ok(Usic),
@@ -32,13 +43,65 @@ impl TryFrom<u8> for NodeType {
             _ => Err(Error::InvalidNodeType),
         }
     }
+
+    /// Decodes a node definition parameter, keeping an unrecognised one
+    /// as `Unknown`
+    pub fn from_lossy(nt: u8) -> Self {
+        Self::try_from_strict(nt).unwrap_or(NodeType::Unknown(nt))
+    }
+
+    /// Size of each input/output card. Unknown nodes are treated as
+    /// having 8 bit cards so that their data can be shown a byte at a
+    /// time
+    pub fn card_size(&self) -> CardSize {
+        use NodeType::*;
+        match self {
+            Usic | Smini => CardSize::Bits24,
+            Susic => CardSize::Bits32,
+            Cpnode | Unknown(_) => CardSize::Bits8,
+        }
+    }
+
+    /// Number of bytes in each input/output card
+    pub fn card_bytes(&self) -> usize {
+        self.card_size().bytes()
+    }
+
+    /// Number of bits in each input/output card
+    pub fn card_bits(&self) -> usize {
+        self.card_size().bits()
+    }
 }
 
+impl TryFrom<u8> for NodeType {
+    type Error = Error;
+    fn try_from(nt: u8) -> Result<Self, Error> {
+        Self::try_from_strict(nt)
+    }
+}
+
+impl From<NodeType> for u8 {
+    fn from(nt: NodeType) -> u8 {
+        use NodeType::*;
+        match nt {
+            Usic => b'N',
+            Susic => b'X',
+            Smini => b'M',
+            Cpnode => b'C',
+            Unknown(nt) => nt,
+        }
+    }
+}
+
+#[cfg(feature = "fmt")]
 impl core::fmt::Display for NodeType {
     fn fmt(
         &self,
         fmt: &mut core::fmt::Formatter<'_>,
     ) -> core::result::Result<(), core::fmt::Error> {
-        write!(fmt, "{:?}", self)
+        match self {
+            NodeType::Unknown(nt) => write!(fmt, "Unknown(0x{:02x})", nt),
+            _ => write!(fmt, "{:?}", self),
+        }
     }
 }
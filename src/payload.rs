@@ -0,0 +1,314 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::card::{Card, CardSet, CardSize};
+use crate::iox::{IoxMap, PortLocation};
+use crate::{CmriMessage, Error, MessageType, NodeType, Result};
+use core::convert::TryFrom;
+
+/// Structured view of a message payload. Borrows from the message that
+/// it was decoded from.
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub enum DecodedMessage<'a> {
+    Init(InitPayload<'a>),
+    Set(OutputData<'a>),
+    Get(InputData<'a>),
+    Poll,
+}
+
+/// What is plugged into a card slot, according to an Init message
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum CardType {
+    None,
+    Input,
+    Output,
+}
+
+/// Node definition parameters from an Init message
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct InitPayload<'a> {
+    pub node_type: NodeType,
+    /// Transmit delay in units of 10us
+    pub transmit_delay: u16,
+    /// Number of card sets for USIC/SUSIC, or number of two-lead signals
+    /// for SMINI
+    pub num_sets: u8,
+    /// Remaining bytes: card types for USIC/SUSIC, signal locations for
+    /// SMINI, options for CPNODE
+    pub card_types: &'a [u8],
+}
+
+impl<'a> InitPayload<'a> {
+    pub fn parse(payload: &'a [u8]) -> Result<Self> {
+        // At least the node definition parameter, a two byte transmit
+        // delay and the number of cards/sets
+        match *payload {
+            [node_type, delay_hi, delay_lo, num_sets, ref card_types @ ..] => {
+                Ok(Self {
+                    node_type: NodeType::try_from(node_type)?,
+                    transmit_delay: u16::from_be_bytes([delay_hi, delay_lo]),
+                    num_sets,
+                    card_types,
+                })
+            }
+            _ => Err(Error::InitTooShort),
+        }
+    }
+
+    /// The node's cards. For USIC and SUSIC nodes these come from the
+    /// card type bytes
+    pub fn card_set(&self) -> CardSet<'a> {
+        CardSet::for_node(self.node_type, self.card_types)
+    }
+
+    /// Iterates over the card slots described by the card type bytes.
+    /// Only meaningful for USIC and SUSIC nodes.
+    pub fn cards(&self) -> impl Iterator<Item = CardType> + 'a {
+        CardSet::from_card_types(self.node_type.card_size(), self.card_types)
+            .slots()
+    }
+
+    /// Total number of input bytes the node will report when polled
+    pub fn input_bytes(&self) -> usize {
+        self.card_set().input_bytes()
+    }
+
+    /// Total number of output bytes the node expects in a Set message
+    pub fn output_bytes(&self) -> usize {
+        self.card_set().output_bytes()
+    }
+}
+
+/// Output bytes from a Set message, split into cards
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct OutputData<'a> {
+    pub bytes: &'a [u8],
+    size: CardSize,
+}
+
+/// Input bytes from a Get message, split into cards
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct InputData<'a> {
+    pub bytes: &'a [u8],
+    size: CardSize,
+}
+
+macro_rules! card_data_impl {
+    ($t:ident, $direction:expr) => {
+        impl<'a> $t<'a> {
+            fn new(bytes: &'a [u8], node_type: NodeType) -> Result<Self> {
+                let size = node_type.card_size();
+                if bytes.is_empty() || bytes.len() % size.bytes() != 0 {
+                    return Err(Error::CardSizeMismatch);
+                }
+                Ok(Self { bytes, size })
+            }
+
+            pub fn num_cards(&self) -> usize {
+                self.bytes.len() / self.size.bytes()
+            }
+
+            /// The cards making up the payload, one after another
+            pub fn card_set(&self) -> CardSet<'static> {
+                let n = self.num_cards();
+                match $direction {
+                    CardType::Input => CardSet::fixed(self.size, n, 0),
+                    _ => CardSet::fixed(self.size, 0, n),
+                }
+            }
+
+            /// Pairs each card with its bytes
+            pub fn cards(&self) -> impl Iterator<Item = (Card, &'a [u8])> {
+                let bytes = self.bytes;
+                self.card_set()
+                    .iter()
+                    .filter_map(move |card| Some((card, card.data(bytes)?)))
+            }
+
+            /// Bytes belonging to the nth card
+            pub fn card(&self, n: usize) -> Option<&'a [u8]> {
+                self.bytes.get(self.size.range(n))
+            }
+
+            /// Pairs each byte with the cpNode port it belongs to
+            pub fn ports<'m>(
+                &self,
+                map: &'m IoxMap,
+            ) -> impl Iterator<Item = (PortLocation, u8)> + 'm
+            where
+                'a: 'm,
+            {
+                map.ports_of($direction).zip(self.bytes.iter().copied())
+            }
+        }
+    };
+}
+
+card_data_impl!(OutputData, CardType::Output);
+card_data_impl!(InputData, CardType::Input);
+
+impl CmriMessage {
+    /// Decodes the payload according to the message type. The node type
+    /// is needed to split Set/Get payloads into cards; Init messages
+    /// carry their own node type.
+    pub fn decode_payload(
+        &self,
+        node_type: NodeType,
+    ) -> Result<DecodedMessage<'_>> {
+        use MessageType::*;
+        let payload = self.data();
+        match self.message_type.ok_or(Error::MissingType)? {
+            Init => Ok(DecodedMessage::Init(InitPayload::parse(payload)?)),
+            Set => {
+                Ok(DecodedMessage::Set(OutputData::new(payload, node_type)?))
+            }
+            Get => Ok(DecodedMessage::Get(InputData::new(payload, node_type)?)),
+            Poll => Ok(DecodedMessage::Poll),
+            Unknown(_) => Err(Error::InvalidMessageType),
+        }
+    }
+
+    /// Decodes a cpNode payload, checking that Set and Get messages have
+    /// one byte for each output or input port in the IOX map
+    pub fn decode_cpnode_payload(
+        &self,
+        map: &IoxMap,
+    ) -> Result<DecodedMessage<'_>> {
+        let expected = match self.message_type {
+            Some(MessageType::Set) => map.output_bytes(),
+            Some(MessageType::Get) => map.input_bytes(),
+            _ => return self.decode_payload(NodeType::Cpnode),
+        };
+        if self.len != expected {
+            return Err(Error::CardSizeMismatch);
+        }
+        self.decode_payload(NodeType::Cpnode)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MessageBuilder;
+
+    #[test]
+    fn decode_init() {
+        // SUSIC, delay 0x0102, 2 sets, IIOO then I---
+        let config = [b'X', 0x01, 0x02, 2, 0b1010_0101, 0b0000_0001];
+        let m = MessageBuilder::init(0x41, &config).build().unwrap();
+        let init = match m.decode_payload(NodeType::Susic).unwrap() {
+            DecodedMessage::Init(init) => init,
+            other => panic!("Expected Init, got {:?}", other),
+        };
+        assert_eq!(init.node_type, NodeType::Susic);
+        assert_eq!(init.transmit_delay, 0x0102);
+        assert_eq!(init.num_sets, 2);
+
+        let mut cards = init.cards();
+        assert_eq!(cards.next(), Some(CardType::Input));
+        assert_eq!(cards.next(), Some(CardType::Input));
+        assert_eq!(cards.next(), Some(CardType::Output));
+        assert_eq!(cards.next(), Some(CardType::Output));
+        assert_eq!(cards.next(), Some(CardType::Input));
+        assert_eq!(cards.next(), Some(CardType::None));
+
+        assert_eq!(init.input_bytes(), 12);
+        assert_eq!(init.output_bytes(), 8);
+    }
+
+    #[test]
+    fn decode_smini_init() {
+        let config = [b'M', 0, 0, 0];
+        let init = InitPayload::parse(&config).unwrap();
+        assert_eq!(init.input_bytes(), 3);
+        assert_eq!(init.output_bytes(), 6);
+    }
+
+    #[test]
+    fn decode_set_and_get() {
+        let m = MessageBuilder::set(0x41, &[1, 2, 3, 4, 5, 6])
+            .build()
+            .unwrap();
+        let out = match m.decode_payload(NodeType::Usic).unwrap() {
+            DecodedMessage::Set(out) => out,
+            other => panic!("Expected Set, got {:?}", other),
+        };
+        assert_eq!(out.num_cards(), 2);
+        assert_eq!(out.card(1), Some(&[4_u8, 5, 6][..]));
+        assert_eq!(out.card(2), None);
+        let (card, bytes) = out.cards().nth(1).unwrap();
+        assert_eq!((card.card_type, card.offset), (CardType::Output, 3));
+        assert_eq!(bytes, &[4, 5, 6]);
+
+        let m = MessageBuilder::get(0x41, &[1, 2, 3, 4]).build().unwrap();
+        let inputs = match m.decode_payload(NodeType::Cpnode).unwrap() {
+            DecodedMessage::Get(inputs) => inputs,
+            other => panic!("Expected Get, got {:?}", other),
+        };
+        assert_eq!(inputs.num_cards(), 4);
+
+        // Four bytes doesn't divide into 24-bit cards
+        let res = m.decode_payload(NodeType::Usic);
+        assert_eq!(res, Err(Error::CardSizeMismatch));
+    }
+
+    #[test]
+    fn decode_cpnode_with_iox() {
+        use crate::iox::Bank;
+        let mut map = IoxMap::new();
+        map.onboard(0, CardType::Input)
+            .unwrap()
+            .onboard(1, CardType::Output)
+            .unwrap()
+            .expander(0x21, Bank::B, CardType::Input)
+            .unwrap();
+
+        let m = MessageBuilder::get(0x41, &[0xaa, 0x55]).build().unwrap();
+        let inputs = match m.decode_cpnode_payload(&map).unwrap() {
+            DecodedMessage::Get(inputs) => inputs,
+            other => panic!("Expected Get, got {:?}", other),
+        };
+        let mut ports = inputs.ports(&map);
+        assert_eq!(ports.next(), Some((PortLocation::Onboard(0), 0xaa)));
+        assert_eq!(
+            ports.next(),
+            Some((
+                PortLocation::Iox {
+                    address: 0x21,
+                    bank: Bank::B
+                },
+                0x55
+            ))
+        );
+        assert_eq!(ports.next(), None);
+
+        // One output port, so two output bytes is wrong
+        let m = MessageBuilder::set(0x41, &[1, 2]).build().unwrap();
+        let res = m.decode_cpnode_payload(&map);
+        assert_eq!(res, Err(Error::CardSizeMismatch));
+    }
+
+    #[test]
+    fn decode_poll() {
+        let m = MessageBuilder::poll(0x41).build().unwrap();
+        assert_eq!(m.decode_payload(NodeType::Smini), Ok(DecodedMessage::Poll));
+        assert_eq!(
+            CmriMessage::new().decode_payload(NodeType::Smini),
+            Err(Error::MissingType)
+        );
+    }
+}
@@ -0,0 +1,177 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Payloads as a `heapless::Vec`, for embedded code which would rather
+// not juggle a `[u8; MAX_PAYLOAD_LEN]` and a separate length by hand. A
+// `Payload` dereferences to the bytes it holds, so it can be handed
+// straight to `CmriMessage::payload()` or `MessageBuilder`, and converts
+// to and from the array form that `CmriMessage` stores. The capacity can
+// be cut down on nodes which never see a full SUSIC payload.
+
+use crate::{payload_from_slice, CmriMessage, Error, Result, MAX_PAYLOAD_LEN};
+use core::convert::TryFrom;
+use core::ops::{Deref, DerefMut};
+use heapless::Vec;
+
+/// Up to `N` payload bytes
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct Payload<const N: usize = MAX_PAYLOAD_LEN>(Vec<u8, N>);
+
+impl<const N: usize> Payload<N> {
+    pub const fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Result<Self> {
+        let mut payload = Self::new();
+        payload.try_extend_from_slice(bytes)?;
+        Ok(payload)
+    }
+
+    /// The first `len` bytes of a payload array, as in a `CmriMessage`
+    pub fn from_raw(
+        payload: &[u8; MAX_PAYLOAD_LEN],
+        len: usize,
+    ) -> Result<Self> {
+        Self::from_slice(payload.get(..len).ok_or(Error::DataTooLong)?)
+    }
+
+    /// The payload as an array and length, as in a `CmriMessage`
+    pub fn to_raw(&self) -> Result<([u8; MAX_PAYLOAD_LEN], usize)> {
+        let mut raw = [0; MAX_PAYLOAD_LEN];
+        payload_from_slice(&mut raw, self)?;
+        Ok((raw, self.len()))
+    }
+
+    /// Appends all of `bytes`, or nothing and `Error::DataTooLong` if
+    /// they don't fit
+    pub fn try_extend_from_slice(&mut self, bytes: &[u8]) -> Result<()> {
+        self.0
+            .extend_from_slice(bytes)
+            .map_err(|_| Error::DataTooLong)
+    }
+
+    pub fn push(&mut self, byte: u8) -> Result<()> {
+        self.0.push(byte).map_err(|_| Error::DataTooLong)
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn into_inner(self) -> Vec<u8, N> {
+        self.0
+    }
+}
+
+impl<const N: usize> Deref for Payload<N> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl<const N: usize> DerefMut for Payload<N> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+impl<const N: usize> AsRef<[u8]> for Payload<N> {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+/// Fails with `Error::DataTooLong` if the bytes don't fit
+impl<const N: usize> TryFrom<&[u8]> for Payload<N> {
+    type Error = Error;
+    fn try_from(bytes: &[u8]) -> Result<Self> {
+        Self::from_slice(bytes)
+    }
+}
+
+impl<const N: usize> From<Vec<u8, N>> for Payload<N> {
+    fn from(vec: Vec<u8, N>) -> Self {
+        Self(vec)
+    }
+}
+
+impl<const N: usize> TryFrom<&CmriMessage> for Payload<N> {
+    type Error = Error;
+    fn try_from(msg: &CmriMessage) -> Result<Self> {
+        Self::from_raw(&msg.payload, msg.len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MessageBuilder;
+
+    #[test]
+    fn extend_and_deref() {
+        let mut payload = Payload::<4>::from_slice(&[1, 2]).unwrap();
+        assert_eq!(
+            payload.try_extend_from_slice(&[3, 4, 5]),
+            Err(Error::DataTooLong)
+        );
+        // A failed extend leaves the payload alone
+        assert_eq!(*payload, [1, 2]);
+        payload.try_extend_from_slice(&[3]).unwrap();
+        payload.push(4).unwrap();
+        assert_eq!(payload.push(5), Err(Error::DataTooLong));
+        payload[0] = 0xff;
+        assert_eq!(*payload, [0xff, 2, 3, 4]);
+        assert_eq!(payload.iter().map(|b| *b as u32).sum::<u32>(), 264);
+        payload.clear();
+        assert!(payload.is_empty());
+        assert_eq!(payload.capacity(), 4);
+    }
+
+    #[test]
+    fn raw_array_round_trip() {
+        let msg = MessageBuilder::set(0x41, &[0x01, 0x03, 0x10])
+            .build()
+            .unwrap();
+        let payload = Payload::<8>::try_from(&msg).unwrap();
+        assert_eq!(*payload, [0x01, 0x03, 0x10]);
+        let (raw, len) = payload.to_raw().unwrap();
+        assert_eq!(raw[..len], msg.payload[..msg.len]);
+        assert_eq!(Payload::<2>::try_from(&msg), Err(Error::DataTooLong));
+
+        let mut copy = CmriMessage::new();
+        copy.payload(&payload).unwrap();
+        assert_eq!(copy.data(), msg.data());
+
+        let raw = [0; MAX_PAYLOAD_LEN];
+        assert!(Payload::<8>::from_raw(&raw, MAX_PAYLOAD_LEN + 1).is_err());
+        let full = Payload::<{ MAX_PAYLOAD_LEN + 1 }>::from_slice(
+            &[0; MAX_PAYLOAD_LEN + 1],
+        )
+        .unwrap();
+        assert_eq!(full.to_raw(), Err(Error::DataTooLong));
+    }
+
+    #[test]
+    fn conversions() {
+        let bytes: &[u8] = &[1, 2, 3];
+        let payload = Payload::<4>::try_from(bytes).unwrap();
+        assert_eq!(payload.as_ref(), bytes);
+        assert_eq!(Payload::<2>::try_from(bytes), Err(Error::DataTooLong));
+
+        let mut seen = std::collections::HashSet::new();
+        assert!(seen.insert(payload.clone()));
+        assert!(!seen.insert(payload));
+    }
+}
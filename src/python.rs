@@ -0,0 +1,360 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Python bindings, for quick layout test scripts and notebooks. Build an
+// importable module with
+//
+//   cargo rustc --release --features python-extension --crate-type cdylib
+//
+// and copy target/release/libcmri.so to cmri.so (cmri.pyd on Windows)
+// somewhere on the Python path. The `python-extension` feature leaves
+// libpython to be supplied by the interpreter loading the module; plain
+// `python` links against it, which is what the tests need.
+//
+// Message types are the single characters sent on the wire, "I", "T",
+// "R" or "P", addresses are the address bytes and payloads are bytes.
+// Errors are raised as `cmri.CmriError`.
+
+use crate::sim::{Behaviour, VirtualBus, VirtualNode};
+use crate::transport::udp::parse_datagram;
+use crate::{
+    CmriMessage, CmriSocket, CmriStateMachine, Controller, ControllerHandle,
+    Error, InitSequence, MessageType, RxState,
+};
+use core::convert::TryFrom;
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyList};
+use std::boxed::Box;
+use std::format;
+use std::string::{String, ToString};
+use std::vec::Vec;
+
+create_exception!(cmri, CmriError, PyException);
+
+fn py_err(e: Error) -> PyErr {
+    CmriError::new_err(e.to_string())
+}
+
+fn message_type(t: &str) -> PyResult<MessageType> {
+    match t.as_bytes() {
+        [b] => MessageType::try_from(*b).map_err(py_err),
+        _ => Err(PyValueError::new_err("message type must be one character")),
+    }
+}
+
+/// A C/MRI message
+#[pyclass(name = "CmriMessage")]
+#[derive(Clone)]
+pub struct PyMessage {
+    inner: CmriMessage,
+}
+
+#[pymethods]
+impl PyMessage {
+    #[new]
+    #[pyo3(signature = (address, message_type, payload = Vec::new()))]
+    fn new(
+        address: u8,
+        message_type: &str,
+        payload: Vec<u8>,
+    ) -> PyResult<Self> {
+        let t = self::message_type(message_type)?;
+        let mut inner = CmriMessage::new();
+        inner
+            .address(address)
+            .message_type(t)
+            .payload(&payload)
+            .map_err(py_err)?;
+        Ok(Self { inner })
+    }
+
+    #[getter]
+    fn address(&self) -> Option<u8> {
+        self.inner.address
+    }
+
+    #[getter]
+    fn message_type(&self) -> Option<String> {
+        self.inner
+            .message_type
+            .map(|t| char::from(u8::from(t)).to_string())
+    }
+
+    #[getter]
+    fn payload(&self) -> Vec<u8> {
+        self.inner.data().to_vec()
+    }
+
+    /// The whole frame, ready to send
+    fn encode(&self) -> PyResult<Vec<u8>> {
+        let mut buf = [0_u8; crate::TX_BUFFER_LEN];
+        let len = self.inner.encode(&mut buf).map_err(py_err)?;
+        Ok(buf.get(..len).unwrap_or_default().to_vec())
+    }
+
+    fn __eq__(&self, other: &Self) -> bool {
+        self.inner == other.inner
+    }
+
+    fn __repr__(&self, py: Python<'_>) -> PyResult<String> {
+        let args = (
+            self.address(),
+            self.message_type(),
+            PyBytes::new(py, self.inner.data()),
+        );
+        Ok(format!("CmriMessage{}", args.into_pyobject(py)?.repr()?))
+    }
+}
+
+/// Decodes exactly one frame
+#[pyfunction]
+fn parse(frame: &[u8]) -> PyResult<PyMessage> {
+    let inner = parse_datagram(frame).map_err(py_err)?;
+    Ok(PyMessage { inner })
+}
+
+/// The receiving state machine, fed bytes as they arrive
+#[pyclass(name = "StateMachine")]
+pub struct PyStateMachine {
+    inner: CmriStateMachine,
+}
+
+#[pymethods]
+impl PyStateMachine {
+    #[new]
+    fn new() -> Self {
+        Self {
+            inner: CmriStateMachine::new(),
+        }
+    }
+
+    /// Feeds one byte in, returning True once a whole frame has arrived
+    fn process(&mut self, byte: u8) -> PyResult<bool> {
+        match self.inner.process(byte).map_err(py_err)? {
+            RxState::Complete => Ok(true),
+            RxState::Listening => Ok(false),
+        }
+    }
+
+    /// Feeds a buffer in, returning every message completed by it. Bad
+    /// frames are skipped
+    fn feed(&mut self, data: &[u8]) -> Vec<PyMessage> {
+        let mut messages = Vec::new();
+        for byte in data {
+            if let Ok(RxState::Complete) = self.inner.process(*byte) {
+                messages.push(PyMessage {
+                    inner: *self.inner.message(),
+                });
+            }
+        }
+        messages
+    }
+
+    /// The last complete message
+    #[getter]
+    fn message(&self) -> PyMessage {
+        PyMessage {
+            inner: *self.inner.message(),
+        }
+    }
+
+    fn clear(&mut self) {
+        self.inner.clear();
+    }
+}
+
+/// A polling controller together with the bus it drives
+#[pyclass(name = "Controller", unsendable)]
+pub struct PyController {
+    controller: Controller,
+    handle: ControllerHandle,
+    socket: CmriSocket,
+}
+
+impl PyController {
+    fn with_socket(socket: CmriSocket) -> Self {
+        let controller = Controller::new();
+        Self {
+            handle: controller.handle(),
+            controller,
+            socket,
+        }
+    }
+}
+
+#[pymethods]
+impl PyController {
+    /// Opens a serial port, e.g. "/dev/ttyUSB0" or "COM3"
+    #[cfg(feature = "serial")]
+    #[staticmethod]
+    #[pyo3(signature = (path, baud = 9600))]
+    fn serial(path: &str, baud: u32) -> PyResult<Self> {
+        use crate::transport::serial::SerialTransport;
+        use crate::SerialConfig;
+
+        let transport = SerialTransport::open(path, &SerialConfig::new(baud))
+            .map_err(py_err)?;
+        Ok(Self::with_socket(
+            CmriSocket::builder(Box::new(transport)).build(),
+        ))
+    }
+
+    /// A virtual layout with no hardware. Each node is an (address,
+    /// input bytes) pair, and reports its outputs back as its inputs
+    #[staticmethod]
+    fn simulated(nodes: Vec<(u8, usize)>) -> PyResult<Self> {
+        let bus = VirtualBus::new();
+        for (address, input_len) in nodes {
+            let node =
+                VirtualNode::new(address, input_len, Behaviour::MirrorOutputs)
+                    .map_err(py_err)?;
+            bus.add_node(node);
+        }
+        Ok(Self::with_socket(
+            CmriSocket::builder(Box::new(bus)).build(),
+        ))
+    }
+
+    /// Adds a node to the polling cycle, optionally with the Init payload
+    /// to send it in `initialise()`
+    #[pyo3(signature = (address, init = None))]
+    fn add_node(&mut self, address: u8, init: Option<Vec<u8>>) -> PyResult<()> {
+        match init {
+            Some(init) => self
+                .controller
+                .add_node_with_init(address, &init)
+                .map_err(py_err),
+            None => {
+                self.controller.add_node(address);
+                Ok(())
+            }
+        }
+    }
+
+    fn remove_node(&mut self, address: u8) {
+        self.controller.remove_node(address);
+    }
+
+    /// Nodes in the polling cycle. A list rather than bytes
+    #[getter]
+    fn nodes<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyList>> {
+        PyList::new(py, self.controller.nodes())
+    }
+
+    /// Sends every node its Init and checks that it answers
+    fn initialise(&mut self) -> PyResult<()> {
+        self.controller
+            .initialise(&mut self.socket, &InitSequence::default())
+            .map_err(py_err)
+    }
+
+    /// Sends any changed outputs and polls the next node
+    fn step(&mut self) -> PyResult<()> {
+        self.controller.step(&mut self.socket).map_err(py_err)
+    }
+
+    fn poll(&mut self, address: u8) -> PyResult<PyMessage> {
+        let inner = self.socket.poll(address).map_err(py_err)?;
+        Ok(PyMessage { inner })
+    }
+
+    fn send(&mut self, message: &PyMessage) -> PyResult<()> {
+        self.socket.send(&message.inner).map_err(py_err)
+    }
+
+    /// Inputs from the node's most recent Get, or None if it hasn't
+    /// answered yet
+    fn inputs(&self, address: u8) -> Option<Vec<u8>> {
+        self.handle.inputs(address)
+    }
+
+    fn input(&self, address: u8, bit: usize) -> Option<bool> {
+        self.handle.input(address, bit)
+    }
+
+    /// Replaces a node's outputs, sent on the next step
+    fn set_outputs(&self, address: u8, outputs: Vec<u8>) -> PyResult<()> {
+        self.handle.set_outputs(address, &outputs).map_err(py_err)
+    }
+
+    fn set_output(&self, address: u8, bit: usize, value: bool) -> PyResult<()> {
+        self.handle.set_output(address, bit, value).map_err(py_err)
+    }
+}
+
+#[pymodule]
+fn cmri(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("CmriError", m.py().get_type::<CmriError>())?;
+    m.add_class::<PyMessage>()?;
+    m.add_class::<PyStateMachine>()?;
+    m.add_class::<PyController>()?;
+    m.add_function(wrap_pyfunction!(parse, m)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pyo3::types::PyDict;
+    use std::ffi::CString;
+
+    /// Runs a script with the module imported as `cmri`
+    fn run(script: &str) {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let module = PyModule::new(py, "cmri").unwrap();
+            cmri(&module).unwrap();
+            let globals = PyDict::new(py);
+            globals.set_item("cmri", module).unwrap();
+            if let Err(e) =
+                py.run(&CString::new(script).unwrap(), Some(&globals), None)
+            {
+                e.print(py);
+                panic!("script failed");
+            }
+        });
+    }
+
+    #[test]
+    fn messages() {
+        run(r#"
+msg = cmri.CmriMessage(0x41, "T", b"\x01\x03\x02")
+frame = msg.encode()
+assert frame == b"\xff\xff\x02\x41\x54\x01\x10\x03\x02\x03", frame
+assert cmri.parse(frame) == msg
+assert cmri.parse(frame).payload == b"\x01\x03\x02"
+assert repr(msg) == "CmriMessage(65, 'T', b'\\x01\\x03\\x02')", repr(msg)
+
+sm = cmri.StateMachine()
+got = sm.feed(frame + cmri.CmriMessage(0x42, "P").encode())
+assert [(m.address, m.message_type) for m in got] == [(0x41, "T"), (0x42, "P")]
+
+try:
+    cmri.CmriMessage(0x41, "Z")
+    raise AssertionError("bad type accepted")
+except cmri.CmriError:
+    pass
+"#);
+    }
+
+    #[test]
+    fn simulated_controller() {
+        run(r#"
+c = cmri.Controller.simulated([(0x41, 2)])
+c.add_node(0x41)
+assert c.nodes == [0x41]
+assert c.inputs(0x41) is None
+c.set_outputs(0x41, b"\x05\x06")
+c.step()
+assert c.inputs(0x41) == b"\x05\x06"
+assert c.input(0x41, 2)
+assert c.poll(0x41).payload == b"\x05\x06"
+"#);
+    }
+}
@@ -0,0 +1,140 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Frames which failed to decode, kept for later inspection. When a node
+// sends a bad type byte or overruns the payload buffer, the state machine
+// puts the start of the frame as received, along with the time and the
+// reason, into a small ring here. That is usually enough to see which
+// node it was and what it thought it was sending, without paying for
+// `raw-capture` on every frame. Only the first `QUARANTINE_BYTES` of each
+// frame are kept, and the oldest frame makes way once the ring is full.
+//
+// Times come from `CmriStateMachine::set_time()`, in whatever ticks the
+// caller's clock uses; `CmriSocket` gives microseconds since it was
+// built.
+
+use crate::ResetReason;
+
+/// Frames kept in the quarantine ring
+pub const QUARANTINE_LEN: usize = 4;
+
+/// Bytes kept from the start of each quarantined frame, enough for the
+/// preamble, header and the first few payload bytes
+pub const QUARANTINE_BYTES: usize = 16;
+
+/// A frame dropped part way through decoding
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct QuarantinedFrame {
+    /// Time at which the frame was dropped
+    pub at: u64,
+    pub reason: ResetReason,
+    /// Bytes received up to and including the one which failed, which may
+    /// be more than were kept
+    pub len: usize,
+    bytes: [u8; QUARANTINE_BYTES],
+}
+
+impl QuarantinedFrame {
+    /// The start of the frame exactly as received, from the first
+    /// preamble byte
+    pub fn bytes(&self) -> &[u8] {
+        self.bytes.get(..self.len).unwrap_or(&self.bytes)
+    }
+
+    /// True if only the start of the frame was kept
+    pub fn truncated(&self) -> bool {
+        self.len > QUARANTINE_BYTES
+    }
+}
+
+/// The last `QUARANTINE_LEN` frames which failed to decode
+#[derive(Copy, Clone, Default, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct Quarantine {
+    frames: [Option<QuarantinedFrame>; QUARANTINE_LEN],
+    /// Frames quarantined in total, so the oldest is at
+    /// `total % QUARANTINE_LEN` once the ring is full
+    total: u32,
+}
+
+impl Quarantine {
+    pub(crate) fn record(
+        &mut self,
+        at: u64,
+        reason: ResetReason,
+        head: &[u8; QUARANTINE_BYTES],
+        len: usize,
+    ) {
+        let slot = self.frames.get_mut(self.total as usize % QUARANTINE_LEN);
+        if let Some(slot) = slot {
+            *slot = Some(QuarantinedFrame {
+                at,
+                reason,
+                len,
+                bytes: *head,
+            });
+        }
+        self.total = self.total.wrapping_add(1);
+    }
+
+    /// Kept frames, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &QuarantinedFrame> + '_ {
+        let start = self.total as usize % QUARANTINE_LEN;
+        let (newer, older) = self.frames.split_at(start);
+        older.iter().chain(newer).flatten()
+    }
+
+    /// The most recently quarantined frame
+    pub fn last(&self) -> Option<&QuarantinedFrame> {
+        self.iter().last()
+    }
+
+    /// Number of frames kept
+    pub fn len(&self) -> usize {
+        self.frames.iter().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Frames quarantined since creation or the last `clear()`, including
+    /// those which have since made way for newer ones. Wraps on overflow
+    pub fn total(&self) -> u32 {
+        self.total
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keeps_the_newest() {
+        let mut quarantine = Quarantine::default();
+        assert!(quarantine.is_empty());
+        let head = [0xff; QUARANTINE_BYTES];
+        for n in 0..6 {
+            quarantine.record(n, ResetReason::BadType, &head, 5);
+        }
+        assert_eq!(quarantine.total(), 6);
+        assert_eq!(quarantine.len(), QUARANTINE_LEN);
+        assert!(quarantine.iter().map(|f| f.at).eq(2..6));
+        assert_eq!(quarantine.last().map(|f| f.at), Some(5));
+
+        let frame = quarantine.last().unwrap();
+        assert_eq!(frame.bytes(), [0xff; 5]);
+        assert!(!frame.truncated());
+        quarantine.clear();
+        assert_eq!(quarantine.iter().count(), 0);
+    }
+}
@@ -0,0 +1,359 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Named I/O points, so that application code can say
+// `registry.set_output("yard_throat_turnout", true)` rather than poking
+// bit 0 of node 3. The registry keeps an image of every node's inputs and
+// outputs: Get messages from the bus update the input images, and Set
+// messages are produced for nodes whose outputs have changed.
+
+use crate::config::LayoutConfig;
+use crate::{
+    BitOrder, CmriMessage, Error, MessageBuilder, MessageType, Result,
+};
+use std::collections::BTreeMap;
+use std::format;
+use std::string::String;
+use std::vec;
+use std::vec::Vec;
+
+/// Where a named point lives
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct IoPoint {
+    /// Node address as sent on the wire
+    pub node: u8,
+    pub byte: usize,
+    /// Counting from the least significant bit of the byte, whatever the
+    /// node's bit order
+    pub bit: u8,
+}
+
+/// Last known inputs and desired outputs for one node
+struct NodeImage {
+    address: u8,
+    inputs: Vec<u8>,
+    outputs: Vec<u8>,
+    /// Outputs have changed since the last Set message
+    dirty: bool,
+}
+
+pub struct IoRegistry {
+    nodes: Vec<NodeImage>,
+    inputs: BTreeMap<String, IoPoint>,
+    outputs: BTreeMap<String, IoPoint>,
+}
+
+impl IoRegistry {
+    /// Builds the registry from the named points in a layout. Every point
+    /// must fit within its node's inputs or outputs, and names must be
+    /// unique across the layout.
+    pub fn from_layout(layout: &LayoutConfig) -> Result<Self> {
+        let mut registry = Self {
+            nodes: Vec::new(),
+            inputs: BTreeMap::new(),
+            outputs: BTreeMap::new(),
+        };
+        for (node, entry) in layout.nodes.iter().zip(layout.roster()?) {
+            for (name, bit) in node.inputs.iter() {
+                let point = point(
+                    entry.address,
+                    node.bit_order,
+                    *bit,
+                    entry.input_bytes,
+                )?;
+                insert(&mut registry.inputs, name, point)?;
+            }
+            for (name, bit) in node.outputs.iter() {
+                let point = point(
+                    entry.address,
+                    node.bit_order,
+                    *bit,
+                    entry.output_bytes,
+                )?;
+                insert(&mut registry.outputs, name, point)?;
+            }
+            registry.nodes.push(NodeImage {
+                address: entry.address,
+                inputs: vec![0; entry.input_bytes],
+                outputs: vec![0; entry.output_bytes],
+                dirty: false,
+            });
+        }
+        Ok(registry)
+    }
+
+    /// Rebuilds the named points from a new layout. Nodes whose input and
+    /// output sizes haven't changed keep their images, so outputs stay
+    /// as they were set. If the layout is rejected the registry is
+    /// unchanged
+    pub fn reload(&mut self, layout: &LayoutConfig) -> Result<()> {
+        let mut new = Self::from_layout(layout)?;
+        for node in new.nodes.iter_mut() {
+            let old = match self.node(node.address) {
+                Ok(old) => old,
+                Err(_) => continue,
+            };
+            if old.inputs.len() == node.inputs.len()
+                && old.outputs.len() == node.outputs.len()
+            {
+                node.inputs.clone_from(&old.inputs);
+                node.outputs.clone_from(&old.outputs);
+                node.dirty = old.dirty;
+            }
+        }
+        *self = new;
+        Ok(())
+    }
+
+    pub fn input_point(&self, name: &str) -> Option<IoPoint> {
+        self.inputs.get(name).copied()
+    }
+
+    pub fn output_point(&self, name: &str) -> Option<IoPoint> {
+        self.outputs.get(name).copied()
+    }
+
+    /// Last known state of a named input
+    pub fn input(&self, name: &str) -> Result<bool> {
+        let point = self.input_point(name).ok_or(Error::UnknownPoint)?;
+        let node = self.node(point.node)?;
+        let byte = node.inputs.get(point.byte).ok_or(Error::OutOfBounds)?;
+        Ok(byte & (1 << point.bit) != 0)
+    }
+
+    /// Current state of a named output
+    pub fn output(&self, name: &str) -> Result<bool> {
+        let point = self.output_point(name).ok_or(Error::UnknownPoint)?;
+        let node = self.node(point.node)?;
+        let byte = node.outputs.get(point.byte).ok_or(Error::OutOfBounds)?;
+        Ok(byte & (1 << point.bit) != 0)
+    }
+
+    /// Changes a named output. It is sent to the node by the next
+    /// `set_messages()`
+    pub fn set_output(&mut self, name: &str, val: bool) -> Result<()> {
+        let point = self.output_point(name).ok_or(Error::UnknownPoint)?;
+        let node = self.node_mut(point.node)?;
+        let byte =
+            node.outputs.get_mut(point.byte).ok_or(Error::OutOfBounds)?;
+        let old = *byte;
+        if val {
+            *byte |= 1 << point.bit;
+        } else {
+            *byte &= !(1 << point.bit);
+        }
+        node.dirty |= *byte != old;
+        Ok(())
+    }
+
+    /// Updates the input image from a Get message. Returns false if the
+    /// message wasn't a Get for a known node
+    pub fn update_inputs(&mut self, msg: &CmriMessage) -> bool {
+        if msg.message_type != Some(MessageType::Get) {
+            return false;
+        }
+        let node = match msg.address.and_then(|a| self.node_mut(a).ok()) {
+            Some(node) => node,
+            None => return false,
+        };
+        for (input, byte) in node.inputs.iter_mut().zip(msg.data()) {
+            *input = *byte;
+        }
+        true
+    }
+
+    /// Set messages for every node whose outputs have changed since the
+    /// last call
+    pub fn set_messages(&mut self) -> Result<Vec<CmriMessage>> {
+        let mut messages = Vec::new();
+        for node in self.nodes.iter_mut().filter(|n| n.dirty) {
+            messages.push(
+                MessageBuilder::set(node.address, &node.outputs).build()?,
+            );
+            node.dirty = false;
+        }
+        Ok(messages)
+    }
+
+    fn node(&self, address: u8) -> Result<&NodeImage> {
+        self.nodes
+            .iter()
+            .find(|n| n.address == address)
+            .ok_or(Error::UnknownPoint)
+    }
+
+    fn node_mut(&mut self, address: u8) -> Result<&mut NodeImage> {
+        self.nodes
+            .iter_mut()
+            .find(|n| n.address == address)
+            .ok_or(Error::UnknownPoint)
+    }
+}
+
+/// Splits a bit number into byte and bit, checking it fits the node
+fn point(node: u8, order: BitOrder, bit: usize, len: usize) -> Result<IoPoint> {
+    let (byte, bit) = order.locate(bit);
+    if byte >= len {
+        return Err(Error::OutOfBounds);
+    }
+    Ok(IoPoint { node, byte, bit })
+}
+
+fn insert(
+    points: &mut BTreeMap<String, IoPoint>,
+    name: &str,
+    point: IoPoint,
+) -> Result<()> {
+    if points.insert(name.into(), point).is_some() {
+        return Err(Error::ConfigError(format!(
+            "I/O point {} is defined more than once",
+            name
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LAYOUT: &str = r#"
+        [[nodes]]
+        address = 0
+        node_type = "smini"
+
+        [nodes.inputs]
+        yard_throat_occupied = 10
+
+        [nodes.outputs]
+        yard_throat_turnout = 0
+        yard_signal_red = 9
+
+        [[nodes]]
+        address = 1
+        node_type = "usic"
+        cards = ["output"]
+
+        [nodes.outputs]
+        platform_lights = 0
+    "#;
+
+    fn registry() -> IoRegistry {
+        IoRegistry::from_layout(&LayoutConfig::from_toml(LAYOUT).unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn lookup_points() {
+        let r = registry();
+        assert_eq!(
+            r.output_point("yard_signal_red"),
+            Some(IoPoint {
+                node: 65,
+                byte: 1,
+                bit: 1
+            })
+        );
+        assert_eq!(r.input("nonexistent"), Err(Error::UnknownPoint));
+        // Inputs and outputs are looked up separately
+        assert_eq!(r.input("yard_throat_turnout"), Err(Error::UnknownPoint));
+    }
+
+    #[test]
+    fn outputs_produce_set_messages() {
+        let mut r = registry();
+        assert!(r.set_messages().unwrap().is_empty());
+
+        r.set_output("yard_signal_red", true).unwrap();
+        r.set_output("yard_throat_turnout", true).unwrap();
+        assert_eq!(r.output("yard_signal_red"), Ok(true));
+        let msgs = r.set_messages().unwrap();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].address, Some(65));
+        assert_eq!(msgs[0].payload[..msgs[0].len], [1, 0b10, 0, 0, 0, 0]);
+
+        // Nothing changed, so nothing to send
+        r.set_output("yard_signal_red", true).unwrap();
+        assert!(r.set_messages().unwrap().is_empty());
+    }
+
+    #[test]
+    fn inputs_follow_get_messages() {
+        let mut r = registry();
+        assert_eq!(r.input("yard_throat_occupied"), Ok(false));
+        let get = MessageBuilder::get(65, &[0, 0b100, 0]).build().unwrap();
+        assert!(r.update_inputs(&get));
+        assert_eq!(r.input("yard_throat_occupied"), Ok(true));
+
+        let other = MessageBuilder::get(99, &[1]).build().unwrap();
+        assert!(!r.update_inputs(&other));
+    }
+
+    #[test]
+    fn msb_first_nodes() {
+        let layout = LayoutConfig::from_toml(
+            r#"
+            [[nodes]]
+            address = 0
+            node_type = "smini"
+            bit_order = "msb_first"
+            [nodes.inputs]
+            block_1 = 0
+            [nodes.outputs]
+            signal = 9
+            "#,
+        )
+        .unwrap();
+        let mut r = IoRegistry::from_layout(&layout).unwrap();
+        r.set_output("signal", true).unwrap();
+        let msgs = r.set_messages().unwrap();
+        assert_eq!(msgs[0].payload[..2], [0, 0b0100_0000]);
+
+        let get = MessageBuilder::get(65, &[0x80, 0, 0]).build().unwrap();
+        r.update_inputs(&get);
+        assert_eq!(r.input("block_1"), Ok(true));
+    }
+
+    #[test]
+    fn reject_bad_points() {
+        let layout = LayoutConfig::from_toml(
+            r#"
+            [[nodes]]
+            address = 0
+            node_type = "smini"
+            [nodes.inputs]
+            too_far = 24
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            IoRegistry::from_layout(&layout).err(),
+            Some(Error::OutOfBounds)
+        );
+    }
+
+    #[test]
+    fn reload_keeps_images() {
+        let mut registry = registry();
+        registry.set_output("yard_throat_turnout", true).unwrap();
+        registry.set_output("platform_lights", true).unwrap();
+        registry.set_messages().unwrap();
+
+        // Rename a point and give node 1 more cards
+        let layout = LAYOUT
+            .replace("yard_signal_red", "yard_signal_green")
+            .replace(r#"["output"]"#, r#"["output", "output"]"#);
+        let layout = LayoutConfig::from_toml(&layout).unwrap();
+        registry.reload(&layout).unwrap();
+
+        assert!(registry.output("yard_throat_turnout").unwrap());
+        assert!(registry.output_point("yard_signal_red").is_none());
+        assert!(!registry.output("yard_signal_green").unwrap());
+        assert!(!registry.output("platform_lights").unwrap());
+        assert!(registry.set_messages().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,458 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Recording a controller's conversation with its nodes, and playing the
+// nodes' side of it back, so that a problem seen on a layout can be
+// reproduced against the application at a desk.
+//
+// A `Recorder` wraps the real transport and notes every frame sent and
+// received, with the time since recording started, in a `Session`. The
+// session saves as text, one frame per line:
+//
+//     1520 tx ff ff 02 41 50 03
+//     3310 rx ff ff 02 41 47 01 03
+//
+// A `Replayer` stands in for the bus. Whenever the controller under test
+// polls a node, it answers with whatever followed the next recorded Poll
+// to that node, or stays quiet if nothing did, so a run goes the same way
+// however fast or slow the test is. Everything else the controller sends
+// is kept for comparing against the recording.
+
+use crate::transport::udp::parse_datagram;
+use crate::{
+    CmriMessage, CmriStateMachine, MessageType, Result, RxState, TX_BUFFER_LEN,
+};
+use core::convert::TryFrom;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, ErrorKind, Read, Write};
+use std::string::String;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+
+/// Which way a recorded frame went
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// From the controller to the bus
+    Sent,
+    /// From the bus to the controller
+    Received,
+}
+
+/// One frame of a recorded session
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Event {
+    /// Time since recording started
+    pub at: Duration,
+    pub direction: Direction,
+    pub message: CmriMessage,
+}
+
+/// Frames exchanged between a controller and its nodes, in order
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Session {
+    events: Vec<Event>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    pub fn push(&mut self, event: Event) {
+        self.events.push(event);
+    }
+
+    /// Frames sent by the controller
+    pub fn sent(&self) -> impl Iterator<Item = &CmriMessage> + '_ {
+        self.events
+            .iter()
+            .filter(|e| e.direction == Direction::Sent)
+            .map(|e| &e.message)
+    }
+
+    /// Saves the session as text, one frame per line
+    pub fn write_to<W: Write>(&self, mut out: W) -> Result<()> {
+        let mut frame = [0_u8; TX_BUFFER_LEN];
+        for event in self.events.iter() {
+            let direction = match event.direction {
+                Direction::Sent => "tx",
+                Direction::Received => "rx",
+            };
+            write!(out, "{} {}", event.at.as_micros(), direction)?;
+            for byte in event.message.encode_slice(&mut frame)? {
+                write!(out, " {:02x}", byte)?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    /// Loads a session saved by `write_to()`. Blank lines and lines
+    /// starting with `#` are skipped
+    pub fn read_from<R: BufRead>(input: R) -> Result<Self> {
+        let mut session = Self::new();
+        for line in input.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            session.push(parse_line(line).ok_or_else(|| bad_line(line))?);
+        }
+        Ok(session)
+    }
+}
+
+fn parse_line(line: &str) -> Option<Event> {
+    let mut fields = line.split_whitespace();
+    let at = Duration::from_micros(fields.next()?.parse().ok()?);
+    let direction = match fields.next()? {
+        "tx" => Direction::Sent,
+        "rx" => Direction::Received,
+        _ => return None,
+    };
+    let frame = fields
+        .map(|byte| u8::from_str_radix(byte, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    let message = parse_datagram(&frame).ok()?;
+    Some(Event {
+        at,
+        direction,
+        message,
+    })
+}
+
+fn bad_line(line: &str) -> crate::Error {
+    let mut msg = String::from("bad session line: ");
+    msg.push_str(line);
+    io::Error::new(ErrorKind::InvalidData, msg).into()
+}
+
+/// Decodes bytes a chunk at a time, handing over each complete frame
+fn decode(
+    state: &mut CmriStateMachine,
+    mut bytes: &[u8],
+    mut complete: impl FnMut(&CmriMessage),
+) {
+    while !bytes.is_empty() {
+        let (used, res) = state.process_buf(bytes);
+        if res == Ok(RxState::Complete) {
+            complete(state.message());
+        }
+        bytes = bytes.get(used..).unwrap_or_default();
+    }
+}
+
+/// A session being recorded, which can be read from any thread
+#[derive(Clone, Default)]
+pub struct Recording(Arc<Mutex<Session>>);
+
+impl Recording {
+    /// A copy of the session so far
+    pub fn session(&self) -> Session {
+        self.lock().clone()
+    }
+
+    fn push(&self, at: Duration, direction: Direction, message: &CmriMessage) {
+        self.lock().push(Event {
+            at,
+            direction,
+            message: *message,
+        });
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Session> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Wraps a transport and records every frame going either way
+pub struct Recorder<T> {
+    inner: T,
+    recording: Recording,
+    started: Instant,
+    tx: CmriStateMachine,
+    rx: CmriStateMachine,
+}
+
+impl<T> Recorder<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            recording: Recording::default(),
+            started: Instant::now(),
+            tx: CmriStateMachine::new(),
+            rx: CmriStateMachine::new(),
+        }
+    }
+
+    /// The session being recorded, to keep hold of once the recorder
+    /// has been handed to a socket
+    pub fn recording(&self) -> Recording {
+        self.recording.clone()
+    }
+
+    /// Time since recording started, to the microsecond as saved
+    fn elapsed(&self) -> Duration {
+        let micros = self.started.elapsed().as_micros();
+        Duration::from_micros(u64::try_from(micros).unwrap_or(u64::MAX))
+    }
+}
+
+impl<T: Read> Read for Recorder<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        let (recording, at) = (&self.recording, self.elapsed());
+        decode(&mut self.rx, buf.get(..len).unwrap_or_default(), |msg| {
+            recording.push(at, Direction::Received, msg)
+        });
+        Ok(len)
+    }
+}
+
+impl<T: Write> Write for Recorder<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        let (recording, at) = (&self.recording, self.elapsed());
+        decode(&mut self.tx, buf.get(..len).unwrap_or_default(), |msg| {
+            recording.push(at, Direction::Sent, msg)
+        });
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct ReplayState {
+    session: Session,
+    /// Index of the first recorded event not yet played back
+    cursor: usize,
+    decoder: CmriStateMachine,
+    to_controller: VecDeque<u8>,
+    /// Frames sent by the controller under test
+    sent: Vec<CmriMessage>,
+    /// Polls with nothing left in the recording to match them
+    unmatched_polls: u32,
+}
+
+impl ReplayState {
+    /// Queues the responses which followed the next recorded Poll to the
+    /// same node
+    fn answer(&mut self, poll: &CmriMessage) {
+        let events = self.session.events.get(self.cursor..).unwrap_or_default();
+        let found = events.iter().position(|e| {
+            e.direction == Direction::Sent
+                && e.message.message_type == Some(MessageType::Poll)
+                && e.message.address == poll.address
+        });
+        let start = match found {
+            Some(found) => self.cursor + found + 1,
+            None => {
+                self.unmatched_polls = self.unmatched_polls.wrapping_add(1);
+                return;
+            }
+        };
+        let mut frame = [0_u8; TX_BUFFER_LEN];
+        let mut end = start;
+        for event in self.session.events.get(start..).unwrap_or_default() {
+            if event.direction == Direction::Sent {
+                break;
+            }
+            if let Ok(bytes) = event.message.encode_slice(&mut frame) {
+                self.to_controller.extend(bytes);
+            }
+            end += 1;
+        }
+        self.cursor = end;
+    }
+}
+
+/// Plays the nodes' side of a recorded session back to a controller.
+/// Clones share the same playback, so one can be kept to check what the
+/// controller sent once the replayer has been handed to a socket
+#[derive(Clone)]
+pub struct Replayer {
+    inner: Arc<Mutex<ReplayState>>,
+}
+
+impl Replayer {
+    pub fn new(session: Session) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(ReplayState {
+                session,
+                cursor: 0,
+                decoder: CmriStateMachine::new(),
+                to_controller: VecDeque::new(),
+                sent: Vec::new(),
+                unmatched_polls: 0,
+            })),
+        }
+    }
+
+    /// Frames sent by the controller under test so far
+    pub fn sent(&self) -> Vec<CmriMessage> {
+        self.lock().sent.clone()
+    }
+
+    /// Polls which found no more recorded Polls to the same node, and so
+    /// went unanswered
+    pub fn unmatched_polls(&self) -> u32 {
+        self.lock().unmatched_polls
+    }
+
+    /// Position of the first frame the controller under test sent which
+    /// differs from the recording, or `None` if everything it has sent so
+    /// far matches. Sending fewer frames than were recorded is fine, as
+    /// the test may stop early
+    pub fn divergence(&self) -> Option<usize> {
+        let inner = self.lock();
+        let mut recorded = inner.session.sent();
+        inner
+            .sent
+            .iter()
+            .position(|msg| recorded.next() != Some(msg))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ReplayState> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Write for Replayer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut inner = self.lock();
+        let inner = &mut *inner;
+        let mut sent = Vec::new();
+        decode(&mut inner.decoder, buf, |msg| sent.push(*msg));
+        for msg in sent {
+            if msg.message_type == Some(MessageType::Poll) {
+                inner.answer(&msg);
+            }
+            inner.sent.push(msg);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for Replayer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut inner = self.lock();
+        if inner.to_controller.is_empty() && !buf.is_empty() {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        let mut count = 0;
+        for slot in buf.iter_mut() {
+            match inner.to_controller.pop_front() {
+                Some(byte) => *slot = byte,
+                None => break,
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sim::{Behaviour, VirtualBus, VirtualNode};
+    use crate::{CmriSocket, Controller, InputChanged};
+    use std::boxed::Box;
+    use std::vec;
+
+    fn controller() -> Controller {
+        let mut controller = Controller::new();
+        controller.add_node(0x41);
+        controller.add_node(0x42);
+        controller
+    }
+
+    fn socket(transport: Box<dyn crate::transport::ReadWrite>) -> CmriSocket {
+        CmriSocket::builder(transport)
+            .read_timeout(Duration::from_millis(5))
+            .build()
+    }
+
+    /// Runs the application: light an output and note every input edge
+    fn run(
+        controller: &mut Controller,
+        socket: &mut CmriSocket,
+    ) -> Vec<InputChanged> {
+        let edges = controller.handle().subscribe();
+        controller.handle().set_output(0x41, 3, true).unwrap();
+        for _ in 0..6 {
+            controller.step(socket).unwrap();
+        }
+        std::iter::from_fn(|| edges.try_recv().ok()).collect()
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let bus = VirtualBus::new();
+        let patterns = vec![vec![0x00], vec![0x01], vec![0x03]];
+        let node = VirtualNode::new(0x41, 1, Behaviour::Sequence(patterns));
+        bus.add_node(node.unwrap());
+        // 0x42 is missing, so its Polls go unanswered
+        let recorder = Recorder::new(bus);
+        let recording = recorder.recording();
+        let mut socket = socket(Box::new(recorder));
+        let edges = run(&mut controller(), &mut socket);
+        assert!(!edges.is_empty());
+
+        // Through the text format and back
+        let mut text = Vec::new();
+        recording.session().write_to(&mut text).unwrap();
+        let session = Session::read_from(&text[..]).unwrap();
+        assert_eq!(session, recording.session());
+        // One Set and six Polls, and three Gets
+        assert_eq!(session.sent().count(), 7);
+        assert_eq!(session.events().len(), 10);
+
+        // The replay goes the same way as the original run
+        let replayer = Replayer::new(session.clone());
+        let mut socket = self::socket(Box::new(replayer.clone()));
+        let mut controller = controller();
+        assert_eq!(run(&mut controller, &mut socket), edges);
+        assert_eq!(controller.handle().inputs(0x41), Some(vec![0x03]));
+        assert_eq!(controller.handle().inputs(0x42), None);
+        assert_eq!(replayer.divergence(), None);
+        assert_eq!(replayer.unmatched_polls(), 0);
+
+        // An application which sets a different output
+        let replayer = Replayer::new(session);
+        let mut socket = self::socket(Box::new(replayer.clone()));
+        let mut controller = self::controller();
+        controller.handle().set_output(0x41, 4, true).unwrap();
+        run(&mut controller, &mut socket);
+        assert_eq!(replayer.divergence(), Some(0));
+    }
+
+    #[test]
+    fn bad_session_lines() {
+        let text = "# comment\n\n10 tx ff ff 02 41 50 03\n";
+        assert_eq!(
+            Session::read_from(text.as_bytes()).unwrap().events().len(),
+            1
+        );
+        for line in
+            ["10 xx ff ff 02 41 50 03", "10 tx ff ff 02 41", "tx"].iter()
+        {
+            assert!(Session::read_from(line.as_bytes()).is_err());
+        }
+    }
+}
@@ -0,0 +1,63 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Resending after a failed write. USB serial adapters sometimes fail a
+// write outright, for instance while the host controller resets, and
+// usually recover a moment later. The socket retries such a write after a
+// delay which doubles each time, up to a limit. Anything which outlasts
+// the retries is likely a disconnected adapter, which is where
+// `CmriSocket::send_or_queue()` comes in: it keeps the frame on the TX
+// queue to go out once a new transport has been handed to the socket.
+
+use std::time::Duration;
+
+/// How often to retry a write which failed with an IO error
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Retries before giving up and reporting the error
+    pub retries: u8,
+    /// Delay before the first retry
+    pub initial_backoff: Duration,
+    /// Longest delay between retries
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// How long to wait before retry number `attempt`, counting from 1
+    pub fn delay(&self, attempt: u8) -> Duration {
+        let factor = 1_u32 << attempt.saturating_sub(1).min(16);
+        self.initial_backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        let policy = RetryPolicy::default();
+        let delays: std::vec::Vec<_> = (1..=8)
+            .map(|n| policy.delay(n).as_millis() as u64)
+            .collect();
+        assert_eq!(delays, [10, 20, 40, 80, 160, 320, 500, 500]);
+        assert_eq!(policy.delay(255), policy.max_backoff);
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// A small ring of received bytes between a UART receive interrupt and the
+// main loop, so that a node doesn't lose bytes while it is busy updating
+// its outputs. The interrupt pushes each byte as it arrives and the main
+// loop drains them, e.g. with `CmriProcessor::process_ring()`:
+//
+// static RX: RxRing<64> = RxRing::new();
+//
+// fn on_uart_rx() { RX.push(read_udr()); }
+//
+// loop { if processor.process_ring(&RX) { update_outputs(); } }
+//
+// heapless's spsc queue does the same job on Cortex-M, but wants
+// pointer-sized atomics, which AVR doesn't have, and a `static mut` split
+// apart with unsafe code. Everything here is a byte-sized atomic, so a
+// `RxRing` can be an ordinary `static` on any target. It is only sound as
+// a queue with one pusher and one popper; anything more can jumble bytes
+// but can't cause undefined behaviour.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// Up to `N` bytes on their way from an interrupt handler to the main
+/// loop. `N` must be a power of two no greater than 128, so that the
+/// positions can wrap around in a byte
+pub struct RxRing<const N: usize> {
+    bytes: [AtomicU8; N],
+    /// Count of bytes pushed, wrapping. Only written by `push()`
+    head: AtomicU8,
+    /// Count of bytes popped, wrapping. Only written by `pop()`
+    tail: AtomicU8,
+    /// Bytes dropped because the ring was full, wrapping. Only written
+    /// by `push()`
+    overflows: AtomicU8,
+    /// `overflows` as of the last `take_overflows()`
+    overflows_seen: AtomicU8,
+}
+
+impl<const N: usize> RxRing<N> {
+    const VALID_LEN: () = assert!(N.is_power_of_two() && N <= 128);
+
+    pub const fn new() -> Self {
+        let () = Self::VALID_LEN;
+        Self {
+            bytes: [const { AtomicU8::new(0) }; N],
+            head: AtomicU8::new(0),
+            tail: AtomicU8::new(0),
+            overflows: AtomicU8::new(0),
+            overflows_seen: AtomicU8::new(0),
+        }
+    }
+
+    /// Adds a byte, from the interrupt handler. Returns false and counts
+    /// an overflow if the ring is full, in which case the byte is lost
+    pub fn push(&self, byte: u8) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if usize::from(head.wrapping_sub(tail)) >= N {
+            let overflows = self.overflows.load(Ordering::Relaxed);
+            self.overflows
+                .store(overflows.wrapping_add(1), Ordering::Relaxed);
+            return false;
+        }
+        if let Some(slot) = self.bytes.get(usize::from(head) % N) {
+            slot.store(byte, Ordering::Relaxed);
+        }
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Takes the oldest byte, from the main loop
+    pub fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let byte = self.bytes.get(usize::from(tail) % N)?;
+        let byte = byte.load(Ordering::Relaxed);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(byte)
+    }
+
+    /// Number of bytes waiting
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        usize::from(head.wrapping_sub(tail))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Bytes dropped since the last call, from the main loop. Counts
+    /// wrap at 256, so call this more often than that many can be lost
+    pub fn take_overflows(&self) -> u8 {
+        let overflows = self.overflows.load(Ordering::Relaxed);
+        let seen = self.overflows_seen.load(Ordering::Relaxed);
+        self.overflows_seen.store(overflows, Ordering::Relaxed);
+        overflows.wrapping_sub(seen)
+    }
+}
+
+impl<const N: usize> Default for RxRing<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn push_and_pop() {
+        let ring = RxRing::<4>::new();
+        assert!(ring.is_empty());
+        assert_eq!(ring.pop(), None);
+        for byte in 1..=4 {
+            assert!(ring.push(byte));
+        }
+        assert!(!ring.push(5));
+        assert!(!ring.push(6));
+        assert_eq!(ring.len(), 4);
+        assert_eq!(ring.take_overflows(), 2);
+        assert_eq!(ring.take_overflows(), 0);
+
+        // Round and round, well past where the positions wrap
+        for n in 0..600_u16 {
+            assert_eq!(ring.pop(), Some((n + 1) as u8));
+            assert!(ring.push((n + 5) as u8));
+        }
+        assert_eq!(ring.len(), 4);
+        let rest: std::vec::Vec<_> =
+            core::iter::from_fn(|| ring.pop()).collect();
+        // 601 to 604
+        assert_eq!(rest, [89, 90, 91, 92]);
+        assert_eq!(ring.take_overflows(), 0);
+    }
+
+    #[test]
+    fn isr_and_main_loop() {
+        use crate::{CmriStateMachine, MessageBuilder, RxState};
+
+        static RX: RxRing<8> = RxRing::new();
+        let msg = MessageBuilder::set(0x41, &[0x10, 0x20, 0x30, 0x40])
+            .build()
+            .unwrap();
+        let frame: std::vec::Vec<u8> = msg.encode_iter().unwrap().collect();
+
+        // An interrupt handler on another thread, the main loop here
+        let isr = std::thread::spawn(move || {
+            for byte in frame {
+                while !RX.push(byte) {
+                    std::thread::yield_now();
+                }
+            }
+        });
+        let mut state = CmriStateMachine::new();
+        loop {
+            match RX.pop().map(|b| state.process(b)) {
+                Some(Ok(RxState::Complete)) => break,
+                Some(res) => assert!(res.is_ok()),
+                None => std::thread::yield_now(),
+            }
+        }
+        isr.join().unwrap();
+        assert_eq!(*state.message(), msg);
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Line settings shared by every serial transport and by the node-side
+// UART setup, so that the controller and nodes can be configured from
+// the same value. Data bits are always 8. Classic C/MRI hardware is run
+// as 8N2, which is the default.
+//
+// Many USB RS485 adapters have no automatic direction control and key
+// their transmitter from a modem control line instead. `tx_enable` says
+// which line, which way up, and how long to wait either side of a frame;
+// serial transports which can drive the line do so around each write.
+
+use crate::line::LineTiming;
+use crate::timing;
+
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+/// Modem control line wired to an adapter's transmit enable
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub enum ControlLine {
+    Rts,
+    Dtr,
+    /// Both lines together, for adapters wired to either
+    RtsAndDtr,
+}
+
+/// Keying of the transmitter from a control line
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct TxEnable {
+    pub line: ControlLine,
+    /// Drive the line low while transmitting rather than high, for
+    /// adapters which invert it
+    pub active_low: bool,
+    /// Delays either side of keying the transmitter
+    pub timing: LineTiming,
+}
+
+impl TxEnable {
+    /// `line` driven high while transmitting, with the default timing
+    pub const fn new(line: ControlLine) -> Self {
+        Self {
+            line,
+            active_low: false,
+            timing: LineTiming::new(),
+        }
+    }
+
+    pub const fn active_low(mut self) -> Self {
+        self.active_low = true;
+        self
+    }
+
+    pub const fn timing(mut self, timing: LineTiming) -> Self {
+        self.timing = timing;
+        self
+    }
+
+    /// Level to drive the line to for the given transmitter state
+    pub const fn level(&self, enabled: bool) -> bool {
+        enabled != self.active_low
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct SerialConfig {
+    pub baud: u32,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    /// Control line keying the transmitter, if the adapter doesn't switch
+    /// direction by itself
+    pub tx_enable: Option<TxEnable>,
+}
+
+impl SerialConfig {
+    /// 8N2 at the given baud rate
+    pub const fn new(baud: u32) -> Self {
+        Self {
+            baud,
+            parity: Parity::None,
+            stop_bits: StopBits::Two,
+            tx_enable: None,
+        }
+    }
+
+    pub const fn parity(mut self, parity: Parity) -> Self {
+        self.parity = parity;
+        self
+    }
+
+    pub const fn stop_bits(mut self, stop_bits: StopBits) -> Self {
+        self.stop_bits = stop_bits;
+        self
+    }
+
+    pub const fn tx_enable(mut self, tx_enable: TxEnable) -> Self {
+        self.tx_enable = Some(tx_enable);
+        self
+    }
+
+    /// Bits on the wire for each byte, including start, parity and stop
+    /// bits
+    pub fn bits_per_byte(&self) -> u32 {
+        let parity = match self.parity {
+            Parity::None => 0,
+            Parity::Even | Parity::Odd => 1,
+        };
+        let stop = match self.stop_bits {
+            StopBits::One => 1,
+            StopBits::Two => 2,
+        };
+        1 + 8 + parity + stop
+    }
+
+    /// Time taken to send one byte, in microseconds
+    pub fn byte_time(&self) -> u64 {
+        timing::byte_time(self.baud, self.bits_per_byte())
+    }
+}
+
+impl Default for SerialConfig {
+    /// 8N2 at 9600 baud
+    fn default() -> Self {
+        Self::new(9600)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn framing() {
+        let config = SerialConfig::new(19200);
+        assert_eq!(config.bits_per_byte(), timing::BITS_PER_BYTE_8N2);
+        assert_eq!(config.byte_time(), 573);
+
+        let config = config.stop_bits(StopBits::One);
+        assert_eq!(config.bits_per_byte(), timing::BITS_PER_BYTE_8N1);
+        let config = config.parity(Parity::Even);
+        assert_eq!(config.bits_per_byte(), 11);
+        assert_eq!(SerialConfig::default().baud, 9600);
+    }
+
+    #[test]
+    fn tx_enable_polarity() {
+        let config = SerialConfig::new(19200);
+        assert_eq!(config.tx_enable, None);
+
+        let rts = TxEnable::new(ControlLine::Rts);
+        assert!(rts.level(true));
+        assert!(!rts.level(false));
+        let config = config.tx_enable(rts.active_low());
+        let tx_enable = config.tx_enable.unwrap();
+        assert_eq!(tx_enable.line, ControlLine::Rts);
+        assert!(!tx_enable.level(true));
+        assert!(tx_enable.level(false));
+        assert_eq!(tx_enable.timing, LineTiming::default());
+    }
+}
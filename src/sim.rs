@@ -0,0 +1,358 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Virtual layout for testing controller software without hardware. Each
+// `VirtualNode` runs the same `NodeDriver` logic as a real node, with its
+// inputs driven by a scripted `Behaviour`. A `VirtualBus` connects the
+// nodes to a controller: it implements `Read` and `Write`, so it can be
+// handed straight to `CmriSocket` in place of a serial port.
+
+use crate::{Action, Clock, NodeDriver, Result, StdClock};
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::vec::Vec;
+
+/// How a virtual node's inputs change over time
+#[derive(Clone, Debug, PartialEq)]
+pub enum Behaviour {
+    /// Inputs only change when set by the test
+    Manual,
+    /// Flip one input bit after every `every` polls
+    Toggle { bit: usize, every: u32 },
+    /// Report each pattern in turn, moving on after every poll and
+    /// starting again at the end
+    Sequence(Vec<Vec<u8>>),
+    /// Report the outputs back as inputs, as if each output were wired
+    /// to the matching input
+    MirrorOutputs,
+    /// Report new pseudo-random inputs after every poll. The same seed
+    /// gives the same inputs each run
+    Random { seed: u32 },
+}
+
+pub struct VirtualNode {
+    driver: NodeDriver,
+    behaviour: Behaviour,
+    polls: u32,
+    /// xorshift state for `Behaviour::Random`
+    rng: u32,
+}
+
+impl VirtualNode {
+    /// Create a node at the given address reporting `input_len` bytes of
+    /// inputs
+    pub fn new(
+        address: u8,
+        input_len: usize,
+        behaviour: Behaviour,
+    ) -> Result<Self> {
+        let rng = match behaviour {
+            // xorshift never leaves zero
+            Behaviour::Random { seed } => seed.max(1),
+            _ => 0,
+        };
+        let mut node = Self {
+            driver: NodeDriver::new(address, input_len)?,
+            behaviour,
+            polls: 0,
+            rng,
+        };
+        node.apply_sequence();
+        node.randomise();
+        Ok(node)
+    }
+
+    pub fn address(&self) -> u8 {
+        self.driver.address()
+    }
+
+    /// The protocol logic behind this node
+    pub fn driver(&self) -> &NodeDriver {
+        &self.driver
+    }
+
+    pub fn driver_mut(&mut self) -> &mut NodeDriver {
+        &mut self.driver
+    }
+
+    pub fn inputs(&self) -> &[u8] {
+        self.driver.inputs()
+    }
+
+    pub fn outputs(&self) -> &[u8] {
+        self.driver.outputs()
+    }
+
+    /// Number of polls answered so far
+    pub fn polls(&self) -> u32 {
+        self.polls
+    }
+
+    /// Feed a byte from the controller into the node, returning any
+    /// response it sends
+    fn process(&mut self, byte: u8, now: u64) -> Option<Vec<u8>> {
+        match self.driver.process(byte, now) {
+            Action::Transmit(bytes) => {
+                let response = bytes.to_vec();
+                self.polls = self.polls.wrapping_add(1);
+                self.after_poll();
+                Some(response)
+            }
+            Action::OutputsChanged => {
+                if self.behaviour == Behaviour::MirrorOutputs {
+                    self.mirror_outputs();
+                }
+                None
+            }
+            Action::None => None,
+        }
+    }
+
+    /// Moves the inputs on ready for the next poll
+    fn after_poll(&mut self) {
+        match self.behaviour {
+            Behaviour::Toggle { bit, every } => {
+                if every > 0 && self.polls.is_multiple_of(every) {
+                    if let Some(byte) =
+                        self.driver.inputs_mut().get_mut(bit / 8)
+                    {
+                        *byte ^= 1 << (bit % 8);
+                    }
+                }
+            }
+            Behaviour::Sequence(_) => self.apply_sequence(),
+            Behaviour::Random { .. } => self.randomise(),
+            Behaviour::Manual | Behaviour::MirrorOutputs => {}
+        }
+    }
+
+    fn apply_sequence(&mut self) {
+        if let Behaviour::Sequence(ref patterns) = self.behaviour {
+            if patterns.is_empty() {
+                return;
+            }
+            let n = self.polls as usize % patterns.len();
+            if let Some(pattern) = patterns.get(n) {
+                copy_truncated(self.driver.inputs_mut(), pattern);
+            }
+        }
+    }
+
+    fn randomise(&mut self) {
+        if !matches!(self.behaviour, Behaviour::Random { .. }) {
+            return;
+        }
+        for byte in self.driver.inputs_mut() {
+            self.rng ^= self.rng << 13;
+            self.rng ^= self.rng >> 17;
+            self.rng ^= self.rng << 5;
+            *byte = self.rng as u8;
+        }
+    }
+
+    fn mirror_outputs(&mut self) {
+        let outputs = self.driver.outputs().to_vec();
+        copy_truncated(self.driver.inputs_mut(), &outputs);
+    }
+}
+
+/// Copies as much of `src` as fits into `dest`, zeroing the rest
+fn copy_truncated(dest: &mut [u8], src: &[u8]) {
+    for (i, byte) in dest.iter_mut().enumerate() {
+        *byte = src.get(i).copied().unwrap_or(0);
+    }
+}
+
+struct BusInner {
+    nodes: Vec<VirtualNode>,
+    /// Bytes sent by the nodes which the controller hasn't read yet
+    to_controller: VecDeque<u8>,
+    /// Times passed to the nodes are in microseconds
+    clock: StdClock,
+}
+
+/// Bus connecting virtual nodes to a controller. Clones share the same
+/// nodes, so keep one to inspect the layout after handing another to the
+/// controller. Reads return `WouldBlock` when no node has replied.
+#[derive(Clone)]
+pub struct VirtualBus {
+    inner: Arc<Mutex<BusInner>>,
+}
+
+impl VirtualBus {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(BusInner {
+                nodes: Vec::new(),
+                to_controller: VecDeque::new(),
+                clock: StdClock::new(),
+            })),
+        }
+    }
+
+    pub fn add_node(&self, node: VirtualNode) {
+        self.lock().nodes.push(node);
+    }
+
+    /// Runs a closure against the node at the given address, e.g. to
+    /// check its outputs or change its inputs
+    pub fn with_node<T>(
+        &self,
+        address: u8,
+        f: impl FnOnce(&mut VirtualNode) -> T,
+    ) -> Option<T> {
+        self.lock()
+            .nodes
+            .iter_mut()
+            .find(|n| n.address() == address)
+            .map(f)
+    }
+
+    /// Address of every node on the bus, in the order they were added
+    pub fn addresses(&self) -> Vec<u8> {
+        self.lock().nodes.iter().map(VirtualNode::address).collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BusInner> {
+        // A panic while holding the lock leaves nothing half-updated that
+        // matters for a simulation
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for VirtualBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Write for VirtualBus {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut inner = self.lock();
+        let inner = &mut *inner;
+        let now = inner.clock.now();
+        for byte in buf {
+            for node in inner.nodes.iter_mut() {
+                if let Some(response) = node.process(*byte, now) {
+                    inner.to_controller.extend(response);
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for VirtualBus {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut inner = self.lock();
+        if inner.to_controller.is_empty() && !buf.is_empty() {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        let mut count = 0;
+        for slot in buf.iter_mut() {
+            match inner.to_controller.pop_front() {
+                Some(byte) => *slot = byte,
+                None => break,
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CmriSocket, Error, MessageBuilder};
+    use std::boxed::Box;
+    use std::time::Duration;
+    use std::vec;
+
+    fn socket(bus: &VirtualBus) -> CmriSocket {
+        CmriSocket::builder(Box::new(bus.clone()))
+            .read_timeout(Duration::from_millis(10))
+            .build()
+    }
+
+    fn inputs(socket: &mut CmriSocket, addr: u8) -> Vec<u8> {
+        let msg = socket.poll(addr).unwrap();
+        msg.payload[..msg.len].to_vec()
+    }
+
+    #[test]
+    fn toggle_every_n_polls() {
+        let bus = VirtualBus::new();
+        let behaviour = Behaviour::Toggle { bit: 9, every: 2 };
+        bus.add_node(VirtualNode::new(0x41, 2, behaviour).unwrap());
+        let mut socket = socket(&bus);
+
+        assert_eq!(inputs(&mut socket, 0x41), [0, 0]);
+        assert_eq!(inputs(&mut socket, 0x41), [0, 0]);
+        assert_eq!(inputs(&mut socket, 0x41), [0, 0b10]);
+        assert_eq!(inputs(&mut socket, 0x41), [0, 0b10]);
+        assert_eq!(inputs(&mut socket, 0x41), [0, 0]);
+        assert_eq!(bus.with_node(0x41, |n| n.polls()), Some(5));
+    }
+
+    #[test]
+    fn follow_a_sequence() {
+        let bus = VirtualBus::new();
+        let behaviour = Behaviour::Sequence(vec![vec![1], vec![2, 3]]);
+        bus.add_node(VirtualNode::new(0x41, 2, behaviour).unwrap());
+        let mut socket = socket(&bus);
+
+        assert_eq!(inputs(&mut socket, 0x41), [1, 0]);
+        assert_eq!(inputs(&mut socket, 0x41), [2, 3]);
+        assert_eq!(inputs(&mut socket, 0x41), [1, 0]);
+    }
+
+    #[test]
+    fn random_inputs_repeat_for_a_seed() {
+        let bus = VirtualBus::new();
+        let behaviour = Behaviour::Random { seed: 42 };
+        bus.add_node(VirtualNode::new(0x41, 3, behaviour.clone()).unwrap());
+        bus.add_node(VirtualNode::new(0x42, 3, behaviour).unwrap());
+        let mut socket = socket(&bus);
+
+        let first = inputs(&mut socket, 0x41);
+        let second = inputs(&mut socket, 0x41);
+        assert_eq!(first.len(), 3);
+        assert_ne!(first, second);
+        assert_eq!(inputs(&mut socket, 0x42), first);
+    }
+
+    #[test]
+    fn mirror_outputs_and_addressing() {
+        let bus = VirtualBus::new();
+        bus.add_node(
+            VirtualNode::new(0x41, 3, Behaviour::MirrorOutputs).unwrap(),
+        );
+        bus.add_node(VirtualNode::new(0x42, 1, Behaviour::Manual).unwrap());
+        let mut socket = socket(&bus);
+
+        let set = MessageBuilder::set(0x41, &[7, 8]).build().unwrap();
+        socket.send(&set).unwrap();
+        assert_eq!(
+            bus.with_node(0x41, |n| n.outputs().to_vec()),
+            Some(vec![7, 8])
+        );
+        assert_eq!(inputs(&mut socket, 0x41), [7, 8, 0]);
+
+        bus.with_node(0x42, |n| n.driver_mut().set_inputs(&[0x55]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(inputs(&mut socket, 0x42), [0x55]);
+
+        // Nobody answers for a missing node
+        assert_eq!(socket.poll(0x43).unwrap_err(), Error::NoResponse);
+    }
+}
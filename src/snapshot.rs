@@ -0,0 +1,109 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Saved output state, so that turnouts and signals go back to where they
+// were after the controller restarts. A snapshot holds every node's
+// output image as JSON:
+//
+//     {
+//       "nodes": {
+//         "65": [1, 0, 128]
+//       }
+//     }
+//
+// `Controller::snapshot()` takes one and `Controller::restore()` puts it
+// back, marking every node's outputs as changed so that the next step
+// sends them all a Set. Saving writes to a temporary file and renames
+// it over the old one, so a power cut part way through leaves the
+// previous snapshot intact.
+
+use crate::config::config_error;
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::string::String;
+use std::vec::Vec;
+
+/// Output image of every node, keyed by address byte
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct OutputSnapshot {
+    #[serde(default)]
+    pub nodes: BTreeMap<u8, Vec<u8>>,
+}
+
+impl OutputSnapshot {
+    pub fn from_json(s: &str) -> Result<Self> {
+        serde_json::from_str(s).map_err(config_error)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).map_err(config_error)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_json(&fs::read_to_string(path)?)
+    }
+
+    /// As `load()`, but an empty snapshot if there's no file yet, as on
+    /// the first run
+    pub fn load_or_default<P: AsRef<Path>>(path: P) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Self::from_json(&contents),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut tmp = OsString::from(path);
+        tmp.push(".tmp");
+        fs::write(&tmp, self.to_json()?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Error;
+    use std::format;
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir()
+            .join(format!("cmri-snapshot-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        assert_eq!(
+            OutputSnapshot::load_or_default(&path),
+            Ok(OutputSnapshot::default())
+        );
+        assert!(matches!(
+            OutputSnapshot::load(&path),
+            Err(Error::IoError(_))
+        ));
+
+        let mut snapshot = OutputSnapshot::default();
+        snapshot.nodes.insert(0x41, std::vec![0x01, 0x00, 0x80]);
+        snapshot.nodes.insert(0x42, std::vec![0xff]);
+        snapshot.save(&path).unwrap();
+        assert_eq!(OutputSnapshot::load(&path), Ok(snapshot.clone()));
+        assert_eq!(OutputSnapshot::load_or_default(&path), Ok(snapshot));
+
+        fs::write(&path, "{\"nodes\": 3}").unwrap();
+        assert!(matches!(
+            OutputSnapshot::load(&path),
+            Err(Error::ConfigError(_))
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,100 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::MessageType;
+
+/// Counters kept by the state machine while decoding. These are handy for
+/// spotting flaky wiring: lots of resyncs or discarded bytes usually
+/// means noise on the bus. All counters wrap on overflow.
+#[derive(Copy, Clone, Default, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct Stats {
+    /// Complete frames decoded
+    pub frames_decoded: u32,
+    /// Frames dropped because they were for another address
+    pub frames_filtered: u32,
+    /// Bytes ignored while waiting for a preamble
+    pub bytes_discarded: u32,
+    /// Partially received frames abandoned because of a bad byte
+    pub resyncs: u32,
+    /// Preamble bytes ignored while regaining sync, see `SyncGuard`
+    pub preambles_ignored: u32,
+    /// Escape bytes seen in payloads
+    pub escape_bytes: u32,
+    pub init_messages: u32,
+    pub set_messages: u32,
+    pub get_messages: u32,
+    pub poll_messages: u32,
+    /// Frames with an unrecognised type, when they are accepted
+    pub unknown_messages: u32,
+}
+
+impl Stats {
+    /// Zero all counters
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Number of decoded frames of the given type
+    pub fn message_count(&self, message_type: MessageType) -> u32 {
+        use MessageType::*;
+        match message_type {
+            Init => self.init_messages,
+            Set => self.set_messages,
+            Get => self.get_messages,
+            Poll => self.poll_messages,
+            Unknown(_) => self.unknown_messages,
+        }
+    }
+
+    pub(crate) fn count_message(&mut self, message_type: MessageType) {
+        use MessageType::*;
+        bump(&mut self.frames_decoded);
+        bump(match message_type {
+            Init => &mut self.init_messages,
+            Set => &mut self.set_messages,
+            Get => &mut self.get_messages,
+            Poll => &mut self.poll_messages,
+            Unknown(_) => &mut self.unknown_messages,
+        });
+    }
+}
+
+/// Increment a counter, wrapping on overflow
+pub(crate) fn bump(counter: &mut u32) {
+    *counter = counter.wrapping_add(1);
+}
+
+/// Number of bytes of inter-frame noise kept
+pub const NOISE_HISTORY_LEN: usize = 8;
+
+/// The most recent bytes discarded between frames, for telling a noisy
+/// bus from a silent one
+#[derive(Copy, Clone, Default, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub(crate) struct NoiseHistory {
+    bytes: [u8; NOISE_HISTORY_LEN],
+    /// Total recorded, so the oldest is at `len % NOISE_HISTORY_LEN` once
+    /// the history is full
+    len: usize,
+}
+
+impl NoiseHistory {
+    pub(crate) fn record(&mut self, byte: u8) {
+        if let Some(slot) = self.bytes.get_mut(self.len % NOISE_HISTORY_LEN) {
+            *slot = byte;
+        }
+        self.len = self.len.wrapping_add(1);
+    }
+
+    /// Oldest first
+    pub(crate) fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        let kept = self.len.min(NOISE_HISTORY_LEN);
+        (self.len - kept..self.len)
+            .filter_map(move |n| self.bytes.get(n % NOISE_HISTORY_LEN).copied())
+    }
+}
@@ -0,0 +1,29 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Synchronisation primitives behind the cross-thread handles. The crate
+// has no unsafe code of its own here: `ControllerHandle`,
+// `GatewayHandle` and `MultiBusHandle` are `Send + Sync` only because
+// they are built from these types, and the tests assert as much so that
+// a change of field can't quietly lose it. The owning sides, `Controller`
+// and `Gateway`, hold the receiving end of a channel and so are `Send`
+// but not `Sync`. `CmriSocket` is neither, as transports need only be
+// `Read + Write`.
+//
+// Under `--cfg cmri_loom` the unit tests swap in loom's versions, which
+// lets the `loom_` tests check every interleaving of a handle and its
+// owner:
+//
+//     RUSTFLAGS="--cfg cmri_loom" cargo test --lib --release loom_
+//
+// Only those tests understand loom's primitives; the rest of the suite
+// is for normal builds.
+
+#[cfg(all(test, cmri_loom))]
+pub(crate) use loom::sync::{mpsc, Arc, Mutex, MutexGuard};
+#[cfg(not(all(test, cmri_loom)))]
+pub(crate) use std::sync::{mpsc, Arc, Mutex, MutexGuard};
@@ -0,0 +1,107 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Helpers for fuzzing and property testing. These panic when something
+// doesn't add up, which is what a fuzzer is looking for.
+#![allow(clippy::indexing_slicing)]
+
+use crate::{
+    CmriMessage, CmriStateMachine, MessageType, RxState, MAX_PAYLOAD_LEN,
+    TX_BUFFER_LEN,
+};
+
+/// Types in the order picked by the second byte of fuzz input
+const MESSAGE_TYPES: [MessageType; 4] = [
+    MessageType::Init,
+    MessageType::Set,
+    MessageType::Get,
+    MessageType::Poll,
+];
+
+/// Builds a message from arbitrary bytes, encodes it and decodes it
+/// again, both a byte at a time with `process()` and in bulk with
+/// `process_buf()`. Panics unless both decoders give back the original
+/// message and re-encoding it gives the same bytes.
+///
+/// The first byte of `data` is the address, the second picks the message
+/// type and the rest is the payload, truncated to `MAX_PAYLOAD_LEN`.
+/// Input shorter than two bytes is ignored.
+pub fn encode_decode_roundtrip(data: &[u8]) {
+    let (address, message_type, payload) = match data {
+        [address, t, payload @ ..] => (
+            *address,
+            MESSAGE_TYPES[*t as usize % MESSAGE_TYPES.len()],
+            &payload[..payload.len().min(MAX_PAYLOAD_LEN)],
+        ),
+        _ => return,
+    };
+    let mut msg = CmriMessage::new();
+    msg.address(address).message_type(message_type);
+    msg.payload(payload).expect("payload was truncated to fit");
+
+    let mut encoded = [0_u8; TX_BUFFER_LEN];
+    let len = msg.encode(&mut encoded).expect("message is complete");
+    let encoded = &encoded[..len];
+
+    // One byte at a time. Payload lengths aren't checked, as this is
+    // about framing rather than whether the message makes sense
+    let mut state = CmriStateMachine::new();
+    state.check_payload_lengths(false);
+    let mut complete = false;
+    for (i, byte) in encoded.iter().enumerate() {
+        let res = state.process(*byte).expect("valid frame failed to decode");
+        complete = res == RxState::Complete;
+        assert!(
+            !complete || i == len - 1,
+            "frame completed early at byte {}",
+            i
+        );
+    }
+    assert!(complete, "frame did not complete");
+    assert_same(&msg, state.message());
+
+    // In bulk
+    let mut bulk = CmriStateMachine::new();
+    bulk.check_payload_lengths(false);
+    let (used, res) = bulk.process_buf(encoded);
+    assert_eq!(used, len);
+    assert_eq!(res, Ok(RxState::Complete));
+    assert_same(&msg, bulk.message());
+
+    // Re-encoding the decoded message gives the same frame
+    let mut again = [0_u8; TX_BUFFER_LEN];
+    let again_len = state.message().encode(&mut again).unwrap();
+    assert_eq!(&again[..again_len], encoded);
+}
+
+fn assert_same(expected: &CmriMessage, decoded: &CmriMessage) {
+    assert_eq!(decoded.address, expected.address);
+    assert_eq!(decoded.message_type, expected.message_type);
+    assert_eq!(
+        decoded.payload[..decoded.len],
+        expected.payload[..expected.len]
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn roundtrip_random_messages() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(2020);
+        let mut data = [0_u8; MAX_PAYLOAD_LEN + 2];
+        for _ in 0..500 {
+            let len = rng.gen_range(0..data.len());
+            rng.fill(&mut data[..len]);
+            encode_decode_roundtrip(&data[..len]);
+        }
+        // Payload bytes which look like frame markers
+        encode_decode_roundtrip(&[0x03, 1, 0x02, 0x03, 0x10, 0xff]);
+    }
+}
@@ -0,0 +1,81 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Serial timing helpers for working out how long a byte or a frame takes
+// to go over the wire, for pacing transmissions and flipping the RS-485
+// driver at the right moment. All times are in microseconds.
+
+/// Start bit, 8 data bits and one stop bit
+pub const BITS_PER_BYTE_8N1: u32 = 10;
+/// Start bit, 8 data bits and two stop bits. C/MRI nodes are usually run
+/// with two stop bits
+pub const BITS_PER_BYTE_8N2: u32 = 11;
+
+/// Default used by `frame_time()`
+pub const DEFAULT_BITS_PER_BYTE: u32 = BITS_PER_BYTE_8N2;
+
+/// The transmit delay (DL) in an Init message is in units of 10us
+pub const DELAY_UNIT_US: u64 = 10;
+
+/// Number of byte times to wait after the last byte has been handed to
+/// the UART before releasing the RS-485 driver, to allow for the UART's
+/// own buffering
+pub const TURNAROUND_BYTES: u64 = 4;
+
+/// Time to wait for a node to respond to a Poll before giving up
+pub const RESPONSE_TIMEOUT_US: u64 = 100_000;
+
+/// Time taken to send one byte with the given number of bits per byte. A
+/// baud rate of 0 is taken as the slowest possible line, 1 baud, rather
+/// than dividing by zero
+pub fn byte_time(baud: u32, bits: u32) -> u64 {
+    // Round up so that waiting this long is always long enough
+    (bits as u64 * 1_000_000).div_ceil(baud.max(1) as u64)
+}
+
+/// Time taken to send a frame of `len` bytes using the default framing
+pub fn frame_time(len: usize, baud: u32) -> u64 {
+    len as u64 * byte_time(baud, DEFAULT_BITS_PER_BYTE)
+}
+
+/// Time to hold the RS-485 driver on after writing a frame of `len`
+/// bytes, including the turnaround margin
+pub fn transmit_hold_time(len: usize, baud: u32) -> u64 {
+    frame_time(len + TURNAROUND_BYTES as usize, baud)
+}
+
+/// Converts the DL value from an Init message into microseconds
+pub fn transmit_delay(dl: u16) -> u64 {
+    dl as u64 * DELAY_UNIT_US
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn byte_times() {
+        assert_eq!(byte_time(9600, BITS_PER_BYTE_8N1), 1042);
+        assert_eq!(byte_time(19200, BITS_PER_BYTE_8N2), 573);
+        assert_eq!(byte_time(1_000_000, BITS_PER_BYTE_8N1), 10);
+        assert_eq!(byte_time(0, BITS_PER_BYTE_8N1), 10_000_000);
+        assert_eq!(frame_time(10, 0), 110_000_000);
+    }
+
+    #[test]
+    fn frame_times() {
+        assert_eq!(frame_time(0, 9600), 0);
+        assert_eq!(frame_time(10, 19200), 5730);
+        assert_eq!(transmit_hold_time(10, 19200), 14 * 573);
+    }
+
+    #[test]
+    fn init_delay() {
+        assert_eq!(transmit_delay(0), 0);
+        assert_eq!(transmit_delay(150), 1500);
+    }
+}
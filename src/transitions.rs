@@ -0,0 +1,71 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Watching the state machine move between states, for tests which want
+// to check how a run of bytes was taken apart rather than only what came
+// out. With the `state-trace` feature, `CmriStateMachine` keeps the last
+// `TRANSITION_LOG_LEN` transitions and can call a hook on each one as it
+// happens. Without it none of this is compiled in, so nodes pay nothing.
+
+use crate::CmriState;
+
+/// Number of transitions kept by the state machine
+pub const TRANSITION_LOG_LEN: usize = 32;
+
+/// Called with the old state, the new state and the byte which moved
+/// the state machine between them
+pub type TransitionHook = fn(CmriState, CmriState, u8);
+
+/// The state machine moving from one state to another on a byte
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(any(feature = "fmt", test), derive(Debug))]
+pub struct Transition {
+    pub from: CmriState,
+    pub to: CmriState,
+    pub byte: u8,
+}
+
+/// The most recent transitions, along with the hook to call on each new
+/// one
+#[derive(Copy, Clone, Default)]
+pub(crate) struct TransitionLog {
+    entries: [Option<Transition>; TRANSITION_LOG_LEN],
+    /// Total recorded, so the oldest is at `len % TRANSITION_LOG_LEN` once
+    /// the log is full
+    len: usize,
+    pub(crate) hook: Option<TransitionHook>,
+}
+
+impl TransitionLog {
+    /// Notes the move from `from` to `to`, if the state changed at all
+    pub(crate) fn record(&mut self, from: CmriState, to: CmriState, byte: u8) {
+        if from == to {
+            return;
+        }
+        if let Some(hook) = self.hook {
+            hook(from, to, byte);
+        }
+        let slot = self.entries.get_mut(self.len % TRANSITION_LOG_LEN);
+        if let Some(slot) = slot {
+            *slot = Some(Transition { from, to, byte });
+        }
+        self.len = self.len.wrapping_add(1);
+    }
+
+    /// Oldest first
+    pub(crate) fn iter(&self) -> impl Iterator<Item = Transition> + '_ {
+        let kept = self.len.min(TRANSITION_LOG_LEN);
+        (self.len - kept..self.len).filter_map(move |n| {
+            self.entries.get(n % TRANSITION_LOG_LEN).copied().flatten()
+        })
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries = [None; TRANSITION_LOG_LEN];
+        self.len = 0;
+    }
+}
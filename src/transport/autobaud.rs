@@ -0,0 +1,201 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Finding the baud rate of a bus with unknown settings. A live bus has a
+// controller polling its nodes, so we listen at each candidate rate in
+// turn and see how much of what arrives decodes. At the wrong rate bytes
+// come out as junk and almost never make it through the preamble and
+// start byte, never mind all the way to a stop byte.
+
+use crate::{CmriMessage, CmriStateMachine, Error, ProtocolEvents, Result};
+use std::io::{ErrorKind, Read};
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+
+/// Rates C/MRI hardware is commonly run at, slowest first
+pub const COMMON_BAUDS: [u32; 6] = [9600, 19200, 28800, 38400, 57600, 115200];
+
+/// A transport whose baud rate can be changed while it is open
+pub trait SetBaud: Read {
+    fn set_baud(&mut self, baud: u32) -> Result<()>;
+}
+
+/// What was heard while listening at one baud rate
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct BaudScore {
+    pub baud: u32,
+    /// Bytes received
+    pub bytes: usize,
+    /// Preambles followed by a start byte
+    pub frame_starts: u32,
+    /// Complete frames decoded
+    pub frames: u32,
+}
+
+impl BaudScore {
+    /// Whole frames count for more than starts, which can turn up by
+    /// chance in junk
+    fn rank(&self) -> (u32, u32) {
+        (self.frames, self.frame_starts)
+    }
+}
+
+/// Listens at each candidate rate for `listen` and returns the one at
+/// which the most frames decoded. The transport is left at that rate.
+/// If no rate heard so much as a frame start this is
+/// `Error::NoResponse`, which usually means that the bus is idle
+pub fn detect_baud<T: SetBaud>(
+    transport: &mut T,
+    candidates: &[u32],
+    listen: Duration,
+) -> Result<BaudScore> {
+    let mut scores = Vec::with_capacity(candidates.len());
+    for baud in candidates {
+        scores.push(listen_at(transport, *baud, listen)?);
+    }
+    let best = scores
+        .into_iter()
+        .filter(|s| s.frame_starts > 0)
+        // max_by_key keeps the last of equals; prefer the first
+        .rev()
+        .max_by_key(BaudScore::rank)
+        .ok_or(Error::NoResponse)?;
+    transport.set_baud(best.baud)?;
+    Ok(best)
+}
+
+/// Scores a single baud rate
+pub fn listen_at<T: SetBaud>(
+    transport: &mut T,
+    baud: u32,
+    listen: Duration,
+) -> Result<BaudScore> {
+    transport.set_baud(baud)?;
+    let mut score = BaudScore {
+        baud,
+        ..Default::default()
+    };
+    let mut state = CmriStateMachine::new();
+    let mut buf = [0_u8; 64];
+    let deadline = Instant::now() + listen;
+    while Instant::now() < deadline {
+        let len = match transport.read(&mut buf) {
+            Ok(len) => len,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::WouldBlock
+                        | ErrorKind::TimedOut
+                        | ErrorKind::Interrupted
+                ) =>
+            {
+                continue
+            }
+            Err(e) => return Err(e.into()),
+        };
+        score.bytes += len;
+        for byte in buf.iter().take(len) {
+            // Errors are just more evidence of the wrong rate
+            let _ = state.process_with_events(*byte, &mut score);
+        }
+    }
+    Ok(score)
+}
+
+impl ProtocolEvents for BaudScore {
+    fn on_frame_start(&mut self) {
+        self.frame_starts += 1;
+    }
+
+    fn on_frame_complete(&mut self, _message: &CmriMessage) {
+        self.frames += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MessageBuilder;
+    use crate::TX_BUFFER_LEN;
+    use std::io;
+
+    /// A bus running at `baud`: at that rate it hands out the frames,
+    /// and at any other rate the same bytes mangled
+    struct TestBus {
+        baud: u32,
+        current: u32,
+        frames: Vec<u8>,
+        pos: usize,
+    }
+
+    impl TestBus {
+        fn new(baud: u32) -> Self {
+            let mut frames = Vec::new();
+            for ua in 0..4 {
+                let mut buf = [0_u8; TX_BUFFER_LEN];
+                let msg = MessageBuilder::get(0x41 + ua, &[0xff, 0x02, ua])
+                    .build()
+                    .unwrap();
+                let len = msg.encode(&mut buf).unwrap();
+                frames.extend_from_slice(&buf[..len]);
+            }
+            Self {
+                baud,
+                current: 0,
+                frames,
+                pos: 0,
+            }
+        }
+    }
+
+    impl Read for TestBus {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.pos >= self.frames.len() {
+                return Err(ErrorKind::TimedOut.into());
+            }
+            let len = buf.len().min(self.frames.len() - self.pos);
+            for (out, b) in buf.iter_mut().zip(&self.frames[self.pos..]) {
+                *out = if self.current == self.baud {
+                    *b
+                } else {
+                    b.rotate_left(3) ^ 0x5a
+                };
+            }
+            self.pos += len;
+            Ok(len)
+        }
+    }
+
+    impl SetBaud for TestBus {
+        fn set_baud(&mut self, baud: u32) -> Result<()> {
+            self.current = baud;
+            self.pos = 0;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn finds_the_bus_rate() {
+        let mut bus = TestBus::new(38400);
+        let best =
+            detect_baud(&mut bus, &COMMON_BAUDS, Duration::from_millis(5))
+                .unwrap();
+        assert_eq!(best.baud, 38400);
+        assert_eq!(best.frames, 4);
+        assert_eq!(best.frame_starts, 4);
+        assert_eq!(bus.current, 38400);
+    }
+
+    #[test]
+    fn silent_bus() {
+        let mut bus = TestBus::new(38400);
+        bus.frames.clear();
+        let res =
+            detect_baud(&mut bus, &COMMON_BAUDS, Duration::from_millis(1));
+        assert_eq!(res, Err(Error::NoResponse));
+    }
+}
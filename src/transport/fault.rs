@@ -0,0 +1,261 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Line noise on demand, for checking that a controller copes with a bad
+// bus. `FaultInjector` wraps any transport and drops or corrupts bytes
+// going either way, sends some frames twice and holds up some reads and
+// writes. Every choice comes from a seeded generator, so a run which
+// fails in CI can be repeated exactly.
+//
+// A write is taken to be one frame, which is how `CmriSocket` writes.
+
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::Duration;
+use std::vec::Vec;
+
+/// How often each kind of fault happens, each as a probability from 0.0
+/// to 1.0. The default injects nothing
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Faults {
+    /// Chance of each byte being lost
+    pub drop_byte: f64,
+    /// Chance of each byte having one of its bits flipped
+    pub flip_bit: f64,
+    /// Chance of each written frame going out twice
+    pub duplicate_frame: f64,
+    /// Chance of each read or write being held up
+    pub delay: f64,
+    /// Longest a read or write is held up for
+    pub max_delay: Duration,
+    /// Starting point for the random choices
+    pub seed: u32,
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Self {
+            drop_byte: 0.0,
+            flip_bit: 0.0,
+            duplicate_frame: 0.0,
+            delay: 0.0,
+            max_delay: Duration::from_millis(10),
+            seed: 1,
+        }
+    }
+}
+
+/// Count of each fault injected so far
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct FaultStats {
+    pub bytes_dropped: u32,
+    pub bits_flipped: u32,
+    pub frames_duplicated: u32,
+    pub delays: u32,
+}
+
+pub struct FaultInjector<T> {
+    inner: T,
+    faults: Faults,
+    /// xorshift state, never zero
+    rng: u32,
+    stats: FaultStats,
+}
+
+impl<T> FaultInjector<T> {
+    pub fn new(inner: T, faults: Faults) -> Self {
+        Self {
+            inner,
+            faults,
+            // xorshift never leaves zero
+            rng: faults.seed.max(1),
+            stats: FaultStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> FaultStats {
+        self.stats
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn next(&mut self) -> u32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        self.rng
+    }
+
+    /// True with the given probability
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0
+            && (self.next() as f64) <= probability * u32::MAX as f64
+    }
+
+    fn maybe_delay(&mut self) {
+        if self.chance(self.faults.delay) {
+            self.stats.delays = self.stats.delays.wrapping_add(1);
+            let fraction = self.next() as f64 / u32::MAX as f64;
+            thread::sleep(self.faults.max_delay.mul_f64(fraction));
+        }
+    }
+
+    /// Drops and corrupts bytes in place, returning how many are left
+    fn mangle(&mut self, buf: &mut [u8]) -> usize {
+        let mut kept = 0;
+        for n in 0..buf.len() {
+            if self.chance(self.faults.drop_byte) {
+                self.stats.bytes_dropped =
+                    self.stats.bytes_dropped.wrapping_add(1);
+                continue;
+            }
+            let mut byte = buf.get(n).copied().unwrap_or_default();
+            if self.chance(self.faults.flip_bit) {
+                self.stats.bits_flipped =
+                    self.stats.bits_flipped.wrapping_add(1);
+                byte ^= 1 << (self.next() % 8);
+            }
+            if let Some(b) = buf.get_mut(kept) {
+                *b = byte;
+            }
+            kept += 1;
+        }
+        kept
+    }
+}
+
+impl<T: Read> Read for FaultInjector<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.maybe_delay();
+        loop {
+            let len = self.inner.read(buf)?;
+            let kept = self.mangle(buf.get_mut(..len).unwrap_or_default());
+            // Returning nothing would look like the end of the stream,
+            // so if every byte was lost wait for some more
+            if kept > 0 || len == 0 {
+                return Ok(kept);
+            }
+        }
+    }
+}
+
+impl<T: Write> Write for FaultInjector<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.maybe_delay();
+        let mut frame: Vec<u8> = buf.to_vec();
+        let len = self.mangle(&mut frame);
+        let frame = frame.get(..len).unwrap_or_default();
+        self.inner.write_all(frame)?;
+        if self.chance(self.faults.duplicate_frame) {
+            self.stats.frames_duplicated =
+                self.stats.frames_duplicated.wrapping_add(1);
+            self.inner.write_all(frame)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CmriStateMachine, RxState};
+    use std::io::Cursor;
+
+    const FRAME: [u8; 9] =
+        [0xff, 0xff, 0x02, 0x41, 0x52, 0x01, 0x02, 0x04, 0x03];
+
+    #[test]
+    fn no_faults_passes_through() {
+        let mut fi =
+            FaultInjector::new(Cursor::new(FRAME.to_vec()), Faults::default());
+        let mut buf = Vec::new();
+        fi.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, FRAME);
+
+        let mut fi = FaultInjector::new(Vec::new(), Faults::default());
+        fi.write_all(&FRAME).unwrap();
+        assert_eq!(fi.into_inner(), FRAME);
+    }
+
+    #[test]
+    fn every_fault() {
+        let faults = Faults {
+            flip_bit: 1.0,
+            duplicate_frame: 1.0,
+            ..Default::default()
+        };
+        let mut fi = FaultInjector::new(Vec::new(), faults);
+        fi.write_all(&FRAME).unwrap();
+        assert_eq!(
+            fi.stats(),
+            FaultStats {
+                bits_flipped: 9,
+                frames_duplicated: 1,
+                ..Default::default()
+            }
+        );
+        let written = fi.into_inner();
+        assert_eq!(written.len(), 18);
+        for (sent, orig) in written.iter().zip(FRAME.iter().cycle()) {
+            assert_eq!((sent ^ orig).count_ones(), 1);
+        }
+
+        let faults = Faults {
+            drop_byte: 1.0,
+            ..Default::default()
+        };
+        let mut fi = FaultInjector::new(Cursor::new(FRAME.to_vec()), faults);
+        let mut buf = Vec::new();
+        fi.read_to_end(&mut buf).unwrap();
+        assert!(buf.is_empty());
+        assert_eq!(fi.stats().bytes_dropped, 9);
+    }
+
+    #[test]
+    fn same_seed_same_faults() {
+        let faults = Faults {
+            drop_byte: 0.05,
+            flip_bit: 0.05,
+            seed: 1360,
+            ..Default::default()
+        };
+        let capture: Vec<u8> =
+            FRAME.iter().cycle().take(900).copied().collect();
+        let run = || {
+            let mut fi =
+                FaultInjector::new(Cursor::new(capture.clone()), faults);
+            let mut buf = Vec::new();
+            fi.read_to_end(&mut buf).unwrap();
+            (buf, fi.stats())
+        };
+        let (first, stats) = run();
+        assert_eq!(run(), (first.clone(), stats));
+        assert!(stats.bytes_dropped > 0 && stats.bits_flipped > 0);
+
+        // The state machine resyncs and still picks up clean frames
+        let mut state = CmriStateMachine::new();
+        let frames = first
+            .iter()
+            .filter(|b| matches!(state.process(**b), Ok(RxState::Complete)))
+            .count();
+        assert!(frames > 0 && frames < 100);
+    }
+}
@@ -0,0 +1,24 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Ready-made transports for `CmriSocket`. Anything implementing `Read`
+// and `Write` is a transport; these handle opening the underlying
+// hardware with settings that suit C/MRI.
+
+pub use crate::cmri_socket::ReadWrite;
+
+pub mod autobaud;
+pub mod fault;
+pub mod reconnect;
+
+#[cfg(feature = "serial")]
+pub mod serial;
+
+pub mod udp;
+
+#[cfg(feature = "rpi")]
+pub mod rppal;
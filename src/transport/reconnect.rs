@@ -0,0 +1,151 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Getting a transport back after it fails, e.g. when a USB adapter is
+// unplugged or a TCP bridge restarts. An `Opener` opens the transport in
+// the first place; given one, `CmriSocket` reopens the transport whenever
+// a read or write fails with an IO error, trying again after a growing
+// delay until its `RetryPolicy` runs out.
+//
+// Each reconnection starts a new session. Nodes on the far side may have
+// lost power along with the adapter and forgotten their Init and their
+// outputs, so `Controller` watches `CmriSocket::session()` and brings
+// every node back up when it changes.
+
+use super::ReadWrite;
+use crate::{Result, RetryPolicy};
+use std::boxed::Box;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+/// Opens a fresh transport
+pub type Opener = Box<dyn FnMut() -> Result<Box<dyn ReadWrite>>>;
+
+/// Reported to `CmriSocketBuilder::on_connection_change()` as the socket
+/// loses and regains its transport
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ConnectionState {
+    /// An IO error broke the connection
+    Lost,
+    /// Reopening failed and will be tried again, counting from 1
+    Retrying { attempt: u8 },
+    /// The transport was reopened
+    Reconnected,
+    /// Every attempt to reopen the transport failed
+    Failed,
+}
+
+/// Connects to a TCP bridge, setting the read timeout on each new stream
+pub fn tcp<A>(addr: A, read_timeout: Duration) -> Opener
+where
+    A: ToSocketAddrs + 'static,
+{
+    Box::new(move || {
+        let stream = TcpStream::connect(&addr)?;
+        stream.set_read_timeout(Some(read_timeout))?;
+        Ok(Box::new(stream) as Box<dyn ReadWrite>)
+    })
+}
+
+/// Opens a serial port, which may take a different path once the
+/// adapter has been plugged back in if the OS doesn't give it a stable
+/// name
+#[cfg(feature = "serial")]
+pub fn serial(path: &str, config: crate::SerialConfig) -> Opener {
+    let path = std::string::String::from(path);
+    Box::new(move || {
+        let port = super::serial::SerialTransport::open(&path, &config)?;
+        Ok(Box::new(port) as Box<dyn ReadWrite>)
+    })
+}
+
+pub(crate) struct Reconnector {
+    opener: Opener,
+    policy: RetryPolicy,
+    on_change: fn(ConnectionState),
+}
+
+impl Reconnector {
+    pub(crate) fn new(
+        opener: Opener,
+        policy: RetryPolicy,
+        on_change: fn(ConnectionState),
+    ) -> Self {
+        Self {
+            opener,
+            policy,
+            on_change,
+        }
+    }
+
+    /// Opens a new transport, straight away and then after each backoff,
+    /// giving the last error if none of the attempts work
+    pub(crate) fn reconnect(&mut self) -> Result<Box<dyn ReadWrite>> {
+        (self.on_change)(ConnectionState::Lost);
+        let mut attempt = 0;
+        loop {
+            match (self.opener)() {
+                Ok(transport) => {
+                    (self.on_change)(ConnectionState::Reconnected);
+                    return Ok(transport);
+                }
+                Err(_) if attempt < self.policy.retries => {
+                    attempt += 1;
+                    (self.on_change)(ConnectionState::Retrying { attempt });
+                    thread::sleep(self.policy.delay(attempt));
+                }
+                Err(e) => {
+                    (self.on_change)(ConnectionState::Failed);
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Error;
+    use std::io::Cursor;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::vec::Vec;
+
+    #[test]
+    fn retries_until_open() {
+        static RETRIES: AtomicUsize = AtomicUsize::new(0);
+        let policy = RetryPolicy {
+            retries: 3,
+            initial_backoff: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        };
+        let mut failures = 2;
+        let opener: Opener = Box::new(move || {
+            if failures > 0 {
+                failures -= 1;
+                return Err(Error::Disconnected);
+            }
+            Ok(Box::new(Cursor::new(Vec::new())) as Box<dyn ReadWrite>)
+        });
+        let mut reconnector = Reconnector::new(opener, policy, |state| {
+            if let ConnectionState::Retrying { .. } = state {
+                RETRIES.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        assert!(reconnector.reconnect().is_ok());
+        assert_eq!(RETRIES.load(Ordering::SeqCst), 2);
+
+        let opener: Opener = Box::new(|| Err(Error::Disconnected));
+        let policy = RetryPolicy {
+            retries: 0,
+            ..policy
+        };
+        let mut reconnector = Reconnector::new(opener, policy, |_| {});
+        assert_eq!(reconnector.reconnect().err(), Some(Error::Disconnected));
+    }
+}
@@ -0,0 +1,215 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Raspberry Pi UART driving an RS485 transceiver such as a MAX485, with
+// the driver enable (RTS) line on a GPIO pin. The pin is raised for each
+// frame and only dropped once the frame has had time to leave the UART,
+// so the caller never has to think about line turnaround. The pin on its
+// own is a `GpioLine`, for use with other UARTs. Anything the
+// UART picks up while transmitting, such as our own echo or noise from
+// the transceiver switching, is thrown away rather than left for the
+// next read.
+
+use super::autobaud::SetBaud;
+use crate::serial_config::{self, SerialConfig};
+use crate::{Error, LineDiscipline, LineTiming, Result};
+use ::rppal::gpio::{Gpio, OutputPin};
+use ::rppal::uart::{Parity, Queue, Uart};
+use std::format;
+use std::io::{self, ErrorKind, Read, Write};
+use std::thread;
+use std::time::Duration;
+
+/// How long a read waits for data before returning `WouldBlock`, which
+/// `CmriSocket` reports as `Error::Timeout`
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// RS485 driver enable on a GPIO pin, which is high while transmitting
+pub struct GpioLine {
+    pin: OutputPin,
+    config: SerialConfig,
+    timing: LineTiming,
+}
+
+impl GpioLine {
+    /// Takes the pin, leaving the transceiver in receive mode. `config`
+    /// must match the UART's, as it sets how long the transceiver is held
+    /// in transmit
+    pub fn new(mut pin: OutputPin, config: SerialConfig) -> Self {
+        pin.set_low();
+        Self {
+            pin,
+            config,
+            timing: LineTiming::default(),
+        }
+    }
+
+    pub fn with_timing(mut self, timing: LineTiming) -> Self {
+        self.timing = timing;
+        self
+    }
+
+    pub fn into_pin(self) -> OutputPin {
+        self.pin
+    }
+}
+
+impl LineDiscipline for GpioLine {
+    fn set_driver(&mut self, enabled: bool) -> Result<()> {
+        if enabled {
+            self.pin.set_high();
+        } else {
+            self.pin.set_low();
+        }
+        Ok(())
+    }
+
+    fn delay_us(&mut self, us: u64) {
+        thread::sleep(Duration::from_micros(us));
+    }
+
+    fn timing(&self) -> LineTiming {
+        self.timing
+    }
+
+    fn config(&self) -> SerialConfig {
+        self.config
+    }
+}
+
+pub struct HalfDuplexUart {
+    uart: Uart,
+    line: GpioLine,
+    /// Bytes written since the last flush
+    pending: usize,
+    /// Bytes received while transmitting and thrown away
+    rx_discarded: u64,
+}
+
+impl HalfDuplexUart {
+    /// Opens the UART and claims the RTS pin, leaving the transceiver in
+    /// receive mode
+    pub fn open(
+        path: &str,
+        config: &SerialConfig,
+        rts_pin: u8,
+    ) -> Result<Self> {
+        let rts = Gpio::new()
+            .and_then(|gpio| gpio.get(rts_pin))
+            .map_err(rppal_error)?
+            .into_output();
+        let parity = match config.parity {
+            serial_config::Parity::None => Parity::None,
+            serial_config::Parity::Even => Parity::Even,
+            serial_config::Parity::Odd => Parity::Odd,
+        };
+        let stop_bits = match config.stop_bits {
+            serial_config::StopBits::One => 1,
+            serial_config::StopBits::Two => 2,
+        };
+        let uart = Uart::with_path(path, config.baud, parity, 8, stop_bits)
+            .map_err(rppal_error)?;
+        Self::new(uart, rts, *config)
+    }
+
+    /// Wraps an already configured UART and pin. `config` must match the
+    /// UART's, as it sets how long the transceiver is held in transmit
+    pub fn new(
+        mut uart: Uart,
+        rts: OutputPin,
+        config: SerialConfig,
+    ) -> Result<Self> {
+        let line = GpioLine::new(rts, config);
+        uart.set_read_mode(0, DEFAULT_READ_TIMEOUT)
+            .map_err(rppal_error)?;
+        uart.set_write_mode(true).map_err(rppal_error)?;
+        Ok(Self {
+            uart,
+            line,
+            pending: 0,
+            rx_discarded: 0,
+        })
+    }
+
+    pub fn set_read_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.uart.set_read_mode(0, timeout).map_err(rppal_error)
+    }
+
+    /// Guard and hold times around each frame
+    pub fn set_line_timing(&mut self, timing: LineTiming) {
+        self.line.timing = timing;
+    }
+
+    /// Number of bytes received while transmitting, which are discarded
+    pub fn rx_discarded(&self) -> u64 {
+        self.rx_discarded
+    }
+
+    /// Gives back the UART and pin
+    pub fn release(self) -> (Uart, OutputPin) {
+        (self.uart, self.line.into_pin())
+    }
+
+    /// Throws away anything received while the transceiver was driving
+    /// the bus
+    fn discard_input(&mut self) -> io::Result<()> {
+        let len = self.uart.input_len().map_err(io::Error::other)?;
+        if len > 0 {
+            self.uart.flush(Queue::Input).map_err(io::Error::other)?;
+            self.rx_discarded += len as u64;
+        }
+        Ok(())
+    }
+}
+
+impl Read for HalfDuplexUart {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.uart.read(buf) {
+            Ok(0) if !buf.is_empty() => Err(ErrorKind::WouldBlock.into()),
+            Ok(n) => Ok(n),
+            Err(e) => Err(io::Error::other(e)),
+        }
+    }
+}
+
+impl Write for HalfDuplexUart {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.pending == 0 {
+            self.line.begin_tx().map_err(io::Error::other)?;
+        }
+        let n = self.uart.write(buf).map_err(io::Error::other)?;
+        self.pending += n;
+        Ok(n)
+    }
+
+    /// Waits for everything written to leave the UART, then releases the
+    /// bus
+    fn flush(&mut self) -> io::Result<()> {
+        if self.pending > 0 {
+            self.line.hold(self.pending);
+            self.pending = 0;
+            self.uart.drain().map_err(io::Error::other)?;
+            self.discard_input()?;
+        } else {
+            self.uart.drain().map_err(io::Error::other)?;
+        }
+        self.line.set_driver(false).map_err(io::Error::other)
+    }
+}
+
+impl SetBaud for HalfDuplexUart {
+    /// Also changes how long the transceiver is held in transmit
+    fn set_baud(&mut self, baud: u32) -> Result<()> {
+        self.uart.set_baud_rate(baud).map_err(rppal_error)?;
+        self.line.config.baud = baud;
+        Ok(())
+    }
+}
+
+fn rppal_error<E: core::fmt::Display>(e: E) -> Error {
+    Error::IoError(format!("{}", e))
+}
@@ -0,0 +1,207 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Cross-platform serial ports via the `serialport` crate, for desktop
+// controllers on Windows, macOS and Linux.
+//
+// Adapters which key their RS485 transmitter from RTS or DTR are handled
+// by setting `SerialConfig::tx_enable`: the port then asserts the line
+// before the first byte of each write and releases it when flushed, once
+// the frame has had time to leave the adapter. Don't also hand the socket
+// a line discipline for the same port, or the line is switched twice.
+
+use super::autobaud::SetBaud;
+use crate::serial_config::{self, ControlLine, SerialConfig, TxEnable};
+use crate::{LineDiscipline, LineTiming, Result};
+use serialport::{DataBits, FlowControl, Parity, SerialPort, StopBits};
+use std::boxed::Box;
+use std::io::{self, Read, Write};
+use std::string::String;
+use std::thread;
+use std::time::Duration;
+use std::vec::Vec;
+
+/// How long a read waits for data before returning `TimedOut`, which
+/// `CmriSocket` reports as `Error::Timeout`
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Serial port opened with 8 data bits and no flow control
+pub struct SerialTransport {
+    port: Box<dyn SerialPort>,
+    /// Transmit enable, when set in the config
+    line: Option<TxEnableLine>,
+    /// Bytes written since the line was keyed
+    pending: usize,
+}
+
+impl SerialTransport {
+    pub fn open(path: &str, config: &SerialConfig) -> Result<Self> {
+        let parity = match config.parity {
+            serial_config::Parity::None => Parity::None,
+            serial_config::Parity::Even => Parity::Even,
+            serial_config::Parity::Odd => Parity::Odd,
+        };
+        let stop_bits = match config.stop_bits {
+            serial_config::StopBits::One => StopBits::One,
+            serial_config::StopBits::Two => StopBits::Two,
+        };
+        let port = serialport::new(path, config.baud)
+            .data_bits(DataBits::Eight)
+            .parity(parity)
+            .stop_bits(stop_bits)
+            .flow_control(FlowControl::None)
+            .timeout(DEFAULT_READ_TIMEOUT)
+            .open()
+            .map_err(io::Error::from)?;
+        let mut transport = Self {
+            port,
+            line: None,
+            pending: 0,
+        };
+        if let Some(tx_enable) = config.tx_enable {
+            let mut line = transport.tx_enable_line(config, tx_enable)?;
+            line.set_driver(false)?;
+            transport.line = Some(line);
+        }
+        Ok(transport)
+    }
+
+    pub fn set_read_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.port.set_timeout(timeout).map_err(io::Error::from)?;
+        Ok(())
+    }
+
+    /// A handle on one of this port's control lines, to pass to
+    /// `CmriSocketBuilder::line_discipline()` on a port opened without
+    /// `tx_enable`. `config` must match the port's, as it sets how long
+    /// the transmitter is keyed
+    pub fn tx_enable_line(
+        &self,
+        config: &SerialConfig,
+        tx_enable: TxEnable,
+    ) -> Result<TxEnableLine> {
+        let port = self.port.try_clone().map_err(io::Error::from)?;
+        Ok(TxEnableLine {
+            port,
+            config: *config,
+            tx_enable,
+        })
+    }
+
+    /// The underlying port, for anything not covered here
+    pub fn port_mut(&mut self) -> &mut dyn SerialPort {
+        self.port.as_mut()
+    }
+}
+
+impl Read for SerialTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.port.read(buf)
+    }
+}
+
+impl Write for SerialTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(line) = &mut self.line {
+            if self.pending == 0 {
+                line.begin_tx().map_err(io::Error::other)?;
+            }
+        }
+        let n = self.port.write(buf)?;
+        self.pending += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let res = self.port.flush();
+        let pending = core::mem::take(&mut self.pending);
+        // Release the line even if the flush failed, so that it isn't left
+        // holding the bus
+        if let (Some(line), true) = (&mut self.line, pending > 0) {
+            line.end_tx(pending).map_err(io::Error::other)?;
+        }
+        res
+    }
+}
+
+impl SetBaud for SerialTransport {
+    fn set_baud(&mut self, baud: u32) -> Result<()> {
+        self.port.set_baud_rate(baud).map_err(io::Error::from)?;
+        if let Some(line) = &mut self.line {
+            line.config.baud = baud;
+        }
+        Ok(())
+    }
+}
+
+/// RS485 transmit enable on a serial port's RTS and/or DTR line
+pub struct TxEnableLine {
+    port: Box<dyn SerialPort>,
+    config: SerialConfig,
+    tx_enable: TxEnable,
+}
+
+impl TxEnableLine {
+    pub fn with_timing(mut self, timing: LineTiming) -> Self {
+        self.tx_enable.timing = timing;
+        self
+    }
+}
+
+impl LineDiscipline for TxEnableLine {
+    fn set_driver(&mut self, enabled: bool) -> Result<()> {
+        let level = self.tx_enable.level(enabled);
+        let (rts, dtr) = match self.tx_enable.line {
+            ControlLine::Rts => (true, false),
+            ControlLine::Dtr => (false, true),
+            ControlLine::RtsAndDtr => (true, true),
+        };
+        if rts {
+            self.port
+                .write_request_to_send(level)
+                .map_err(io::Error::from)?;
+        }
+        if dtr {
+            self.port
+                .write_data_terminal_ready(level)
+                .map_err(io::Error::from)?;
+        }
+        Ok(())
+    }
+
+    fn delay_us(&mut self, us: u64) {
+        thread::sleep(Duration::from_micros(us));
+    }
+
+    fn timing(&self) -> LineTiming {
+        self.tx_enable.timing
+    }
+
+    fn config(&self) -> SerialConfig {
+        self.config
+    }
+}
+
+/// Names of the serial ports on this machine, e.g. `COM3` or
+/// `/dev/ttyUSB0`
+pub fn available_ports() -> Result<Vec<String>> {
+    let ports = serialport::available_ports().map_err(io::Error::from)?;
+    Ok(ports.into_iter().map(|p| p.port_name).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Error;
+
+    #[test]
+    fn open_failures() {
+        let config = SerialConfig::new(9600);
+        let res = SerialTransport::open("/nonexistent/serial/port", &config);
+        assert!(matches!(res.err(), Some(Error::IoError(_))));
+    }
+}
@@ -0,0 +1,199 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// C/MRI over UDP, as spoken by some DIY wireless nodes such as ESP8266
+// boards. Each datagram carries exactly one frame, so there's no need to
+// feed bytes through the streaming state machine and wait for the stop
+// byte: a datagram either holds a whole frame or is rejected.
+//
+// Some nodes can only take small datagrams, so frames longer than a
+// configurable limit are refused before they're sent rather than being
+// dropped or truncated somewhere along the way.
+//
+// Nodes are found by their address byte. A peer can be registered up
+// front, and any node we hear from is remembered at the address it sent
+// from, so nodes which announce themselves need no configuration.
+
+use crate::{
+    CmriMessage, CmriStateMachine, Error, Result, RxState, TX_BUFFER_LEN,
+};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+/// Largest datagram we expect, with room for a fully escaped frame
+const RX_DATAGRAM_LEN: usize = TX_BUFFER_LEN;
+
+/// Decodes a datagram holding a single frame
+pub fn parse_datagram(datagram: &[u8]) -> Result<CmriMessage> {
+    let mut state = CmriStateMachine::new();
+    match state.process_buf(datagram) {
+        (used, Ok(RxState::Complete)) if used == datagram.len() => {
+            Ok(*state.message())
+        }
+        (_, Err(e)) => Err(e),
+        _ => Err(Error::IncompleteFrame),
+    }
+}
+
+pub struct UdpTransport {
+    socket: UdpSocket,
+    /// Network address of each node, keyed by address byte
+    peers: HashMap<u8, SocketAddr>,
+    tx_buffer: [u8; TX_BUFFER_LEN],
+    max_datagram: usize,
+}
+
+impl UdpTransport {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        Ok(Self {
+            socket: UdpSocket::bind(addr)?,
+            peers: HashMap::new(),
+            tx_buffer: [0; TX_BUFFER_LEN],
+            max_datagram: TX_BUFFER_LEN,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Maximum time `recv_msg()` waits for a datagram, after which it
+    /// reports `Error::Timeout`. `None` waits forever
+    pub fn set_read_timeout(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        Ok(self.socket.set_read_timeout(timeout)?)
+    }
+
+    /// Largest datagram to send, for nodes with small receive buffers.
+    /// Defaults to `TX_BUFFER_LEN`, which fits any frame
+    pub fn set_max_datagram(&mut self, len: usize) {
+        self.max_datagram = len;
+    }
+
+    /// Sets the network address of the node with the given address byte,
+    /// replacing any address learned from its datagrams
+    pub fn add_peer(&mut self, node: u8, addr: SocketAddr) {
+        self.peers.insert(node, addr);
+    }
+
+    pub fn remove_peer(&mut self, node: u8) {
+        self.peers.remove(&node);
+    }
+
+    /// Network address of a node, if it has been added or heard from
+    pub fn peer(&self, node: u8) -> Option<SocketAddr> {
+        self.peers.get(&node).copied()
+    }
+
+    /// Every known node and its network address
+    pub fn peers(&self) -> impl Iterator<Item = (u8, SocketAddr)> + '_ {
+        self.peers.iter().map(|(node, addr)| (*node, *addr))
+    }
+
+    /// Sends a message as one datagram to the node it's addressed to.
+    /// Reports `Error::UnknownPeer` if there's no address for that node,
+    /// and `Error::DataTooLong` if the frame is over the datagram limit
+    pub fn send_msg(&mut self, msg: &CmriMessage) -> Result<()> {
+        let node = msg.address.ok_or(Error::MissingAddress)?;
+        let peer = self.peer(node).ok_or(Error::UnknownPeer)?;
+        if msg.try_encoded_len()? > self.max_datagram {
+            return Err(Error::DataTooLong);
+        }
+        let frame = msg.encode_slice(&mut self.tx_buffer)?;
+        self.socket.send_to(frame, peer)?;
+        Ok(())
+    }
+
+    /// Waits for a datagram and decodes it, remembering where it came from
+    /// as the sending node's address. A datagram which doesn't hold
+    /// exactly one frame is reported as an error rather than skipped, so
+    /// that the caller can see a misbehaving node
+    pub fn recv_msg(&mut self) -> Result<(CmriMessage, SocketAddr)> {
+        let mut buf = [0_u8; RX_DATAGRAM_LEN];
+        let (len, from) = match self.socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) => {
+                return Err(match e.kind() {
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+                        Error::Timeout
+                    }
+                    _ => e.into(),
+                })
+            }
+        };
+        let msg = parse_datagram(buf.get(..len).unwrap_or_default())?;
+        if let Some(node) = msg.address {
+            self.peers.insert(node, from);
+        }
+        Ok((msg, from))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MessageBuilder;
+
+    #[test]
+    fn parse_whole_datagrams() {
+        let msg = MessageBuilder::set(0x41, &[0x03, 0x10]).build().unwrap();
+        let mut buf = [0_u8; TX_BUFFER_LEN];
+        let len = msg.encode(&mut buf).unwrap();
+
+        let parsed = parse_datagram(&buf[..len]).unwrap();
+        assert_eq!(parsed.payload[..parsed.len], [0x03, 0x10]);
+
+        // Missing the stop byte, or with trailing bytes
+        let res = parse_datagram(&buf[..len - 1]);
+        assert_eq!(res.unwrap_err(), Error::IncompleteFrame);
+        buf[len] = 0xff;
+        let res = parse_datagram(&buf[..=len]);
+        assert_eq!(res.unwrap_err(), Error::IncompleteFrame);
+    }
+
+    #[test]
+    fn send_and_receive() {
+        let mut controller = UdpTransport::bind("127.0.0.1:0").unwrap();
+        let mut node = UdpTransport::bind("127.0.0.1:0").unwrap();
+        let timeout = Some(Duration::from_secs(1));
+        controller.set_read_timeout(timeout).unwrap();
+        node.set_read_timeout(timeout).unwrap();
+
+        let poll = MessageBuilder::poll(0x41).build().unwrap();
+        assert_eq!(controller.send_msg(&poll), Err(Error::UnknownPeer));
+        controller.add_peer(0x41, node.local_addr().unwrap());
+        controller.send_msg(&poll).unwrap();
+
+        let (msg, from) = node.recv_msg().unwrap();
+        assert_eq!(msg.address, Some(0x41));
+        assert_eq!(from, controller.local_addr().unwrap());
+
+        // The node learned the controller's address from the Poll
+        let get = MessageBuilder::get(0x41, &[1]).build().unwrap();
+        node.send_msg(&get).unwrap();
+        let (msg, _) = controller.recv_msg().unwrap();
+        assert_eq!(msg.payload[..msg.len], [1]);
+        assert_eq!(controller.peers().count(), 1);
+
+        // Too long for the node to take
+        let set = MessageBuilder::set(0x41, &[0x03; 4]).build().unwrap();
+        controller.set_max_datagram(set.encoded_len() - 1);
+        assert_eq!(controller.send_msg(&set), Err(Error::DataTooLong));
+        controller.set_max_datagram(set.encoded_len());
+        controller.send_msg(&set).unwrap();
+        assert_eq!(node.recv_msg().unwrap().0, set);
+
+        controller
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        assert_eq!(controller.recv_msg().unwrap_err(), Error::Timeout);
+    }
+}
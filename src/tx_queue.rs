@@ -0,0 +1,236 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Transmit queue for `CmriSocket`, with two priority classes. Control
+// frames (Set and Init) go ahead of any queued Polls so that outputs
+// change as soon as possible, but after a run of control frames a waiting
+// Poll is let through so that polling never stops entirely. A frame that
+// has started going out is always finished before anything else is sent.
+
+use crate::{CmriMessage, Error, MessageType, Result};
+use std::collections::VecDeque;
+use std::vec::Vec;
+
+/// Default limit on the number of frames waiting in the queue
+pub const DEFAULT_TX_QUEUE_LEN: usize = 32;
+/// Default number of control frames in a row before a waiting Poll is
+/// sent
+pub const DEFAULT_STARVATION_LIMIT: u32 = 8;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TxPriority {
+    /// Set and Init messages
+    Control,
+    /// Poll messages, and anything without a message type
+    Polling,
+}
+
+impl TxPriority {
+    pub fn of(msg: &CmriMessage) -> Self {
+        match msg.message_type {
+            Some(MessageType::Poll) | None => TxPriority::Polling,
+            Some(_) => TxPriority::Control,
+        }
+    }
+}
+
+/// Number of frames waiting in each class. A frame which has been partly
+/// written counts towards its own class
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct TxQueueDepth {
+    pub control: usize,
+    pub polling: usize,
+}
+
+impl TxQueueDepth {
+    pub fn total(&self) -> usize {
+        self.control + self.polling
+    }
+}
+
+/// An encoded frame and how much of it has been written so far
+pub(crate) struct QueuedFrame {
+    pub(crate) id: u32,
+    pub(crate) msg: CmriMessage,
+    pub(crate) bytes: Vec<u8>,
+    pub(crate) written: usize,
+}
+
+pub(crate) struct TxQueue {
+    control: VecDeque<QueuedFrame>,
+    polling: VecDeque<QueuedFrame>,
+    /// Frame currently going out, which can't be preempted
+    current: Option<(QueuedFrame, TxPriority)>,
+    capacity: usize,
+    starvation_limit: u32,
+    /// Control frames sent in a row while a Poll was waiting
+    control_run: u32,
+    next_id: u32,
+    peak: usize,
+}
+
+impl TxQueue {
+    pub(crate) fn new(capacity: usize, starvation_limit: u32) -> Self {
+        Self {
+            control: VecDeque::new(),
+            polling: VecDeque::new(),
+            current: None,
+            capacity,
+            starvation_limit,
+            control_run: 0,
+            next_id: 0,
+            peak: 0,
+        }
+    }
+
+    /// Adds an encoded frame, returning its ID
+    pub(crate) fn push(
+        &mut self,
+        msg: &CmriMessage,
+        bytes: &[u8],
+    ) -> Result<u32> {
+        if self.is_full() {
+            return Err(Error::QueueFull);
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let frame = QueuedFrame {
+            id,
+            msg: *msg,
+            bytes: bytes.to_vec(),
+            written: 0,
+        };
+        match TxPriority::of(msg) {
+            TxPriority::Control => self.control.push_back(frame),
+            TxPriority::Polling => self.polling.push_back(frame),
+        }
+        self.peak = self.peak.max(self.depth().total());
+        Ok(id)
+    }
+
+    /// The frame to write next, choosing one if none is in progress
+    pub(crate) fn front_mut(&mut self) -> Option<&mut QueuedFrame> {
+        if self.current.is_none() {
+            self.current = self.select();
+        }
+        self.current.as_mut().map(|(frame, _)| frame)
+    }
+
+    /// Removes the frame in progress once it has been written
+    pub(crate) fn complete(&mut self) -> Option<QueuedFrame> {
+        self.current.take().map(|(frame, _)| frame)
+    }
+
+    fn select(&mut self) -> Option<(QueuedFrame, TxPriority)> {
+        let poll_starved = self.control_run >= self.starvation_limit;
+        if self.control.is_empty() || (poll_starved && !self.polling.is_empty())
+        {
+            self.control_run = 0;
+            return self.polling.pop_front().map(|f| (f, TxPriority::Polling));
+        }
+        if self.polling.is_empty() {
+            self.control_run = 0;
+        } else {
+            self.control_run += 1;
+        }
+        self.control.pop_front().map(|f| (f, TxPriority::Control))
+    }
+
+    /// Starts the frame in progress again from its first byte, for a new
+    /// transport which never saw the first part of it
+    pub(crate) fn rewind(&mut self) {
+        if let Some((frame, _)) = &mut self.current {
+            frame.written = 0;
+        }
+    }
+
+    pub(crate) fn is_full(&self) -> bool {
+        self.depth().total() >= self.capacity
+    }
+
+    pub(crate) fn depth(&self) -> TxQueueDepth {
+        let mut depth = TxQueueDepth {
+            control: self.control.len(),
+            polling: self.polling.len(),
+        };
+        match self.current {
+            Some((_, TxPriority::Control)) => depth.control += 1,
+            Some((_, TxPriority::Polling)) => depth.polling += 1,
+            None => {}
+        }
+        depth
+    }
+
+    /// Deepest the queue has been since creation or the last reset
+    pub(crate) fn peak(&self) -> usize {
+        self.peak
+    }
+
+    pub(crate) fn reset_peak(&mut self) {
+        self.peak = self.depth().total();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MessageBuilder;
+
+    fn drain(queue: &mut TxQueue) -> Vec<u32> {
+        let mut ids = Vec::new();
+        while queue.front_mut().is_some() {
+            ids.push(queue.complete().unwrap().id);
+        }
+        ids
+    }
+
+    #[test]
+    fn set_preempts_polls() {
+        let poll = MessageBuilder::poll(0x41).build().unwrap();
+        let set = MessageBuilder::set(0x41, &[1]).build().unwrap();
+        let mut queue = TxQueue::new(8, DEFAULT_STARVATION_LIMIT);
+        queue.push(&poll, &[0]).unwrap();
+        queue.push(&poll, &[0]).unwrap();
+
+        // A frame in progress isn't preempted
+        queue.front_mut().unwrap().written = 1;
+        queue.push(&set, &[0]).unwrap();
+        queue.push(&set, &[0]).unwrap();
+        assert_eq!(
+            queue.depth(),
+            TxQueueDepth {
+                control: 2,
+                polling: 2
+            }
+        );
+        assert_eq!(drain(&mut queue), [0, 2, 3, 1]);
+        assert_eq!(queue.peak(), 4);
+        queue.reset_peak();
+        assert_eq!(queue.peak(), 0);
+    }
+
+    #[test]
+    fn polls_are_not_starved() {
+        let poll = MessageBuilder::poll(0x41).build().unwrap();
+        let set = MessageBuilder::set(0x41, &[1]).build().unwrap();
+        let mut queue = TxQueue::new(8, 2);
+        queue.push(&poll, &[0]).unwrap();
+        for _ in 0..5 {
+            queue.push(&set, &[0]).unwrap();
+        }
+        assert_eq!(drain(&mut queue), [1, 2, 0, 3, 4, 5]);
+        assert_eq!(TxPriority::of(&poll), TxPriority::Polling);
+    }
+
+    #[test]
+    fn capacity() {
+        let poll = MessageBuilder::poll(0x41).build().unwrap();
+        let mut queue = TxQueue::new(1, DEFAULT_STARVATION_LIMIT);
+        queue.push(&poll, &[0]).unwrap();
+        assert_eq!(queue.push(&poll, &[0]), Err(Error::QueueFull));
+    }
+}
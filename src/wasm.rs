@@ -0,0 +1,137 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// JavaScript bindings for the browser, so that a web page can decode a
+// traffic capture without sending it anywhere. Build with e.g.
+//
+//   cargo rustc --release --target wasm32-unknown-unknown \
+//       --no-default-features --features wasm --crate-type cdylib
+//   wasm-bindgen --target web --out-dir pkg \
+//       target/wasm32-unknown-unknown/release/cmri.wasm
+//
+// then `import init, { decode, encode } from "./pkg/cmri.js"`. Byte
+// arrays cross as `Uint8Array`s and errors are thrown as JS `Error`s.
+
+use crate::{CmriMessage, CmriStateMachine, MessageType, RxState};
+use crate::{Result, TX_BUFFER_LEN};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use wasm_bindgen::prelude::*;
+
+/// A message found in a capture
+#[wasm_bindgen]
+pub struct Frame {
+    message: CmriMessage,
+    end: usize,
+}
+
+#[wasm_bindgen]
+impl Frame {
+    #[wasm_bindgen(getter)]
+    pub fn address(&self) -> Option<u8> {
+        self.message.address
+    }
+
+    /// "I", "T", "R" or "P"
+    #[wasm_bindgen(getter, js_name = messageType)]
+    pub fn message_type(&self) -> Option<String> {
+        self.message
+            .message_type
+            .map(|t| char::from(u8::from(t)).to_string())
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn payload(&self) -> Vec<u8> {
+        self.message.data().to_vec()
+    }
+
+    /// Offset in the capture just past the frame's last byte
+    #[wasm_bindgen(getter)]
+    pub fn end(&self) -> usize {
+        self.end
+    }
+}
+
+/// Every message in a capture of raw bus traffic, in order. Bad frames
+/// and noise between frames are skipped
+#[wasm_bindgen]
+pub fn decode(capture: &[u8]) -> Vec<Frame> {
+    let mut state = CmriStateMachine::new();
+    let mut frames = Vec::new();
+    for (n, byte) in capture.iter().enumerate() {
+        if let Ok(RxState::Complete) = state.process(*byte) {
+            frames.push(Frame {
+                message: *state.message(),
+                end: n + 1,
+            });
+        }
+    }
+    frames
+}
+
+/// Encodes a frame, ready to send
+#[wasm_bindgen]
+pub fn encode(
+    address: u8,
+    message_type: &str,
+    payload: &[u8],
+) -> core::result::Result<Vec<u8>, JsError> {
+    encode_frame(address, message_type, payload)
+        .map_err(|e| JsError::new(&e.to_string()))
+}
+
+fn encode_frame(
+    address: u8,
+    message_type: &str,
+    payload: &[u8],
+) -> Result<Vec<u8>> {
+    let t = match message_type.as_bytes() {
+        [t] => MessageType::try_from(*t)?,
+        _ => return Err(crate::Error::InvalidMessageType),
+    };
+    let mut msg = CmriMessage::new();
+    msg.address(address).message_type(t).payload(payload)?;
+    let mut buf = [0_u8; TX_BUFFER_LEN];
+    let len = msg.encode(&mut buf)?;
+    Ok(buf.get(..len).unwrap_or_default().to_vec())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Error;
+
+    #[test]
+    fn decode_a_capture() {
+        let mut capture = std::vec![0x55, 0x03];
+        capture.extend(encode_frame(0x41, "T", &[1, 3, 2]).unwrap());
+        let first_end = capture.len();
+        capture.extend(encode_frame(0x42, "P", &[]).unwrap());
+
+        let frames = decode(&capture);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].address(), Some(0x41));
+        assert_eq!(frames[0].message_type().as_deref(), Some("T"));
+        assert_eq!(frames[0].payload(), [1, 3, 2]);
+        assert_eq!(frames[0].end(), first_end);
+        assert_eq!(frames[1].message_type().as_deref(), Some("P"));
+        assert_eq!(frames[1].end(), capture.len());
+    }
+
+    #[test]
+    fn bad_message_types() {
+        assert_eq!(
+            encode_frame(0x41, "Z", &[]),
+            Err(Error::InvalidMessageType)
+        );
+        assert_eq!(
+            encode_frame(0x41, "TT", &[]),
+            Err(Error::InvalidMessageType)
+        );
+    }
+}
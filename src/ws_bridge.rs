@@ -0,0 +1,393 @@
+// Copyright 2020 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// WebSocket server for browser-based control panels. Decoded C/MRI
+// traffic is sent to every connected browser as JSON, and browsers send
+// back output commands which become Set messages.
+//
+// The WebSocket protocol itself comes from tungstenite. Everything runs on
+// the caller's thread with non-blocking sockets, including each client's
+// opening handshake, which is picked up wherever it left off on each call
+// to `accept()`. That way the bridge can sit in the same loop as the
+// `CmriSocket` that talks to the bus without ever holding it up.
+//
+// Messages to the browser look like
+//   {"address":65,"ua":0,"message_type":"get","payload":[1,0,128]}
+// and output commands from the browser like
+//   {"address":65,"outputs":[255,0,0]}
+
+use crate::{CmriMessage, CmriSocket, MessageBuilder, MessageType, Result};
+use serde::{Deserialize, Serialize};
+use std::format;
+use std::io::ErrorKind;
+use std::mem;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+use tungstenite::handshake::server::{NoCallback, ServerHandshake};
+use tungstenite::handshake::{HandshakeError, MidHandshake};
+use tungstenite::protocol::WebSocketConfig;
+use tungstenite::{Message, Utf8Bytes, WebSocket};
+
+/// How long a new connection has to finish its handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Largest incoming message accepted. Commands are small, so anything
+/// bigger is a misbehaving client
+const MAX_MESSAGE_LEN: usize = 4096;
+
+/// A C/MRI message as published to browsers
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TrafficEvent {
+    /// Address byte as sent on the wire
+    pub address: Option<u8>,
+    /// Unit address, if the address byte is a valid one
+    pub ua: Option<u8>,
+    pub message_type: Option<MessageType>,
+    pub payload: Vec<u8>,
+}
+
+impl TrafficEvent {
+    pub fn new(msg: &CmriMessage) -> Self {
+        Self {
+            address: msg.address,
+            ua: msg.to_ua(),
+            message_type: msg.message_type,
+            payload: msg.data().to_vec(),
+        }
+    }
+}
+
+/// New output states for a node, sent by a browser
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OutputCommand {
+    /// Address byte as sent on the wire
+    pub address: u8,
+    pub outputs: Vec<u8>,
+}
+
+impl OutputCommand {
+    pub fn to_message(&self) -> Result<CmriMessage> {
+        MessageBuilder::set(self.address, &self.outputs).build()
+    }
+}
+
+type Handshake = ServerHandshake<TcpStream, NoCallback>;
+
+/// A connection which hasn't finished its opening handshake yet
+struct Pending {
+    handshake: MidHandshake<Handshake>,
+    peer: SocketAddr,
+    /// When to give up on it
+    deadline: Instant,
+}
+
+struct Client {
+    socket: WebSocket<TcpStream>,
+    peer: SocketAddr,
+}
+
+pub struct WsBridge {
+    listener: TcpListener,
+    pending: Vec<Pending>,
+    clients: Vec<Client>,
+    /// Commands which couldn't be parsed, since the last reset
+    bad_commands: u32,
+}
+
+impl WsBridge {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            pending: Vec::new(),
+            clients: Vec::new(),
+            bad_commands: 0,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Takes any new connections and carries on with the handshakes of
+    /// those which are part way through, returning how many have
+    /// finished. Never blocks. A connection which sends an invalid
+    /// handshake, or doesn't finish it within `HANDSHAKE_TIMEOUT`, is
+    /// dropped
+    pub fn accept(&mut self) -> Result<usize> {
+        let clients = self.clients.len();
+        loop {
+            let (stream, peer) = match self.listener.accept() {
+                Ok(conn) => conn,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            };
+            if stream.set_nonblocking(true).is_err() {
+                continue;
+            }
+            let config = WebSocketConfig::default()
+                .max_message_size(Some(MAX_MESSAGE_LEN))
+                .max_frame_size(Some(MAX_MESSAGE_LEN));
+            let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+            let handshake =
+                tungstenite::accept_with_config(stream, Some(config));
+            self.handshake_done(handshake, peer, deadline);
+        }
+
+        let now = Instant::now();
+        for pending in mem::take(&mut self.pending) {
+            if now < pending.deadline {
+                let handshake = pending.handshake.handshake();
+                self.handshake_done(handshake, pending.peer, pending.deadline);
+            }
+        }
+        Ok(self.clients.len() - clients)
+    }
+
+    /// Files a connection according to how far its handshake has got
+    fn handshake_done(
+        &mut self,
+        handshake: core::result::Result<
+            WebSocket<TcpStream>,
+            HandshakeError<Handshake>,
+        >,
+        peer: SocketAddr,
+        deadline: Instant,
+    ) {
+        match handshake {
+            Ok(socket) => self.clients.push(Client { socket, peer }),
+            Err(HandshakeError::Interrupted(handshake)) => {
+                self.pending.push(Pending {
+                    handshake,
+                    peer,
+                    deadline,
+                })
+            }
+            Err(HandshakeError::Failure(_)) => {}
+        }
+    }
+
+    /// Addresses of the connected browsers
+    pub fn clients(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.clients.iter().map(|c| c.peer)
+    }
+
+    /// Commands from browsers which weren't valid JSON or didn't make a
+    /// valid Set message
+    pub fn bad_commands(&self) -> u32 {
+        self.bad_commands
+    }
+
+    /// Sends a message to every connected browser, dropping any which
+    /// have gone away
+    pub fn publish(&mut self, msg: &CmriMessage) -> Result<()> {
+        let json = serde_json::to_string(&TrafficEvent::new(msg))
+            .map_err(|e| crate::Error::IoError(format!("{}", e)))?;
+        let json = Utf8Bytes::from(json);
+        self.clients.retain_mut(|client| {
+            // Whatever doesn't fit in the socket goes with the next send
+            let sent = client.socket.send(Message::Text(json.clone()));
+            sent.is_ok() || would_block(&sent)
+        });
+        Ok(())
+    }
+
+    /// Reads whatever the browsers have sent, returning the Set messages
+    /// it translates to. Never blocks
+    pub fn poll_commands(&mut self) -> Vec<CmriMessage> {
+        let mut commands = Vec::new();
+        let mut bad_commands = 0;
+        let mut on_text = |text: &[u8]| match parse_command(text) {
+            Some(msg) => commands.push(msg),
+            None => bad_commands += 1,
+        };
+        self.clients
+            .retain_mut(|client| client.receive(&mut on_text));
+        self.bad_commands = self.bad_commands.saturating_add(bad_commands);
+        commands
+    }
+
+    /// Sends every pending command to the bus, returning how many were
+    /// sent
+    pub fn forward_commands(
+        &mut self,
+        socket: &mut CmriSocket,
+    ) -> Result<usize> {
+        let commands = self.poll_commands();
+        for msg in &commands {
+            socket.send(msg)?;
+        }
+        Ok(commands.len())
+    }
+}
+
+impl Client {
+    /// Reads and handles any complete messages, passing text to
+    /// `on_text`. tungstenite answers pings as it reads. Returns false
+    /// once the connection should be dropped
+    fn receive(&mut self, on_text: &mut dyn FnMut(&[u8])) -> bool {
+        loop {
+            match self.socket.read() {
+                Ok(Message::Text(text)) => on_text(text.as_bytes()),
+                Ok(Message::Close(_)) => {
+                    // Sends the reply to the close
+                    let _ = self.socket.flush();
+                    return false;
+                }
+                Ok(_) => {}
+                read @ Err(_) => return would_block(&read),
+            }
+        }
+    }
+}
+
+/// Whether a tungstenite call only stopped because the socket would
+/// block
+fn would_block<T>(res: &tungstenite::Result<T>) -> bool {
+    matches!(
+        res,
+        Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock
+    )
+}
+
+/// Decodes an output command into a Set message, or `None` if it isn't
+/// valid
+fn parse_command(text: &[u8]) -> Option<CmriMessage> {
+    let command: OutputCommand = serde_json::from_slice(text).ok()?;
+    command.to_message().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::string::String;
+
+    const OPCODE_TEXT: u8 = 0x1;
+    const OPCODE_CLOSE: u8 = 0x8;
+
+    /// Opening handshake as a browser would send it, with the key from
+    /// the example in RFC 6455
+    const HANDSHAKE: &[u8] = b"GET / HTTP/1.1\r\nHost: localhost\r\n\
+        Upgrade: websocket\r\nConnection: Upgrade\r\n\
+        Sec-WebSocket-Version: 13\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+
+    /// Frame as a browser would send it
+    fn client_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = std::vec![0x80 | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    /// Reads the response to the opening handshake
+    fn read_response(browser: &mut TcpStream) -> String {
+        let mut response = Vec::new();
+        let mut byte = [0_u8; 1];
+        while !response.ends_with(b"\r\n\r\n") {
+            browser.read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+        String::from_utf8(response).unwrap()
+    }
+
+    #[test]
+    fn handshake_in_pieces() {
+        let mut bridge = WsBridge::bind("127.0.0.1:0").unwrap();
+        let mut browser =
+            TcpStream::connect(bridge.local_addr().unwrap()).unwrap();
+        let (start, rest) = HANDSHAKE.split_at(40);
+        browser.write_all(start).unwrap();
+
+        // Half a handshake doesn't hold up the caller
+        let started = Instant::now();
+        while bridge.pending.is_empty() {
+            assert_eq!(bridge.accept().unwrap(), 0);
+        }
+        assert_eq!(bridge.accept().unwrap(), 0);
+        assert!(started.elapsed() < HANDSHAKE_TIMEOUT);
+
+        browser.write_all(rest).unwrap();
+        while bridge.accept().unwrap() == 0 {
+            std::thread::yield_now();
+        }
+        assert!(bridge.pending.is_empty());
+        let response = read_response(&mut browser);
+        assert!(response.starts_with("HTTP/1.1 101"));
+        assert!(response.contains("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+        // Not a WebSocket client at all
+        let mut browser =
+            TcpStream::connect(bridge.local_addr().unwrap()).unwrap();
+        browser.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        browser.set_nonblocking(true).unwrap();
+        let mut buf = [0_u8; 256];
+        loop {
+            assert_eq!(bridge.accept().unwrap(), 0);
+            match browser.read(&mut buf) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) => assert_eq!(e.kind(), ErrorKind::WouldBlock),
+            }
+        }
+        assert!(bridge.pending.is_empty());
+        assert_eq!(bridge.clients().count(), 1);
+    }
+
+    #[test]
+    fn bridge_round_trip() {
+        let mut bridge = WsBridge::bind("127.0.0.1:0").unwrap();
+        let mut browser =
+            TcpStream::connect(bridge.local_addr().unwrap()).unwrap();
+        browser.write_all(HANDSHAKE).unwrap();
+        while bridge.accept().unwrap() == 0 {
+            std::thread::yield_now();
+        }
+        assert!(read_response(&mut browser).starts_with("HTTP/1.1 101"));
+
+        let msg = MessageBuilder::get(0x41, &[1, 0, 128]).build().unwrap();
+        bridge.publish(&msg).unwrap();
+        let mut header = [0_u8; 2];
+        browser.read_exact(&mut header).unwrap();
+        assert_eq!(header[0], 0x80 | OPCODE_TEXT);
+        let mut json = std::vec![0; header[1] as usize];
+        browser.read_exact(&mut json).unwrap();
+        assert_eq!(
+            json,
+            br#"{"address":65,"ua":0,"message_type":"get","payload":[1,0,128]}"#
+                .to_vec()
+        );
+
+        let command = br#"{"address":66,"outputs":[255,0]}"#;
+        browser
+            .write_all(&client_frame(OPCODE_TEXT, command))
+            .unwrap();
+        browser
+            .write_all(&client_frame(OPCODE_TEXT, b"{}"))
+            .unwrap();
+        let mut commands = Vec::new();
+        while commands.is_empty() {
+            commands = bridge.poll_commands();
+        }
+        assert_eq!(commands[0].address, Some(0x42));
+        assert_eq!(commands[0].message_type, Some(MessageType::Set));
+        assert_eq!(commands[0].payload[..commands[0].len], [255, 0]);
+        while bridge.bad_commands() == 0 {
+            bridge.poll_commands();
+        }
+
+        browser.write_all(&client_frame(OPCODE_CLOSE, &[])).unwrap();
+        while bridge.clients().count() > 0 {
+            bridge.poll_commands();
+        }
+        browser.read_exact(&mut header).unwrap();
+        assert_eq!(header[0], 0x80 | OPCODE_CLOSE);
+    }
+}